@@ -1,8 +1,23 @@
+use crate::state::{state_fields, take, StateField};
 use crate::utils::pack_bits;
 
 pub const AUDIO_FREQUENCY: u32 = 44_100;
 const CPU_CLOCK: u32 = 4_194_304;
 const SAMPLE_PERIOD: u16 = (CPU_CLOCK / AUDIO_FREQUENCY) as u16; // CPU clock / host audio buffer
+const LEVELS_PERIOD: u32 = 70224; // Ticks in a frame
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK / 512; // Length, envelope and sweep are only clocked on multiples of this
+
+// Bits of the registers 0xFF10-0xFF2F that cannot be read and always return 1
+#[rustfmt::skip]
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Unused
+];
+const SOFT_CLIP_KNEE: f32 = 0.8; // Samples below this amplitude are not altered by the soft clipper
 const NOISE_DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
 const SQUARE_WAVES_DUTY: [[u8; 8]; 4] = [
     [0, 0, 0, 0, 0, 0, 0, 1],
@@ -11,6 +26,51 @@ const SQUARE_WAVES_DUTY: [[u8; 8]; 4] = [
     [0, 1, 1, 1, 1, 1, 1, 0],
 ];
 
+const STATE_VERSION: u8 = 1; // Version of the ApuState layout, increased when the channel state changes
+
+// Limiter applied on the mixed samples to keep them in [-1.0, 1.0]
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub enum Limiter {
+    #[default]
+    HardClamp,
+    SoftClip,
+}
+
+impl Limiter {
+    pub fn apply(self, sample: f32) -> f32 {
+        match self {
+            Limiter::HardClamp => sample.clamp(-1.0, 1.0),
+            Limiter::SoftClip => {
+                // Linear up to the knee, then smoothly compressed towards 1.0
+                let amplitude = sample.abs();
+                if amplitude <= SOFT_CLIP_KNEE {
+                    sample
+                } else {
+                    let range = 1.0 - SOFT_CLIP_KNEE;
+                    sample.signum() * (SOFT_CLIP_KNEE + range * ((amplitude - SOFT_CLIP_KNEE) / range).tanh())
+                }
+            }
+        }
+    }
+}
+
+// Advance a frequency timer by the given ticks, and return how many times it expired and was reloaded with `period`
+fn advance_timer(timer: &mut u16, period: u16, ticks: u32) -> u32 {
+    let period = period.max(1) as u32;
+    let first = (*timer).max(1) as u32; // A timer at 0 expires on the next tick
+    if ticks < first {
+        *timer -= ticks as u16;
+        return 0;
+    }
+    let rest = ticks - first;
+    *timer = (period - rest % period) as u16;
+    1 + rest / period
+}
+
+// Serialized registers and internal state of the channels, restored with `APU::restore`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApuState(pub Vec<u8>);
+
 #[derive(Copy, Clone, Default)]
 struct ChGlobal {
     // NR50
@@ -32,6 +92,24 @@ struct ChGlobal {
     ch3_on: bool,
     ch4_on: bool,
 }
+state_fields!(ChGlobal {
+    volume_left,
+    volume_right,
+    ch1_left,
+    ch2_left,
+    ch3_left,
+    ch4_left,
+    ch1_right,
+    ch2_right,
+    ch3_right,
+    ch4_right,
+    audio_on,
+    ch1_on,
+    ch2_on,
+    ch3_on,
+    ch4_on
+});
+
 impl ChGlobal {
     fn r(&self, addr: u16) -> u8 {
         match addr {
@@ -67,9 +145,6 @@ impl ChGlobal {
                 self.ch2_right = val & 0b0000_0010 != 0;
                 self.ch1_right = val & 0b0000_0001 != 0;
             }
-            0xFF26 => {
-                self.audio_on = val & 0b1000_0000 != 0;
-            }
             _ => panic!("Address {:#06x} not part of global channel", addr),
         }
     }
@@ -132,6 +207,30 @@ struct ChPulse {
     frequency_shadow: u16,
     duty_wave_position: u8,
 }
+state_fields!(ChPulse {
+    sweep_period,
+    sweep_direction,
+    sweep_shift,
+    duty_wave,
+    length_load,
+    initial_volume,
+    envelope_direction,
+    envelope_period,
+    frequency,
+    trigger,
+    length_enabled,
+    enabled,
+    dac_enabled,
+    volume,
+    sweep_enabled,
+    sweep_timer,
+    length_timer,
+    envelope_timer,
+    frequency_timer,
+    frequency_shadow,
+    duty_wave_position
+});
+
 impl ChPulse {
     fn r(&self, addr: u16) -> u8 {
         match addr {
@@ -197,6 +296,11 @@ impl ChPulse {
         }
     }
 
+    fn advance(&mut self, ticks: u32) {
+        let steps = advance_timer(&mut self.frequency_timer, (2048 - self.frequency) * 4, ticks);
+        self.duty_wave_position = ((self.duty_wave_position as u32 + steps) % 8) as u8;
+    }
+
     fn compute_sweep(&mut self) -> u16 {
         let mut frequency_new = self.frequency_shadow >> self.sweep_shift;
         if self.sweep_direction {
@@ -210,8 +314,11 @@ impl ChPulse {
         frequency_new
     }
 
-    fn step(&mut self, ticks: u32) -> f32 {
+    fn step(&mut self, ticks: u32, elapsed: u32) -> f32 {
         if self.enabled && self.dac_enabled {
+            // Advance up to the last tick, the frame sequencer can only be clocked on the last one
+            self.advance(elapsed - 1);
+
             // Clock length timer at 256Hz
             if ticks % (CPU_CLOCK / 256) == 0 {
                 if self.length_enabled && self.length_timer > 0 {
@@ -264,13 +371,7 @@ impl ChPulse {
             }
 
             // Move to the next duty step
-            if self.frequency_timer > 0 {
-                self.frequency_timer -= 1;
-            }
-            if self.frequency_timer == 0 {
-                self.frequency_timer = (2048 - self.frequency) * 4;
-                self.duty_wave_position = (self.duty_wave_position + 1) % 8;
-            }
+            self.advance(1);
 
             // Get sample from current duty wave position
             let sample = self.volume * SQUARE_WAVES_DUTY[self.duty_wave as usize][self.duty_wave_position as usize];
@@ -302,7 +403,22 @@ struct ChWave {
     frequency_timer: u16,
     wave_ram: [u8; 0x10],
     wave_position: u8,
+    cgb_mode: bool,
 }
+state_fields!(ChWave {
+    dac_enabled,
+    length_load,
+    volume,
+    frequency,
+    trigger,
+    length_enabled,
+    enabled,
+    length_timer,
+    frequency_timer,
+    wave_ram,
+    wave_position
+});
+
 impl ChWave {
     fn r(&self, addr: u16) -> u8 {
         match addr {
@@ -311,7 +427,10 @@ impl ChWave {
             0xFF1C => self.volume << 5,
             0xFF1D => self.frequency as u8,
             0xFF1E => (self.trigger as u8) << 7 | (self.length_enabled as u8) << 6 | ((self.frequency >> 8) as u8),
-            0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize],
+            0xFF30..=0xFF3F => match self.wave_ram_addr(addr) {
+                Some(idx) => self.wave_ram[idx],
+                None => 0xFF,
+            },
             _ => panic!("Address {:#06x} not part of wave channel", addr),
         }
     }
@@ -345,13 +464,36 @@ impl ChWave {
                     self.wave_position = 0;
                 }
             }
-            0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize] = val,
+            0xFF30..=0xFF3F => {
+                if let Some(idx) = self.wave_ram_addr(addr) {
+                    self.wave_ram[idx] = val
+                }
+            }
             _ => panic!("Address {:#06x} not part of wave channel", addr),
         }
     }
 
-    fn step(&mut self, ticks: u32) -> f32 {
+    fn advance(&mut self, ticks: u32) {
+        let steps = advance_timer(&mut self.frequency_timer, (2048 - self.frequency) * 2, ticks);
+        self.wave_position = ((self.wave_position as u32 + steps) % 32) as u8;
+    }
+
+    fn wave_ram_addr(&self, addr: u16) -> Option<usize> {
+        if !(self.enabled && self.dac_enabled) {
+            Some((addr - 0xFF30) as usize)
+        } else if self.cgb_mode {
+            // While playing, CGB redirects any access to the byte currently being read by the channel
+            Some(self.wave_position as usize / 2)
+        } else {
+            // While playing, DMG blocks the access
+            None
+        }
+    }
+
+    fn step(&mut self, ticks: u32, elapsed: u32) -> f32 {
         if self.enabled && self.dac_enabled {
+            self.advance(elapsed - 1);
+
             // Clock length timer at 256Hz
             if ticks % (CPU_CLOCK / 256) == 0 {
                 if self.length_enabled && self.length_timer > 0 {
@@ -363,13 +505,7 @@ impl ChWave {
             }
 
             // Move to the next wave step
-            if self.frequency_timer > 0 {
-                self.frequency_timer -= 1;
-            }
-            if self.frequency_timer == 0 {
-                self.frequency_timer = (2048 - self.frequency) * 2;
-                self.wave_position = (self.wave_position + 1) % 32;
-            }
+            self.advance(1);
 
             // Get sample from high or low nibble based on position
             let mut sample = self.wave_ram[self.wave_position as usize / 2];
@@ -418,6 +554,25 @@ struct ChNoise {
     envelope_timer: u8,
     lfsr: u16,
 }
+state_fields!(ChNoise {
+    length_load,
+    initial_volume,
+    envelope_direction,
+    envelope_period,
+    lfsr_shift,
+    lfsr_width,
+    lfsr_divisor_code,
+    trigger,
+    length_enabled,
+    enabled,
+    dac_enabled,
+    volume,
+    length_timer,
+    frequency_timer,
+    envelope_timer,
+    lfsr
+});
+
 impl ChNoise {
     fn r(&self, addr: u16) -> u8 {
         match addr {
@@ -470,8 +625,24 @@ impl ChNoise {
         }
     }
 
-    fn step(&mut self, ticks: u32) -> f32 {
+    fn advance(&mut self, ticks: u32) {
+        let period = NOISE_DIVISORS[self.lfsr_divisor_code as usize] << self.lfsr_shift;
+        for _ in 0..advance_timer(&mut self.frequency_timer, period, ticks) {
+            // Xor the last two bits of the LFSR, shift LFSR and put the xor result in the first bit
+            let xor_bit = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
+            self.lfsr >>= 1;
+            self.lfsr |= xor_bit << 15;
+            if self.lfsr_width {
+                self.lfsr &= 0xFF7F;
+                self.lfsr |= xor_bit << 7;
+            }
+        }
+    }
+
+    fn step(&mut self, ticks: u32, elapsed: u32) -> f32 {
         if self.enabled && self.dac_enabled {
+            self.advance(elapsed - 1);
+
             // Clock length timer at 256Hz
             if ticks % (CPU_CLOCK / 256) == 0 {
                 if self.length_enabled && self.length_timer > 0 {
@@ -503,21 +674,7 @@ impl ChNoise {
             }
 
             // Move to the next wave step
-            if self.frequency_timer > 0 {
-                self.frequency_timer -= 1;
-            }
-            if self.frequency_timer == 0 {
-                self.frequency_timer = NOISE_DIVISORS[self.lfsr_divisor_code as usize] << self.lfsr_shift;
-
-                // Xor the last two bits of the LFSR, shift LFSR and put the xor result in the first bit
-                let xor_bit = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
-                self.lfsr >>= 1;
-                self.lfsr |= xor_bit << 15;
-                if self.lfsr_width {
-                    self.lfsr &= 0xFF7F;
-                    self.lfsr |= xor_bit << 7;
-                }
-            }
+            self.advance(1);
 
             // Get sample from current LSFR bit
             let sample = self.volume * ((self.lfsr & 0x1) == 0) as u8;
@@ -544,45 +701,80 @@ pub struct APU {
     sample_right_sum: f32,
     sample_count: u16,
 
+    // Output levels measured over the last frame, for visualizers
+    level_sums: [f32; 4],
+    level_peaks: (f32, f32),
+    level_count: u32,
+    channel_levels: [f32; 4],
+    output_levels: (f32, f32),
+
+    master_volume: f32,           // Output gain in [0.0, 1.0], applied after the limiter
+    fade_out: Option<(u32, u32)>, // Remaining and total samples of the fade-out to silence
+    fast_mode: bool,              // Advance the channels up to the next sample at once instead of on every tick
+    pub enabled: bool,            // If disabled, the APU is not emulated and no samples are produced
+    pub limiter: Limiter,
     pub buffer: Vec<f32>,
 }
 
 impl APU {
-    pub fn new() -> Self {
+    pub fn new(cgb_mode: bool) -> Self {
         Self {
             ch_global: ChGlobal::default(),
             ch1: ChPulse::default(),
             ch2: ChPulse::default(),
-            ch3: ChWave::default(),
+            ch3: ChWave {
+                cgb_mode,
+                ..ChWave::default()
+            },
             ch4: ChNoise::default(),
             ticks: 0,
             sample_left_sum: 0.0,
             sample_right_sum: 0.0,
             sample_count: 0,
+            level_sums: [0.0; 4],
+            level_peaks: (0.0, 0.0),
+            level_count: 0,
+            channel_levels: [0.0; 4],
+            output_levels: (0.0, 0.0),
+            master_volume: 1.0,
+            fade_out: None,
+            fast_mode: false,
+            enabled: true,
+            limiter: Limiter::default(),
             buffer: Vec::with_capacity(AUDIO_FREQUENCY as usize * 2),
         }
     }
 
     pub fn r(&self, addr: u16) -> u8 {
         match addr {
-            0xFF10..=0xFF14 => self.ch1.r(addr),
-            0xFF15..=0xFF19 => self.ch2.r(addr - 0x0005),
-            0xFF1A..=0xFF1E => self.ch3.r(addr),
-            0xFF1F..=0xFF23 => self.ch4.r(addr),
-            0xFF24..=0xFF26 => self.ch_global.r(addr),
-            0xFF27..=0xFF2F => 0, // Unused
+            0xFF10..=0xFF14 => self.ch1.r(addr) | READ_MASKS[(addr - 0xFF10) as usize],
+            0xFF15..=0xFF19 => self.ch2.r(addr - 0x0005) | READ_MASKS[(addr - 0xFF10) as usize],
+            0xFF1A..=0xFF1E => self.ch3.r(addr) | READ_MASKS[(addr - 0xFF10) as usize],
+            0xFF1F..=0xFF23 => self.ch4.r(addr) | READ_MASKS[(addr - 0xFF10) as usize],
+            0xFF24..=0xFF26 => self.ch_global.r(addr) | READ_MASKS[(addr - 0xFF10) as usize],
+            0xFF27..=0xFF2F => 0xFF, // Unused
             0xFF30..=0xFF3F => self.ch3.r(addr),
             _ => panic!("Address {:#06x} not part of APU", addr),
         }
     }
 
     pub fn w(&mut self, addr: u16, val: u8) {
+        // While powered off only NR52 and the wave RAM are writable, and on DMG also the length timers
+        let val = match addr {
+            _ if self.ch_global.audio_on => val,
+            0xFF26 | 0xFF30..=0xFF3F => val,
+            0xFF11 if !self.ch3.cgb_mode => (self.ch1.r(addr) & 0xC0) | (val & 0x3F),
+            0xFF16 if !self.ch3.cgb_mode => (self.ch2.r(addr - 0x0005) & 0xC0) | (val & 0x3F),
+            0xFF1B | 0xFF20 if !self.ch3.cgb_mode => val,
+            _ => return,
+        };
         match addr {
             0xFF10..=0xFF14 => self.ch1.w(addr, val),
             0xFF15..=0xFF19 => self.ch2.w(addr - 0x0005, val),
             0xFF1A..=0xFF1E => self.ch3.w(addr, val),
             0xFF1F..=0xFF23 => self.ch4.w(addr, val),
-            0xFF24..=0xFF26 => self.ch_global.w(addr, val),
+            0xFF24..=0xFF25 => self.ch_global.w(addr, val),
+            0xFF26 => self.set_power(val & 0x80 != 0),
             0xFF27..=0xFF2F => (), // Unused
             0xFF30..=0xFF3F => self.ch3.w(addr, val),
             _ => panic!("Address {:#06x} not part of APU", addr),
@@ -590,28 +782,498 @@ impl APU {
         self.ch_global.update(&self.ch1, &self.ch2, &self.ch3, &self.ch4);
     }
 
+    fn set_power(&mut self, on: bool) {
+        if self.ch_global.audio_on && !on {
+            // Powering off clears all the registers, except the wave RAM and on DMG the length timers
+            let cgb_mode = self.ch3.cgb_mode;
+            let lengths = (
+                self.ch1.length_timer,
+                self.ch2.length_timer,
+                self.ch3.length_timer,
+                self.ch4.length_timer,
+            );
+            self.ch_global = ChGlobal::default();
+            self.ch1 = ChPulse::default();
+            self.ch2 = ChPulse::default();
+            self.ch3 = ChWave {
+                cgb_mode,
+                wave_ram: self.ch3.wave_ram,
+                ..ChWave::default()
+            };
+            self.ch4 = ChNoise::default();
+            if !cgb_mode {
+                (
+                    self.ch1.length_timer,
+                    self.ch2.length_timer,
+                    self.ch3.length_timer,
+                    self.ch4.length_timer,
+                ) = lengths;
+            }
+        } else if !self.ch_global.audio_on && on {
+            // Powering on restarts the frame sequencer
+            self.ticks = 0;
+        }
+        self.ch_global.audio_on = on;
+    }
+
     pub fn step(&mut self, elapsed_ticks: u16) {
+        if !self.enabled {
+            return;
+        }
         // The APU produces 1 sample per CPU cycle at 4.19MHZ, but the host audio buffer only supports 44.1KHz, so we need to saubsample by avg
-        for _ in 0..elapsed_ticks {
-            self.ticks = self.ticks.wrapping_add(1);
+        let mut remaining = elapsed_ticks as u32;
+        while remaining > 0 {
+            // In fast mode, advance up to the next output sample or frame sequencer clock and hold the last channel samples
+            let elapsed = if self.fast_mode {
+                let to_sequencer = FRAME_SEQUENCER_PERIOD - self.ticks % FRAME_SEQUENCER_PERIOD;
+                remaining.min((SAMPLE_PERIOD - self.sample_count) as u32).min(to_sequencer)
+            } else {
+                1
+            };
+            remaining -= elapsed;
+            self.ticks = self.ticks.wrapping_add(elapsed);
 
-            let ch1_sample = self.ch1.step(self.ticks);
-            let ch2_sample = self.ch2.step(self.ticks);
-            let ch3_sample = self.ch3.step(self.ticks);
-            let ch4_sample = self.ch4.step(self.ticks);
+            let ch1_sample = self.ch1.step(self.ticks, elapsed);
+            let ch2_sample = self.ch2.step(self.ticks, elapsed);
+            let ch3_sample = self.ch3.step(self.ticks, elapsed);
+            let ch4_sample = self.ch4.step(self.ticks, elapsed);
 
             let (sample_left, sample_right) = self.ch_global.mix(ch1_sample, ch2_sample, ch3_sample, ch4_sample);
 
-            self.sample_left_sum += sample_left;
-            self.sample_right_sum += sample_right;
-            self.sample_count += 1;
+            self.sample_left_sum += sample_left * elapsed as f32;
+            self.sample_right_sum += sample_right * elapsed as f32;
+            self.sample_count += elapsed as u16;
             if self.sample_count >= SAMPLE_PERIOD {
-                self.buffer.push(self.sample_left_sum / self.sample_count as f32);
-                self.buffer.push(self.sample_right_sum / self.sample_count as f32);
+                let (left, right) = (
+                    self.sample_left_sum / self.sample_count as f32,
+                    self.sample_right_sum / self.sample_count as f32,
+                );
+                // The levels are measured on the output samples, with the channel samples at the same time
+                self.update_levels(
+                    [ch1_sample, ch2_sample, ch3_sample, ch4_sample],
+                    (left, right),
+                    self.sample_count as u32,
+                );
+                let gain = self.master_volume * self.fade_out_gain();
+                self.buffer.push(self.limiter.apply(left) * gain);
+                self.buffer.push(self.limiter.apply(right) * gain);
                 self.sample_left_sum = 0.0;
                 self.sample_right_sum = 0.0;
                 self.sample_count = 0;
             }
         }
     }
+
+    pub fn master_volume(&self) -> f32 {
+        self.master_volume
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn begin_fade_out(&mut self, samples: u32) {
+        // Ramp the output down to silence over the given number of stereo samples, to avoid a click when stopping
+        let samples = samples.max(1);
+        self.fade_out = Some((samples, samples));
+    }
+
+    pub fn cancel_fade_out(&mut self) {
+        // Back to the full volume, for when the playback resumes after a fade-out
+        self.fade_out = None;
+    }
+
+    pub fn fade_out_done(&self) -> bool {
+        // No samples are produced with the APU disabled, so there is nothing to fade out
+        matches!(self.fade_out, Some((0, _))) || (self.fade_out.is_some() && !self.enabled)
+    }
+
+    fn fade_out_gain(&mut self) -> f32 {
+        match &mut self.fade_out {
+            Some((remaining, total)) => {
+                *remaining = remaining.saturating_sub(1);
+                *remaining as f32 / *total as f32
+            }
+            None => 1.0,
+        }
+    }
+
+    pub fn fast_mode(&self) -> bool {
+        self.fast_mode
+    }
+
+    pub fn snapshot(&self) -> ApuState {
+        // Channel state and the frame sequencer and sample timing, the settings of the frontend are not included
+        let mut data = vec![STATE_VERSION];
+        self.ch_global.save(&mut data);
+        self.ch1.save(&mut data);
+        self.ch2.save(&mut data);
+        self.ch3.save(&mut data);
+        self.ch4.save(&mut data);
+        self.ticks.save(&mut data);
+        self.sample_left_sum.save(&mut data);
+        self.sample_right_sum.save(&mut data);
+        self.sample_count.save(&mut data);
+        ApuState(data)
+    }
+
+    pub fn restore(&mut self, state: &ApuState) -> Result<(), String> {
+        let mut data = state.0.as_slice();
+        let version = take::<1>(&mut data)?[0];
+        if version != STATE_VERSION {
+            return Err(format!("Unsupported APU state version {}", version));
+        }
+        // Load into a copy, so that the APU is unchanged if the state is invalid
+        let mut apu = self.clone();
+        apu.ch_global.load(&mut data)?;
+        apu.ch1.load(&mut data)?;
+        apu.ch2.load(&mut data)?;
+        apu.ch3.load(&mut data)?;
+        apu.ch4.load(&mut data)?;
+        apu.ticks.load(&mut data)?;
+        apu.sample_left_sum.load(&mut data)?;
+        apu.sample_right_sum.load(&mut data)?;
+        apu.sample_count.load(&mut data)?;
+        if !data.is_empty() {
+            return Err(String::from("APU state too long"));
+        }
+        *self = apu;
+        Ok(())
+    }
+
+    pub fn set_fast_mode(&mut self, enabled: bool) {
+        self.fast_mode = enabled;
+    }
+
+    fn update_levels(&mut self, samples: [f32; 4], (left, right): (f32, f32), elapsed: u32) {
+        // Accumulate the squared channel samples and the mixed peaks, then publish them once per frame
+        for (sum, sample) in self.level_sums.iter_mut().zip(samples) {
+            *sum += sample * sample * elapsed as f32;
+        }
+        self.level_peaks = (self.level_peaks.0.max(left.abs()), self.level_peaks.1.max(right.abs()));
+        self.level_count += elapsed;
+        if self.level_count >= LEVELS_PERIOD {
+            self.channel_levels = self.level_sums.map(|sum| (sum / self.level_count as f32).sqrt());
+            self.output_levels = self.level_peaks;
+            self.level_sums = [0.0; 4];
+            self.level_peaks = (0.0, 0.0);
+            self.level_count = 0;
+        }
+    }
+
+    pub fn channel_levels(&self) -> [f32; 4] {
+        // RMS amplitude of each channel over the last frame, in [0.0, 1.0]
+        self.channel_levels
+    }
+
+    pub fn output_levels(&self) -> (f32, f32) {
+        // Peak amplitude of the left and right outputs over the last frame, in [0.0, 1.0]
+        self.output_levels
+    }
+}
+
+// The APU is part of the emulator state through its snapshot
+impl StateField for APU {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.snapshot().0.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut state = Vec::new();
+        state.load(data)?;
+        self.restore(&ApuState(state))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ApuState, Limiter, APU, LEVELS_PERIOD, SOFT_CLIP_KNEE};
+
+    #[test]
+    fn read_masks() {
+        let mut apu = APU::new(false);
+        apu.w(0xFF26, 0x80);
+        for addr in (0xFF10..=0xFF25).filter(|addr| *addr != 0xFF15 && *addr != 0xFF1F) {
+            apu.w(addr, 0x00);
+        }
+        #[rustfmt::skip]
+        let expected = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF,
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+            0xFF, 0xFF, 0x00, 0x00, 0xBF,
+            0x00, 0x00, 0xF0,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let read: Vec<u8> = (0xFF10..=0xFF2F).map(|addr| apu.r(addr)).collect();
+        assert_eq!(read, expected);
+
+        // Readable bits are returned as written
+        apu.w(0xFF11, 0xC5); // Duty 3, length 5
+        assert_eq!(apu.r(0xFF11), 0xFF);
+        apu.w(0xFF16, 0x45);
+        assert_eq!(apu.r(0xFF16), 0x7F);
+        apu.w(0xFF12, 0xA3);
+        assert_eq!(apu.r(0xFF12), 0xA3);
+        apu.w(0xFF14, 0x40); // Length enable, no trigger
+        assert_eq!(apu.r(0xFF14), 0xFF);
+        apu.w(0xFF1C, 0x40);
+        assert_eq!(apu.r(0xFF1C), 0xDF);
+    }
+
+    #[test]
+    fn limiter() {
+        for limiter in [Limiter::HardClamp, Limiter::SoftClip] {
+            let mut prev = 0.0;
+            for i in 0..=400 {
+                let sample = i as f32 / 100.0;
+                let (pos, neg) = (limiter.apply(sample), limiter.apply(-sample));
+                assert!((-1.0..=1.0).contains(&pos), "{:?} {} -> {}", limiter, sample, pos);
+                assert_eq!(neg, -pos);
+                assert!(pos >= prev);
+                prev = pos;
+            }
+            assert_eq!(limiter.apply(0.5), 0.5);
+        }
+        assert_eq!(Limiter::HardClamp.apply(1.5), 1.0);
+        assert!(Limiter::SoftClip.apply(1.5) < 1.0);
+        assert!(Limiter::SoftClip.apply(1.0) > SOFT_CLIP_KNEE);
+    }
+
+    #[test]
+    fn channel_levels() {
+        let mut apu = APU::new(false);
+        apu.w(0xFF26, 0x80); // Power on
+        apu.w(0xFF24, 0x77); // Max volume
+        apu.w(0xFF25, 0xFF); // All channels on both outputs
+        apu.w(0xFF16, 0x80); // 50% duty
+        apu.w(0xFF17, 0xF0); // Volume 15, no envelope
+        apu.w(0xFF18, 0x00);
+        apu.w(0xFF19, 0x87); // Trigger
+        for _ in 0..(2 * LEVELS_PERIOD / 456) {
+            apu.step(456);
+        }
+        let levels = apu.channel_levels();
+        assert!(levels[1] > 0.1 && levels[1] <= 1.0, "{:?}", levels);
+        assert_eq!([levels[0], levels[2], levels[3]], [0.0; 3]);
+        let (left, right) = apu.output_levels();
+        assert!(left > 0.0 && left <= 1.0);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn wave_ram_access_while_playing() {
+        // DMG: accesses are blocked while the channel is playing
+        let mut apu = APU::new(false);
+        apu.w(0xFF26, 0x80); // Power on
+        apu.w(0xFF30, 0x12);
+        apu.w(0xFF31, 0x34);
+        assert_eq!(apu.r(0xFF30), 0x12);
+        apu.w(0xFF1A, 0x80); // DAC on
+        apu.w(0xFF1E, 0x80); // Trigger
+        assert_eq!(apu.r(0xFF30), 0xFF);
+        assert_eq!(apu.r(0xFF31), 0xFF);
+        apu.w(0xFF31, 0x56);
+        apu.w(0xFF1A, 0x00); // DAC off
+        assert_eq!(apu.r(0xFF30), 0x12);
+        assert_eq!(apu.r(0xFF31), 0x34);
+
+        // CGB: accesses are redirected to the byte currently being played
+        let mut apu = APU::new(true);
+        apu.w(0xFF26, 0x80);
+        apu.w(0xFF30, 0x12);
+        apu.w(0xFF31, 0x34);
+        apu.w(0xFF1A, 0x80);
+        apu.w(0xFF1E, 0x80);
+        assert_eq!(apu.r(0xFF31), 0x12);
+        apu.w(0xFF31, 0x56);
+        apu.w(0xFF1A, 0x00);
+        assert_eq!(apu.r(0xFF30), 0x56);
+        assert_eq!(apu.r(0xFF31), 0x34);
+    }
+
+    #[test]
+    fn master_volume() {
+        let run = |volume: f32| {
+            let mut apu = APU::new(false);
+            apu.set_master_volume(volume);
+            apu.w(0xFF26, 0x80); // Power on
+            apu.w(0xFF24, 0x77); // Max volume
+            apu.w(0xFF25, 0xFF); // All channels on both outputs
+            apu.w(0xFF16, 0x80); // 50% duty
+            apu.w(0xFF17, 0xF0); // Volume 15, no envelope
+            apu.w(0xFF19, 0x87); // Trigger
+            for _ in 0..(LEVELS_PERIOD / 456) {
+                apu.step(456);
+            }
+            apu.buffer
+        };
+        let (full, half) = (run(1.0), run(0.5));
+        assert!(full.iter().any(|sample| *sample != 0.0));
+        assert_eq!(full.len(), half.len());
+        for (full, half) in full.iter().zip(half) {
+            assert_eq!(full * 0.5, half);
+        }
+        // Out of range values are clamped
+        let mut apu = APU::new(false);
+        apu.set_master_volume(1.5);
+        assert_eq!(apu.master_volume(), 1.0);
+        apu.set_master_volume(-1.0);
+        assert_eq!(apu.master_volume(), 0.0);
+    }
+
+    #[test]
+    fn fade_out() {
+        let run = |fade_out: Option<u32>| {
+            let mut apu = APU::new(false);
+            let step_levels = |apu: &mut APU| {
+                for _ in 0..(LEVELS_PERIOD / 456) {
+                    apu.step(456);
+                }
+            };
+            apu.w(0xFF26, 0x80); // Power on
+            apu.w(0xFF24, 0x77); // Max volume
+            apu.w(0xFF25, 0xFF); // All channels on both outputs
+            apu.w(0xFF16, 0x80); // 50% duty
+            apu.w(0xFF17, 0xF0); // Volume 15, no envelope
+            apu.w(0xFF19, 0x87); // Trigger
+            if let Some(samples) = fade_out {
+                apu.begin_fade_out(samples);
+                assert!(!apu.fade_out_done());
+            }
+            step_levels(&mut apu);
+            let done = apu.fade_out_done();
+            // The sound comes back once the fade-out is cancelled
+            let len = apu.buffer.len();
+            apu.cancel_fade_out();
+            assert!(!apu.fade_out_done());
+            step_levels(&mut apu);
+            assert!(apu.buffer[len..].iter().any(|sample| *sample != 0.0));
+            apu.buffer.truncate(len);
+            (done, apu.buffer)
+        };
+        let ((_, full), (done, faded)) = (run(None), run(Some(100)));
+        assert!(done);
+        assert!(full.len() > 200);
+        // The gain decreases on every sample and reaches zero after 100 samples
+        let gains: Vec<f32> = (0..100).map(|i| (99 - i) as f32 / 100.0).collect();
+        for (i, gain) in gains.iter().enumerate() {
+            assert_eq!(faded[i * 2], full[i * 2] * gain);
+            assert_eq!(faded[i * 2 + 1], full[i * 2 + 1] * gain);
+        }
+        assert!(gains.windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(gains[99], 0.0);
+        assert!(faded[200..].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn fast_mode() {
+        let run = |fast_mode: bool| {
+            let mut apu = APU::new(false);
+            apu.set_fast_mode(fast_mode);
+            apu.w(0xFF26, 0x80); // Power on
+            apu.w(0xFF24, 0x77); // Max volume
+            apu.w(0xFF25, 0xFF); // All channels on both outputs
+            apu.w(0xFF16, 0x80); // 50% duty
+            apu.w(0xFF17, 0xF0); // Volume 15, no envelope
+            apu.w(0xFF18, 0x00);
+            apu.w(0xFF19, 0x87); // Trigger, ~512Hz
+            for _ in 0..(LEVELS_PERIOD / 4) {
+                apu.step(4 * 4); // Like a stream of 4-cycle instructions
+            }
+            (apu.buffer.clone(), apu.channel_levels())
+        };
+        let ((accurate, accurate_levels), (fast, fast_levels)) = (run(false), run(true));
+        assert_eq!(accurate.len(), fast.len());
+        // Only the samples around the duty edges differ
+        let mismatches = accurate.iter().zip(&fast).filter(|(a, f)| (*a - *f).abs() > 0.01).count();
+        assert!(mismatches < accurate.len() / 10, "{} of {}", mismatches, accurate.len());
+        assert!((accurate_levels[1] - fast_levels[1]).abs() < 0.01);
+    }
+
+    #[test]
+    fn power_off() {
+        for cgb_mode in [false, true] {
+            let mut apu = APU::new(cgb_mode);
+            apu.w(0xFF26, 0x80);
+            apu.w(0xFF24, 0x77);
+            apu.w(0xFF25, 0xFF);
+            apu.w(0xFF30, 0x12);
+            apu.w(0xFF16, 0x80); // 50% duty
+            apu.w(0xFF17, 0xF0);
+            apu.w(0xFF19, 0x87); // Trigger
+            assert_eq!(apu.r(0xFF26), 0xF2);
+
+            // Powering off clears the registers and disables the channels, the wave RAM is kept
+            apu.w(0xFF26, 0x00);
+            assert_eq!(apu.r(0xFF26), 0x70);
+            assert_eq!(
+                [apu.r(0xFF24), apu.r(0xFF25), apu.r(0xFF16), apu.r(0xFF17)],
+                [0x00, 0x00, 0x3F, 0x00]
+            );
+            assert_eq!(apu.r(0xFF30), 0x12);
+
+            // Writes are ignored while off, except the wave RAM and on DMG the length timers
+            apu.w(0xFF24, 0x77);
+            apu.w(0xFF17, 0xF0);
+            apu.w(0xFF16, 0xC5);
+            apu.w(0xFF30, 0x34);
+            assert_eq!([apu.r(0xFF24), apu.r(0xFF17), apu.r(0xFF30)], [0x00, 0x00, 0x34]);
+            assert_eq!(apu.ch2.length_timer, if cgb_mode { 0 } else { 64 - 5 });
+            assert_eq!(apu.ch2.duty_wave, 0);
+
+            // Powering on makes the registers writable again, starting from the cleared state
+            apu.w(0xFF26, 0x80);
+            assert_eq!(apu.r(0xFF26), 0xF0);
+            assert_eq!(apu.r(0xFF24), 0x00);
+            apu.w(0xFF24, 0x77);
+            assert_eq!(apu.r(0xFF24), 0x77);
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut apu = APU::new(false);
+        apu.w(0xFF26, 0x80);
+        apu.w(0xFF25, 0xFF); // All channels on both outputs
+        apu.w(0xFF24, 0x77);
+        for (addr, val) in [(0xFF10, 0x16), (0xFF11, 0x80), (0xFF12, 0xF3), (0xFF13, 0x40), (0xFF14, 0x86)] {
+            apu.w(addr, val); // Pulse with sweep and envelope
+        }
+        for addr in 0xFF30..=0xFF3F {
+            apu.w(addr, (addr as u8).wrapping_mul(0x37));
+        }
+        for (addr, val) in [(0xFF1A, 0x80), (0xFF1C, 0x20), (0xFF1D, 0x00), (0xFF1E, 0x87)] {
+            apu.w(addr, val); // Wave
+        }
+        for (addr, val) in [(0xFF21, 0xF1), (0xFF22, 0x2B), (0xFF23, 0x80)] {
+            apu.w(addr, val); // Noise with 7-bit LFSR
+        }
+        apu.step(12345);
+        let state = apu.snapshot();
+        let mut reference = apu.clone();
+
+        // Take a detour, then restore the state
+        apu.step(20000);
+        apu.w(0xFF12, 0x00);
+        apu.w(0xFF23, 0x80);
+        apu.restore(&state).unwrap();
+        apu.buffer.clear();
+        reference.buffer.clear();
+        for _ in 0..40 {
+            apu.step(1000);
+            reference.step(1000);
+        }
+        assert!(!reference.buffer.is_empty());
+        assert!(apu.buffer == reference.buffer);
+        assert_eq!(apu.snapshot(), reference.snapshot());
+
+        // Invalid states leave the APU unchanged
+        let mut data = state.0.clone();
+        data[0] = 0xFF;
+        assert!(apu.restore(&ApuState(data)).is_err());
+        assert!(apu.restore(&ApuState(state.0[..20].to_vec())).is_err());
+        assert!(apu.restore(&ApuState([state.0.as_slice(), &[0]].concat())).is_err());
+        assert_eq!(apu.snapshot(), reference.snapshot());
+    }
 }
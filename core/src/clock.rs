@@ -1,4 +1,5 @@
 use crate::cpu::INT_TIMER;
+use crate::state::state_fields;
 
 #[derive(Copy, Clone)]
 pub struct Clock {
@@ -9,6 +10,13 @@ pub struct Clock {
     tma: u8,
     tac: u8,
 }
+state_fields!(Clock {
+    sysclock,
+    prev_edge_bit,
+    tima,
+    tma,
+    tac
+});
 
 impl Clock {
     pub fn new() -> Self {
@@ -25,12 +33,17 @@ impl Clock {
         (self.sysclock >> 8) as u8
     }
 
+    pub fn set_counter(&mut self, sysclock: u16) {
+        self.sysclock = sysclock;
+        self.prev_edge_bit = false;
+    }
+
     pub fn r(&self, addr: u16) -> u8 {
         match addr {
             0xFF04 => self.div(),
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
+            0xFF07 => self.tac | 0xF8, // Bits 3-7 are unused and read as 1
             _ => panic!("Address {:#06x} not part of clock", addr),
         }
     }
@@ -1,9 +1,13 @@
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
 
 use crate::debug;
 use crate::instructions::{load_opmaps, Instruction, Op, OPMAP_SIZE};
 use crate::mmu::MMU;
-use crate::registers::{Registers, CC, R16, R8};
+use crate::registers::{Registers, RegistersSnapshot, CC, R16, R8};
+use crate::state::state_fields;
 use crate::utils::{Get, Set};
 
 // Interrupts  as (bit masks, address), in order of priority
@@ -13,39 +17,211 @@ pub const INT_TIMER: (u8, u16) = (0x04, 0x0050);
 pub const INT_SERIAL: (u8, u16) = (0x08, 0x0058);
 pub const INT_JOYPAD: (u8, u16) = (0x10, 0x0060);
 
+// IO registers left by the DMG boot ROM, as read back at the cartridge entry point. The CGB boot ROM leaves the same
+// values, followed by POST_BOOT_IO_CGB for CGB games.
+// NR52 comes first to power on the APU, NR14 retriggers channel 1 at an inaudible frequency to leave it on.
+// Serial, STAT, LY, DMA and the uninitialized OBP0/OBP1 are not set.
+pub const POST_BOOT_IO: [(u16, u8); 30] = [
+    (0xFF26, 0xF1), // NR52
+    (0xFF00, 0xCF), // P1
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF47, 0xFC), // BGP
+];
+// CGB-only registers left by the CGB boot ROM for CGB games: single speed, VRAM and WRAM bank 0 (mapped as 1 in
+// 0xD000-0xDFFF), and the palette indexes wrapped back to 0 with auto-increment on after writing the palettes.
+pub const POST_BOOT_IO_CGB: [(u16, u8); 5] = [
+    (0xFF4D, 0x7E), // KEY1
+    (0xFF4F, 0xFE), // VBK
+    (0xFF70, 0xF8), // SVBK
+    (0xFF68, 0xC0), // BCPS
+    (0xFF6A, 0xC0), // OCPS
+];
+// Internal counter of DIV at the entry point, it depends on the boot duration. On CGB, DMG games take longer since the
+// boot ROM also selects their compatibility palette.
+pub const POST_BOOT_DIV_DMG: u16 = 0xABCC;
+pub const POST_BOOT_DIV_CGB: u16 = 0x1EA0;
+pub const POST_BOOT_DIV_CGB_COMPAT: u16 = 0x267C;
+
 #[derive(Clone)]
 pub struct CPU {
     pub reg: Registers,
     pub mmu: MMU,
     ime: bool,
     halt: bool,
+    halt_bug: bool, // HALT with IME=0 and an interrupt already pending, the next opcode is read twice
+    stop: bool,     // Stop mode, left when a button is pressed
 
     opmap: [Instruction; OPMAP_SIZE],
     opmap_cb: [Instruction; OPMAP_SIZE],
 
-    prev_op: Op,
+    ei_pending: bool, // IME is enabled after the instruction following EI
 
     // Debugging helper
     opcode_history: VecDeque<Op>,
+    pub trace: Option<Rc<RefCell<dyn Write>>>, // Output for the instruction trace in the Gameboy Doctor format
+
+    // Cycle-accurate mode, where the devices are ticked after each memory access instead of after each instruction
+    pub mcycle_mode: bool,
+    pending_ticks: Cell<u16>, // T-cycles of the last memory accesses not yet ticked
+    ticked: u16,              // T-cycles already ticked during the current instruction
 }
+// States are taken between instructions, when no cycles are pending
+state_fields!(CPU {
+    reg,
+    mmu,
+    ime,
+    halt,
+    halt_bug,
+    stop,
+    ei_pending
+});
 
 impl CPU {
-    pub fn new(rom: &[u8], force_dmg: bool) -> Self {
+    pub fn new(rom: &[u8], force_dmg: bool, boot_rom: Option<&[u8]>) -> Self {
         let (opmap, opmap_cb) = load_opmaps();
         Self {
             reg: Registers::new(),
-            mmu: MMU::new(rom, force_dmg),
+            mmu: MMU::new(rom, force_dmg, boot_rom),
             ime: false,
             halt: false,
+            halt_bug: false,
+            stop: false,
             opmap,
             opmap_cb,
-            prev_op: Op::INVALID,
+            ei_pending: false,
             opcode_history: VecDeque::new(),
+            trace: None,
+            mcycle_mode: false,
+            pending_ticks: Cell::new(0),
+            ticked: 0,
+        }
+    }
+
+    pub fn skip_boot(&mut self) {
+        // Jump straight to the cartridge entry point, with the state left behind by the boot ROM
+        self.mmu.mbc.boot_rom_unmounted = true;
+        self.reg.pc = 0x0100;
+        let (af, bc, de, hl) = if self.mmu.mbc.force_dmg() {
+            (0x01B0, 0x0013, 0x00D8, 0x014D)
+        } else if self.mmu.mbc.cgb_mode() {
+            (0x1180, 0x0000, 0xFF56, 0x000D)
+        } else {
+            (0x1180, 0x0000, 0x0008, 0x007C)
+        };
+        self.w(R16::AF, af);
+        self.w(R16::BC, bc);
+        self.w(R16::DE, de);
+        self.w(R16::HL, hl);
+        self.reg.sp = 0xFFFE;
+        let (cgb_io, div): (&[(u16, u8)], u16) = if self.mmu.mbc.force_dmg() {
+            (&[], POST_BOOT_DIV_DMG)
+        } else if self.mmu.mbc.cgb_mode() {
+            (&POST_BOOT_IO_CGB, POST_BOOT_DIV_CGB)
+        } else {
+            (&[], POST_BOOT_DIV_CGB_COMPAT)
+        };
+        for (addr, val) in POST_BOOT_IO.iter().chain(cgb_io) {
+            self.mmu.w(*addr, *val);
+        }
+        self.mmu.clock.set_counter(div);
+    }
+
+    pub fn registers(&self) -> RegistersSnapshot {
+        let reg = &self.reg;
+        RegistersSnapshot {
+            a: reg.a,
+            b: reg.b,
+            c: reg.c,
+            d: reg.d,
+            e: reg.e,
+            h: reg.h,
+            l: reg.l,
+            sp: reg.sp,
+            pc: reg.pc,
+            zero: reg.f.z,
+            subtract: reg.f.n,
+            half_carry: reg.f.h,
+            carry: reg.f.c,
+            ime: self.ime,
+            halt: self.halt,
+        }
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_registers(&mut self, regs: &RegistersSnapshot) {
+        let reg = &mut self.reg;
+        (reg.a, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l) = (regs.a, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l);
+        (reg.sp, reg.pc) = (regs.sp, regs.pc);
+        (reg.f.z, reg.f.n, reg.f.h, reg.f.c) = (regs.zero, regs.subtract, regs.half_carry, regs.carry);
+        (self.ime, self.halt, self.ei_pending) = (regs.ime, regs.halt, false);
+    }
+
+    fn sync(&mut self) {
+        // Tick the devices for the memory accesses done so far, before the next one
+        let ticks = self.pending_ticks.take();
+        if ticks > 0 {
+            self.mmu.tick(ticks);
+            self.ticked += ticks;
         }
     }
 
+    fn defer_tick(&self) {
+        // A memory access takes one M-cycle, ticked at the next access or at the end of the instruction
+        if self.mcycle_mode {
+            let ticks = if self.mmu.double_speed { 2 } else { 4 };
+            self.pending_ticks.set(self.pending_ticks.get() + ticks);
+        }
+    }
+
+    pub(crate) fn read(&mut self, addr: u16) -> u8 {
+        self.sync();
+        self.read_unsynced(addr)
+    }
+
+    pub(crate) fn read_unsynced(&self, addr: u16) -> u8 {
+        // Only valid when no access is pending, i.e. for the first (HL) access of an instruction
+        let val = self.mmu.r(addr);
+        self.defer_tick();
+        val
+    }
+
+    pub(crate) fn write(&mut self, addr: u16, val: u8) {
+        self.sync();
+        self.mmu.w(addr, val);
+        self.defer_tick();
+    }
+
     fn fetch(&mut self) -> u8 {
-        let val = self.mmu.r(self.reg.pc);
+        let val = self.read(self.reg.pc);
         self.reg.pc = self.reg.pc.wrapping_add(1);
         val
     }
@@ -57,12 +233,28 @@ impl CPU {
         // Handle interrupts, if any
         opcycles += self.handle_interrupts();
 
-        // If the CPU is in halted state, skip execution
-        if self.halt {
+        // If the CPU is in halted or stopped state, skip execution
+        if self.stop {
+            self.stop = self.mmu.joypad.get(0x00) & 0x0F == 0x0F;
+            opcycles += 1;
+        } else if self.halt {
             opcycles += 1;
         } else {
+            // Enable IME if the previous instruction was EI, so that interrupts are only serviced after this instruction
+            let ei_enabled = std::mem::take(&mut self.ei_pending);
+            self.ime |= ei_enabled;
+
+            // Log the state before the instruction is executed
+            if let Some(trace) = self.trace.as_ref().filter(|_| self.mmu.mbc.boot_rom_unmounted) {
+                trace.borrow_mut().write_all(debug::doctor_trace_line(self).as_bytes()).unwrap();
+            }
+
             // Load next OP from memory
+            let pc = self.reg.pc;
             let mut opcode_byte = self.fetch();
+            if std::mem::take(&mut self.halt_bug) {
+                self.reg.pc = self.reg.pc.wrapping_sub(1);
+            }
             let (mut opcode, mut extra_bytes, mut instr_opcycles) = self.opmap[opcode_byte as usize];
             opcycles += instr_opcycles;
 
@@ -87,22 +279,25 @@ impl CPU {
                 }
             }
 
+            // Tick the fetched bytes, so that (HL) can be read without a pending access
+            self.sync();
+
             // Run corresponding instruction
             match opcode {
                 Op::INVALID => panic!("Received INVALID instruction"),
                 Op::NOP => (),
-                Op::LD_R16_A(r) =>       self.mmu.w(self.r(r), self.reg.a),
-                Op::LD_I16_A =>          self.mmu.w(xword.unwrap(), self.reg.a),
-                Op::LD_HLID_A(sign) => { self.mmu.w(self.r(R16::HL), self.reg.a); self.inc16_(R16::HL, sign) },
-                Op::LDH_C_A =>           self.mmu.w(0xFF00 | self.reg.c as u16, self.reg.a),
-                Op::LDH_I8_A =>          self.mmu.w(0xFF00 | xbyte.unwrap() as u16, self.reg.a),
+                Op::LD_R16_A(r) =>       self.write(self.r(r), self.reg.a),
+                Op::LD_I16_A =>          self.write(xword.unwrap(), self.reg.a),
+                Op::LD_HLID_A(sign) => { self.write(self.r(R16::HL), self.reg.a); self.inc16_(R16::HL, sign) },
+                Op::LDH_C_A =>           self.write(0xFF00 | self.reg.c as u16, self.reg.a),
+                Op::LDH_I8_A =>          self.write(0xFF00 | xbyte.unwrap() as u16, self.reg.a),
                 Op::LD_R16_I16(r) =>     self.w(r, xword.unwrap()),
-                Op::LD_A_R16(r) =>       self.reg.a = self.mmu.r(self.r(r)),
-                Op::LD_A_I16 =>          self.reg.a = self.mmu.r(xword.unwrap()),
-                Op::LD_A_HLID(sign) => { self.reg.a = self.mmu.r(self.r(R16::HL)); self.inc16_(R16::HL, sign) },
-                Op::LDH_A_C =>           self.reg.a = self.mmu.r(0xFF00 | self.reg.c as u16),
-                Op::LDH_A_I8 =>          self.reg.a = self.mmu.r(0xFF00 | xbyte.unwrap() as u16),
-                Op::LD_I16_SP =>         self.mmu.ww(xword.unwrap(), self.r(R16::SP)),
+                Op::LD_A_R16(r) =>       self.reg.a = self.read(self.r(r)),
+                Op::LD_A_I16 =>          self.reg.a = self.read(xword.unwrap()),
+                Op::LD_A_HLID(sign) => { self.reg.a = self.read(self.r(R16::HL)); self.inc16_(R16::HL, sign) },
+                Op::LDH_A_C =>           self.reg.a = self.read(0xFF00 | self.reg.c as u16),
+                Op::LDH_A_I8 =>          self.reg.a = self.read(0xFF00 | xbyte.unwrap() as u16),
+                Op::LD_I16_SP =>         self.write16(xword.unwrap(), self.r(R16::SP)),
                 Op::LD_HL_SPI8 =>      { let res = self.add16i8(R16::SP, xbyte.unwrap()); self.w(R16::HL, res) },
                 Op::LD_SP_HL =>          self.w(R16::SP, self.r(R16::HL)),
                 Op::LD_R8_I8(r) =>       self.w(r, xbyte.unwrap()),
@@ -154,10 +349,10 @@ impl CPU {
                 Op::CALL_CC_I16(cc) => if self.r(cc) { self.call(xword.unwrap()); opcycles += 3; },
                 Op::RET_CC(cc) =>      if self.r(cc) { self.pop(R16::PC); opcycles += 3; },
 
-                Op::STOP => (),
-                Op::HALT => self.halt = true,
+                Op::STOP => self.stop_(xbyte.unwrap()),
+                Op::HALT => self.halt(ei_enabled),
                 Op::DI =>   self.ime = false,
-                Op::EI =>   (),
+                Op::EI =>   self.ei_pending = true,
 
                 Op::CB_PREFIX =>     panic!("CB prefix not handled"),
                 Op::CB_RLC_R8(r) =>  self.rot_(r, true, false, true),
@@ -172,14 +367,35 @@ impl CPU {
                 Op::CB_RES_R8(bit, r) => self.res_(bit, r),
                 Op::CB_SET_R8(bit, r) => self.set_(bit, r),
             }
-            // Set IME to true if previous instruction was EI
-            if self.prev_op == Op::EI { self.ime = true; }
-            self.prev_op = opcode;
+
+            // Warn about writes the cartridge ignores, e.g. bank switching on a ROM without MBC
+            if let Some((addr, val)) = self.mmu.mbc.unhandled_write.take().filter(|_| debug::enabled()) {
+                debug::print_unhandled_write(pc, addr, val);
+            }
         }
 
         // Return adjusted T-cycles based on the CPU speep mode
         let tcycles_multiplier = if self.mmu.double_speed { 2 } else { 4 };
-        opcycles as u16 * tcycles_multiplier
+        let elapsed_ticks = opcycles as u16 * tcycles_multiplier;
+        if self.mcycle_mode {
+            // Tick the internal cycles not spent on memory accesses
+            self.sync();
+            self.mmu.tick(elapsed_ticks.saturating_sub(std::mem::take(&mut self.ticked)));
+        }
+        elapsed_ticks
+    }
+
+    fn halt(&mut self, ei_enabled: bool) {
+        // With IME=0 and an interrupt pending, HALT exits immediately and PC fails to increment after the next opcode
+        let pending = self.mmu.IE & self.mmu.IF & 0x1F != 0;
+        if !self.ime && pending {
+            self.halt_bug = true;
+        } else if ei_enabled && pending {
+            // Right after EI the interrupt is serviced with the return address pointing to the HALT, which is executed again
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        } else {
+            self.halt = true;
+        }
     }
 
     fn handle_interrupts(&mut self) -> u8 {
@@ -191,7 +407,7 @@ impl CPU {
                     if debug::enabled() {
                         println!("INT {:#04x}", int_addr);
                     }
-                    self.ime = false;
+                    (self.ime, self.ei_pending) = (false, false);
                     self.mmu.IF &= !int_flag;
                     self.call(int_addr);
                     return 5;
@@ -221,7 +437,7 @@ impl CPU {
     fn add16(&mut self, rid: R16, r2: u16) -> u16 {
         let r1 = self.r(rid);
         let res = r1.wrapping_add(r2);
-        self.reg.f.h = (r1 & 0x07FF) + (r2 & 0x07FF) > 0x07FF;
+        self.reg.f.h = (r1 & 0x0FFF) + (r2 & 0x0FFF) > 0x0FFF; // Carry from bit 11
         self.reg.f.n = false;
         self.reg.f.c = r1 > 0xFFFF - r2;
         res
@@ -406,18 +622,42 @@ impl CPU {
         self.reg.a = a;
     }
 
+    fn write16(&mut self, addr: u16, val: u16) {
+        let [lo, hi] = val.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
+    fn stop_(&mut self, next: u8) {
+        // Only a STOP followed by 0x00 is a valid one, a corrupted STOP is ignored
+        if next != 0x00 {
+            return;
+        }
+        // DIV is reset both when stopping and when switching speed
+        self.mmu.clock.w(0xFF04, 0x00);
+        if self.mmu.speed_switch && self.mmu.mbc.cgb_mode() {
+            // Switch the CPU speed instead of entering stop mode
+            self.mmu.double_speed = !self.mmu.double_speed;
+            self.mmu.speed_switch = false;
+        } else {
+            self.stop = true;
+        }
+    }
+
     fn push(&mut self, val: u16) {
-        self.reg.sp -= 2;
-        self.mmu.ww(self.reg.sp, val);
+        // The stack pointer wraps around the address space like on hardware
+        self.reg.sp = self.reg.sp.wrapping_sub(2);
+        self.write16(self.reg.sp, val);
     }
 
     fn pop(&mut self, rid: R16) {
-        self.w(rid, self.mmu.rw(self.reg.sp));
-        self.reg.sp += 2;
+        let val = u16::from_le_bytes([self.read(self.reg.sp), self.read(self.reg.sp.wrapping_add(1))]);
+        self.w(rid, val);
+        self.reg.sp = self.reg.sp.wrapping_add(2);
     }
 
     fn jr(&mut self, offset: u8) {
-        self.jp(((self.reg.pc as i32) + (offset as i8 as i32)) as u16);
+        self.jp(self.reg.pc.wrapping_add(offset as i8 as u16));
     }
 
     fn jp(&mut self, addr: u16) {
@@ -429,3 +669,291 @@ impl CPU {
         self.jp(addr);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{CPU, INT_TIMER};
+    use crate::mbc::header_checksum;
+    use crate::registers::R16;
+    use crate::utils::{test_rom, Get, Set, TEST_PROGRAM_LOOP};
+
+    fn daa_reference(a: u8, n: bool, h: bool, c: bool) -> (u8, bool) {
+        // Reference implementation computing the correction on a wider integer, with the carry taken from the overflow
+        let mut result = a as i32;
+        if n {
+            if h {
+                result = (result - 0x06) & 0xFF;
+            }
+            if c {
+                result -= 0x60;
+            }
+        } else {
+            if h || (result & 0x0F) > 0x09 {
+                result += 0x06;
+            }
+            if c || result > 0x9F {
+                result += 0x60;
+            }
+        }
+        ((result & 0xFF) as u8, c || result & 0x100 != 0)
+    }
+
+    #[test]
+    fn daa_exhaustive() {
+        let mut cpu = CPU::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false, None);
+        for a in 0..=0xFF {
+            for flags in 0..8 {
+                let (n, h, c) = (flags & 0x04 != 0, flags & 0x02 != 0, flags & 0x01 != 0);
+                cpu.reg.a = a;
+                (cpu.reg.f.n, cpu.reg.f.h, cpu.reg.f.c) = (n, h, c);
+                cpu.daa_();
+                let (expected_a, expected_c) = daa_reference(a, n, h, c);
+                let input = format!("a={:#04x} n={} h={} c={}", a, n, h, c);
+                assert_eq!(cpu.reg.a, expected_a, "{}", input);
+                assert_eq!(cpu.reg.f.z, expected_a == 0, "{}", input);
+                assert_eq!(cpu.reg.f.n, n, "{}", input);
+                assert!(!cpu.reg.f.h, "{}", input);
+                assert_eq!(cpu.reg.f.c, expected_c, "{}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn add_sp_i8_exhaustive() {
+        let mut cpu = CPU::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false, None);
+        let sp_values: [u16; 11] = [
+            0x0000, 0x0001, 0x000F, 0x00F0, 0x00FF, 0x0F0F, 0x7FFF, 0x8000, 0xD0F8, 0xFFF8, 0xFFFF,
+        ];
+        for sp in sp_values {
+            for e8 in 0..=0xFFu8 {
+                // Reference: flags from the carries out of bits 3 and 7, found from the bits that changed in the sum
+                let expected = sp.wrapping_add(e8 as i8 as u16);
+                let carries = sp ^ (e8 as i8 as u16) ^ expected;
+                let (expected_h, expected_c) = (carries & 0x010 != 0, carries & 0x100 != 0);
+                let input = format!("sp={:#06x} e8={}", sp, e8 as i8);
+                // LD HL, SP+e8 and ADD SP, e8 set the same flags
+                for ld_hl in [true, false] {
+                    cpu.reg.sp = sp;
+                    (cpu.reg.f.z, cpu.reg.f.n) = (true, true);
+                    if ld_hl {
+                        let res = cpu.add16i8(R16::SP, e8);
+                        cpu.w(R16::HL, res);
+                        assert_eq!(cpu.r(R16::HL), expected, "{}", input);
+                        assert_eq!(cpu.reg.sp, sp, "{}", input);
+                    } else {
+                        cpu.add16i8_(R16::SP, e8);
+                        assert_eq!(cpu.reg.sp, expected, "{}", input);
+                    }
+                    assert!(!cpu.reg.f.z && !cpu.reg.f.n, "{}", input);
+                    assert_eq!(cpu.reg.f.h, expected_h, "{}", input);
+                    assert_eq!(cpu.reg.f.c, expected_c, "{}", input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn add_hl_r16() {
+        let mut cpu = CPU::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false, None);
+        let values: [u16; 12] = [
+            0x0000, 0x0001, 0x07FF, 0x0800, 0x0FFF, 0x1000, 0x7FFF, 0x8000, 0xF000, 0xF001, 0xFFFF, 0x8A23,
+        ];
+        for hl in values {
+            for r2 in values {
+                // Reference: flags from the carries out of bits 11 and 15, on a wider integer
+                let sum = hl as u32 + r2 as u32;
+                let carries = hl as u32 ^ r2 as u32 ^ sum;
+                let input = format!("hl={:#06x} r2={:#06x}", hl, r2);
+                for z in [false, true] {
+                    cpu.w(R16::HL, hl);
+                    (cpu.reg.f.z, cpu.reg.f.n) = (z, true);
+                    cpu.add16_(R16::HL, r2);
+                    assert_eq!(cpu.r(R16::HL), sum as u16, "{}", input);
+                    assert_eq!(cpu.reg.f.z, z, "{}", input);
+                    assert!(!cpu.reg.f.n, "{}", input);
+                    assert_eq!(cpu.reg.f.h, carries & 0x1000 != 0, "{}", input);
+                    assert_eq!(cpu.reg.f.c, carries & 0x10000 != 0, "{}", input);
+                }
+            }
+        }
+    }
+
+    fn run_interrupt_test(program: &[u8]) -> CPU {
+        // Request a timer interrupt with the handler at 0x0050, then run the program until the handler or 20 steps
+        let mut rom = test_rom(0x00, 0x00, &[&[0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F], program].concat()); // LD A, 0x04; LDH [IE], A; LDH [IF], A
+        rom[0x0050..0x0052].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut cpu = CPU::new(&rom, false, None);
+        cpu.skip_boot();
+        for _ in 0..20 {
+            cpu.step();
+            if (0x0050..0x0052).contains(&cpu.reg.pc) {
+                break;
+            }
+        }
+        cpu
+    }
+
+    #[test]
+    fn ei_timing() {
+        // The interrupt is serviced after the instruction following EI
+        let cpu = run_interrupt_test(&[0xFB, 0x04, 0x04, 0x18, 0xFE]); // EI; INC B; INC B; JR -2
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x01);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn ei_sequence() {
+        // A second EI does not delay IME further
+        let cpu = run_interrupt_test(&[0xFB, 0xFB, 0x04, 0x18, 0xFE]); // EI; EI; INC B; JR -2
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x00);
+        // The pending EI is cancelled by the interrupt dispatch
+        assert!(!cpu.ime && !cpu.ei_pending);
+    }
+
+    #[test]
+    fn di_timing() {
+        // DI right after EI cancels it, and the interrupt is never serviced
+        let cpu = run_interrupt_test(&[0xFB, 0xF3, 0x04, 0x18, 0xFE]); // EI; DI; INC B; JR -2
+        assert_ne!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x01);
+        assert!(!cpu.ime);
+        // RETI enables IME without delay
+        let cpu = run_interrupt_test(&[0x31, 0xFE, 0xFF, 0x21, 0x5E, 0x01, 0xE5, 0xD9, 0x04, 0x18, 0xFE]); // LD SP, 0xFFFE; LD HL, 0x015E; PUSH HL; RETI; INC B...
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x00);
+    }
+
+    #[test]
+    fn halt_ime() {
+        // The interrupt requested while halted is serviced, returning to the instruction after HALT
+        // XOR A; LDH [IF], A; EI; NOP; HALT; INC B; JR -2
+        let mut cpu = run_interrupt_test(&[0xAF, 0xE0, 0x0F, 0xFB, 0x00, 0x76, 0x04, 0x18, 0xFE]);
+        assert!(cpu.halt);
+        cpu.mmu.IF = INT_TIMER.0;
+        cpu.step();
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x00);
+        assert_eq!(cpu.mmu.rw(cpu.reg.sp), 0x015C);
+        assert!(!cpu.halt);
+    }
+
+    #[test]
+    fn halt_after_ei() {
+        // With HALT right after EI, the interrupt returns to the HALT itself
+        let cpu = run_interrupt_test(&[0xFB, 0x76, 0x04, 0x18, 0xFE]); // EI; HALT; INC B; JR -2
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x00);
+        assert_eq!(cpu.mmu.rw(cpu.reg.sp), 0x0157);
+        assert!(!cpu.halt);
+    }
+
+    #[test]
+    fn halt_bug() {
+        // With IME=0 and the interrupt already pending, HALT doesn't halt and the next byte is executed twice
+        let cpu = run_interrupt_test(&[0x76, 0x04, 0x18, 0xFE]); // HALT; INC B; JR -2
+        assert_ne!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.b, 0x02);
+        assert!(!cpu.halt && !cpu.halt_bug);
+    }
+
+    #[test]
+    fn halt_wake() {
+        // Without pending interrupts HALT waits, then with IME=0 it wakes and continues without servicing
+        let mut cpu = run_interrupt_test(&[0xAF, 0xE0, 0x0F, 0x76, 0x04, 0x18, 0xFE]); // XOR A; LDH [IF], A; HALT; INC B; JR -2
+        assert!(cpu.halt);
+        assert_eq!(cpu.reg.pc, 0x015A);
+        assert_eq!(cpu.reg.b, 0x00);
+        cpu.mmu.IF = INT_TIMER.0;
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert!(!cpu.halt);
+        assert_eq!(cpu.reg.b, 0x01);
+        assert_ne!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.mmu.IF, INT_TIMER.0);
+    }
+
+    #[test]
+    fn stop() {
+        let run = |next: u8| {
+            // LD A, 0x01; LDH [KEY1], A; STOP; JR -2
+            let mut rom = test_rom(0x00, 0x00, &[0x3E, 0x01, 0xE0, 0x4D, 0x10, next, 0x18, 0xFE]);
+            rom[0x0143] = 0x80;
+            rom[0x014D] = header_checksum(&rom);
+            let mut cpu = CPU::new(&rom, false, None);
+            cpu.skip_boot();
+            for _ in 0..5 {
+                cpu.step();
+            }
+            cpu
+        };
+        // A valid STOP performs the armed speed switch
+        let cpu = run(0x00);
+        assert!(cpu.mmu.double_speed && !cpu.mmu.speed_switch);
+        assert_eq!(cpu.mmu.r(0xFF4D), 0xFE);
+        assert_eq!(cpu.reg.pc, 0x0156);
+        // A corrupted STOP skips its operand and is ignored
+        let cpu = run(0x01);
+        assert!(!cpu.mmu.double_speed && cpu.mmu.speed_switch);
+        assert_eq!(cpu.mmu.r(0xFF4D), 0x7F);
+        assert_eq!(cpu.reg.pc, 0x0156);
+
+        // Without a speed switch the CPU stops until a button is pressed
+        let mut cpu = CPU::new(&test_rom(0x00, 0x00, &[0x10, 0x00, 0x04, 0x18, 0xFE]), false, None); // STOP; INC B; JR -2
+        cpu.skip_boot();
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert!(cpu.stop);
+        assert_eq!(cpu.reg.pc, 0x0152);
+        cpu.mmu.joypad.start = true;
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert!(!cpu.stop);
+        assert_eq!(cpu.reg.b, 0x01);
+    }
+
+    #[test]
+    fn stop_div_reset() {
+        // STOP; JR -2, with and without an armed speed switch
+        for speed_switch in [false, true] {
+            let mut rom = test_rom(0x00, 0x00, &[0x10, 0x00, 0x18, 0xFE]);
+            rom[0x0143] = 0x80;
+            rom[0x014D] = header_checksum(&rom);
+            let mut cpu = CPU::new(&rom, false, None);
+            cpu.skip_boot();
+            cpu.step(); // NOP at the entry point
+            cpu.mmu.speed_switch = speed_switch;
+            cpu.mmu.clock.set_counter(0x1234);
+            assert_eq!(cpu.mmu.r(0xFF04), 0x12);
+            cpu.step(); // JP 0x0150
+            cpu.step(); // STOP
+            assert_eq!(cpu.mmu.double_speed, speed_switch);
+            assert_eq!(cpu.mmu.r(0xFF04), 0x00, "speed_switch: {}", speed_switch);
+        }
+    }
+
+    #[test]
+    fn stack_wrapping() {
+        // LD SP, 0x0001; LD BC, 0x1234; PUSH BC; LD SP, 0x0000; PUSH BC; POP DE; JR -2
+        let program = [0x31, 0x01, 0x00, 0x01, 0x34, 0x12, 0xC5, 0x31, 0x00, 0x00, 0xC5, 0xD1, 0x18, 0xFE];
+        let mut cpu = CPU::new(&test_rom(0x00, 0x00, &program), false, None);
+        cpu.skip_boot();
+        for _ in 0..5 {
+            cpu.step(); // Including the jump to the program
+        }
+        // The low byte lands in IE, the high byte is written to the (read-only) ROM
+        assert_eq!(cpu.reg.sp, 0xFFFF);
+        assert_eq!(cpu.mmu.IE, 0x34);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.reg.sp, 0xFFFE);
+        assert_eq!([cpu.mmu.r(0xFFFE), cpu.mmu.r(0xFFFF)], [0x34, 0x12]);
+        cpu.step();
+        assert_eq!(cpu.reg.sp, 0x0000);
+        assert_eq!([cpu.reg.d, cpu.reg.e], [0x12, 0x34]);
+    }
+}
@@ -1,25 +1,97 @@
 use std::fmt::Write;
 
-use crate::cpu::CPU;
+use crate::cpu::{CPU, INT_JOYPAD, INT_SERIAL, INT_STAT, INT_TIMER, INT_VBLANK};
 use crate::instructions::Op;
-use crate::lcd::LCD;
-use crate::ppu::PPU;
+use crate::lcd::{LCD, LCDH, LCDW};
+use crate::ppu::{PPUMode, Sprite, PPU};
 use crate::registers::R8;
 use crate::utils::Get;
 
-static mut DEBUG_ENABLED: bool = false;
+static mut DEBUG_LEVEL: u8 = 0; // 0 = disabled, 1 = CPU registers, 2 = also interrupts, PPU and timer registers
 
 pub const TILE_NCOLS: usize = 32;
 pub const TILE_NROWS: usize = 768 / TILE_NCOLS;
 pub const TILEW: usize = TILE_NCOLS * 8;
 pub const TILEH: usize = TILE_NROWS * 8;
+const GRID_COLOR: u32 = 0x808080FF;
+const HIGHLIGHT_COLOR: u32 = 0x00C000FF; // Tint of the tiles in use
+
+// VRAM banks shown by the tile viewer, a single bank is drawn in the top half
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileBank {
+    Both,
+    Bank0,
+    Bank1,
+}
+
+impl TileBank {
+    pub fn next(self) -> Self {
+        match self {
+            TileBank::Both => TileBank::Bank0,
+            TileBank::Bank0 => TileBank::Bank1,
+            TileBank::Bank1 => TileBank::Both,
+        }
+    }
+}
 
 pub fn set_enabled(val: bool) {
-    unsafe { DEBUG_ENABLED = val }
+    set_level(val as u8);
 }
 
 pub fn enabled() -> bool {
-    unsafe { DEBUG_ENABLED }
+    level() > 0
+}
+
+pub fn set_level(level: u8) {
+    unsafe { DEBUG_LEVEL = level }
+}
+
+pub fn level() -> u8 {
+    unsafe { DEBUG_LEVEL }
+}
+
+pub fn interrupt_names(flags: u8) -> String {
+    let names: Vec<&str> = [
+        (INT_VBLANK, "VBLANK"),
+        (INT_STAT, "STAT"),
+        (INT_TIMER, "TIMER"),
+        (INT_SERIAL, "SERIAL"),
+        (INT_JOYPAD, "JOYPAD"),
+    ]
+    .iter()
+    .filter(|((flag, _), _)| flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect();
+    if names.is_empty() {
+        String::from("-")
+    } else {
+        names.join("|")
+    }
+}
+
+pub fn io_status(cpu: &CPU) -> String {
+    // Interrupts, PPU and timer state
+    let mmu = &cpu.mmu;
+    let mode = match mmu.ppu.mode() {
+        PPUMode::HBLANK => "HBLANK",
+        PPUMode::VBLANK => "VBLANK",
+        PPUMode::OAM => "OAM",
+        _ => "DRAW",
+    };
+    format!(
+        "IME={} IE={:#04x}({}) IF={:#04x}({}) MODE={} LY={:#04x} DIV={:#04x} TIMA={:#04x} TMA={:#04x} TAC={:#04x}",
+        cpu.ime() as u8,
+        mmu.IE,
+        interrupt_names(mmu.IE),
+        mmu.IF,
+        interrupt_names(mmu.IF),
+        mode,
+        mmu.r(0xFF44),
+        mmu.r(0xFF04),
+        mmu.r(0xFF05),
+        mmu.r(0xFF06),
+        mmu.r(0xFF07)
+    )
 }
 
 pub fn print_cpu_status(cpu: &CPU, opcode_byte: u8, opcode: Op, extra_bytes: u8, xbyte: Option<u8>, xword: Option<u16>) {
@@ -46,23 +118,298 @@ pub fn print_cpu_status(cpu: &CPU, opcode_byte: u8, opcode: Op, extra_bytes: u8,
     )
     .unwrap();
     write!(log, "SP={:#06x} ", cpu.reg.sp).unwrap();
+    if level() >= 2 {
+        write!(log, "{}", io_status(cpu)).unwrap();
+    }
     println!("{}", log);
 }
 
-pub fn draw_tilemap(ppu: &PPU, out: &mut [u8]) {
-    for tile_nr in 0..768 {
-        for row_idx in 0..8 {
-            let vbank = tile_nr >= 384;
-            let tile_row_addr = row_idx as u16 * 2 + 0x8000 + ((tile_nr % 384) as u16) * 16;
-            let tile_row_l = ppu.vram[PPU::vram_addr(tile_row_addr, vbank)];
-            let tile_row_h = ppu.vram[PPU::vram_addr(tile_row_addr + 1, vbank)];
-            for i in 0..8 {
-                let px = (tile_row_l >> (7 - i) & 1) | ((tile_row_h >> (7 - i) & 1) << 1);
-                let (x, y) = ((tile_nr % TILE_NCOLS) * 8 + i, (tile_nr / TILE_NCOLS) * 8 + row_idx);
-                let color = LCD::to_color_dmg(px, 0b11100100, 0);
-                let idx = 4 * (x + y * TILE_NCOLS * 8);
+pub fn print_unhandled_write(pc: u16, addr: u16, val: u8) {
+    println!("{:#06x}: write of {:#04x} to {:#06x} ignored by the cartridge", pc, val, addr);
+}
+
+pub fn doctor_trace_line(cpu: &CPU) -> String {
+    // CPU state in the Gameboy Doctor log format, including the 4 bytes at PC
+    let reg = &cpu.reg;
+    let pcmem: Vec<String> = (0..4).map(|i| format!("{:02X}", cpu.mmu.r(reg.pc.wrapping_add(i)))).collect();
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}\n",
+        reg.a,
+        u8::from(&reg.f),
+        reg.b,
+        reg.c,
+        reg.d,
+        reg.e,
+        reg.h,
+        reg.l,
+        reg.sp,
+        reg.pc,
+        pcmem.join(",")
+    )
+}
+
+fn tilemap_entries(ppu: &PPU) -> impl Iterator<Item = (usize, u8)> + '_ {
+    // Index in the tile viewer (bank 1 tiles from 384) and attributes of each entry of both BG tilemaps
+    let tile_mode = ppu.r(0xFF40) & 0x10 != 0;
+    (0x9800..=0x9FFF).map(move |addr| {
+        let tile_nr = ppu.vram[PPU::vram_addr(addr, false)];
+        let attrs = ppu.vram[PPU::vram_addr(addr, true)];
+        let tile_idx = if tile_mode {
+            tile_nr as usize
+        } else {
+            (256 + tile_nr as i8 as i16) as usize
+        };
+        (tile_idx + if attrs & 0x08 != 0 { 384 } else { 0 }, attrs)
+    })
+}
+
+fn tile_palettes(ppu: &PPU) -> [u8; 768] {
+    // Use the BG palette of the first tilemap entry referencing each tile, if any
+    let mut palettes = [None; 768];
+    for (tile_idx, attrs) in tilemap_entries(ppu) {
+        palettes[tile_idx].get_or_insert(attrs & 0x07);
+    }
+    palettes.map(|palette| palette.unwrap_or(0))
+}
+
+pub fn referenced_tiles(ppu: &PPU) -> [bool; 768] {
+    // Tiles used by the BG tilemaps or by the OBJs on screen
+    let mut referenced = [false; 768];
+    for (tile_idx, _) in tilemap_entries(ppu) {
+        referenced[tile_idx] = true;
+    }
+    let on_screen = |s: &Sprite| s.y > -(s.height as i16) && s.y < LCDH as i16 && s.x > -8 && s.x < LCDW as i16;
+    for sprite in ppu.sprites().iter().filter(|s| on_screen(s)) {
+        let bank_offset = if ppu.cgb_mode() && sprite.bank { 384 } else { 0 };
+        for tile in sprite.tile..sprite.tile + sprite.height / 8 {
+            referenced[tile as usize + bank_offset] = true;
+        }
+    }
+    referenced
+}
+
+pub fn dump_tile(ppu: &PPU, tile_addr: u16, vbank: bool) -> [[u8; 8]; 8] {
+    // Color indexes of the tile containing the given VRAM address, decoded from the two bitplanes of each row
+    let tile_addr = tile_addr & !0x000F;
+    std::array::from_fn(|row_idx| {
+        let row_addr = tile_addr + row_idx as u16 * 2;
+        let row_l = ppu.vram[PPU::vram_addr(row_addr, vbank)];
+        let row_h = ppu.vram[PPU::vram_addr(row_addr + 1, vbank)];
+        std::array::from_fn(|i| (row_l >> (7 - i) & 1) | ((row_h >> (7 - i) & 1) << 1))
+    })
+}
+
+pub fn tile_at(bank: TileBank, x: usize, y: usize) -> Option<usize> {
+    // Tile shown at the given position of the tile viewer, with the bank 1 tiles from 384
+    let slot = (x < TILEW && y < TILEH).then_some(x / 8 + (y / 8) * TILE_NCOLS)?;
+    match bank {
+        TileBank::Both => Some(slot),
+        TileBank::Bank0 => (slot < 384).then_some(slot),
+        TileBank::Bank1 => (slot < 384).then_some(slot + 384),
+    }
+}
+
+pub fn draw_tilemap(ppu: &PPU, out: &mut [u8], bank: TileBank) {
+    // With both banks, tiles from VRAM bank 0 are drawn in the top half and tiles from bank 1 (CGB only) in the bottom half
+    let palettes = if ppu.cgb_mode() { Some(tile_palettes(ppu)) } else { None };
+    for slot in 0..768 {
+        let (slot_x, slot_y) = ((slot % TILE_NCOLS) * 8, (slot / TILE_NCOLS) * 8);
+        let tile_nr = tile_at(bank, slot_x, slot_y);
+        let tile = match tile_nr {
+            Some(tile_nr) => dump_tile(ppu, 0x8000 + ((tile_nr % 384) as u16) * 16, tile_nr >= 384),
+            None => [[0; 8]; 8],
+        };
+        for (row_idx, row) in tile.iter().enumerate() {
+            for (i, px) in row.iter().copied().enumerate() {
+                let color = match (tile_nr, palettes) {
+                    (None, _) => 0x000000FF,
+                    (Some(tile_nr), Some(palettes)) => LCD::to_color_cgb(px, ppu.bg_palette(palettes[tile_nr])),
+                    (Some(_), None) => LCD::to_color_dmg(px, 0b11100100, 0),
+                };
+                let idx = 4 * (slot_x + i + (slot_y + row_idx) * TILEW);
                 out[idx..idx + 4].copy_from_slice(&color.to_be_bytes());
             }
         }
     }
 }
+
+pub fn draw_tilemap_overlay(ppu: &PPU, out: &mut [u8], bank: TileBank) {
+    // Tint the tiles in use and separate the tiles with a grid, on top of the output of draw_tilemap
+    let referenced = referenced_tiles(ppu);
+    let blend = |out: &mut [u8], idx: usize, color: u32| {
+        for (channel, tint) in out[idx..idx + 3].iter_mut().zip(color.to_be_bytes()) {
+            *channel = ((*channel as u16 + tint as u16) / 2) as u8;
+        }
+    };
+    for y in 0..TILEH {
+        for x in 0..TILEW {
+            let idx = 4 * (x + y * TILEW);
+            if tile_at(bank, x, y).is_some_and(|tile_nr| referenced[tile_nr]) {
+                blend(out, idx, HIGHLIGHT_COLOR);
+            }
+            if x % 8 == 7 || y % 8 == 7 {
+                blend(out, idx, GRID_COLOR);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        draw_tilemap, draw_tilemap_overlay, dump_tile, interrupt_names, io_status, referenced_tiles, tile_at, TileBank, HIGHLIGHT_COLOR,
+        TILEH, TILEW, TILE_NCOLS,
+    };
+    use crate::cpu::CPU;
+    use crate::lcd::LCD;
+    use crate::ppu::PPU;
+    use crate::utils::{test_rom, TEST_PROGRAM_LOOP};
+
+    #[test]
+    fn cgb_tilemap_colors() {
+        let mut ppu = PPU::new(true);
+        // BG palette 2 with color 3 set to red
+        ppu.w(0xFF68, 0x80 | (2 * 8));
+        for byte in [0xFF, 0x7F, 0x00, 0x00, 0x00, 0x00, 0x1F, 0x00] {
+            ppu.w(0xFF69, byte);
+        }
+        // Tile 1 of bank 1 filled with color 3, referenced by the tilemap with palette 2
+        ppu.vram[PPU::vram_addr(0x8010, true)..][..16].fill(0xFF);
+        ppu.w(0xFF40, 0x10); // Unsigned tile addressing
+        ppu.vram[PPU::vram_addr(0x9800, false)] = 0x01;
+        ppu.vram[PPU::vram_addr(0x9800, true)] = 0x08 | 0x02;
+
+        let mut out = vec![0; TILEW * TILEH * 4];
+        draw_tilemap(&ppu, &mut out, TileBank::Both);
+        let px = |tile_nr: usize| {
+            let idx = 4 * ((tile_nr % TILE_NCOLS) * 8 + (tile_nr / TILE_NCOLS) * 8 * TILEW);
+            u32::from_be_bytes(out[idx..idx + 4].try_into().unwrap())
+        };
+        assert_eq!(px(384 + 1), LCD::to_color_cgb(3, ppu.bg_palette(2)));
+        assert_eq!(px(384 + 1), LCD::to_color_cgb(3, &[0, 0, 0, 0, 0, 0, 0x1F, 0x00]));
+        // Tile 1 of bank 0 is empty and uses palette 0
+        assert_eq!(px(1), LCD::to_color_cgb(0, ppu.bg_palette(0)));
+    }
+
+    #[test]
+    fn tile_overlay() {
+        let mut ppu = PPU::new(false);
+        ppu.w(0xFF40, 0x10); // Unsigned tile addressing
+        ppu.vram[PPU::vram_addr(0x9800, false)..][..0x800].fill(0x05);
+        ppu.oam.fill(0); // All OBJs off screen except the first one, with tile 0x42
+        ppu.oam[0..4].copy_from_slice(&[16, 8, 0x42, 0x00]);
+        let referenced = referenced_tiles(&ppu);
+        assert_eq!(referenced.iter().filter(|r| **r).count(), 2);
+        assert!(referenced[0x05] && referenced[0x42]);
+
+        let mut out = vec![0; TILEW * TILEH * 4];
+        draw_tilemap(&ppu, &mut out, TileBank::Both);
+        let background = out[0..4].to_vec();
+        draw_tilemap_overlay(&ppu, &mut out, TileBank::Both);
+        let px = |tile_nr: usize, x: usize, y: usize| {
+            let idx = 4 * ((tile_nr % TILE_NCOLS) * 8 + x + ((tile_nr / TILE_NCOLS) * 8 + y) * TILEW);
+            out[idx..idx + 4].to_vec()
+        };
+        let tinted: Vec<u8> = background
+            .iter()
+            .zip(HIGHLIGHT_COLOR.to_be_bytes())
+            .map(|(px, tint)| ((*px as u16 + tint as u16) / 2) as u8)
+            .collect();
+        assert_eq!(px(0x05, 0, 0)[..3], tinted[..3]);
+        assert_eq!(px(0x42, 3, 3)[..3], tinted[..3]);
+        assert_eq!(px(0x06, 0, 0), background);
+        // Grid on the last row and column of each tile
+        assert_ne!(px(0x06, 7, 0), background);
+        assert_ne!(px(0x06, 0, 7), background);
+    }
+
+    #[test]
+    fn tile_banks() {
+        let mut ppu = PPU::new(true);
+        // BG palette 0 with white, red, green and blue
+        ppu.w(0xFF68, 0x80);
+        for byte in [0xFF, 0x7F, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C] {
+            ppu.w(0xFF69, byte);
+        }
+        // Tile 1 filled with color 1 in bank 0 and with color 3 in bank 1
+        ppu.vram[PPU::vram_addr(0x8010, false)..][..16].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        ppu.vram[PPU::vram_addr(0x8010, true)..][..16].fill(0xFF);
+        let colors = |bank: TileBank| {
+            let mut out = vec![0; TILEW * TILEH * 4];
+            draw_tilemap(&ppu, &mut out, bank);
+            let mut colors: Vec<u32> = out.chunks_exact(4).map(|px| u32::from_be_bytes(px.try_into().unwrap())).collect();
+            let tile_1 = colors[8];
+            colors.sort();
+            colors.dedup();
+            (tile_1, colors)
+        };
+        let [color_0, color_1, color_3] = [0, 1, 3].map(|px| LCD::to_color_cgb(px, ppu.bg_palette(0)));
+
+        // A single bank is drawn in the top half, the bottom half is left empty
+        let (tile_1, bank_0) = colors(TileBank::Bank0);
+        assert_eq!(tile_1, color_1);
+        assert!(!bank_0.contains(&color_3));
+        assert!(bank_0.contains(&0x000000FF) && bank_0.contains(&color_0));
+        let (tile_1, bank_1) = colors(TileBank::Bank1);
+        assert_eq!(tile_1, color_3);
+        assert!(!bank_1.contains(&color_1));
+        let (tile_1, both) = colors(TileBank::Both);
+        assert_eq!(tile_1, color_1);
+        assert!(both.contains(&color_3) && !both.contains(&0x000000FF));
+
+        assert_eq!(tile_at(TileBank::Both, 8, 12 * 8), Some(385));
+        assert_eq!(tile_at(TileBank::Bank0, 8, 12 * 8), None);
+        assert_eq!(tile_at(TileBank::Bank1, 8, 0), Some(385));
+        assert_eq!(tile_at(TileBank::Both, TILEW, 0), None);
+    }
+
+    #[test]
+    fn tile_dump() {
+        let mut ppu = PPU::new(true);
+        // Rows with the low and high bitplanes, each pixel takes one bit from both bytes
+        let rows: [[u8; 2]; 8] = [
+            [0xFF, 0x00],
+            [0x00, 0xFF],
+            [0xFF, 0xFF],
+            [0x00, 0x00],
+            [0xF0, 0x0F],
+            [0xAA, 0xCC],
+            [0x81, 0x81],
+            [0x3C, 0x7E],
+        ];
+        ppu.vram[PPU::vram_addr(0x8020, true)..][..16].copy_from_slice(rows.as_flattened());
+        let expected = [
+            [1, 1, 1, 1, 1, 1, 1, 1],
+            [2, 2, 2, 2, 2, 2, 2, 2],
+            [3, 3, 3, 3, 3, 3, 3, 3],
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [1, 1, 1, 1, 2, 2, 2, 2],
+            [3, 2, 1, 0, 3, 2, 1, 0],
+            [3, 0, 0, 0, 0, 0, 0, 3],
+            [0, 2, 3, 3, 3, 3, 2, 0],
+        ];
+        assert_eq!(dump_tile(&ppu, 0x8020, true), expected);
+        // Any address inside the tile selects it, and the other bank is still empty
+        assert_eq!(dump_tile(&ppu, 0x802F, true), expected);
+        assert_eq!(dump_tile(&ppu, 0x8020, false), [[0; 8]; 8]);
+    }
+
+    #[test]
+    fn interrupts() {
+        assert_eq!(interrupt_names(0x00), "-");
+        assert_eq!(interrupt_names(0x01), "VBLANK");
+        assert_eq!(interrupt_names(0x16), "STAT|TIMER|JOYPAD");
+        assert_eq!(interrupt_names(0xFF), "VBLANK|STAT|TIMER|SERIAL|JOYPAD");
+
+        let mut cpu = CPU::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false, None);
+        cpu.skip_boot();
+        cpu.mmu.IE = 0x05;
+        cpu.mmu.IF = 0x0C;
+        let status = io_status(&cpu);
+        assert!(
+            status.starts_with("IME=0 IE=0x05(VBLANK|TIMER) IF=0x0c(TIMER|SERIAL) "),
+            "{}",
+            status
+        );
+    }
+}
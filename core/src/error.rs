@@ -0,0 +1,33 @@
+use std::fmt;
+
+use crate::header::RomError;
+
+// Errors returned by the public API of the emulator, with the cause as a human-readable message
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Rom(RomError),
+    BootRom(String),
+    Save(String),      // Battery-backed RAM and RTC
+    SaveState(String), // Save states and APU snapshots
+    Movie(String),
+    Rewind(String), // Rewind settings
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Rom(err) => write!(f, "{}", err),
+            Error::BootRom(msg) | Error::Save(msg) | Error::SaveState(msg) | Error::Movie(msg) | Error::Rewind(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RomError> for Error {
+    fn from(err: RomError) -> Self {
+        Error::Rom(err)
+    }
+}
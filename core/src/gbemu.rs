@@ -1,58 +1,413 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use crate::apu::{ApuState, Limiter};
 use crate::cpu::CPU;
-use crate::debug;
+use crate::debug::{self, TileBank};
+use crate::error::Error;
+use crate::header::{self, CartridgeHeader};
 use crate::joypad::Joypad;
-use crate::lcd::LCD;
+use crate::lcd::{palette, ColorFilter, LCD, LCDW};
+use crate::mbc::{self, MbcKind};
+use crate::movie::Movie;
+use crate::ppu::{Layer, PPUMode, Sprite};
+use crate::registers::RegistersSnapshot;
+use crate::rewind::RewindBuffer;
+use crate::shaders;
+use crate::state::{self, take};
 
-const REWIND_FREQ: usize = 2;
+const REWIND_FREQ: usize = 2; // Save a state every N frames
 const REWIND_MAX_LEN: usize = 20; // In seconds
-const MAX_NUM_STATES: usize = (60 / REWIND_FREQ) * REWIND_MAX_LEN;
+#[cfg(test)]
+const REWIND_MAX_MEMORY: usize = 4 * 1024 * 1024; // Held by the states at the default config, for a game rewriting its WRAM
+const REWIND_INDICATOR: (usize, usize, u32) = (LCDW - 12, 2, 0xff0000ff); // Position and color of the rewind symbol
+const CLOCK_MULTIPLIER_RANGE: (f32, f32) = (0.125, 16.0);
+const STATE_VERSION: u8 = 1; // Version of the serialized SaveState layout, increased when the emulated state changes
+
+// Snapshot of the emulated hardware, kept in memory or serialized with `SaveState::serialize`
+#[derive(Clone)]
+pub struct SaveState {
+    cpu: CPU,
+    checksum: u16,
+}
+
+impl SaveState {
+    pub fn serialize(&self) -> Vec<u8> {
+        // Only the emulated hardware is included, the settings of the frontend are kept when loading it back
+        let mut data = vec![STATE_VERSION];
+        state::StateField::save(&self.checksum, &mut data);
+        state::StateField::save(&self.cpu, &mut data);
+        data
+    }
+}
+
+// Frontend settings of the emulated devices, applied again whenever the hardware is rebuilt or restored
+#[derive(Clone)]
+struct Settings {
+    trace: Option<Rc<RefCell<dyn Write>>>,
+    cycle_accurate: bool,
+    clock_multiplier: f32,
+    sprite_limit: bool,
+    fifo_renderer: bool,
+    layers: [bool; 3], // Indexed by Layer
+    volume: f32,
+    audio_limiter: Limiter,
+    audio_fast_mode: bool,
+    audio_enabled: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            trace: None,
+            cycle_accurate: false,
+            clock_multiplier: 1.0,
+            sprite_limit: true,
+            fifo_renderer: false,
+            layers: [true; 3],
+            volume: 1.0,
+            audio_limiter: Limiter::default(),
+            audio_fast_mode: false,
+            audio_enabled: true,
+        }
+    }
+}
+
+impl Settings {
+    fn apply(&self, cpu: &mut CPU) {
+        cpu.trace = self.trace.clone();
+        cpu.mcycle_mode = self.cycle_accurate;
+        cpu.mmu.clock_multiplier = self.clock_multiplier;
+        cpu.mmu.ppu.set_sprite_limit_enabled(self.sprite_limit);
+        cpu.mmu.ppu.set_fifo_renderer_enabled(self.fifo_renderer);
+        for (layer, enabled) in [Layer::Background, Layer::Window, Layer::Sprites].into_iter().zip(self.layers) {
+            cpu.mmu.ppu.set_layer_enabled(layer, enabled);
+        }
+        cpu.mmu.apu.set_master_volume(self.volume);
+        cpu.mmu.apu.limiter = self.audio_limiter;
+        cpu.mmu.apu.set_fast_mode(self.audio_fast_mode);
+        cpu.mmu.apu.enabled = self.audio_enabled;
+    }
+}
 
 pub struct GBEmu {
     cpu: CPU,
-    lcd: LCD,
+    lcd: Box<LCD>, // Boxed to keep the emulator small when moved around
+    settings: Settings,
 
     frame_count: usize,
-    states: VecDeque<CPU>,
+    tick_count: u64,
+    states: RewindBuffer,
     last_state_frame: usize,
+    rewind_enabled: bool,
+    rewind_freq: usize,
+    rewind_indicator: Option<(usize, usize, u32)>, // Position and color of the symbol drawn while rewinding
+    skip_boot: bool,
+    boot_rom: Option<Vec<u8>>,
+    recording: Option<(PathBuf, Movie)>, // Movie being recorded and its output path
+    playback: Option<(Movie, usize)>,    // Movie being played and the index of the current frame
 }
 
 impl GBEmu {
-    pub fn new(rom: &[u8], force_dmg: bool) -> Self {
-        Self {
-            cpu: CPU::new(rom, force_dmg),
-            lcd: LCD::new(),
+    pub fn new(rom: &[u8], force_dmg: bool) -> Result<Self, Error> {
+        header::validate(rom)?;
+        Ok(Self {
+            cpu: CPU::new(rom, force_dmg, None),
+            lcd: Box::new(LCD::new()),
+            settings: Settings::default(),
             frame_count: 0,
-            states: VecDeque::with_capacity(MAX_NUM_STATES),
+            tick_count: 0,
+            states: RewindBuffer::new(GBEmu::rewind_capacity(REWIND_FREQ, REWIND_MAX_LEN)),
             last_state_frame: 0,
+            rewind_enabled: true,
+            rewind_freq: REWIND_FREQ,
+            rewind_indicator: Some(REWIND_INDICATOR),
+            skip_boot: false,
+            boot_rom: None,
+            recording: None,
+            playback: None,
+        })
+    }
+
+    pub fn new_skip_boot(rom: &[u8], force_dmg: bool) -> Result<Self, Error> {
+        // Start directly from the cartridge entry point, without running the boot ROM
+        let mut emulator = Self::new(rom, force_dmg)?;
+        emulator.skip_boot = true;
+        emulator.cpu.skip_boot();
+        Ok(emulator)
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8], force_dmg: bool, save: &[u8]) -> Result<(), Error> {
+        // Rebuild the emulated hardware with the battery save of the new ROM (empty if none), keeping the settings.
+        // The current ROM is kept if the new one or its save cannot be loaded.
+        self.check_no_movie("load a ROM")?;
+        self.power_on(rom, force_dmg, save)
+    }
+
+    fn power_on(&mut self, rom: &[u8], force_dmg: bool, save: &[u8]) -> Result<(), Error> {
+        header::validate(rom)?;
+        if let Some(boot_rom) = &self.boot_rom {
+            mbc::check_boot_rom(boot_rom, force_dmg).map_err(Error::BootRom)?;
+        }
+        let mut cpu = CPU::new(rom, force_dmg, self.boot_rom.as_deref());
+        // The fixed RTC time restarts from its base, like the emulated ticks
+        if let Some(rtc_base) = self.cpu.mmu.mbc.rtc_base() {
+            cpu.mmu.mbc.set_rtc_base(rtc_base);
+        }
+        if !save.is_empty() {
+            cpu.mmu.mbc.import_sav(save).map_err(Error::Save)?;
+        }
+        self.settings.apply(&mut cpu);
+        if self.skip_boot {
+            cpu.skip_boot();
+        }
+        self.cpu = cpu;
+        self.lcd.clear();
+        self.frame_count = 0;
+        self.tick_count = 0;
+        self.states.clear();
+        self.last_state_frame = 0;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.check_no_movie("reset")?;
+        self.power_cycle(None);
+        Ok(())
+    }
+
+    fn power_cycle(&mut self, save: Option<&[u8]>) {
+        // Power cycle the console, keeping the cartridge and its battery RAM and RTC unless another save is given
+        let mbc = &self.cpu.mmu.mbc;
+        let (rom, force_dmg) = (mbc.rom().to_vec(), mbc.force_dmg());
+        let save = save.map_or_else(|| mbc.export_sav(), |save| save.to_vec());
+        self.power_on(&rom, force_dmg, &save)
+            .expect("The current ROM and save have already been loaded");
+    }
+
+    fn check_no_movie(&self, action: &str) -> Result<(), Error> {
+        // Power cycles, rewinding and save states would make the movie diverge from its inputs
+        if self.recording.is_some() || self.playback.is_some() {
+            return Err(Error::Movie(format!("Cannot {} while a movie is recorded or played", action)));
+        }
+        Ok(())
+    }
+
+    pub fn set_boot_rom(&mut self, boot_rom: Option<&[u8]>) -> Result<(), Error> {
+        // Replace the embedded boot ROM and power cycle the console, the embedded one is kept if the size is invalid
+        self.check_no_movie("change the boot ROM")?;
+        if let Some(boot_rom) = boot_rom {
+            mbc::check_boot_rom(boot_rom, self.cpu.mmu.mbc.force_dmg()).map_err(Error::BootRom)?;
         }
+        self.boot_rom = boot_rom.map(|boot_rom| boot_rom.to_vec());
+        self.power_cycle(None);
+        Ok(())
     }
 
     pub fn step(&mut self) -> Option<&LCD> {
         // Save state once every frame
-        if self.frame_count % REWIND_FREQ == 0 && self.last_state_frame != self.frame_count {
-            self.states.push_back(self.cpu.clone());
-            if self.states.len() >= MAX_NUM_STATES {
-                self.states.pop_front();
-            }
+        if self.rewind_enabled && self.frame_count % self.rewind_freq == 0 && self.last_state_frame != self.frame_count {
+            self.states.push(&self.cpu);
             self.last_state_frame = self.frame_count
         }
 
         // Tick cpu and the rest of the devices
-        let elapsed_ticks = self.cpu.step();
-        let frame_ready = self.cpu.mmu.step(&mut self.lcd, elapsed_ticks);
+        let (elapsed_ticks, frame_ready) = self.step_cpu();
+        self.tick_count += elapsed_ticks as u64;
 
         if frame_ready {
             self.frame_count += 1;
+            self.step_movie();
             Some(&self.lcd)
         } else {
             None
         }
     }
 
+    fn step_cpu(&mut self) -> (u16, bool) {
+        // In cycle-accurate mode the devices have already been ticked by the CPU, only the LCD is left to update
+        let elapsed_ticks = self.cpu.step();
+        let frame_ready = if self.cpu.mcycle_mode {
+            self.cpu.mmu.flush(&mut self.lcd)
+        } else {
+            self.cpu.mmu.step(&mut self.lcd, elapsed_ticks)
+        };
+        (elapsed_ticks, frame_ready)
+    }
+
+    fn step_movie(&mut self) {
+        // Record the inputs of the frame that just ended, or load the ones of the next frame
+        if let Some((_, movie)) = &mut self.recording {
+            movie.inputs.push(self.cpu.mmu.joypad);
+        }
+        if let Some((movie, frame)) = &mut self.playback {
+            *frame += 1;
+            match movie.inputs.get(*frame) {
+                Some(joypad) => self.cpu.mmu.joypad = *joypad,
+                None => {
+                    self.playback = None;
+                    self.cpu.mmu.joypad.reset();
+                }
+            }
+        }
+    }
+
+    pub fn step_frame(&mut self) -> &LCD {
+        while self.step().is_none() {}
+        &self.lcd
+    }
+
+    pub fn step_to_vblank(&mut self) -> &LCD {
+        // Run until the PPU enters VBlank (LY 144), when the frame is fully drawn and the next one has not started yet.
+        // Unlike step_frame, which returns when LY wraps to 0. With the LCD off, return at the end of the frame instead.
+        let mut left_vblank = self.cpu.mmu.ppu.mode() != PPUMode::VBLANK;
+        loop {
+            let frame_ready = self.step().is_some();
+            let in_vblank = self.cpu.mmu.ppu.mode() == PPUMode::VBLANK;
+            if (in_vblank && left_vblank) || (frame_ready && !self.cpu.mmu.ppu.lcd_enabled()) {
+                break;
+            }
+            left_vblank |= !in_vblank;
+        }
+        &self.lcd
+    }
+
+    pub fn tick_count(&self) -> u64 {
+        self.tick_count
+    }
+
+    pub fn boot_completed(&self) -> bool {
+        self.cpu.mmu.mbc.boot_rom_unmounted
+    }
+
+    pub fn sprite_limit_enabled(&self) -> bool {
+        self.cpu.mmu.ppu.sprite_limit_enabled()
+    }
+
+    pub fn set_sprite_limit_enabled(&mut self, enabled: bool) {
+        // Without the limit all the objects on a scanline are drawn, reducing flickering
+        self.settings.sprite_limit = enabled;
+        self.cpu.mmu.ppu.set_sprite_limit_enabled(enabled);
+    }
+
+    pub fn clock_multiplier(&self) -> f32 {
+        self.cpu.mmu.clock_multiplier
+    }
+
+    pub fn set_clock_multiplier(&mut self, multiplier: f32) {
+        // Overclock (> 1.0) or underclock (< 1.0) the emulated CPU, unlike fast-forward the frame rate is unchanged
+        let (min, max) = CLOCK_MULTIPLIER_RANGE;
+        self.settings.clock_multiplier = if multiplier.is_nan() { 1.0 } else { multiplier.clamp(min, max) };
+        self.cpu.mmu.clock_multiplier = self.settings.clock_multiplier;
+    }
+
+    pub fn fifo_renderer_enabled(&self) -> bool {
+        self.cpu.mmu.ppu.fifo_renderer_enabled()
+    }
+
+    pub fn set_fifo_renderer_enabled(&mut self, enabled: bool) {
+        // Render dot by dot during mode 3, slower but mid-scanline register changes are visible
+        self.settings.fifo_renderer = enabled;
+        self.cpu.mmu.ppu.set_fifo_renderer_enabled(enabled);
+    }
+
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        self.cpu.mmu.ppu.layer_enabled(layer)
+    }
+
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        // Hide the BG, window or sprites, to see what each layer contributes to the frame
+        self.settings.layers[layer as usize] = enabled;
+        self.cpu.mmu.ppu.set_layer_enabled(layer, enabled);
+    }
+
+    pub fn window_active(&self) -> bool {
+        // Whether the game used the window layer on the last frame, regardless of the layer toggles
+        self.cpu.mmu.ppu.window_active()
+    }
+
+    pub fn cpu_registers(&self) -> RegistersSnapshot {
+        self.cpu.registers()
+    }
+
+    pub fn set_cpu_registers(&mut self, regs: &RegistersSnapshot) {
+        self.cpu.set_registers(regs);
+    }
+
+    pub fn step_instruction(&mut self) -> RegistersSnapshot {
+        // Run a single CPU step (an instruction, an interrupt dispatch or a halted cycle) and return the registers after it
+        self.step();
+        self.cpu.registers()
+    }
+
+    pub fn trace_line(&self) -> String {
+        // Current CPU state in the Gameboy Doctor format, as written by the trace
+        debug::doctor_trace_line(&self.cpu)
+    }
+
+    pub fn set_cycle_accurate(&mut self, enabled: bool) {
+        // Tick the devices after each memory access of an instruction, slower but needed by timing-sensitive games
+        self.settings.cycle_accurate = enabled;
+        self.cpu.mcycle_mode = enabled;
+    }
+
+    pub fn cycle_accurate(&self) -> bool {
+        self.cpu.mcycle_mode
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Rc<RefCell<dyn Write>>>) {
+        // Log each executed instruction after the boot ROM in the Gameboy Doctor format
+        self.settings.trace = trace.clone();
+        self.cpu.trace = trace;
+    }
+
     pub fn set_joypad(&mut self, joypad: &Joypad) {
-        self.cpu.mmu.joypad = *joypad;
+        // Inputs are ignored while a movie is playing
+        if self.playback.is_none() {
+            self.cpu.mmu.joypad = *joypad;
+        }
+    }
+
+    pub fn start_recording(&mut self, path: &Path) {
+        // Movies are recorded from power-on, with the RTC only advanced by the emulated ticks
+        self.playback = None;
+        let mbc = &self.cpu.mmu.mbc;
+        let rtc_base = mbc.rtc_base().unwrap_or_else(|| mbc.now());
+        self.set_rtc_base(rtc_base);
+        self.power_cycle(None);
+        let movie = Movie::new(self.rom_checksum(), rtc_base, self.export_sav());
+        self.recording = Some((path.to_path_buf(), movie));
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), Error> {
+        let (path, movie) = self
+            .recording
+            .take()
+            .ok_or(Error::Movie(String::from("No movie is being recorded")))?;
+        fs::write(&path, movie.serialize()).map_err(|err| Error::Movie(format!("Could not write movie {}: {}", path.display(), err)))
+    }
+
+    pub fn play_movie(&mut self, path: &Path) -> Result<(), Error> {
+        let data = fs::read(path).map_err(|err| Error::Movie(format!("Could not read movie {}: {}", path.display(), err)))?;
+        let movie = Movie::deserialize(&data).map_err(Error::Movie)?;
+        if movie.checksum != self.rom_checksum() {
+            return Err(Error::Movie(String::from("Movie was recorded on a different ROM")));
+        }
+        self.recording = None;
+        self.set_rtc_base(movie.rtc_base);
+        self.power_cycle(Some(&movie.save));
+        self.cpu.mmu.joypad = movie.inputs.first().copied().unwrap_or_default();
+        if !movie.inputs.is_empty() {
+            self.playback = Some((movie, 0));
+        }
+        Ok(())
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        self.playback.is_some()
     }
 
     pub fn audio_buffer(&self) -> &[f32] {
@@ -63,29 +418,200 @@ impl GBEmu {
         self.cpu.mmu.apu.buffer.clear();
     }
 
+    pub fn channel_levels(&self) -> [f32; 4] {
+        self.cpu.mmu.apu.channel_levels()
+    }
+
+    pub fn output_levels(&self) -> (f32, f32) {
+        self.cpu.mmu.apu.output_levels()
+    }
+
+    pub fn set_audio_limiter(&mut self, limiter: Limiter) {
+        self.settings.audio_limiter = limiter;
+        self.cpu.mmu.apu.limiter = limiter;
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.cpu.mmu.apu.master_volume()
+    }
+
+    pub fn fade_out_audio(&mut self, samples: u32) {
+        self.cpu.mmu.apu.begin_fade_out(samples);
+    }
+
+    pub fn cancel_audio_fade_out(&mut self) {
+        self.cpu.mmu.apu.cancel_fade_out();
+    }
+
+    pub fn audio_faded_out(&self) -> bool {
+        self.cpu.mmu.apu.fade_out_done()
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.cpu.mmu.apu.set_master_volume(volume);
+        self.settings.volume = self.cpu.mmu.apu.master_volume();
+    }
+
+    pub fn set_audio_fast_mode(&mut self, enabled: bool) {
+        self.settings.audio_fast_mode = enabled;
+        self.cpu.mmu.apu.set_fast_mode(enabled);
+    }
+
+    pub fn audio_snapshot(&self) -> ApuState {
+        self.cpu.mmu.apu.snapshot()
+    }
+
+    pub fn restore_audio(&mut self, state: &ApuState) -> Result<(), Error> {
+        self.cpu.mmu.apu.restore(state).map_err(Error::SaveState)
+    }
+
+    pub fn set_audio_enabled(&mut self, enabled: bool) {
+        self.settings.audio_enabled = enabled;
+        self.cpu.mmu.apu.enabled = enabled;
+    }
+
+    pub fn set_rewind_enabled(&mut self, enabled: bool) {
+        self.rewind_enabled = enabled;
+        if !enabled {
+            // Release the memory held by the saved states
+            self.states.clear();
+        }
+    }
+
+    pub fn set_rewind_config(&mut self, freq_frames: usize, max_seconds: usize) -> Result<(), Error> {
+        // States are saved at least every frame and kept for at least one second, the current config is kept otherwise
+        if freq_frames == 0 || max_seconds == 0 {
+            return Err(Error::Rewind(String::from("The rewind frequency and length must be at least 1")));
+        }
+        self.rewind_freq = freq_frames;
+        self.states.set_capacity(GBEmu::rewind_capacity(freq_frames, max_seconds));
+        Ok(())
+    }
+
+    pub fn set_rewind_indicator(&mut self, enabled: bool) {
+        self.rewind_indicator = enabled.then_some(self.rewind_indicator.unwrap_or(REWIND_INDICATOR));
+    }
+
+    pub fn set_rewind_indicator_style(&mut self, x: usize, y: usize, color: u32) {
+        // Also enables the indicator, the symbol is clipped if it doesn't fit on the screen
+        self.rewind_indicator = Some((x, y, color));
+    }
+
+    fn rewind_capacity(freq_frames: usize, max_seconds: usize) -> usize {
+        (60 * max_seconds).div_ceil(freq_frames)
+    }
+
     pub fn can_rewind(&self) -> bool {
-        !self.states.is_empty()
+        !self.states.is_empty() && self.check_no_movie("rewind").is_ok()
     }
 
     pub fn rewind(&mut self) -> Option<&LCD> {
-        if let Some(last_state) = self.states.pop_back() {
+        if !self.can_rewind() {
+            return None;
+        }
+        if let Some(last_state) = self.states.pop(&self.cpu) {
             self.cpu = last_state;
+            self.settings.apply(&mut self.cpu);
             // Tick until a new frame is ready
             let mut frame_ready = false;
             while !frame_ready {
-                let elapsed_ticks = self.cpu.step();
-                frame_ready = self.cpu.mmu.step(&mut self.lcd, elapsed_ticks);
+                frame_ready = self.step_cpu().1;
             }
             self.cpu.mmu.joypad.reset();
-            self.lcd.w_rewind_symbol();
+            if let Some((x, y, color)) = self.rewind_indicator {
+                self.lcd.w_rewind_symbol(x, y, color);
+            }
             Some(&self.lcd)
         } else {
             None
         }
     }
 
-    pub fn draw_tilemap(&self, out: &mut [u8]) {
-        debug::draw_tilemap(&self.cpu.mmu.ppu, out);
+    pub fn save_state(&self) -> SaveState {
+        let mut cpu = self.cpu.clone();
+        cpu.mmu.apu.buffer = Vec::new();
+        SaveState {
+            cpu,
+            checksum: self.rom_checksum(),
+        }
+    }
+
+    pub fn deserialize_state(&self, data: &[u8]) -> Result<SaveState, Error> {
+        let mut data = data;
+        let header = take::<3>(&mut data).map_err(Error::SaveState)?;
+        if header[0] != STATE_VERSION {
+            return Err(Error::SaveState(format!("Unsupported save state version {}", header[0])));
+        }
+        let checksum = u16::from_le_bytes([header[1], header[2]]);
+        if checksum != self.rom_checksum() {
+            return Err(Error::SaveState(String::from("Save state belongs to a different ROM")));
+        }
+        // Load into a copy of the current hardware, which provides the ROM and the settings of the frontend
+        let mut cpu = self.cpu.clone();
+        cpu.mmu.apu.buffer = Vec::new();
+        let sizes = |cpu: &mut CPU| cpu.mmu.memory_regions().map(|region| region.len());
+        let expected_sizes = sizes(&mut cpu);
+        state::StateField::load(&mut cpu, &mut data).map_err(Error::SaveState)?;
+        if !data.is_empty() {
+            return Err(Error::SaveState(String::from("State too long")));
+        }
+        if sizes(&mut cpu) != expected_sizes {
+            return Err(Error::SaveState(String::from("Save state memory size does not match the ROM")));
+        }
+        Ok(SaveState { cpu, checksum })
+    }
+
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), Error> {
+        self.check_no_movie("load a state")?;
+        if state.checksum != self.rom_checksum() {
+            return Err(Error::SaveState(String::from("Save state belongs to a different ROM")));
+        }
+        self.cpu = state.cpu.clone();
+        self.settings.apply(&mut self.cpu);
+        self.states.clear();
+        Ok(())
+    }
+
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, color: u32) {
+        // Overlay text on the last frame, drawn until the next frame replaces it
+        self.lcd.draw_text(x, y, text, color);
+    }
+
+    pub fn frame_hash(&self) -> u64 {
+        self.lcd.frame_hash()
+    }
+
+    pub fn frame_bgr555(&self) -> Vec<u16> {
+        self.lcd.frame_bgr555.to_vec()
+    }
+
+    pub fn draw_tilemap(&self, out: &mut [u8], overlay: bool, bank: TileBank) {
+        // The overlay separates the tiles with a grid and highlights the ones used by the tilemaps and OAM
+        debug::draw_tilemap(&self.cpu.mmu.ppu, out, bank);
+        if overlay {
+            debug::draw_tilemap_overlay(&self.cpu.mmu.ppu, out, bank);
+        }
+    }
+
+    pub fn dump_tile(&self, tile_addr: u16, vbank: bool) -> [[u8; 8]; 8] {
+        debug::dump_tile(&self.cpu.mmu.ppu, tile_addr, vbank)
+    }
+
+    pub fn dump_memory(&self) -> [u8; 0x10000] {
+        // Read the whole address space through the current memory map, reads have no side effects
+        let mut dump = [0; 0x10000];
+        for (addr, byte) in dump.iter_mut().enumerate() {
+            *byte = self.cpu.mmu.r(addr as u16);
+        }
+        dump
+    }
+
+    pub fn sprites(&self) -> Vec<Sprite> {
+        self.cpu.mmu.ppu.sprites()
+    }
+
+    pub fn bg_tile_ids(&self, map: bool) -> [[u8; 32]; 32] {
+        self.cpu.mmu.ppu.bg_tile_ids(map)
     }
 
     pub fn current_palette(&self) -> i16 {
@@ -96,27 +622,735 @@ impl GBEmu {
         self.lcd.set_palette(palette_idx);
     }
 
+    pub fn cycle_palette_in_category(&mut self, step: i16) {
+        self.lcd.cycle_palette_in_category(step);
+    }
+
+    pub fn cycle_palette_category(&mut self, step: i16) {
+        self.lcd.cycle_palette_category(step);
+    }
+
+    pub fn palette_names() -> Vec<&'static str> {
+        palette::DMG_PALETTES.iter().map(|(name, _)| *name).collect()
+    }
+
+    pub fn current_palette_name(&self) -> &'static str {
+        palette::DMG_PALETTES[self.lcd.palette_idx as usize].0
+    }
+
     pub fn current_shader(&self) -> i16 {
         self.lcd.shader_idx
     }
 
+    pub fn shader_names() -> &'static [&'static str] {
+        &shaders::SHADER_NAMES
+    }
+
+    pub fn current_shader_name(&self) -> &'static str {
+        shaders::SHADER_NAMES[self.lcd.shader_idx as usize]
+    }
+
     pub fn set_shader(&mut self, shader_idx: i16) {
         self.lcd.set_shader(shader_idx);
     }
 
+    pub fn color_filter(&self) -> ColorFilter {
+        self.lcd.color_filter()
+    }
+
+    pub fn set_color_filter(&mut self, filter: ColorFilter) {
+        // Remap the colors for a color vision deficiency, applied before the shader
+        self.lcd.set_color_filter(filter);
+    }
+
+    pub fn depth_3d(&self) -> (usize, usize) {
+        self.lcd.depth_3d
+    }
+
+    pub fn set_depth_3d(&mut self, offset_background: usize, offset_foreground: usize) {
+        // Displacement in pixels of the background and foreground between the two views of the 3D shaders
+        self.lcd.depth_3d = (offset_background, offset_foreground);
+    }
+
     pub fn rom_title(&self) -> String {
         self.cpu.mmu.mbc.title()
     }
 
+    pub fn mbc_kind(&self) -> MbcKind {
+        self.cpu.mmu.mbc.kind()
+    }
+
+    pub fn header(&self) -> CartridgeHeader {
+        CartridgeHeader::parse(self.cpu.mmu.mbc.rom())
+    }
+
     pub fn rom_checksum(&self) -> u16 {
         self.cpu.mmu.mbc.checksum()
     }
 
-    pub fn save(&self) -> &[u8] {
+    pub fn save(&mut self) -> &[u8] {
         self.cpu.mmu.mbc.save()
     }
 
-    pub fn load_save(&mut self, save: &[u8]) {
-        self.cpu.mmu.mbc.load(save)
+    pub fn ram_dirty(&self) -> bool {
+        // Whether the cartridge RAM was written since the last save
+        self.cpu.mmu.mbc.ram_dirty()
+    }
+
+    pub fn load_save(&mut self, save: &[u8]) -> Result<(), Error> {
+        // Saves with a different size are rejected, an empty save is accepted for any cartridge
+        if save.is_empty() {
+            return Ok(());
+        }
+        self.import_sav(save)
+    }
+
+    pub fn export_sav(&self) -> Vec<u8> {
+        self.cpu.mmu.mbc.export_sav()
+    }
+
+    pub fn set_rtc_base(&mut self, unix_seconds: u64) {
+        // Use a fixed time instead of the system time for the RTC, to keep tests and movies reproducible
+        self.cpu.mmu.mbc.set_rtc_base(unix_seconds);
+    }
+
+    pub fn import_sav(&mut self, sav: &[u8]) -> Result<(), Error> {
+        self.cpu.mmu.mbc.import_sav(sav).map_err(Error::Save)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::{GBEmu, REWIND_MAX_LEN, REWIND_MAX_MEMORY};
+    use crate::apu::{ApuState, Limiter};
+    use crate::cpu::{POST_BOOT_IO, POST_BOOT_IO_CGB};
+    use crate::error::Error;
+    use crate::header::RomError;
+    use crate::joypad::Joypad;
+    use crate::lcd::{LCD, LCDH, LCDW};
+    use crate::mbc::header_checksum;
+    use crate::registers::RegistersSnapshot;
+    use crate::registers::R16;
+    use crate::utils::Get;
+    use crate::utils::{test_rom, TEST_PROGRAM_LOOP};
+
+    fn run_frames(emulator: &mut GBEmu, frames: usize) {
+        for _ in 0..frames {
+            emulator.step_frame();
+        }
+    }
+
+    #[test]
+    fn rewind_disabled() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        run_frames(&mut emulator, 10);
+        assert!(emulator.can_rewind());
+        emulator.set_rewind_enabled(false);
+        assert!(!emulator.can_rewind());
+        run_frames(&mut emulator, 10);
+        assert!(!emulator.can_rewind());
+        assert_eq!(emulator.states.len(), 0);
+    }
+
+    #[test]
+    fn rewind_memory_usage() {
+        let program = [
+            0x3E, 0x91, // LD A, 0x91
+            0xE0, 0x40, // LDH [LCDC], A
+            0x21, 0x00, 0xC0, // LD HL, 0xC000
+            0x34, // INC [HL]
+            0x23, // INC HL
+            0xCB, 0xAC, // RES 5, H
+            0x18, 0xFA, // JR -6
+        ];
+        let rom = test_rom(0x00, 0x00, &program);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        run_frames(&mut emulator, 60 * REWIND_MAX_LEN + 60);
+        assert_eq!(emulator.states.len(), emulator.states.capacity());
+        assert!(
+            emulator.states.memory_usage() < REWIND_MAX_MEMORY,
+            "{} bytes used",
+            emulator.states.memory_usage()
+        );
+    }
+
+    #[test]
+    fn rewind_config() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        run_frames(&mut emulator, 60);
+        assert!(emulator.states.len() > 15);
+        // Shrinking the buffer drops the oldest states
+        emulator.set_rewind_config(4, 1).unwrap();
+        assert_eq!(emulator.states.capacity(), 15);
+        assert_eq!(emulator.states.len(), 15);
+        for _ in 0..20 {
+            run_frames(&mut emulator, 4);
+            assert!(emulator.states.len() <= 15);
+        }
+        assert_eq!(emulator.states.len(), 15);
+        assert!(emulator.rewind().is_some());
+    }
+
+    #[test]
+    fn rewind_indicator() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        let red_pixels = |lcd: &LCD| {
+            let corner = (0..20).flat_map(|y| (LCDW - 20..LCDW).map(move |x| x + y * LCDW));
+            corner.filter(|&idx| lcd.frame[idx] == 0xff0000ff).count()
+        };
+        run_frames(&mut emulator, 10);
+        assert!(red_pixels(emulator.rewind().unwrap()) > 0);
+        emulator.set_rewind_indicator(false);
+        assert_eq!(red_pixels(emulator.rewind().unwrap()), 0);
+        // A custom style moves the symbol out of the corner
+        emulator.set_rewind_indicator_style(2, 2, 0xff0000ff);
+        let lcd = emulator.rewind().unwrap();
+        assert_eq!(red_pixels(lcd), 0);
+        assert_eq!(lcd.frame[2 + 6 * LCDW], 0xff0000ff);
+    }
+
+    #[test]
+    fn boot_duration() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        for (force_dmg, expected_ticks) in [(true, 23_400_000..23_500_000), (false, 13_000_000..13_100_000)] {
+            let mut emulator = GBEmu::new(&rom, force_dmg).unwrap();
+            emulator.set_rewind_enabled(false);
+            while !emulator.boot_completed() {
+                emulator.step();
+            }
+            assert!(expected_ticks.contains(&emulator.tick_count()), "{} ticks", emulator.tick_count());
+            assert_eq!(emulator.cpu.reg.pc, 0x0100);
+        }
+    }
+
+    #[test]
+    fn skip_boot() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        for (force_dmg, af) in [(true, 0x01B0), (false, 0x1180)] {
+            let mut emulator = GBEmu::new_skip_boot(&rom, force_dmg).unwrap();
+            assert!(emulator.boot_completed());
+            assert_eq!(emulator.cpu.reg.pc, 0x0100);
+            assert_eq!(emulator.cpu.r(R16::AF), af);
+            assert_eq!(emulator.cpu.mmu.r(0xFF40), 0x91);
+            // The cartridge is mapped from address 0, and the boot ROM cannot be mapped back
+            assert_eq!(emulator.cpu.mmu.r(0x0000), rom[0x0000]);
+            emulator.cpu.mmu.w(0xFF50, 0x00);
+            assert_eq!(emulator.cpu.mmu.r(0x0000), rom[0x0000]);
+            // Execution continues from the entry point into the cartridge program
+            emulator.step(); // NOP
+            assert_eq!(emulator.cpu.reg.pc, 0x0101);
+            emulator.step(); // JP 0x0150
+            assert_eq!(emulator.cpu.reg.pc, 0x0150);
+            run_frames(&mut emulator, 5);
+            assert!((0x0150..0x0156).contains(&emulator.cpu.reg.pc));
+            emulator.reset().unwrap();
+            assert_eq!(emulator.cpu.reg.pc, 0x0100);
+        }
+    }
+
+    #[test]
+    fn step_to_vblank() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new_skip_boot(&rom, true).unwrap();
+        for _ in 0..3 {
+            let frame_count = emulator.frame_count;
+            emulator.step_to_vblank();
+            assert_eq!(emulator.cpu.mmu.r(0xFF44), 144);
+            assert_eq!(emulator.cpu.mmu.r(0xFF41) & 0x03, 0x01);
+            // The frame is not completed yet, it ends when LY wraps to 0
+            assert_eq!(emulator.frame_count, frame_count);
+            emulator.step_frame();
+            assert_eq!(emulator.cpu.mmu.r(0xFF44), 0);
+        }
+    }
+
+    #[test]
+    fn clock_multiplier() {
+        // TIMA increments in one frame, with the timer at 4096 Hz
+        let tima_per_frame = |multiplier: f32| {
+            let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+            let mut emulator = GBEmu::new_skip_boot(&rom, true).unwrap();
+            emulator.set_clock_multiplier(multiplier);
+            emulator.step_frame();
+            emulator.cpu.mmu.w(0xFF07, 0x04);
+            emulator.cpu.mmu.w(0xFF05, 0x00);
+            emulator.step_frame();
+            emulator.cpu.mmu.r(0xFF05) as i32
+        };
+        let normal = tima_per_frame(1.0);
+        assert!((normal - 70224 / 1024).abs() <= 1);
+        assert!((tima_per_frame(2.0) - normal * 2).abs() <= 1);
+        assert!((tima_per_frame(0.5) - normal / 2).abs() <= 1);
+    }
+
+    #[test]
+    fn post_boot_io() {
+        let dmg_rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut cgb_rom = dmg_rom.clone();
+        cgb_rom[0x0143] = 0x80;
+        // DMG, CGB game and DMG game on CGB
+        for (rom, force_dmg, cgb_io, div) in [
+            (&dmg_rom, true, false, 0xAB),
+            (&cgb_rom, false, true, 0x1E),
+            (&dmg_rom, false, false, 0x26),
+        ] {
+            let emulator = GBEmu::new_skip_boot(rom, force_dmg).unwrap();
+            let table = POST_BOOT_IO.iter().chain(if cgb_io { POST_BOOT_IO_CGB.as_slice() } else { &[] });
+            for (addr, val) in table {
+                assert_eq!(emulator.cpu.mmu.r(*addr), *val, "{:#06x}", addr);
+            }
+            assert_eq!(emulator.cpu.mmu.r(0xFF04), div);
+        }
+    }
+
+    #[test]
+    fn save_state() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        run_frames(&mut emulator, 10);
+        let state = emulator.save_state();
+        let pc = emulator.cpu.reg.pc;
+        emulator.cpu.mmu.w(0xC000, 0x42);
+        emulator.set_sprite_limit_enabled(false);
+        run_frames(&mut emulator, 10);
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.cpu.reg.pc, pc);
+        assert!(!emulator.sprite_limit_enabled()); // Settings are not part of the state
+        assert_eq!(emulator.cpu.mmu.r(0xC000), 0x00);
+
+        let mut other_rom = rom.clone();
+        other_rom[0x014E] = 0x12;
+        let mut emulator = GBEmu::new(&other_rom, false).unwrap();
+        assert!(emulator.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn serialize_state() {
+        // A deserialized state resumes exactly like the in-memory one
+        let rom = test_rom(0x13, 0x03, &TEST_PROGRAM_LOOP); // MBC3 with RAM and RTC
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        run_frames(&mut emulator, 10);
+        emulator.cpu.mmu.w(0x0000, 0x0A);
+        emulator.cpu.mmu.w(0xA000, 0x42);
+        let state = emulator.save_state();
+        let data = state.serialize();
+        let loaded = GBEmu::new(&rom, false).unwrap().deserialize_state(&data).unwrap();
+        assert_eq!(loaded.serialize(), data);
+
+        let mut runs = Vec::new();
+        for state in [state, loaded] {
+            emulator.load_state(&state).unwrap();
+            run_frames(&mut emulator, 10);
+            assert_eq!(emulator.cpu.mmu.r(0xA000), 0x42);
+            runs.push((emulator.frame_hash(), emulator.save_state().serialize()));
+        }
+        assert_eq!(runs[0], runs[1]);
+
+        assert!(matches!(
+            emulator.deserialize_state(&data[..data.len() - 1]),
+            Err(Error::SaveState(_))
+        ));
+        assert!(matches!(
+            emulator.deserialize_state(&[data.as_slice(), &[0]].concat()),
+            Err(Error::SaveState(_))
+        ));
+        let mut other_rom = rom.clone();
+        other_rom[0x014E] = 0x12;
+        assert!(GBEmu::new(&other_rom, false).unwrap().deserialize_state(&data).is_err());
+    }
+
+    #[test]
+    fn frame_hash() {
+        // Runs from the same ROM render the same frames
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let hashes: Vec<u64> = (0..2)
+            .map(|_| {
+                let mut emulator = GBEmu::new(&rom, true).unwrap();
+                run_frames(&mut emulator, 120);
+                emulator.frame_hash()
+            })
+            .collect();
+        assert_eq!(hashes[0], hashes[1]);
+    }
+
+    #[test]
+    fn cycle_accurate() {
+        // Read TIMA after resetting DIV and waiting a number of NOPs
+        let read_tima = |cycle_accurate: bool, nops: usize| {
+            let mut program = vec![
+                0xAF, //       XOR A
+                0xE0, 0x04, // LDH [DIV], A
+                0xE0, 0x05, // LDH [TIMA], A
+                0x3E, 0x05, // LD A, 0x05
+                0xE0, 0x07, // LDH [TAC], A
+            ];
+            program.extend(vec![0x00; nops]); // NOP
+            program.extend([0xFA, 0x05, 0xFF, 0x18, 0xFE]); // LD A, [TIMA]; JR -2
+            let mut emulator = GBEmu::new_skip_boot(&test_rom(0x00, 0x00, &program), true).unwrap();
+            emulator.set_cycle_accurate(cycle_accurate);
+            while emulator.cpu.reg.pc != 0x0150 + program.len() as u16 - 2 {
+                emulator.step();
+            }
+            emulator.cpu.reg.a
+        };
+        // The timer starts from the same DIV value in both modes, but the cycle-accurate read happens after the operand fetches
+        let fast: Vec<u8> = (0..24).map(|nops| read_tima(false, nops)).collect();
+        let accurate: Vec<u8> = (0..23).map(|nops| read_tima(true, nops)).collect();
+        assert_eq!(accurate[..], fast[1..]);
+        assert_ne!(accurate[..], fast[..23]);
+
+        // Both modes render the same frames
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let hashes: Vec<u64> = [false, true]
+            .iter()
+            .map(|cycle_accurate| {
+                let mut emulator = GBEmu::new_skip_boot(&rom, true).unwrap();
+                emulator.set_cycle_accurate(*cycle_accurate);
+                run_frames(&mut emulator, 10);
+                emulator.frame_hash()
+            })
+            .collect();
+        assert_eq!(hashes[0], hashes[1]);
+    }
+
+    #[test]
+    fn doctor_trace() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut emulator = GBEmu::new_skip_boot(&rom, true).unwrap();
+        emulator.set_trace(Some(trace.clone()));
+        for _ in 0..5 {
+            emulator.step();
+        }
+        let trace = String::from_utf8(trace.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,CE",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:3E,91,E0,40",
+                "A:91 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:E0,40,18,FE",
+            ]
+        );
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn reset() {
+        let rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        run_frames(&mut emulator, 200);
+        assert!(emulator.boot_completed());
+        emulator.cpu.mmu.w(0x0000, 0x0A); // Enable RAM
+        emulator.cpu.mmu.w(0xA000, 0x42);
+        emulator.cpu.mmu.w(0xC000, 0x42);
+
+        emulator.reset().unwrap();
+        assert!(!emulator.boot_completed());
+        assert!(!emulator.can_rewind());
+        assert_eq!(emulator.tick_count(), 0);
+        assert_eq!(emulator.cpu.reg.pc, 0x0000);
+        assert_eq!(emulator.cpu.reg.sp, 0xFFFE);
+        assert_eq!(emulator.cpu.reg.a, 0x01);
+        assert_eq!(emulator.cpu.mmu.r(0xFF40), 0x00);
+        assert_eq!(emulator.cpu.mmu.r(0xFF44), 0x00);
+        assert_eq!(emulator.cpu.mmu.r(0xC000), 0x00);
+        assert_eq!(emulator.save()[0], 0x42);
+        emulator.cpu.mmu.w(0x0000, 0x0A);
+        assert_eq!(emulator.cpu.mmu.r(0xA000), 0x42);
+    }
+
+    #[test]
+    fn reset_rtc() {
+        // The RTC of MBC3 carts keeps running across a power cycle
+        let rom = test_rom(0x10, 0x02, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        emulator.cpu.mmu.w(0x0000, 0x0A);
+        emulator.cpu.mmu.w(0x4000, 0x0A); // Hours
+        emulator.cpu.mmu.w(0xA000, 0x05);
+
+        emulator.reset().unwrap();
+        emulator.cpu.mmu.w(0x0000, 0x0A);
+        emulator.cpu.mmu.w(0x4000, 0x0A);
+        assert_eq!(emulator.cpu.mmu.r(0xA000), 0x05);
+    }
+
+    #[test]
+    fn sav_round_trip() {
+        let rom = test_rom(0x10, 0x02, &TEST_PROGRAM_LOOP); // MBC3 + TIMER + RAM + BATTERY
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        let mmu = &mut emulator.cpu.mmu;
+        mmu.w(0x0000, 0x0A); // Enable RAM and RTC
+        mmu.w(0xA000, 0x42);
+        for (reg, val) in [(0x08, 30), (0x09, 59), (0x0A, 23), (0x0B, 0xFF), (0x0C, 0x01)] {
+            mmu.w(0x4000, reg);
+            mmu.w(0xA000, val);
+        }
+        // Advance by one emulated second
+        for _ in 0..1024 {
+            emulator.cpu.mmu.mbc.step(4096);
+        }
+        let sav = emulator.export_sav();
+        assert_eq!(sav.len(), 8 * 1024 + 48);
+        assert_eq!(sav[0], 0x42);
+        assert_eq!(
+            sav[8 * 1024..][..20],
+            [31, 0, 0, 0, 59, 0, 0, 0, 23, 0, 0, 0, 0xFF, 0, 0, 0, 0x01, 0, 0, 0]
+        );
+
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        emulator.import_sav(&sav).unwrap();
+        assert_eq!(emulator.export_sav()[..8 * 1024 + 20], sav[..8 * 1024 + 20]);
+        let mmu = &mut emulator.cpu.mmu;
+        mmu.w(0x0000, 0x0A);
+        mmu.w(0x6000, 0x00);
+        mmu.w(0x6000, 0x01); // Latch
+        mmu.w(0x4000, 0x00);
+        assert_eq!(mmu.r(0xA000), 0x42);
+        mmu.w(0x4000, 0x09);
+        assert_eq!(mmu.r(0xA000), 59);
+        mmu.w(0x4000, 0x0C);
+        assert_eq!(mmu.r(0xA000), 0x01);
+
+        // Mismatching sizes are rejected, leaving the RAM and RTC unchanged
+        let before = emulator.export_sav();
+        assert!(matches!(emulator.load_save(&[0x01; 16]), Err(Error::Save(_))));
+        assert!(matches!(emulator.import_sav(&vec![0x02; 10 * 1024]), Err(Error::Save(_))));
+        assert_eq!(emulator.export_sav()[..8 * 1024 + 40], before[..8 * 1024 + 40]);
+        let mut emulator = GBEmu::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false).unwrap();
+        assert!(emulator.import_sav(&[0x00; 16]).is_err());
+    }
+
+    #[test]
+    fn rtc_base() {
+        let rom = test_rom(0x10, 0x02, &TEST_PROGRAM_LOOP); // MBC3 with timer and RAM
+        let read_rtc = |emulator: &mut GBEmu| {
+            let mmu = &mut emulator.cpu.mmu;
+            mmu.w(0x6000, 0x00);
+            mmu.w(0x6000, 0x01); // Latch
+            [0x08, 0x09, 0x0A, 0x0B, 0x0C].map(|reg| {
+                mmu.w(0x4000, reg);
+                mmu.r(0xA000)
+            })
+        };
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        emulator.set_rtc_base(1_000_000);
+        let mmu = &mut emulator.cpu.mmu;
+        mmu.w(0x0000, 0x0A);
+        for (reg, val) in [(0x08, 50), (0x09, 59), (0x0A, 23), (0x0B, 0x01), (0x0C, 0x00)] {
+            mmu.w(0x4000, reg);
+            mmu.w(0xA000, val);
+        }
+        // 15 emulated seconds
+        for _ in 0..15 * 4 * 1024 {
+            mmu.mbc.step(1024);
+        }
+        assert_eq!(read_rtc(&mut emulator), [5, 0, 0, 0x02, 0x00]);
+        let sav = emulator.export_sav();
+        assert_eq!(sav[sav.len() - 8..], 1_000_015u64.to_le_bytes());
+
+        // The time elapsed between the sessions comes from the base times
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        emulator.set_rtc_base(1_000_015 + 3600);
+        emulator.import_sav(&sav).unwrap();
+        emulator.cpu.mmu.w(0x0000, 0x0A);
+        assert_eq!(read_rtc(&mut emulator), [5, 0, 1, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn load_rom() {
+        let rom_a = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut rom_b = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        rom_b[0x0134..0x0138].copy_from_slice(b"ROMB");
+        rom_b[0x014D] = header_checksum(&rom_b);
+        rom_b[0x014E] = 0x12;
+        let mut emulator = GBEmu::new(&rom_a, false).unwrap();
+        emulator.set_palette(3);
+        emulator.set_audio_limiter(Limiter::SoftClip);
+        emulator.set_audio_fast_mode(true);
+        emulator.set_audio_enabled(false);
+        run_frames(&mut emulator, 10);
+        assert_eq!(emulator.rom_title(), "TEST");
+        assert!(emulator.can_rewind());
+        assert_ne!(emulator.cpu.reg.pc, 0x0000);
+
+        // A save with the wrong size is rejected and the current ROM keeps running
+        let pc = emulator.cpu.reg.pc;
+        assert!(matches!(emulator.load_rom(&rom_b, false, &[0x42]), Err(Error::Save(_))));
+        assert_eq!(emulator.rom_title(), "TEST");
+        assert_eq!(emulator.cpu.reg.pc, pc);
+        assert!(emulator.can_rewind());
+
+        let mut save = vec![0x00; 8 * 1024];
+        save[0] = 0x42;
+        emulator.load_rom(&rom_b, false, &save).unwrap();
+        assert_eq!(emulator.rom_title(), "ROMB");
+        assert_eq!(emulator.rom_checksum(), 0x0012);
+        assert_eq!(emulator.save().len(), 8 * 1024);
+        assert_eq!(emulator.save()[0], 0x42);
+        assert!(!emulator.can_rewind());
+        assert_eq!(emulator.cpu.reg.pc, 0x0000);
+        assert_eq!(emulator.tick_count(), 0);
+        assert_eq!(emulator.current_palette(), 3);
+        assert_eq!(emulator.cpu.mmu.apu.limiter, Limiter::SoftClip);
+        assert!(emulator.cpu.mmu.apu.fast_mode());
+        assert!(!emulator.cpu.mmu.apu.enabled);
+        run_frames(&mut emulator, 10);
+        assert!(emulator.can_rewind());
+    }
+
+    #[test]
+    fn corrupt_rom() {
+        // Called through a function, to avoid keeping multiple emulators on the stack
+        fn load_error(rom: &[u8]) -> Option<Error> {
+            GBEmu::new(rom, false).err()
+        }
+        assert_eq!(load_error(&[0x00; 10]), Some(Error::Rom(RomError::TooShort(10))));
+        assert_eq!(load_error(&[0x00; 0x0150]), Some(Error::Rom(RomError::InvalidHeader)));
+        // The current ROM is kept when the new one cannot be loaded
+        let mut emulator = GBEmu::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false).unwrap();
+        assert_eq!(
+            emulator.load_rom(&[0x00; 0x0150], false, &[]),
+            Err(Error::Rom(RomError::InvalidHeader))
+        );
+        // Also when its save cannot be loaded, here for a cartridge without battery-backed memory
+        let mut rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        rom[0x014E] = 0x12;
+        assert!(matches!(emulator.load_rom(&rom, false, &[0x42]), Err(Error::Save(_))));
+        assert_ne!(emulator.rom_checksum(), 0x0012);
+    }
+
+    #[test]
+    fn invalid_input() {
+        // Invalid input to the public API is reported as an error or clamped, without panicking
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut emulator = GBEmu::new(&rom, false).unwrap();
+        assert!(matches!(emulator.set_boot_rom(Some(&[0x00; 16])), Err(Error::BootRom(_))));
+        // A CGB boot ROM cannot run a game in DMG mode
+        emulator.set_boot_rom(Some(&[0x00; 2304])).unwrap();
+        assert!(matches!(emulator.load_rom(&rom, true, &[]), Err(Error::BootRom(_))));
+        emulator.set_boot_rom(None).unwrap();
+        assert!(matches!(emulator.load_save(&[0x00; 16]), Err(Error::Save(_))));
+        assert_eq!(emulator.load_save(&[]), Ok(()));
+        assert!(matches!(emulator.restore_audio(&ApuState(vec![0xFF; 4])), Err(Error::SaveState(_))));
+        let mut other_rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        other_rom[0x014E] = 0x12;
+        let state = GBEmu::new(&other_rom, false).unwrap().save_state();
+        assert!(matches!(emulator.load_state(&state), Err(Error::SaveState(_))));
+        assert_eq!(
+            emulator
+                .play_movie(Path::new("/nonexistent/movie.gbmovie"))
+                .map_err(|err| matches!(err, Error::Movie(_))),
+            Err(true)
+        );
+        // Zero rewind settings are rejected, keeping the current config
+        let capacity = emulator.states.capacity();
+        for (freq_frames, max_seconds) in [(0, 0), (0, 10), (2, 0)] {
+            assert!(matches!(
+                emulator.set_rewind_config(freq_frames, max_seconds),
+                Err(Error::Rewind(_))
+            ));
+            assert_eq!((emulator.rewind_freq, emulator.states.capacity()), (2, capacity));
+        }
+        // Out of range settings are clamped or wrapped
+        for (multiplier, expected) in [(0.0, 0.125), (-1.0, 0.125), (100.0, 16.0), (f32::NAN, 1.0)] {
+            emulator.set_clock_multiplier(multiplier);
+            assert_eq!(emulator.clock_multiplier(), expected);
+        }
+        emulator.set_shader(100);
+        emulator.set_palette(-100);
+        let mut out = vec![0; LCDW * LCDH * 4];
+        emulator.step_frame().draw_frame(&mut out, 1);
+        // Errors can be displayed
+        assert_eq!(Error::Rom(RomError::TooShort(10)).to_string(), "ROM is too short (10 bytes)");
+    }
+
+    #[test]
+    fn cpu_registers() {
+        let rom = test_rom(0x00, 0x00, &[0x80, 0x18, 0xFE]); // ADD A, B; JR -2
+        let mut emulator = GBEmu::new_skip_boot(&rom, true).unwrap();
+        let regs = emulator.cpu_registers();
+        assert_eq!((regs.a, regs.f(), regs.pc, regs.sp), (0x01, 0xB0, 0x0100, 0xFFFE));
+        emulator.set_cpu_registers(&RegistersSnapshot {
+            a: 0x3A,
+            b: 0xC6,
+            pc: 0x0150,
+            carry: true,
+            ..regs
+        });
+        emulator.step();
+        let regs = emulator.cpu_registers();
+        assert_eq!(regs.a, 0x00);
+        assert!(regs.zero && !regs.subtract && regs.half_carry && regs.carry);
+        assert_eq!(regs.pc, 0x0151);
+        assert!(!regs.ime && !regs.halt);
+    }
+
+    #[test]
+    fn dump_memory() {
+        let mut emulator = GBEmu::new_skip_boot(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), false).unwrap();
+        emulator.cpu.mmu.w(0xC123, 0x42);
+        emulator.cpu.mmu.w(0xFF80, 0x24);
+        let dump = emulator.dump_memory();
+        for addr in [
+            0x0000, 0x0104, 0x0150, 0x8000, 0xC123, 0xE123, 0xFF00, 0xFF40, 0xFF44, 0xFF80, 0xFFFF,
+        ] {
+            assert_eq!(dump[addr as usize], emulator.cpu.mmu.r(addr), "{:04X}", addr);
+        }
+        assert_eq!(dump[0xC123], 0x42);
+        assert_eq!(dump[0xFF80], 0x24);
+    }
+
+    #[test]
+    fn movie() {
+        let path = std::env::temp_dir().join(format!("gbemu_test_{}.gbmovie", std::process::id()));
+        // Program that copies the joypad state to the background palette, so that inputs affect the frame
+        let program = [0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x47, 0x18, 0xFA]; // LD A, 0x10; LDH [P1], A; LDH A, [P1]; LDH [BGP], A; JR -6
+        let rom = test_rom(0x13, 0x02, &program); // MBC3 with battery-backed RAM
+        let mut emulator = GBEmu::new_skip_boot(&rom, false).unwrap();
+        emulator.load_save(&[0x42; 8 * 1024]).unwrap();
+        emulator.start_recording(&path);
+        let state = emulator.save_state();
+        let mut hashes = Vec::new();
+        for frame in 0..30 {
+            emulator.set_joypad(&Joypad::from_bits(frame as u8 * 7));
+            hashes.push(emulator.step_frame().frame_hash());
+        }
+        // Anything that would make the emulation diverge from the inputs is rejected
+        assert!(!emulator.can_rewind());
+        assert!(emulator.rewind().is_none());
+        assert!(matches!(emulator.load_state(&state), Err(Error::Movie(_))));
+        assert!(matches!(emulator.reset(), Err(Error::Movie(_))));
+        assert!(matches!(emulator.load_rom(&rom, false, &[]), Err(Error::Movie(_))));
+        emulator.stop_recording().unwrap();
+        assert!(emulator.stop_recording().is_err());
+        assert!(hashes.windows(2).any(|w| w[0] != w[1]));
+
+        // Replay ignoring the live inputs, from the battery RAM of the recording
+        emulator.load_save(&[0x24; 8 * 1024]).unwrap();
+        emulator.play_movie(&path).unwrap();
+        assert_eq!(emulator.save()[0], 0x42);
+        for hash in hashes {
+            emulator.set_joypad(&Joypad::from_bits(0xFF));
+            assert!(emulator.is_playing_movie());
+            assert_eq!(emulator.step_frame().frame_hash(), hash);
+        }
+        assert!(!emulator.is_playing_movie());
+
+        // Movies recorded on a different ROM are rejected
+        let mut other_rom = rom.clone();
+        other_rom[0x014F] = 0x01; // Global checksum
+        let mut other = GBEmu::new_skip_boot(&other_rom, false).unwrap();
+        assert!(other.play_movie(&path).is_err());
+        fs::remove_file(&path).unwrap();
     }
 }
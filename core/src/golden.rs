@@ -0,0 +1,81 @@
+// Compares the CPU trace of a small program exercising the ALU flags with a checked-in golden trace.
+// To regenerate it after an intended change, run: GBEMU_BLESS=1 cargo test -p gb-core golden
+
+use std::fs;
+
+use crate::gbemu::GBEmu;
+use crate::utils::test_rom;
+
+const GOLDEN_TRACE: &str = include_str!("./golden_trace.log");
+const GOLDEN_TRACE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/golden_trace.log");
+const MAX_STEPS: usize = 1000;
+
+#[rustfmt::skip]
+const PROGRAM: [u8; 63] = [
+    0x31, 0xFE, 0xFF, // LD SP, 0xFFFE
+    0x3E, 0x0F,       // LD A, 0x0F
+    0xC6, 0x01,       // ADD A, 0x01
+    0xC6, 0xF0,       // ADD A, 0xF0
+    0xCE, 0x00,       // ADC A, 0x00
+    0xD6, 0x02,       // SUB A, 0x02
+    0xDE, 0x01,       // SBC A, 0x01
+    0x27,             // DAA
+    0xE6, 0xF0,       // AND A, 0xF0
+    0xF6, 0x0F,       // OR A, 0x0F
+    0xEE, 0xFF,       // XOR A, 0xFF
+    0xFE, 0x00,       // CP A, 0x00
+    0x06, 0xFF,       // LD B, 0xFF
+    0x04,             // INC B
+    0x05,             // DEC B
+    0x2F,             // CPL
+    0x37,             // SCF
+    0x3F,             // CCF
+    0x07,             // RLCA
+    0x1F,             // RRA
+    0x0E, 0x81,       // LD C, 0x81
+    0xCB, 0x11,       // RL C
+    0xCB, 0x39,       // SRL C
+    0xCB, 0x31,       // SWAP C
+    0xCB, 0x79,       // BIT 7, C
+    0x21, 0xFF, 0x0F, // LD HL, 0x0FFF
+    0x29,             // ADD HL, HL
+    0x09,             // ADD HL, BC
+    0xE8, 0xFF,       // ADD SP, -1
+    0xF8, 0x02,       // LD HL, SP+2
+    0x3E, 0x99,       // LD A, 0x99
+    0xC6, 0x01,       // ADD A, 0x01
+    0x27,             // DAA
+    0xF5,             // PUSH AF
+    0xC1,             // POP BC
+    0xC5,             // PUSH BC
+    0xF1,             // POP AF
+    0x18, 0xFE,       // JR -2
+];
+
+fn trace() -> String {
+    // Trace each instruction until the final loop is reached
+    let end_pc = 0x0150 + PROGRAM.len() as u16 - 2;
+    let mut emulator = GBEmu::new_skip_boot(&test_rom(0x00, 0x00, &PROGRAM), true).unwrap();
+    let mut trace = emulator.trace_line();
+    for _ in 0..MAX_STEPS {
+        if emulator.step_instruction().pc == end_pc {
+            trace += &emulator.trace_line();
+            return trace;
+        }
+        trace += &emulator.trace_line();
+    }
+    panic!("End of the program not reached after {} steps", MAX_STEPS);
+}
+
+#[test]
+fn golden_trace() {
+    let trace = trace();
+    if std::env::var_os("GBEMU_BLESS").is_some() {
+        fs::write(GOLDEN_TRACE_PATH, &trace).unwrap();
+        return;
+    }
+    for (i, (line, expected)) in trace.lines().zip(GOLDEN_TRACE.lines()).enumerate() {
+        assert_eq!(line, expected, "Mismatch at instruction {}", i);
+    }
+    assert_eq!(trace.lines().count(), GOLDEN_TRACE.lines().count());
+}
@@ -0,0 +1,271 @@
+use std::fmt;
+
+use crate::mbc::{self, header_checksum};
+
+pub const HEADER_END: usize = 0x0150;
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, //
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, //
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RomError {
+    TooShort(usize), // Size in bytes
+    InvalidHeader,
+    UnsupportedCartridgeType(u8),
+    UnsupportedRamSize(u8),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TooShort(len) => write!(f, "ROM is too short ({} bytes)", len),
+            RomError::InvalidHeader => write!(f, "ROM header is invalid, the Nintendo logo is missing"),
+            RomError::UnsupportedCartridgeType(code) => write!(f, "MBC type {:#04x} not supported", code),
+            RomError::UnsupportedRamSize(code) => write!(f, "RAM size {:#04x} not supported", code),
+        }
+    }
+}
+
+pub fn validate(rom: &[u8]) -> Result<(), RomError> {
+    // Check that the ROM can be loaded, the boot ROM would also lock up on a missing logo
+    if rom.len() < HEADER_END {
+        return Err(RomError::TooShort(rom.len()));
+    }
+    if rom[0x0104..0x0134] != NINTENDO_LOGO {
+        return Err(RomError::InvalidHeader);
+    }
+    if !mbc::mbc_supported(rom[0x0147]) {
+        return Err(RomError::UnsupportedCartridgeType(rom[0x0147]));
+    }
+    if ram_size(rom[0x0149]).is_none() {
+        return Err(RomError::UnsupportedRamSize(rom[0x0149]));
+    }
+    // The smallest cartridges have two 16KB banks
+    if rom.len() < 0x8000 {
+        return Err(RomError::TooShort(rom.len()));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: Option<usize>, // In bytes, None if the size code is unknown
+    pub ram_size: Option<usize>, // In bytes, None if the size code is unknown
+    pub old_licensee: u8,
+    pub new_licensee: [u8; 2],
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub header_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Self {
+        // Parse the header at 0x0100-0x014F
+        let cgb_flag = rom[0x0143];
+        // CGB games use the last bytes of the title for the manufacturer code and the CGB flag
+        let title_size = if cgb_flag & 0x80 != 0 { 11 } else { 16 };
+        let title = rom[0x0134..][..title_size]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+        Self {
+            title,
+            cgb_flag,
+            sgb_flag: rom[0x0146],
+            cartridge_type: rom[0x0147],
+            rom_size: rom_size(rom[0x0148]),
+            ram_size: ram_size(rom[0x0149]),
+            old_licensee: rom[0x014B],
+            new_licensee: [rom[0x0144], rom[0x0145]],
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            global_checksum: u16::from_be_bytes([rom[0x014E], rom[0x014F]]),
+            header_checksum_valid: header_checksum(rom) == rom[0x014D],
+        }
+    }
+
+    pub fn cgb_supported(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    pub fn sgb_supported(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+
+    pub fn publisher(&self) -> Option<&'static str> {
+        // The old licensee code 0x33 means that the two-character new licensee code is used instead
+        if self.old_licensee == 0x33 {
+            licensee::NEW_LICENSEES
+                .iter()
+                .find(|(code, _)| **code == self.new_licensee)
+                .map(|(_, name)| *name)
+        } else {
+            licensee::OLD_LICENSEES
+                .iter()
+                .find(|(code, _)| *code == self.old_licensee)
+                .map(|(_, name)| *name)
+        }
+    }
+}
+
+pub fn rom_size(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some((32 * 1024) << code),
+        _ => None,
+    }
+}
+
+pub fn ram_size(code: u8) -> Option<usize> {
+    match code {
+        0 | 1 => Some(0),
+        2 => Some(8 * 1024),
+        3 => Some(32 * 1024),
+        4 => Some(128 * 1024),
+        5 => Some(64 * 1024),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate, CartridgeHeader, RomError};
+    use crate::mbc::header_checksum;
+    use crate::utils::{test_rom, TEST_PROGRAM_LOOP};
+
+    #[test]
+    fn parse() {
+        let mut rom = test_rom(0x1B, 0x03, &TEST_PROGRAM_LOOP); // MBC5 + RAM + BATTERY
+        rom[0x0134..0x0144].copy_from_slice(b"POKEMON_SLVAAXE\x80");
+        rom[0x0146] = 0x03;
+        rom[0x0148] = 0x06;
+        rom[0x014B] = 0x33;
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        rom[0x014C] = 0x01;
+        rom[0x014D] = header_checksum(&rom);
+        rom[0x014E..0x0150].copy_from_slice(&[0x12, 0x34]);
+
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert!(header.cgb_supported() && !header.cgb_only());
+        assert!(header.sgb_supported());
+        assert_eq!(header.cartridge_type, 0x1B);
+        assert_eq!(header.rom_size, Some(2 * 1024 * 1024));
+        assert_eq!(header.ram_size, Some(32 * 1024));
+        assert_eq!(header.old_licensee, 0x33);
+        assert_eq!(&header.new_licensee, b"01");
+        assert_eq!(header.version, 0x01);
+        assert_eq!(header.global_checksum, 0x1234);
+        assert!(header.header_checksum_valid);
+        assert_eq!(header.publisher(), Some("Nintendo Research & Development 1"));
+
+        // Header checksum mismatch
+        rom[0x014D] = rom[0x014D].wrapping_add(1);
+        let header = CartridgeHeader::parse(&rom);
+        assert!(!header.header_checksum_valid);
+        assert_eq!(header.header_checksum, rom[0x014D]);
+    }
+
+    #[test]
+    fn publisher() {
+        let header = |old_licensee: u8, new_licensee: &[u8; 2]| {
+            let mut rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+            rom[0x014B] = old_licensee;
+            rom[0x0144..0x0146].copy_from_slice(new_licensee);
+            CartridgeHeader::parse(&rom)
+        };
+        assert_eq!(header(0x33, b"A4").publisher(), Some("Konami (Yu-Gi-Oh!)"));
+        assert_eq!(header(0x33, b"ZZ").publisher(), None);
+        // The new licensee code is ignored with an old licensee code
+        assert_eq!(header(0x01, b"A4").publisher(), Some("Nintendo"));
+        assert_eq!(header(0xC3, b"\0\0").publisher(), Some("Square"));
+        assert_eq!(header(0x00, b"\0\0").publisher(), None);
+    }
+
+    #[test]
+    fn invalid_rom() {
+        assert_eq!(validate(&[0x00; 10]), Err(RomError::TooShort(10)));
+        assert_eq!(validate(&[0x00; 0x0150]), Err(RomError::InvalidHeader));
+        assert_eq!(
+            validate(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP)[..0x4000]),
+            Err(RomError::TooShort(0x4000))
+        );
+        assert_eq!(
+            validate(&test_rom(0xFC, 0x00, &TEST_PROGRAM_LOOP)),
+            Err(RomError::UnsupportedCartridgeType(0xFC))
+        );
+        assert_eq!(
+            validate(&test_rom(0x00, 0x07, &TEST_PROGRAM_LOOP)),
+            Err(RomError::UnsupportedRamSize(0x07))
+        );
+        assert_eq!(validate(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP)), Ok(()));
+    }
+}
+
+#[rustfmt::skip]
+mod licensee {
+    // Publisher names from Pan Docs, 0x00 (none) is left out
+    pub const OLD_LICENSEES: &[(u8, &str)] = &[
+        (0x01, "Nintendo"), (0x08, "Capcom"), (0x09, "HOT-B"), (0x0A, "Jaleco"), (0x0B, "Coconuts Japan"),
+        (0x0C, "Elite Systems"), (0x13, "EA (Electronic Arts)"), (0x18, "Hudson Soft"), (0x19, "ITC Entertainment"),
+        (0x1A, "Yanoman"), (0x1D, "Japan Clary"), (0x1F, "Virgin Games Ltd."), (0x24, "PCM Complete"), (0x25, "San-X"),
+        (0x28, "Kemco"), (0x29, "SETA Corporation"), (0x30, "Infogrames"), (0x31, "Nintendo"), (0x32, "Bandai"),
+        (0x34, "Konami"), (0x35, "HectorSoft"), (0x38, "Capcom"), (0x39, "Banpresto"), (0x3C, "Entertainment Interactive"),
+        (0x3E, "Gremlin"), (0x41, "Ubi Soft"), (0x42, "Atlus"), (0x44, "Malibu Interactive"), (0x46, "Angel"),
+        (0x47, "Spectrum HoloByte"), (0x49, "Irem"), (0x4A, "Virgin Games Ltd."), (0x4D, "Malibu Interactive"),
+        (0x4F, "U.S. Gold"), (0x50, "Absolute"), (0x51, "Acclaim Entertainment"), (0x52, "Activision"),
+        (0x53, "Sammy USA Corporation"), (0x54, "GameTek"), (0x55, "Park Place"), (0x56, "LJN"), (0x57, "Matchbox"),
+        (0x59, "Milton Bradley Company"), (0x5A, "Mindscape"), (0x5B, "Romstar"), (0x5C, "Naxat Soft"), (0x5D, "Tradewest"),
+        (0x60, "Titus Interactive"), (0x61, "Virgin Games Ltd."), (0x67, "Ocean Software"), (0x69, "EA (Electronic Arts)"),
+        (0x6E, "Elite Systems"), (0x6F, "Electro Brain"), (0x70, "Infogrames"), (0x71, "Interplay Entertainment"),
+        (0x72, "Broderbund"), (0x73, "Sculptured Software"), (0x75, "The Sales Curve Limited"), (0x78, "THQ"),
+        (0x79, "Accolade"), (0x7A, "Triffix Entertainment"), (0x7C, "MicroProse"), (0x7F, "Kemco"),
+        (0x80, "Misawa Entertainment"), (0x83, "LOZC G."), (0x86, "Tokuma Shoten"), (0x8B, "Bullet-Proof Software"),
+        (0x8C, "Vic Tokai Corp."), (0x8E, "Ape Inc."), (0x8F, "I'Max"), (0x91, "Chunsoft Co."), (0x92, "Video System"),
+        (0x93, "Tsubaraya Productions"), (0x95, "Varie"), (0x96, "Yonezawa/S'Pal"), (0x97, "Kemco"), (0x99, "Arc"),
+        (0x9A, "Nihon Bussan"), (0x9B, "Tecmo"), (0x9C, "Imagineer"), (0x9D, "Banpresto"), (0x9F, "Nova"),
+        (0xA1, "Hori Electric"), (0xA2, "Bandai"), (0xA4, "Konami"), (0xA6, "Kawada"), (0xA7, "Takara"),
+        (0xA9, "Technos Japan"), (0xAA, "Broderbund"), (0xAC, "Toei Animation"), (0xAD, "Toho"), (0xAF, "Namco"),
+        (0xB0, "Acclaim Entertainment"), (0xB1, "ASCII Corporation or Nexsoft"), (0xB2, "Bandai"), (0xB4, "Square Enix"),
+        (0xB6, "HAL Laboratory"), (0xB7, "SNK"), (0xB9, "Pony Canyon"), (0xBA, "Culture Brain"), (0xBB, "Sunsoft"),
+        (0xBD, "Sony Imagesoft"), (0xBF, "Sammy Corporation"), (0xC0, "Taito"), (0xC2, "Kemco"), (0xC3, "Square"),
+        (0xC4, "Tokuma Shoten"), (0xC5, "Data East"), (0xC6, "Tonkin House"), (0xC8, "Koei"), (0xC9, "UFL"),
+        (0xCA, "Ultra Games"), (0xCB, "VAP, Inc."), (0xCC, "Use Corporation"), (0xCD, "Meldac"), (0xCE, "Pony Canyon"),
+        (0xCF, "Angel"), (0xD0, "Taito"), (0xD1, "SOFEL"), (0xD2, "Quest"), (0xD3, "Sigma Enterprises"),
+        (0xD4, "ASK Kodansha Co."), (0xD6, "Naxat Soft"), (0xD7, "Copya System"), (0xD9, "Banpresto"), (0xDA, "Tomy"),
+        (0xDB, "LJN"), (0xDD, "Nippon Computer Systems"), (0xDE, "Human Ent."), (0xDF, "Altron"), (0xE0, "Jaleco"),
+        (0xE1, "Towa Chiki"), (0xE2, "Yutaka"), (0xE3, "Varie"), (0xE5, "Epoch"), (0xE7, "Athena"),
+        (0xE8, "Asmik Ace Entertainment"), (0xE9, "Natsume"), (0xEA, "King Records"), (0xEB, "Atlus"),
+        (0xEC, "Epic/Sony Records"), (0xEE, "IGS"), (0xF0, "A Wave"), (0xF3, "Extreme Entertainment"), (0xFF, "LJN"),
+    ];
+
+    pub const NEW_LICENSEES: &[(&[u8; 2], &str)] = &[
+        (b"01", "Nintendo Research & Development 1"), (b"08", "Capcom"), (b"13", "EA (Electronic Arts)"),
+        (b"18", "Hudson Soft"), (b"19", "B-AI"), (b"20", "KSS"), (b"22", "Planning Office WADA"), (b"24", "PCM Complete"),
+        (b"25", "San-X"), (b"28", "Kemco"), (b"29", "SETA Corporation"), (b"30", "Viacom"), (b"31", "Nintendo"),
+        (b"32", "Bandai"), (b"33", "Ocean Software/Acclaim Entertainment"), (b"34", "Konami"), (b"35", "HectorSoft"),
+        (b"37", "Taito"), (b"38", "Hudson Soft"), (b"39", "Banpresto"), (b"41", "Ubi Soft"), (b"42", "Atlus"),
+        (b"44", "Malibu Interactive"), (b"46", "Angel"), (b"47", "Bullet-Proof Software"), (b"49", "Irem"),
+        (b"50", "Absolute"), (b"51", "Acclaim Entertainment"), (b"52", "Activision"), (b"53", "Sammy USA Corporation"),
+        (b"54", "Konami"), (b"55", "Hi Tech Expressions"), (b"56", "LJN"), (b"57", "Matchbox"), (b"58", "Mattel"),
+        (b"59", "Milton Bradley Company"), (b"60", "Titus Interactive"), (b"61", "Virgin Games Ltd."),
+        (b"64", "Lucasfilm Games"), (b"67", "Ocean Software"), (b"69", "EA (Electronic Arts)"), (b"70", "Infogrames"),
+        (b"71", "Interplay Entertainment"), (b"72", "Broderbund"), (b"73", "Sculptured Software"),
+        (b"75", "The Sales Curve Limited"), (b"78", "THQ"), (b"79", "Accolade"), (b"80", "Misawa Entertainment"),
+        (b"83", "LOZC G."), (b"86", "Tokuma Shoten"), (b"87", "Tsukuda Original"), (b"91", "Chunsoft Co."),
+        (b"92", "Video System"), (b"93", "Ocean Software/Acclaim Entertainment"), (b"95", "Varie"),
+        (b"96", "Yonezawa/S'Pal"), (b"97", "Kaneko"), (b"99", "Pack-In-Video"), (b"9H", "Bottom Up"),
+        (b"A4", "Konami (Yu-Gi-Oh!)"), (b"BL", "MTO"), (b"DK", "Kodansha"),
+    ];
+}
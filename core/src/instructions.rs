@@ -123,8 +123,9 @@ pub type Instruction = (Op, u8, u8);
 macro_rules! add_cycles {
     ($inst:expr, $reg:expr) => {
         if format!("{:?}", $reg) == "HL" {
-            // TODO: DEC [hl] requires 3 cycles
-            if format!("{:?}", $inst).starts_with("CB") && !format!("{:?}", $inst).starts_with("CB_BIT_R8")
+            // Read-modify-write on [HL] (INC, DEC and CB except BIT) reads and writes memory, the others only access it once
+            let name = format!("{:?}", $inst);
+            if name.starts_with("INC_R8") || name.starts_with("DEC_R8") || (name.starts_with("CB") && !name.starts_with("CB_BIT_R8"))
             { 2 } else { 1 }
         } else { 0 }
     };
@@ -299,4 +300,21 @@ mod test {
         }
         assert_eq!(op_invalid, expected_op_invalid);
     }
+
+    #[test]
+    fn hl_op_cycles() {
+        let (op, cb_op) = load_opmaps();
+        // M-cycles of the instructions accessing [HL], without the CB prefix
+        let mut expected = vec![(0x34, 3), (0x35, 3), (0x36, 3)];
+        expected.extend((0x46..=0x7E).step_by(8).filter(|code| *code != 0x76).map(|code| (code, 2))); // LD r, [HL]
+        expected.extend((0x70..=0x77).filter(|code| *code != 0x76).map(|code| (code, 2))); // LD [HL], r
+        expected.extend((0x86..=0xBE).step_by(8).map(|code| (code, 2))); // ALU A, [HL]
+        for (code, cycles) in expected {
+            assert_eq!(op[code].2, cycles, "{:?}", op[code].0);
+        }
+        for code in (0x06..=0xFE).step_by(8) {
+            let cycles = if (0x40..0x80).contains(&code) { 2 } else { 3 }; // BIT only reads [HL]
+            assert_eq!(cb_op[code].2, cycles, "{:?}", cb_op[code].0);
+        }
+    }
 }
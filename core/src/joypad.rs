@@ -1,4 +1,9 @@
-#[derive(Default, Clone, Copy)]
+use crate::state::state_fields;
+use crate::utils::pack_bits;
+
+const FRAMES_PER_SECOND: u32 = 60;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct Joypad {
     // Buttons status, 1 = pressed
     pub a: bool,
@@ -10,18 +15,48 @@ pub struct Joypad {
     pub start: bool,
     pub select: bool,
 }
+state_fields!(Joypad {
+    a,
+    b,
+    up,
+    down,
+    left,
+    right,
+    start,
+    select
+});
 
 impl Joypad {
     pub fn get(&self, joyp: u8) -> u8 {
-        let joyp = joyp & 0x20; // Zero-out other values
-        joyp | if joyp & 0x20 == 0 {
+        // Bits 6-7 are unused and read as 1, the selection bits 4-5 are read back as written
+        let select = joyp & 0x30;
+        let mut keys = 0x0F; // Low when pressed, on all the selected lines
+        if select & 0x20 == 0 {
             // Buttons
-            (!self.start as u8) << 3 | (!self.select as u8) << 2 | (!self.b as u8) << 1 | (!self.a as u8)
-        } else if joyp & 0x10 == 0 {
+            keys &= (!self.start as u8) << 3 | (!self.select as u8) << 2 | (!self.b as u8) << 1 | (!self.a as u8);
+        }
+        if select & 0x10 == 0 {
             // D-pad
-            (!self.down as u8) << 3 | (!self.up as u8) << 2 | (!self.left as u8) << 1 | (!self.right as u8)
-        } else {
-            0x0F
+            keys &= (!self.down as u8) << 3 | (!self.up as u8) << 2 | (!self.left as u8) << 1 | (!self.right as u8);
+        }
+        0xC0 | select | keys
+    }
+
+    pub fn bits(&self) -> u8 {
+        // Pressed buttons as a bitmask, 1 = pressed
+        pack_bits(&[self.down, self.up, self.left, self.right, self.start, self.select, self.b, self.a])
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self {
+            a: bits & 0x01 != 0,
+            b: bits & 0x02 != 0,
+            select: bits & 0x04 != 0,
+            start: bits & 0x08 != 0,
+            right: bits & 0x10 != 0,
+            left: bits & 0x20 != 0,
+            up: bits & 0x40 != 0,
+            down: bits & 0x80 != 0,
         }
     }
 
@@ -36,3 +71,102 @@ impl Joypad {
         self.select = false;
     }
 }
+
+/*
+ Auto-fire: the turbo buttons, as a bitmask in the Joypad::bits order, are toggled at the given rate while held.
+ Apply it once per frame to the joypad state of the frontend.
+*/
+pub struct Turbo {
+    pub buttons: u8,
+    rate: u32,  // Presses per second
+    frame: u32, // Frames since the turbo buttons were pressed
+}
+
+impl Turbo {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            buttons: 0,
+            rate: rate.clamp(1, FRAMES_PER_SECOND / 2),
+            frame: 0,
+        }
+    }
+
+    pub fn toggle(&mut self, buttons: u8) {
+        self.buttons ^= buttons;
+    }
+
+    pub fn apply(&mut self, joypad: &Joypad) -> Joypad {
+        let held = joypad.bits() & self.buttons;
+        if held == 0 {
+            self.frame = 0;
+            return *joypad;
+        }
+        // Pressed for the first half of each period, starting from the press
+        let period = FRAMES_PER_SECOND / self.rate;
+        let released = self.frame % period >= period / 2;
+        self.frame += 1;
+        if released {
+            Joypad::from_bits(joypad.bits() & !held)
+        } else {
+            *joypad
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Joypad, Turbo};
+
+    #[test]
+    fn selection() {
+        let joypad = Joypad {
+            a: true,
+            down: true,
+            ..Joypad::default()
+        };
+        // No line selected
+        assert_eq!(joypad.get(0x30), 0xFF);
+        assert_eq!(joypad.get(0xFF), 0xFF);
+        // Buttons only
+        assert_eq!(joypad.get(0x10), 0xDE);
+        // D-pad only
+        assert_eq!(joypad.get(0x20), 0xE7);
+        // Both lines, the keys are combined
+        assert_eq!(joypad.get(0x00), 0xC6);
+        // A fresh register with nothing pressed
+        assert_eq!(Joypad::default().get(0x00), 0xCF);
+    }
+
+    #[test]
+    fn turbo() {
+        let mut turbo = Turbo::new(15);
+        turbo.toggle(
+            Joypad {
+                a: true,
+                ..Joypad::default()
+            }
+            .bits(),
+        );
+        let held = Joypad {
+            a: true,
+            b: true,
+            ..Joypad::default()
+        };
+        let frames: Vec<(bool, bool)> = (0..10).map(|_| turbo.apply(&held)).map(|j| (j.a, j.b)).collect();
+        // A toggles every 2 frames at 15Hz, B is held
+        let a: Vec<bool> = frames.iter().map(|f| f.0).collect();
+        assert_eq!(a, [true, true, false, false, true, true, false, false, true, true]);
+        assert!(frames.iter().all(|f| f.1));
+        // Releasing the button restarts the period, disarmed buttons are unchanged
+        assert_eq!(turbo.apply(&Joypad::default()), Joypad::default());
+        assert!(turbo.apply(&held).a);
+        turbo.toggle(
+            Joypad {
+                a: true,
+                ..Joypad::default()
+            }
+            .bits(),
+        );
+        assert!((0..10).all(|_| turbo.apply(&held) == held));
+    }
+}
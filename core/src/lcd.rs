@@ -1,18 +1,98 @@
+use std::cell::RefCell;
+
 use crate::shaders;
 
 pub const LCDW: usize = 160;
 pub const LCDH: usize = 144;
 pub const LCD_BUFFER_SIZE: usize = LCDW * LCDH;
+pub const GLYPH_W: usize = 3; // Size of the OSD font glyphs, drawn with one pixel of spacing
+pub const GLYPH_H: usize = 5;
+pub const REWIND_SYMBOL_W: usize = 10; // Size of the rewind symbol, drawn from its top-left corner
+pub const REWIND_SYMBOL_H: usize = 9;
+
+// Color transform for color vision deficiencies, applied to the frame before the shaders
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorFilter {
+    None,
+    Protanopia,
+    Deuteranopia,
+    Tritanopia,
+}
+
+impl ColorFilter {
+    pub const ALL: [ColorFilter; 4] = [
+        ColorFilter::None,
+        ColorFilter::Protanopia,
+        ColorFilter::Deuteranopia,
+        ColorFilter::Tritanopia,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorFilter::None => "No filter",
+            ColorFilter::Protanopia => "Protanopia",
+            ColorFilter::Deuteranopia => "Deuteranopia",
+            ColorFilter::Tritanopia => "Tritanopia",
+        }
+    }
+
+    #[rustfmt::skip]
+    fn matrix(&self) -> Option<[[f32; 3]; 3]> {
+        // Daltonization: the colors lost with the deficiency, simulated with the Machado et al. (2009) matrices, are
+        // shifted to the channels that are still perceived
+        let (simulation, error_shift) = match self {
+            ColorFilter::None => return None,
+            ColorFilter::Protanopia => (
+                [[0.152286, 1.052583, -0.204868], [0.114503, 0.786281, 0.099216], [-0.003882, -0.048116, 1.051998]],
+                [[0.0, 0.0, 0.0], [0.7, 1.0, 0.0], [0.7, 0.0, 1.0]],
+            ),
+            ColorFilter::Deuteranopia => (
+                [[0.367322, 0.860646, -0.227968], [0.280085, 0.672501, 0.047413], [-0.011820, 0.042940, 0.968881]],
+                [[0.0, 0.0, 0.0], [0.7, 1.0, 0.0], [0.7, 0.0, 1.0]],
+            ),
+            ColorFilter::Tritanopia => (
+                [[1.255528, -0.076749, -0.178779], [-0.078411, 0.930809, 0.147602], [0.004733, 0.691367, 0.303900]],
+                [[1.0, 0.0, 0.7], [0.0, 1.0, 0.7], [0.0, 0.0, 0.0]],
+            ),
+        };
+        // Correction matrix: I + E * (I - S)
+        let identity = |i: usize, j: usize| if i == j { 1.0 } else { 0.0 };
+        Some(std::array::from_fn(|i| std::array::from_fn(|j| {
+            identity(i, j) + (0..3).map(|k| error_shift[i][k] * (identity(k, j) - simulation[k][j])).sum::<f32>()
+        })))
+    }
+
+    fn apply(matrix: &[[f32; 3]; 3], color: u32) -> u32 {
+        // Transform the RGB channels of an RGBA color, keeping the alpha
+        let [r, g, b, a] = color.to_be_bytes();
+        let rgb = [r, g, b].map(|c| c as f32);
+        let [r, g, b] = matrix.map(|row| (row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]).round().clamp(0.0, 255.0) as u8);
+        u32::from_be_bytes([r, g, b, a])
+    }
+
+    pub fn transform(&self, color: u32) -> u32 {
+        match self.matrix() {
+            Some(matrix) => ColorFilter::apply(&matrix, color),
+            None => color,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct LCD {
     pub frame: [u32; LCD_BUFFER_SIZE],
     pub background: [u32; LCD_BUFFER_SIZE],
     pub foreground: [u32; LCD_BUFFER_SIZE],
+    pub frame_bgr555: [u16; LCD_BUFFER_SIZE], // Frame colors before color correction
 
     cgb_mode: bool,
     pub shader_idx: i16,
+    pub depth_3d: (usize, usize), // Displacement of the background and foreground for the 3D shaders
     pub palette_idx: i16,
+    pub sgb_palette: Option<[u32; 4]>, // Palette sent by SGB games, replaces the selected DMG palette
+    color_filter: ColorFilter,
+    color_matrix: Option<[[f32; 3]; 3]>, // Matrix of the color filter, computed when it's selected
+    filtered: RefCell<Vec<[u32; LCD_BUFFER_SIZE]>>, // Buffers read by the shader after the color filter, reused between frames
 }
 impl LCD {
     pub fn new() -> Self {
@@ -20,12 +100,30 @@ impl LCD {
             frame: [0; LCD_BUFFER_SIZE],
             background: [0; LCD_BUFFER_SIZE],
             foreground: [0; LCD_BUFFER_SIZE],
+            frame_bgr555: [0; LCD_BUFFER_SIZE],
             cgb_mode: false,
             shader_idx: 0,
+            depth_3d: (2, 6),
             palette_idx: 0,
+            sgb_palette: None,
+            color_filter: ColorFilter::None,
+            color_matrix: None,
+            filtered: RefCell::new(Vec::new()),
         }
     }
 
+    pub fn clear(&mut self) {
+        // Blank the screen for a new cartridge, the display settings are kept
+        *self = Self {
+            shader_idx: self.shader_idx,
+            depth_3d: self.depth_3d,
+            palette_idx: self.palette_idx,
+            color_filter: self.color_filter,
+            color_matrix: self.color_matrix,
+            ..Self::new()
+        };
+    }
+
     pub fn to_idx(x: usize, y: usize, scale: usize, dx: usize, dy: usize) -> usize {
         (x * scale + dx) + (y * scale + dy) * LCDW * scale
     }
@@ -34,11 +132,37 @@ impl LCD {
         self.palette_idx = index.rem_euclid(palette::DMG_PALETTES.len() as i16);
     }
 
+    pub fn cycle_palette_in_category(&mut self, step: i16) {
+        // Next palette of the current category, wrapping around
+        let palettes = palette::CATEGORIES[palette::category(self.palette_idx as usize)].1;
+        let pos = palettes.iter().position(|idx| *idx == self.palette_idx as usize).unwrap() as i16;
+        self.palette_idx = palettes[(pos + step).rem_euclid(palettes.len() as i16) as usize] as i16;
+    }
+
+    pub fn cycle_palette_category(&mut self, step: i16) {
+        // First palette of the next category, wrapping around
+        let category = (palette::category(self.palette_idx as usize) as i16 + step).rem_euclid(palette::CATEGORIES.len() as i16);
+        self.palette_idx = palette::CATEGORIES[category as usize].1[0] as i16;
+    }
+
     pub fn set_shader(&mut self, index: i16) {
-        self.shader_idx = index.rem_euclid(5);
+        self.shader_idx = index.rem_euclid(shaders::SHADER_NAMES.len() as i16);
+    }
+
+    pub fn color_filter(&self) -> ColorFilter {
+        self.color_filter
+    }
+
+    pub fn set_color_filter(&mut self, filter: ColorFilter) {
+        self.color_filter = filter;
+        self.color_matrix = filter.matrix();
     }
 
     pub fn to_color_dmg(val: u8, palette: u8, palette_idx: usize) -> u32 {
+        LCD::to_color_sgb(val, palette, &palette::DMG_PALETTES[palette_idx].1)
+    }
+
+    pub fn to_color_sgb(val: u8, palette: u8, colors: &[u32; 4]) -> u32 {
         let color_idx = match val {
             0 => (palette & 0x03) >> 0,
             1 => (palette & 0x0C) >> 2,
@@ -46,18 +170,22 @@ impl LCD {
             3 => (palette & 0xC0) >> 6,
             _ => panic!("Color ID {} not supported", val),
         };
-        palette::DMG_PALETTES[palette_idx].1[color_idx as usize]
+        colors[color_idx as usize]
     }
 
-    pub fn to_color_cgb(val: u8, palette: &[u8]) -> u32 {
+    pub fn to_color15(val: u8, palette: &[u8]) -> u16 {
         // Get 15bit color from palette
-        let color15 = match val {
+        match val {
             0 => u16::from_le_bytes([palette[0], palette[1]]),
             1 => u16::from_le_bytes([palette[2], palette[3]]),
             2 => u16::from_le_bytes([palette[4], palette[5]]),
             3 => u16::from_le_bytes([palette[6], palette[7]]),
             _ => panic!("Color ID {} not supported", val),
-        };
+        }
+    }
+
+    pub fn to_color_cgb(val: u8, palette: &[u8]) -> u32 {
+        let color15 = LCD::to_color15(val, palette);
         let (r5, g5, b5) = (color15 & 0x1F, (color15 >> 5) & 0x1F, (color15 >> 10) & 0x1F);
         // Convert to 32bit using color correction
         let r8 = (((r5 * 13 + g5 * 2 + b5) >> 1) & 0xFF) as u8;
@@ -67,9 +195,15 @@ impl LCD {
         (r8 as u32) << 24 | (g8 as u32) << 16 | (b8 as u32) << 8 | 0xFF
     }
 
-    fn w(&mut self, x: u8, y: u8, color: u32, is_foreground: bool) {
+    fn rgba_to_color15(color: u32) -> u16 {
+        let [r, g, b, _] = color.to_be_bytes();
+        (b as u16 >> 3) << 10 | (g as u16 >> 3) << 5 | (r as u16 >> 3)
+    }
+
+    fn w(&mut self, x: u8, y: u8, color: u32, color15: u16, is_foreground: bool) {
         let idx = LCD::to_idx(x as usize, y as usize, 1, 0, 0);
         self.frame[idx] = color;
+        self.frame_bgr555[idx] = color15;
         if is_foreground {
             self.foreground[idx] = color;
         } else {
@@ -80,44 +214,207 @@ impl LCD {
 
     pub fn w_dmg(&mut self, x: u8, y: u8, val: u8, palette: u8, is_foreground: bool) {
         self.cgb_mode = false;
-        self.w(x, y, LCD::to_color_dmg(val, palette, self.palette_idx as usize), is_foreground);
+        let color = match self.sgb_palette {
+            Some(colors) => LCD::to_color_sgb(val, palette, &colors),
+            None => LCD::to_color_dmg(val, palette, self.palette_idx as usize),
+        };
+        self.w(x, y, color, LCD::rgba_to_color15(color), is_foreground);
     }
 
     pub fn w_cgb(&mut self, x: u8, y: u8, val: u8, palette: &[u8], is_foreground: bool) {
         self.cgb_mode = true;
-        self.w(x, y, LCD::to_color_cgb(val, palette), is_foreground);
+        self.w(x, y, LCD::to_color_cgb(val, palette), LCD::to_color15(val, palette), is_foreground);
+    }
+
+    fn w_osd(&mut self, x: usize, y: usize, color: u32) {
+        // Overlay pixel drawn on top of the frame, clipped to the screen
+        if x < LCDW && y < LCDH {
+            self.w(x as u8, y as u8, color, LCD::rgba_to_color15(color), true);
+        }
     }
 
-    pub fn w_rewind_symbol(&mut self) {
-        // Draw two left triangles on top-right corner
-        let (size, px, py) = (5, LCDW as u8 - 12, 2);
+    pub fn w_rewind_symbol(&mut self, px: usize, py: usize, color: u32) {
+        // Draw two left triangles, with the top-left corner at the given position
+        let size = REWIND_SYMBOL_W / 2;
         for i in 0..2 {
-            for y in 0..(size * 2) - 1 {
+            for y in 0..REWIND_SYMBOL_H {
                 let x_start = if y < size { size - y - 1 } else { y - size + 1 };
                 for x in x_start..size {
-                    self.w(px + x + (i * size), py + y, 0xff0000ff, true);
+                    self.w_osd(px + x + (i * size), py + y, color);
                 }
             }
         }
     }
 
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, color: u32) {
+        // Draw text with the OSD font on top of the current frame, starting from the top-left corner of the first glyph
+        let (mut px, mut py) = (x, y);
+        for c in text.chars() {
+            if c == '\n' {
+                (px, py) = (x, py + GLYPH_H + 1);
+                continue;
+            }
+            for (dy, row) in font::glyph(c).iter().enumerate() {
+                for dx in (0..GLYPH_W).filter(|dx| row & (1 << (GLYPH_W - 1 - dx)) != 0) {
+                    self.w_osd(px + dx, py + dy, color);
+                }
+            }
+            px += GLYPH_W + 1;
+        }
+    }
+
+    pub fn frame_hash(&self) -> u64 {
+        // 64-bit FNV-1a hash of the frame colors
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x00000100000001b3;
+        self.frame
+            .iter()
+            .flat_map(|px| px.to_be_bytes())
+            .fold(FNV_OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+    }
+
     pub fn draw_frame(&self, out: &mut [u8], scale: usize) {
+        // The color filter is applied to copies of the buffers read by the selected shader
+        let mut filtered = self.filtered.borrow_mut();
+        let reads_layers = matches!(self.shader_idx, 3..=5);
+        let (frame, background, foreground) = match self.color_matrix {
+            Some(matrix) => {
+                let (layers, frame) = ([&self.background, &self.foreground], [&self.frame]);
+                let sources: &[&[u32; LCD_BUFFER_SIZE]] = if reads_layers { &layers } else { &frame };
+                filtered.resize(sources.len(), [0; LCD_BUFFER_SIZE]);
+                for (dst, src) in filtered.iter_mut().zip(sources) {
+                    dst.iter_mut()
+                        .zip(src.iter())
+                        .for_each(|(dst, src)| *dst = ColorFilter::apply(&matrix, *src));
+                }
+                if reads_layers {
+                    (&self.frame, &filtered[0], &filtered[1])
+                } else {
+                    (&filtered[0], &self.background, &self.foreground)
+                }
+            }
+            None => (&self.frame, &self.background, &self.foreground),
+        };
         let dmg_bg_palette = palette::DMG_PALETTES[self.palette_idx as usize].1[0];
+        let dmg_bg_palette = self
+            .color_matrix
+            .map_or(dmg_bg_palette, |matrix| ColorFilter::apply(&matrix, dmg_bg_palette));
         match self.shader_idx {
-            0 => shaders::normal(&self.frame, out, scale),
-            1 => shaders::lcd(&self.frame, out, scale, if self.cgb_mode { None } else { Some(dmg_bg_palette) }),
-            2 => shaders::crt(&self.frame, out, scale),
-            3 => shaders::drop_shadow(&self.background, &self.foreground, out, scale, 2, 2),
-            4 => shaders::anaglyph_3d(&self.background, &self.foreground, out, scale, 2, 6),
-            val => panic!("shader {} not supported", val),
+            0 => shaders::normal(frame, out, scale),
+            1 => shaders::lcd(frame, out, scale, if self.cgb_mode { None } else { Some(dmg_bg_palette) }),
+            2 => shaders::crt(frame, out, scale),
+            3 => shaders::drop_shadow(background, foreground, out, scale, 2, 2),
+            4 => shaders::anaglyph_3d(background, foreground, out, scale, self.depth_3d.0, self.depth_3d.1),
+            5 => shaders::side_by_side_3d(background, foreground, out, scale, self.depth_3d.0, self.depth_3d.1),
+            val => {
+                // Only reachable by setting shader_idx directly, set_shader keeps it in range
+                debug_assert!(false, "shader {} not supported", val);
+                shaders::normal(frame, out, scale)
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::{palette, ColorFilter, GLYPH_H, GLYPH_W, LCD, LCDH, LCDW};
+
+    fn draw_pattern(lcd: &mut LCD) {
+        for y in 0..LCDH as u8 {
+            for x in 0..LCDW as u8 {
+                lcd.w_dmg(x, y, (x / 8 + y / 8) % 4, 0b11100100, false);
+            }
+        }
+    }
+
+    #[test]
+    fn palette_categories() {
+        // Every palette belongs to exactly one category
+        let mut indices: Vec<usize> = palette::categories()
+            .iter()
+            .flat_map(|(_, palettes)| palettes.iter().copied())
+            .collect();
+        indices.sort();
+        assert_eq!(indices, (0..palette::DMG_PALETTES.len()).collect::<Vec<usize>>());
+        let mut lcd = LCD::new();
+        lcd.set_palette(1); // DMG
+        lcd.cycle_palette_in_category(1);
+        assert_eq!(palette::DMG_PALETTES[lcd.palette_idx as usize].0, "Pocket");
+        lcd.cycle_palette_in_category(1);
+        assert_eq!(palette::DMG_PALETTES[lcd.palette_idx as usize].0, "Light");
+        assert_eq!(LCD::to_color_dmg(3, 0xE4, lcd.palette_idx as usize), 0x004f3bff);
+        lcd.cycle_palette_in_category(1);
+        assert_eq!(lcd.palette_idx, 1);
+        lcd.cycle_palette_category(1);
+        assert_eq!(palette::DMG_PALETTES[lcd.palette_idx as usize].0, "Autumn");
+        lcd.cycle_palette_category(-2);
+        assert_eq!(lcd.palette_idx, 0);
+    }
+
+    #[test]
+    fn draw_text() {
+        let mut lcd = LCD::new();
+        lcd.draw_text(10, 20, "H1", 0xff0000ff);
+        let is_set = |x: usize, y: usize| lcd.frame[LCD::to_idx(x, y, 1, 0, 0)] == 0xff0000ff;
+        let rows: Vec<String> = (20..20 + GLYPH_H)
+            .map(|y| (10..10 + GLYPH_W * 2 + 1).map(|x| if is_set(x, y) { '#' } else { '.' }).collect())
+            .collect();
+        assert_eq!(rows, ["#.#..#.", "#.#.##.", "###..#.", "#.#..#.", "#.#.###"]);
+        assert_eq!(lcd.frame.iter().filter(|px| **px == 0xff0000ff).count(), 19);
+        assert_eq!(lcd.foreground[LCD::to_idx(10, 20, 1, 0, 0)], 0xff0000ff);
+        // Text out of the screen is clipped
+        lcd.draw_text(LCDW - 2, LCDH - 2, "8\n8", 0x00ff00ff);
+        assert_eq!(lcd.frame.iter().filter(|px| **px == 0x00ff00ff).count(), 3);
+    }
+
+    #[test]
+    fn frame_hash() {
+        let pattern_hash = || {
+            let mut lcd = LCD::new();
+            draw_pattern(&mut lcd);
+            lcd.frame_hash()
+        };
+        let hash = pattern_hash();
+        assert_eq!(hash, pattern_hash());
+        assert_ne!(hash, LCD::new().frame_hash());
+        // A single changed pixel changes the hash
+        let mut lcd = LCD::new();
+        draw_pattern(&mut lcd);
+        lcd.w_dmg(100, 100, (100 / 8 + 100 / 8 + 1) % 4, 0b11100100, false);
+        assert_ne!(lcd.frame_hash(), hash);
+    }
+
+    #[test]
+    fn color_filters() {
+        for (filter, red, green) in [
+            (ColorFilter::None, 0xff0000ff, 0x9bbc0fff),
+            (ColorFilter::Protanopia, 0xff7a98ff, 0x9ba500ff),
+            (ColorFilter::Deuteranopia, 0xff2a74ff, 0x9ba300ff),
+            (ColorFilter::Tritanopia, 0xbd1300ff, 0x307f0fff),
+        ] {
+            assert_eq!(filter.transform(0xff0000ff), red, "{:?}", filter);
+            assert_eq!(filter.transform(0x9bbc0fff), green, "{:?}", filter);
+            // Grays are unchanged and the alpha is kept
+            assert_eq!(filter.transform(0x80808000), 0x80808000, "{:?}", filter);
+        }
+        // Applied to the drawn frame
+        let mut lcd = LCD::new();
+        lcd.draw_text(0, 0, "A", 0xff0000ff);
+        let mut out = vec![0; LCDW * LCDH * 4];
+        lcd.set_color_filter(ColorFilter::Deuteranopia);
+        lcd.draw_frame(&mut out, 1);
+        assert_eq!(out[4..8], [0xff, 0x2a, 0x74, 0xff]);
+        // Also with the shaders reading the background and foreground
+        lcd.set_shader(3);
+        lcd.draw_frame(&mut out, 1);
+        assert_eq!(out[4..8], [0xff, 0x2a, 0x74, 0xff]);
+    }
+}
+
 #[rustfmt::skip]
 pub mod palette{
     // Color mappings for DMG
-    pub const DMG_PALETTES: [(&str, [u32; 4]); 13] = [
+    pub const DMG_PALETTES: [(&str, [u32; 4]); 15] = [
         ( "Default", [0xfafbf6ff, 0xc6b7beff, 0x565a75ff, 0x0f0f1bff]),
         (     "DMG", [0x818f38ff, 0x647d43ff, 0x566d3fff, 0x314a2dff]),
         (  "Autumn", [0xdad3afff, 0xd58863ff, 0xc23a73ff, 0x2c1e74ff]),
@@ -131,5 +428,45 @@ pub mod palette{
         ( "Nuclear", [0xe2f3e4ff, 0x94e344ff, 0x46878fff, 0x332c50ff]),
         (  "Rustic", [0xa96868ff, 0xedb4a1ff, 0x764462ff, 0x2c2137ff]),
         (    "Wish", [0x8be5ffff, 0x608fcfff, 0x7550e8ff, 0x622e4cff]),
+        (  "Pocket", [0xc4cfa1ff, 0x8b956dff, 0x4d533cff, 0x1f1f1fff]),
+        (   "Light", [0x00b581ff, 0x009a71ff, 0x00694aff, 0x004f3bff]),
+    ];
+
+    // Palettes indices grouped by category, in the order used to cycle through them
+    pub const CATEGORIES: [(&str, &[usize]); 3] = [
+        (  "Classic", &[0]),
+        ("Authentic", &[1, 13, 14]),
+        (    "Fancy", &[2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
     ];
+
+    pub fn categories() -> &'static [(&'static str, &'static [usize])] {
+        &CATEGORIES
+    }
+
+    pub fn category(palette_idx: usize) -> usize {
+        CATEGORIES.iter().position(|(_, palettes)| palettes.contains(&palette_idx)).unwrap()
+    }
+}
+
+// OSD font, also used by the frontends to draw their own overlays
+#[rustfmt::skip]
+pub mod font {
+    // 3x5 glyphs, one row per byte with the leftmost pixel in bit 2. Lowercase letters are drawn as uppercase
+    pub fn glyph(c: char) -> [u8; super::GLYPH_H] {
+        match c.to_ascii_uppercase() {
+            '0' => [7, 5, 5, 5, 7], '1' => [2, 6, 2, 2, 7], '2' => [7, 1, 7, 4, 7], '3' => [7, 1, 7, 1, 7],
+            '4' => [5, 5, 7, 1, 1], '5' => [7, 4, 7, 1, 7], '6' => [7, 4, 7, 5, 7], '7' => [7, 1, 1, 1, 1],
+            '8' => [7, 5, 7, 5, 7], '9' => [7, 5, 7, 1, 7],
+            'A' => [2, 5, 7, 5, 5], 'B' => [6, 5, 6, 5, 6], 'C' => [3, 4, 4, 4, 3], 'D' => [6, 5, 5, 5, 6],
+            'E' => [7, 4, 6, 4, 7], 'F' => [7, 4, 6, 4, 4], 'G' => [3, 4, 5, 5, 3], 'H' => [5, 5, 7, 5, 5],
+            'I' => [7, 2, 2, 2, 7], 'J' => [1, 1, 1, 5, 2], 'K' => [5, 5, 6, 5, 5], 'L' => [4, 4, 4, 4, 7],
+            'M' => [5, 7, 7, 5, 5], 'N' => [6, 5, 5, 5, 5], 'O' => [2, 5, 5, 5, 2], 'P' => [6, 5, 6, 4, 4],
+            'Q' => [2, 5, 5, 6, 3], 'R' => [6, 5, 6, 5, 5], 'S' => [3, 4, 2, 1, 6], 'T' => [7, 2, 2, 2, 2],
+            'U' => [5, 5, 5, 5, 7], 'V' => [5, 5, 5, 5, 2], 'W' => [5, 5, 7, 7, 5], 'X' => [5, 5, 2, 5, 5],
+            'Y' => [5, 5, 2, 2, 2], 'Z' => [7, 1, 2, 4, 7],
+            ' ' => [0, 0, 0, 0, 0], '.' => [0, 0, 0, 0, 2], ':' => [0, 2, 0, 2, 0], '-' => [0, 0, 7, 0, 0],
+            '+' => [0, 2, 7, 2, 0], '!' => [2, 2, 2, 0, 2], '/' => [1, 1, 2, 4, 4], '%' => [5, 1, 2, 4, 5],
+            _ => [6, 1, 2, 0, 2], // '?'
+        }
+    }
 }
@@ -2,16 +2,34 @@ pub mod apu;
 pub mod clock;
 pub mod cpu;
 pub mod debug;
+pub mod error;
 pub mod gbemu;
+pub mod header;
 pub mod instructions;
 pub mod joypad;
 pub mod lcd;
 pub mod mbc;
 pub mod mmu;
+pub mod movie;
+pub mod patch;
 pub mod ppu;
 pub mod registers;
+pub mod rewind;
+pub mod rom;
+pub mod serial;
+pub mod sgb;
 pub mod shaders;
+mod state;
 pub mod utils;
 
-pub use gbemu::GBEmu;
-pub use joypad::Joypad;
+#[cfg(test)]
+mod golden;
+
+pub use error::Error;
+pub use gbemu::{GBEmu, SaveState};
+pub use header::{CartridgeHeader, RomError};
+pub use joypad::{Joypad, Turbo};
+pub use lcd::ColorFilter;
+pub use mbc::MbcKind;
+pub use ppu::{Layer, Sprite};
+pub use registers::RegistersSnapshot;
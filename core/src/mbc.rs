@@ -1,48 +1,66 @@
+use std::fmt;
 use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::header;
+use crate::state::{state_fields, StateField};
 
 pub const DMG_BOOT_ROM: &[u8] = include_bytes!("./boot_dmg.bin");
 pub const CGB_BOOT_ROM: &[u8] = include_bytes!("./boot_cgb.bin");
+pub const DMG_BOOT_ROM_SIZE: usize = 0x0100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x0900;
 
 #[derive(Clone)]
 pub struct MBC {
     rom: Rc<Vec<u8>>,
-    ram: Vec<u8>,
+    pub(crate) ram: Vec<u8>,
     mbc_type: Box<dyn MBCType>,
+    kind: MbcKind,
 
     force_dmg: bool,
+    boot_rom: Option<Rc<Vec<u8>>>, // Replaces the embedded boot ROM
     pub boot_rom_unmounted: bool,
+    ram_dirty: bool,                        // RAM written since the last save
+    pub unhandled_write: Option<(u16, u8)>, // Last write ignored by the MBC, as address and value
+    rtc_clock: Option<(u64, u64)>,          // Fixed base time and emulated ticks since then, replacing the system time
 }
 
 impl MBC {
-    pub fn new(rom: &[u8], force_dmg: bool) -> Self {
-        let mbc_type = rom[0x0147];
-        let ram_size = match rom[0x0149] {
-            0 | 1 => 0,
-            2 => 8 * 1024,
-            3 => 32 * 1024,
-            4 => 128 * 1024,
-            5 => 64 * 1024,
-            v => panic!("RAM size {:#04x} not supported", v),
+    pub fn new(rom: &[u8], force_dmg: bool, boot_rom: Option<&[u8]>) -> Self {
+        let kind = MbcKind::from_cartridge_type(rom[0x0147]).unwrap_or_else(|| panic!("MBC type {:#04x} not supported", rom[0x0147]));
+        let ram_size = match header::ram_size(rom[0x0149]) {
+            Some(ram_size) => ram_size,
+            None => panic!("RAM size {:#04x} not supported", rom[0x0149]),
         };
+        // Custom boot ROMs are checked by GBEmu, the embedded one is used if the size is still invalid
+        let boot_rom = boot_rom.filter(|boot_rom| check_boot_rom(boot_rom, force_dmg).is_ok());
         Self {
             rom: Rc::new(rom.to_vec()),
             ram: vec![0; ram_size],
-            mbc_type: new_mbc(mbc_type),
+            mbc_type: new_mbc(kind),
+            kind,
             force_dmg: force_dmg,
+            boot_rom: boot_rom.map(|boot_rom| Rc::new(boot_rom.to_vec())),
             boot_rom_unmounted: false,
+            ram_dirty: false,
+            unhandled_write: None,
+            rtc_clock: None,
         }
     }
 
     pub fn r(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x00FF if self.force_dmg && !self.boot_rom_unmounted => DMG_BOOT_ROM[addr as usize],
-            0x0000..=0x00FF | 0x0200..=0x08FF if !self.force_dmg && !self.boot_rom_unmounted => CGB_BOOT_ROM[addr as usize],
+            0x0000..=0x00FF if self.force_dmg && !self.boot_rom_unmounted => self.boot_rom()[addr as usize],
+            0x0000..=0x00FF | 0x0200..=0x08FF if !self.force_dmg && !self.boot_rom_unmounted => self.boot_rom()[addr as usize],
             _ => self.mbc_type.r(addr, &self.rom, &self.ram),
         }
     }
 
     pub fn w(&mut self, addr: u16, val: u8) {
-        self.mbc_type.w(addr, val, &self.rom, &mut self.ram)
+        if self.mbc_type.ignores_write(addr, val, &self.rom, &self.ram) {
+            self.unhandled_write = Some((addr, val));
+        }
+        self.ram_dirty |= self.mbc_type.w(addr, val, &self.rom, &mut self.ram);
     }
 
     pub fn title(&self) -> String {
@@ -57,28 +75,166 @@ impl MBC {
         title
     }
 
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn kind(&self) -> MbcKind {
+        self.kind
+    }
+
+    fn boot_rom(&self) -> &[u8] {
+        match &self.boot_rom {
+            Some(boot_rom) => boot_rom,
+            None if self.force_dmg => DMG_BOOT_ROM,
+            None => CGB_BOOT_ROM,
+        }
+    }
+
+    pub fn force_dmg(&self) -> bool {
+        self.force_dmg
+    }
+
     pub fn cgb_mode(&self) -> bool {
         let mode = self.rom[0x143];
         !self.force_dmg && mode & 0x80 != 0
     }
 
+    pub fn sgb_mode(&self) -> bool {
+        !self.cgb_mode() && self.rom[0x146] == 0x03
+    }
+
     pub fn checksum(&self) -> u16 {
         u16::from_le_bytes([self.rom[0x014E], self.rom[0x014F]])
     }
 
-    pub fn save(&self) -> &[u8] {
+    pub fn save(&mut self) -> &[u8] {
+        self.ram_dirty = false;
         &self.ram
     }
 
-    pub fn load(&mut self, save: &[u8]) {
+    pub fn ram_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+
+    pub fn load(&mut self, save: &[u8]) -> Result<(), String> {
+        // RAM content, optionally followed by the RTC registers as written by export_sav or other emulators.
+        // Saves with an unexpected size are rejected without changing the memory.
         let ram_size = self.ram.len();
-        self.ram.copy_from_slice(&save[..std::cmp::min(ram_size, save.len())]);
+        let rtc_size = match save.len().checked_sub(ram_size) {
+            Some(size @ (RTC_SAV_SIZE | RTC_SAV_SIZE_SHORT)) if self.mbc_type.rtc().is_some() => size,
+            _ => 0,
+        };
+        if save.len() != ram_size + rtc_size {
+            return Err(format!(
+                "Save size ({} bytes) does not match the cartridge RAM size ({} bytes)",
+                save.len(),
+                ram_size
+            ));
+        }
+        self.ram.copy_from_slice(&save[..ram_size]);
+        let now = self.now();
+        if let Some(rtc) = self.mbc_type.rtc_mut().filter(|_| rtc_size > 0) {
+            rtc.import(&save[ram_size..], now);
+        }
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    pub fn step(&mut self, elapsed_ticks: u16) {
+        self.mbc_type.step(elapsed_ticks);
+        if let Some((_, ticks)) = &mut self.rtc_clock {
+            *ticks += elapsed_ticks as u64;
+        }
+    }
+
+    pub fn rtc_base(&self) -> Option<u64> {
+        self.rtc_clock.map(|(base, _)| base)
+    }
+
+    pub fn set_rtc_base(&mut self, unix_seconds: u64) {
+        // Freeze the clock used for the save timestamps, the time then only advances with the emulated cycles
+        self.rtc_clock = Some((unix_seconds, 0));
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        match self.rtc_clock {
+            Some((base, ticks)) => base + ticks / RTC_TICKS_PER_SECOND as u64,
+            None => unix_time(),
+        }
+    }
+
+    pub fn export_sav(&self) -> Vec<u8> {
+        // RAM content followed by the RTC registers, using the same layout as BGB and VBA-M
+        let mut sav = self.ram.clone();
+        if let Some(rtc) = self.mbc_type.rtc() {
+            sav.extend_from_slice(&rtc.export(self.now()));
+        }
+        sav
+    }
+
+    pub fn import_sav(&mut self, sav: &[u8]) -> Result<(), String> {
+        if self.ram.is_empty() && self.mbc_type.rtc().is_none() {
+            return Err(String::from("The cartridge has no battery-backed memory"));
+        }
+        self.load(sav)
     }
 }
 
+impl StateField for MBC {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.ram.save(out);
+        self.mbc_type.save_state(out);
+        self.boot_rom_unmounted.save(out);
+        self.rtc_clock.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.ram.load(data)?;
+        self.mbc_type.load_state(data)?;
+        self.boot_rom_unmounted.load(data)?;
+        self.rtc_clock.load(data)
+    }
+}
+
+fn unix_time() -> u64 {
+    // The system time is not available on wasm, in that case the time elapsed between sessions is ignored
+    if cfg!(target_arch = "wasm32") {
+        return 0;
+    }
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 pub trait MBCType: MBCTypeClone {
     fn r(&self, addr: u16, rom: &[u8], ram: &[u8]) -> u8;
-    fn w(&mut self, addr: u16, val: u8, rom: &[u8], ram: &mut [u8]);
+    // Returns whether the write changed the battery-backed data, i.e. the RAM or the RTC
+    fn w(&mut self, addr: u16, val: u8, rom: &[u8], ram: &mut [u8]) -> bool;
+
+    fn step(&mut self, _elapsed_ticks: u16) {}
+
+    // Whether a write has no effect, to help debugging games expecting a different MBC
+    fn ignores_write(&self, _addr: u16, _val: u8, _rom: &[u8], _ram: &[u8]) -> bool {
+        false
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    // Banking registers, saved and loaded as part of the emulator state
+    fn save_state(&self, _out: &mut Vec<u8>) {}
+
+    fn load_state(&mut self, _data: &mut &[u8]) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 pub trait MBCTypeClone {
@@ -100,6 +256,63 @@ impl Clone for Box<dyn MBCType> {
     }
 }
 
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..0x014D].iter().fold(0u8, |acc, v| acc.wrapping_sub(*v).wrapping_sub(1))
+}
+
+/*
+ CGB compatibility palettes for DMG games, looked up with the same tables used by the CGB boot ROM.
+ Source: https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+*/
+const COMPAT_CHECKSUMS: usize = 0x06C7; // Title checksums, the last 14 are disambiguated by the 4th title letter
+const COMPAT_CHECKSUMS_LEN: usize = 79;
+const COMPAT_CHECKSUMS_AMBIGUOUS: usize = 65;
+const COMPAT_LETTERS: usize = 0x0716; // 4th title letters for ambiguous checksums, in rows of 14
+const COMPAT_LETTERS_LEN: usize = 29;
+const COMPAT_IDS: usize = 0x0733; // Palette combination (low 5 bits) and shuffling flags (high 3 bits) for each ID
+const COMPAT_COMBINATIONS: usize = 0x0791; // OBJ0, OBJ1 and BG palette offsets for each combination
+const COMPAT_PALETTES: usize = 0x07E8; // BGR555 palettes
+
+pub fn compat_palette_id(rom: &[u8]) -> Option<usize> {
+    // Only games published by Nintendo are recognized
+    let nintendo = rom[0x014B] == 0x01 || (rom[0x014B] == 0x33 && &rom[0x0144..0x0146] == b"01");
+    if !nintendo {
+        return None;
+    }
+    let title = &rom[0x0134..0x0144];
+    let hash = title.iter().fold(0u8, |acc, v| acc.wrapping_add(*v));
+    let checksums = &CGB_BOOT_ROM[COMPAT_CHECKSUMS..][..COMPAT_CHECKSUMS_LEN];
+    let letters = &CGB_BOOT_ROM[COMPAT_LETTERS..][..COMPAT_LETTERS_LEN];
+    for (i, _) in checksums.iter().enumerate().filter(|(_, v)| **v == hash) {
+        if i < COMPAT_CHECKSUMS_AMBIGUOUS {
+            return Some(i);
+        }
+        let letter_idx = (i - COMPAT_CHECKSUMS_AMBIGUOUS..COMPAT_LETTERS_LEN)
+            .step_by(14)
+            .find(|idx| letters[*idx] == title[3]);
+        if let Some(letter_idx) = letter_idx {
+            return Some(COMPAT_CHECKSUMS_AMBIGUOUS + letter_idx);
+        }
+    }
+    None
+}
+
+pub fn compat_palettes(id: usize) -> [&'static [u8]; 3] {
+    // Returns the BG, OBJ0 and OBJ1 palettes
+    let flags = CGB_BOOT_ROM[COMPAT_IDS + id];
+    let combination = &CGB_BOOT_ROM[COMPAT_COMBINATIONS + (flags & 0x1F) as usize * 3..][..3];
+    let bg = combination[2];
+    let obj0 = if flags & 0x20 != 0 { combination[0] } else { bg };
+    let obj1 = if flags & 0x80 != 0 {
+        combination[1]
+    } else if flags & 0x40 != 0 {
+        combination[0]
+    } else {
+        bg
+    };
+    [bg, obj0, obj1].map(|offset| &CGB_BOOT_ROM[COMPAT_PALETTES + offset as usize..][..8])
+}
+
 fn bank_addr(addr: u16, bank_nr: u16, base: u16, size: u16) -> usize {
     (addr - base) as usize + bank_nr as usize * size as usize
 }
@@ -108,16 +321,107 @@ fn mask_bank_nr(bank_nr: u16, size: usize) -> u16 {
     bank_nr & ((size - 1) >> 14) as u16
 }
 
-fn new_mbc(mbc_type: u8) -> Box<dyn MBCType> {
-    match mbc_type {
-        0x00 => Box::new(MBC0::default()),
-        0x01..=0x03 => Box::new(MBC1::default()),
-        0x0F..=0x13 => Box::new(MBC3::default()),
-        0x19..=0x1E => Box::new(MBC5::default()),
-        v => panic!("MBC type {:#04x} not supported", v),
+// Supported cartridge types, with the hardware found on the cartridge besides the ROM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MbcKind {
+    RomOnly,
+    MBC1 { ram: bool, battery: bool },
+    MBC3 { ram: bool, battery: bool, rtc: bool },
+    MBC5 { ram: bool, battery: bool, rumble: bool },
+}
+
+impl MbcKind {
+    pub fn from_cartridge_type(code: u8) -> Option<Self> {
+        match code {
+            0x00 => Some(MbcKind::RomOnly),
+            0x01..=0x03 => Some(MbcKind::MBC1 {
+                ram: code >= 0x02,
+                battery: code == 0x03,
+            }),
+            0x0F..=0x13 => Some(MbcKind::MBC3 {
+                ram: matches!(code, 0x10 | 0x12 | 0x13),
+                battery: matches!(code, 0x0F | 0x10 | 0x13),
+                rtc: code <= 0x10,
+            }),
+            0x19..=0x1E => Some(MbcKind::MBC5 {
+                ram: !matches!(code, 0x19 | 0x1C),
+                battery: matches!(code, 0x1B | 0x1E),
+                rumble: code >= 0x1C,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn ram(&self) -> bool {
+        match *self {
+            MbcKind::RomOnly => false,
+            MbcKind::MBC1 { ram, .. } | MbcKind::MBC3 { ram, .. } | MbcKind::MBC5 { ram, .. } => ram,
+        }
+    }
+
+    pub fn battery(&self) -> bool {
+        match *self {
+            MbcKind::RomOnly => false,
+            MbcKind::MBC1 { battery, .. } | MbcKind::MBC3 { battery, .. } | MbcKind::MBC5 { battery, .. } => battery,
+        }
+    }
+
+    pub fn rtc(&self) -> bool {
+        matches!(self, MbcKind::MBC3 { rtc: true, .. })
+    }
+
+    pub fn rumble(&self) -> bool {
+        matches!(self, MbcKind::MBC5 { rumble: true, .. })
+    }
+}
+
+impl fmt::Display for MbcKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Same naming as the cartridge type list of Pan Docs, e.g. MBC3+TIMER+RAM+BATTERY
+        let name = match self {
+            MbcKind::RomOnly => return write!(f, "ROM ONLY"),
+            MbcKind::MBC1 { .. } => "MBC1",
+            MbcKind::MBC3 { .. } => "MBC3",
+            MbcKind::MBC5 { .. } => "MBC5",
+        };
+        write!(f, "{}", name)?;
+        for (enabled, feature) in [
+            (self.rtc(), "TIMER"),
+            (self.rumble(), "RUMBLE"),
+            (self.ram(), "RAM"),
+            (self.battery(), "BATTERY"),
+        ] {
+            if enabled {
+                write!(f, "+{}", feature)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn new_mbc(kind: MbcKind) -> Box<dyn MBCType> {
+    match kind {
+        MbcKind::RomOnly => Box::new(MBC0::default()),
+        MbcKind::MBC1 { .. } => Box::new(MBC1::default()),
+        MbcKind::MBC3 { rtc: true, .. } => Box::new(MBC3::with_rtc()),
+        MbcKind::MBC3 { .. } => Box::new(MBC3::default()),
+        MbcKind::MBC5 { .. } => Box::new(MBC5::default()),
     }
 }
 
+pub fn check_boot_rom(boot_rom: &[u8], force_dmg: bool) -> Result<(), String> {
+    // The DMG boot ROM is mapped at 0x0000-0x00FF, the CGB one also at 0x0200-0x08FF
+    let size = if force_dmg { DMG_BOOT_ROM_SIZE } else { CGB_BOOT_ROM_SIZE };
+    if boot_rom.len() != size {
+        return Err(format!("Boot ROM must be {} bytes, found {}", size, boot_rom.len()));
+    }
+    Ok(())
+}
+
+pub fn mbc_supported(mbc_type: u8) -> bool {
+    MbcKind::from_cartridge_type(mbc_type).is_some()
+}
+
 #[derive(Default, Clone, Copy)]
 struct MBC0;
 impl MBCType for MBC0 {
@@ -128,7 +432,13 @@ impl MBCType for MBC0 {
         }
     }
 
-    fn w(&mut self, _: u16, _: u8, _: &[u8], _: &mut [u8]) {}
+    fn w(&mut self, _: u16, _: u8, _: &[u8], _: &mut [u8]) -> bool {
+        false
+    }
+
+    fn ignores_write(&self, _: u16, _: u8, _: &[u8], _: &[u8]) -> bool {
+        true
+    }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -138,6 +448,12 @@ struct MBC1 {
     ram_enabled: bool,
     mode: bool,
 }
+state_fields!(MBC1 {
+    rom_bank,
+    ram_bank,
+    ram_enabled,
+    mode
+});
 impl MBC1 {
     fn default() -> Self {
         Self {
@@ -191,7 +507,8 @@ impl MBCType for MBC1 {
             }
             0x4000..=0x7FFF => rom[bank_addr(addr, self.high_bank(rom.len(), false) as u16, 0x4000, 0x4000)],
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
+                // Carts without RAM read open bus even when it is enabled
+                if self.ram_enabled && !ram.is_empty() {
                     ram[self.ram_addr(addr, &ram)]
                 } else {
                     0xFF
@@ -201,7 +518,7 @@ impl MBCType for MBC1 {
         }
     }
 
-    fn w(&mut self, addr: u16, val: u8, rom: &[u8], ram: &mut [u8]) {
+    fn w(&mut self, addr: u16, val: u8, rom: &[u8], ram: &mut [u8]) -> bool {
         match addr {
             0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
             0x2000..=0x3FFF => {
@@ -213,13 +530,126 @@ impl MBCType for MBC1 {
             }
             0x4000..=0x5FFF => self.ram_bank = val & 0x03,
             0x6000..=0x7FFF => self.mode = val & 0x01 != 0,
-            0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    ram[self.ram_addr(addr, &ram)] = val
-                }
+            0xA000..=0xBFFF if self.ram_enabled && !ram.is_empty() => {
+                ram[self.ram_addr(addr, &ram)] = val;
+                return true;
             }
             _ => (),
         }
+        false
+    }
+
+    fn ignores_write(&self, addr: u16, _: u8, rom: &[u8], ram: &[u8]) -> bool {
+        match addr {
+            // The secondary bank and the mode only matter with more than 8KB of RAM or 512KB of ROM
+            0x4000..=0x7FFF => ram.len() <= 0x2000 && rom.len() <= 0x80000,
+            0xA000..=0xBFFF => !self.ram_enabled || ram.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.save(out);
+    }
+
+    fn load_state(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.load(data)
+    }
+}
+
+const RTC_TICKS_PER_SECOND: u32 = 4 * 1024 * 1024;
+const RTC_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+const RTC_SAV_SIZE: usize = 48; // Registers, latched registers and 64-bit timestamp
+const RTC_SAV_SIZE_SHORT: usize = 44; // Same, with a 32-bit timestamp
+
+#[derive(Default, Clone, Copy)]
+pub struct Rtc {
+    regs: [u8; 5],    // Seconds, minutes, hours, day counter low, day counter high + halt + carry
+    latched: [u8; 5], // Registers visible to the game
+    latch_prev: u8,
+    ticks: u32, // Ticks elapsed in the current second
+}
+state_fields!(Rtc {
+    regs,
+    latched,
+    latch_prev,
+    ticks
+});
+impl Rtc {
+    fn step(&mut self, elapsed_ticks: u16) {
+        if self.halted() {
+            return;
+        }
+        self.ticks += elapsed_ticks as u32;
+        if self.ticks >= RTC_TICKS_PER_SECOND {
+            self.ticks -= RTC_TICKS_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.regs[4] & 0x40 != 0
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        let [s, m, h, dl, dh] = self.regs.map(|v| v as u64);
+        let days = dl | (dh & 0x01) << 8;
+        let total = s + m * 60 + h * 3600 + days * 86400 + seconds;
+        let days = total / 86400;
+        let carry = dh & 0x80 != 0 || days > 0x1FF;
+        self.regs = [
+            (total % 60) as u8,
+            (total / 60 % 60) as u8,
+            (total / 3600 % 24) as u8,
+            days as u8,
+            (days >> 8 & 0x01) as u8 | (carry as u8) << 7,
+        ];
+    }
+
+    fn latch(&mut self, val: u8) {
+        if self.latch_prev == 0x00 && val == 0x01 {
+            self.latched = self.regs;
+        }
+        self.latch_prev = val;
+    }
+
+    fn r(&self, reg: usize) -> u8 {
+        self.latched[reg]
+    }
+
+    fn w(&mut self, reg: usize, val: u8) {
+        self.regs[reg] = val & RTC_MASKS[reg];
+        self.latched[reg] = self.regs[reg];
+        if reg == 0 {
+            self.ticks = 0;
+        }
+    }
+
+    fn export(&self, timestamp: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RTC_SAV_SIZE);
+        for reg in self.regs.iter().chain(self.latched.iter()) {
+            data.extend_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data
+    }
+
+    fn import(&mut self, data: &[u8], timestamp: u64) {
+        let reg = |i: usize| data[i * 4];
+        for (i, ((reg_val, latched), mask)) in self.regs.iter_mut().zip(&mut self.latched).zip(RTC_MASKS).enumerate() {
+            *reg_val = reg(i) & mask;
+            *latched = reg(i + 5) & mask;
+        }
+        let mut saved_timestamp = [0; 8];
+        saved_timestamp[..data.len() - 40].copy_from_slice(&data[40..]);
+        let saved_timestamp = u64::from_le_bytes(saved_timestamp);
+        // Account for the time elapsed since the save was exported
+        if saved_timestamp != 0 && timestamp > saved_timestamp {
+            self.advance(timestamp - saved_timestamp);
+        }
     }
 }
 
@@ -229,7 +659,17 @@ struct MBC3 {
     ram_bank: u8,
     ram_enabled: bool,
     rtc_mapped: bool,
+    rtc_reg: usize,
+    rtc: Option<Rtc>,
 }
+state_fields!(MBC3 {
+    rom_bank,
+    ram_bank,
+    ram_enabled,
+    rtc_mapped,
+    rtc_reg,
+    rtc
+});
 impl MBC3 {
     fn default() -> Self {
         Self {
@@ -237,6 +677,13 @@ impl MBC3 {
             ..Default::default()
         }
     }
+
+    fn with_rtc() -> Self {
+        Self {
+            rtc: Some(Rtc::default()),
+            ..MBC3::default()
+        }
+    }
 }
 impl MBCType for MBC3 {
     fn r(&self, addr: u16, rom: &[u8], ram: &[u8]) -> u8 {
@@ -247,7 +694,7 @@ impl MBCType for MBC3 {
                 if !self.ram_enabled {
                     0xFF
                 } else if self.rtc_mapped {
-                    0x00
+                    self.rtc.map_or(0xFF, |rtc| rtc.r(self.rtc_reg))
                 } else {
                     ram[bank_addr(addr, self.ram_bank as u16, 0xA000, 0x2000)]
                 }
@@ -256,7 +703,7 @@ impl MBCType for MBC3 {
         }
     }
 
-    fn w(&mut self, addr: u16, val: u8, _: &[u8], ram: &mut [u8]) {
+    fn w(&mut self, addr: u16, val: u8, _: &[u8], ram: &mut [u8]) -> bool {
         match addr {
             0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
             0x2000..=0x3FFF => self.rom_bank = if val & 0x7F != 0 { val & 0x7F } else { 1 },
@@ -265,16 +712,60 @@ impl MBCType for MBC3 {
                     self.rtc_mapped = false;
                     self.ram_bank = val & 0x03
                 }
-                0x08..=0x0C => self.rtc_mapped = true,
+                0x08..=0x0C => {
+                    self.rtc_mapped = true;
+                    self.rtc_reg = (val & 0x0F) as usize - 0x08
+                }
                 _ => (),
             },
-            0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    ram[bank_addr(addr, self.ram_bank as u16, 0xA000, 0x2000)] = val
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.latch(val)
+                }
+            }
+            0xA000..=0xBFFF if self.ram_enabled => {
+                if !self.rtc_mapped {
+                    ram[bank_addr(addr, self.ram_bank as u16, 0xA000, 0x2000)] = val;
+                    return true;
+                } else if let Some(rtc) = &mut self.rtc {
+                    rtc.w(self.rtc_reg, val);
+                    return true;
                 }
             }
             _ => (),
         }
+        false
+    }
+
+    fn ignores_write(&self, addr: u16, val: u8, _: &[u8], ram: &[u8]) -> bool {
+        match addr {
+            0x4000..=0x5FFF => !matches!(val & 0x0F, 0x00..=0x03) && (self.rtc.is_none() || !matches!(val & 0x0F, 0x08..=0x0C)),
+            0x6000..=0x7FFF => self.rtc.is_none(),
+            0xA000..=0xBFFF => !self.ram_enabled || if self.rtc_mapped { self.rtc.is_none() } else { ram.is_empty() },
+            _ => false,
+        }
+    }
+
+    fn step(&mut self, elapsed_ticks: u16) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.step(elapsed_ticks);
+        }
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.save(out);
+    }
+
+    fn load_state(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.load(data)
     }
 }
 
@@ -284,6 +775,11 @@ struct MBC5 {
     ram_bank: u8,
     ram_enabled: bool,
 }
+state_fields!(MBC5 {
+    rom_bank,
+    ram_bank,
+    ram_enabled
+});
 impl MBC5 {
     fn default() -> Self {
         Self {
@@ -308,18 +804,271 @@ impl MBCType for MBC5 {
         }
     }
 
-    fn w(&mut self, addr: u16, val: u8, rom: &[u8], ram: &mut [u8]) {
+    fn w(&mut self, addr: u16, val: u8, rom: &[u8], ram: &mut [u8]) -> bool {
         match addr {
             0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
             0x2000..=0x2FFF => self.rom_bank = mask_bank_nr((self.rom_bank & 0xFF00) | (((val as u16) << 0) & 0x00FF), rom.len()),
             0x3000..=0x3FFF => self.rom_bank = mask_bank_nr((self.rom_bank & 0x00FF) | (((val as u16) << 8) & 0x0100), rom.len()),
             0x4000..=0x5FFF => self.ram_bank = val & 0x0F,
-            0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    ram[bank_addr(addr, self.ram_bank as u16, 0xA000, 0x2000)] = val
-                }
+            0xA000..=0xBFFF if self.ram_enabled => {
+                ram[bank_addr(addr, self.ram_bank as u16, 0xA000, 0x2000)] = val;
+                return true;
             }
             _ => (),
         }
+        false
+    }
+
+    fn ignores_write(&self, addr: u16, _: u8, _: &[u8], ram: &[u8]) -> bool {
+        match addr {
+            0x6000..=0x7FFF => true,
+            0xA000..=0xBFFF => !self.ram_enabled || ram.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.save(out);
+    }
+
+    fn load_state(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.load(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compat_palette_id, compat_palettes, header_checksum, MbcKind, CGB_BOOT_ROM, DMG_BOOT_ROM, MBC};
+    use crate::utils::{test_rom, TEST_PROGRAM_LOOP};
+
+    fn titled_rom(title: &str, licensee: u8) -> Vec<u8> {
+        let mut rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        rom[0x0134..0x0144].fill(0);
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x014B] = licensee;
+        rom[0x014D] = header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn compat_palette_hash() {
+        assert_eq!(compat_palette_id(&titled_rom("TETRIS", 0x01)), Some(5));
+        assert_eq!(compat_palette_id(&titled_rom("ZELDA", 0x01)), Some(15));
+        // Same checksum, disambiguated by the 4th letter
+        assert_eq!(compat_palette_id(&titled_rom("SUPER MARIOLAND", 0x01)), Some(66));
+        assert_eq!(compat_palette_id(&titled_rom("METROID2", 0x01)), Some(80));
+        // Only Nintendo games are recognized
+        assert_eq!(compat_palette_id(&titled_rom("TETRIS", 0x00)), None);
+        let tetris = compat_palettes(5);
+        assert!(tetris.iter().all(|p| *p == [0xFF, 0x7F, 0xFF, 0x03, 0x1F, 0x00, 0x00, 0x00]));
+        let zelda = compat_palettes(15);
+        assert_eq!(zelda[0], [0xFF, 0x7F, 0x1F, 0x42, 0xF2, 0x1C, 0x00, 0x00]);
+        assert_eq!(zelda[2], [0xFF, 0x7F, 0x8C, 0x7E, 0x00, 0x7C, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn custom_boot_rom() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let boot_rom: Vec<u8> = (0..0x100).map(|i| (i as u8).wrapping_mul(7)).collect();
+        let mut mbc = MBC::new(&rom, true, Some(&boot_rom));
+        for addr in [0x0000, 0x0042, 0x00FF] {
+            assert_eq!(mbc.r(addr), boot_rom[addr as usize]);
+        }
+        assert_eq!(mbc.r(0x0100), rom[0x0100]);
+        mbc.boot_rom_unmounted = true;
+        assert_eq!(mbc.r(0x0000), rom[0x0000]);
+
+        // A boot ROM with the wrong size for the mode falls back to the embedded one
+        let mbc = MBC::new(&rom, false, Some(&boot_rom));
+        assert_eq!(mbc.r(0x0042), CGB_BOOT_ROM[0x0042]);
+        let mbc = MBC::new(&rom, true, None);
+        assert_eq!(mbc.r(0x0042), DMG_BOOT_ROM[0x0042]);
+    }
+
+    #[test]
+    fn mbc1_mode1() {
+        // 2MB ROM, each bank tagged with its number
+        let mut rom = test_rom(0x01, 0x00, &TEST_PROGRAM_LOOP);
+        rom.resize(0x200000, 0);
+        rom[0x0148] = 0x06;
+        rom[0x014D] = header_checksum(&rom);
+        for bank in 0..0x80 {
+            rom[bank * 0x4000 + 0x2000] = bank as u8;
+        }
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x2000, 0x05);
+        for ram_bank in 0..4 {
+            mbc.w(0x4000, ram_bank);
+            // Mode 0 always maps bank 0 in the lower region
+            mbc.w(0x6000, 0x00);
+            assert_eq!(mbc.r(0x2000), 0x00);
+            assert_eq!(mbc.r(0x6000), ram_bank << 5 | 0x05);
+            // Mode 1 maps banks 0x00/0x20/0x40/0x60, the upper region is unchanged
+            mbc.w(0x6000, 0x01);
+            assert_eq!(mbc.r(0x2000), ram_bank << 5);
+            assert_eq!(mbc.r(0x6000), ram_bank << 5 | 0x05);
+        }
+        // Bank 0 in the upper region is remapped to 1, also with the upper bits set
+        mbc.w(0x2000, 0x00);
+        assert_eq!(mbc.r(0x6000), 0x61);
+        mbc.w(0x2000, 0x20);
+        assert_eq!(mbc.r(0x6000), 0x61);
+
+        // On carts smaller than 1MB the upper bits are ignored
+        let mut rom = rom[..0x80000].to_vec();
+        rom[0x0148] = 0x04;
+        rom[0x014D] = header_checksum(&rom);
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x2000, 0x05);
+        mbc.w(0x4000, 0x03);
+        mbc.w(0x6000, 0x01);
+        assert_eq!(mbc.r(0x2000), 0x00);
+        assert_eq!(mbc.r(0x6000), 0x05);
+    }
+
+    #[test]
+    fn ram_dirty() {
+        let rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP); // MBC1 with 8KB RAM
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0xA000, 0x42); // Ignored while the RAM is disabled
+        assert!(!mbc.ram_dirty());
+        mbc.w(0x0000, 0x0A); // Enable RAM
+        assert!(!mbc.ram_dirty());
+        mbc.w(0xA000, 0x42);
+        assert!(mbc.ram_dirty());
+        assert_eq!(mbc.save()[0], 0x42);
+        assert!(!mbc.ram_dirty());
+        // Bank switching does not change the RAM content
+        mbc.w(0x2000, 0x02);
+        assert!(!mbc.ram_dirty());
+        mbc.w(0xBFFF, 0x01);
+        assert!(mbc.ram_dirty());
+        mbc.load(&[0; 0x2000]).unwrap();
+        assert!(!mbc.ram_dirty());
+    }
+
+    #[test]
+    fn mbc_kind() {
+        let kind = |code: u8| MbcKind::from_cartridge_type(code).unwrap();
+        assert_eq!(kind(0x00), MbcKind::RomOnly);
+        assert_eq!(kind(0x02), MbcKind::MBC1 { ram: true, battery: false });
+        assert_eq!(
+            kind(0x0F),
+            MbcKind::MBC3 {
+                ram: false,
+                battery: true,
+                rtc: true
+            }
+        );
+        assert_eq!(
+            kind(0x13),
+            MbcKind::MBC3 {
+                ram: true,
+                battery: true,
+                rtc: false
+            }
+        );
+        assert_eq!(
+            kind(0x1C),
+            MbcKind::MBC5 {
+                ram: false,
+                battery: false,
+                rumble: true
+            }
+        );
+        assert!(kind(0x1E).ram() && kind(0x1E).battery() && kind(0x1E).rumble() && !kind(0x1E).rtc());
+        assert!(!kind(0x19).ram() && !kind(0x19).battery());
+        assert_eq!(kind(0x00).to_string(), "ROM ONLY");
+        assert_eq!(kind(0x10).to_string(), "MBC3+TIMER+RAM+BATTERY");
+        assert_eq!(kind(0x1D).to_string(), "MBC5+RUMBLE+RAM");
+        assert_eq!(MbcKind::from_cartridge_type(0x05), None);
+        // Stored from the cartridge header
+        let rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        assert_eq!(MBC::new(&rom, true, None).kind(), MbcKind::MBC1 { ram: true, battery: true });
+    }
+
+    #[test]
+    fn load_sizes() {
+        let rom = test_rom(0x10, 0x02, &TEST_PROGRAM_LOOP); // MBC3 with timer and 8KB RAM
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x0000, 0x0A); // Enable RAM and RTC
+
+        // Exact size
+        mbc.load(&[0x11; 0x2000]).unwrap();
+        assert!(mbc.save().iter().all(|val| *val == 0x11));
+        // RAM followed by the RTC registers, with the 64-bit and 32-bit timestamps
+        for rtc_size in [48, 44] {
+            let mut save = vec![0x22; 0x2000 + rtc_size];
+            save[0x2000..].fill(0);
+            save[0x2000] = 0x05; // Seconds
+            save[0x2000 + 5 * 4] = 0x05; // Latched seconds
+            save[0x2000 + 6 * 4] = 0x03; // Latched minutes
+            mbc.load(&save).unwrap();
+            assert!(mbc.save().iter().all(|val| *val == 0x22));
+            mbc.w(0x4000, 0x08);
+            assert_eq!(mbc.r(0xA000), 0x05);
+            mbc.w(0x4000, 0x09);
+            assert_eq!(mbc.r(0xA000), 0x03);
+            mbc.w(0x4000, 0x00);
+        }
+        // Undersized, the RAM and RTC are left unchanged
+        assert!(mbc.load(&[0x33; 16]).is_err());
+        assert!(mbc.save().iter().all(|val| *val == 0x22));
+        mbc.w(0x4000, 0x08);
+        assert_eq!(mbc.r(0xA000), 0x05);
+        // Oversized without RTC
+        let rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        let mut mbc = MBC::new(&rom, true, None);
+        assert!(mbc.load(&[0x44; 0x2000 + 48]).is_err());
+        assert_eq!(mbc.save().len(), 0x2000);
+        assert!(mbc.save().iter().all(|val| *val == 0x00));
+    }
+
+    #[test]
+    fn mbc1_without_ram() {
+        let rom = test_rom(0x01, 0x00, &TEST_PROGRAM_LOOP);
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x0000, 0x0A); // Enable RAM
+        mbc.w(0xA000, 0x42);
+        assert_eq!(mbc.r(0xA000), 0xFF);
+        assert_eq!(mbc.r(0xBFFF), 0xFF);
+        assert!(mbc.save().is_empty());
+    }
+
+    #[test]
+    fn unhandled_write() {
+        let rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        let mut mbc = MBC::new(&rom, true, None);
+        // Bank switching on a ROM without MBC
+        mbc.w(0x2000, 0x02);
+        assert_eq!(mbc.unhandled_write.take(), Some((0x2000, 0x02)));
+        // MBC1 ignores RAM writes while it is disabled
+        let rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x2000, 0x02);
+        mbc.w(0xA000, 0x42);
+        assert_eq!(mbc.unhandled_write.take(), Some((0xA000, 0x42)));
+        mbc.w(0x0000, 0x0A);
+        mbc.w(0xA000, 0x42);
+        assert_eq!(mbc.unhandled_write, None);
+        // The secondary bank register is unused with 8KB of RAM and 32KB of ROM
+        mbc.w(0x4000, 0x01);
+        assert_eq!(mbc.unhandled_write.take(), Some((0x4000, 0x01)));
+        // MBC3 without RTC ignores the RTC registers
+        let rom = test_rom(0x13, 0x02, &TEST_PROGRAM_LOOP);
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x4000, 0x08);
+        assert_eq!(mbc.unhandled_write.take(), Some((0x4000, 0x08)));
+        mbc.w(0x6000, 0x01);
+        assert_eq!(mbc.unhandled_write.take(), Some((0x6000, 0x01)));
+        mbc.w(0x4000, 0x01);
+        assert_eq!(mbc.unhandled_write, None);
+        // MBC5 has no register at 0x6000
+        let rom = test_rom(0x1B, 0x02, &TEST_PROGRAM_LOOP);
+        let mut mbc = MBC::new(&rom, true, None);
+        mbc.w(0x2000, 0x01);
+        assert_eq!(mbc.unhandled_write, None);
+        mbc.w(0x6000, 0x01);
+        assert_eq!(mbc.unhandled_write.take(), Some((0x6000, 0x01)));
     }
 }
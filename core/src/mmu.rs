@@ -2,8 +2,11 @@ use crate::apu::APU;
 use crate::clock::Clock;
 use crate::joypad::Joypad;
 use crate::lcd::LCD;
-use crate::mbc::MBC;
+use crate::mbc::{compat_palette_id, compat_palettes, MBC};
 use crate::ppu::{PPUMode, PPU};
+use crate::serial::Serial;
+use crate::sgb::Sgb;
+use crate::state::state_fields;
 
 const WRAM_SIZE: usize = 0x8000;
 const HRAM_SIZE: usize = 0x0080;
@@ -12,45 +15,93 @@ const HRAM_SIZE: usize = 0x0080;
 #[derive(Clone)]
 pub struct MMU {
     pub mbc: MBC,
-    wram: [u8; WRAM_SIZE],
-    hram: [u8; HRAM_SIZE],
+    wram: Vec<u8>,
+    hram: Vec<u8>,
     pub ppu: PPU,
     pub clock: Clock,
+    pub serial: Serial,
     pub apu: APU,
 
     pub IF: u8,
     pub IE: u8,
     pub joypad: Joypad,
     joyp: u8,
+    sgb: Option<Sgb>,
 
     pub double_speed: bool,
+    pub speed_switch: bool,    // Speed switch armed, performed by the next STOP
+    pub clock_multiplier: f32, // Speed of the CPU and timer relative to the other devices
+    ticks_remainder: f32,      // Fraction of the device ticks not elapsed yet with a non-integer multiplier
     wbank: u8,
+    dma_src: u8, // High byte of the last OAM DMA source address
     hdma: [u8; 4],
     hdma_mode: Option<bool>,
     hdma_len: u8,
     hdma_last_ly: Option<u8>,
+    undocumented: [u8; 4], // FF72-FF75 (CGB)
+    frame_ready: bool,     // Frame completed by the PPU since the last flush
 }
+state_fields!(MMU {
+    mbc,
+    wram,
+    hram,
+    ppu,
+    clock,
+    serial,
+    apu,
+    IF,
+    IE,
+    joypad,
+    joyp,
+    sgb,
+    double_speed,
+    speed_switch,
+    ticks_remainder,
+    wbank,
+    dma_src,
+    hdma,
+    hdma_mode,
+    hdma_len,
+    hdma_last_ly,
+    undocumented,
+    frame_ready
+});
 
 impl MMU {
-    pub fn new(rom: &[u8], force_dmg: bool) -> Self {
-        let mbc = MBC::new(&rom, force_dmg);
+    pub fn new(rom: &[u8], force_dmg: bool, boot_rom: Option<&[u8]>) -> Self {
+        let mbc = MBC::new(&rom, force_dmg, boot_rom);
         let gcb_mode = mbc.cgb_mode();
+        let sgb = if mbc.sgb_mode() { Some(Sgb::default()) } else { None };
+        let mut ppu = PPU::new(gcb_mode);
+        if !force_dmg && !gcb_mode && sgb.is_none() {
+            if let Some(id) = compat_palette_id(rom) {
+                ppu.set_compat_palettes(compat_palettes(id));
+            }
+        }
         Self {
             mbc: mbc,
-            wram: [0; WRAM_SIZE],
-            hram: [0; HRAM_SIZE],
-            ppu: PPU::new(gcb_mode),
+            wram: vec![0; WRAM_SIZE],
+            hram: vec![0; HRAM_SIZE],
+            ppu,
             clock: Clock::new(),
-            apu: APU::new(),
+            serial: Serial::new(gcb_mode),
+            apu: APU::new(gcb_mode),
             IF: 0,
             IE: 0,
             joypad: Joypad::default(),
             joyp: 0,
+            sgb,
             double_speed: false,
-            wbank: 1,
+            speed_switch: false,
+            clock_multiplier: 1.0,
+            ticks_remainder: 0.0,
+            frame_ready: false,
+            undocumented: [0; 4],
+            wbank: 0,
+            dma_src: 0xFF,
             hdma: [0xFF; 4],
             hdma_mode: None,
-            hdma_len: 0,
+            hdma_len: 0x7F,
             hdma_last_ly: None,
         }
     }
@@ -61,28 +112,30 @@ impl MMU {
             0x8000..=0x9FFF /*  VRAM  */ => self.ppu.r(addr),
             0xA000..=0xBFFF /* ExtRAM */ => self.mbc.r(addr),
             0xC000..=0xCFFF /*  WRAM  */ => self.wram[(addr - 0xC000) as usize],
-            0xD000..=0xDFFF /* WRAM bk*/ => self.wram[(addr - 0xD000 + (self.wbank as u16 * 0x1000)) as usize],
+            0xD000..=0xDFFF /* WRAM bk*/ => self.wram[(addr - 0xD000 + (self.wbank.max(1) as u16 * 0x1000)) as usize],
             0xE000..=0xFDFF /* Mirror */ => self.wram[(addr - 0xE000) as usize],
             0xFE00..=0xFE9F /*  OAM   */ => self.ppu.r(addr),
 
-            0xFEA0..=0xFEFF /*  N/A   */ => 0xFF,
-            0xFF00          /* Joypad */ => self.joypad.get(self.joyp),
-            0xFF01..=0xFF02 /* Serial */ => 0xFF,
+            0xFEA0..=0xFEFF /*  N/A   */ => self.rprohibited(addr),
+            0xFF00          /* Joypad */ => self.rjoyp(),
+            0xFF01..=0xFF02 /* Serial */ => self.serial.r(addr),
             0xFF04..=0xFF07 /* Clock  */ => self.clock.r(addr),
-            0xFF0F          /*   IF   */ => self.IF,
+            0xFF0F          /*   IF   */ => self.IF | 0xE0, // Bits 5-7 are unused and read as 1
             0xFF10..=0xFF3F /*  APU   */ => self.apu.r(addr),
-            0xFF46          /*  DMA   */ => 0xFF,
-            0xFF4D          /* Speed  */ => (self.double_speed as u8) << 7,
+            0xFF46          /*  DMA   */ => self.dma_src,
+            0xFF4D          /* Speed  */ => (self.double_speed as u8) << 7 | 0x7E | self.speed_switch as u8,
             0xFF50          /*Boot ROM*/ => self.mbc.boot_rom_unmounted as u8,
-            0xFF51..=0xFF54 /*  HDMA  */ => self.hdma[(addr - 0xFF51) as usize],
+            0xFF51..=0xFF54 /*  HDMA  */ => 0xFF, // Write only
             0xFF55          /*  HDMA  */ => self.hdma_len | if self.hdma_mode == Some(true) { 0x00 } else { 0x80 },
             0xFF40..=0xFF6C /* VRAM R */ => self.ppu.r(addr),
-            0xFF70          /* WBank  */ => self.wbank,
+            0xFF70          /* WBank  */ => self.wbank | 0xF8,
+            0xFF72..=0xFF74 /* Undoc  */ => self.undocumented[(addr - 0xFF72) as usize],
+            0xFF75          /* Undoc  */ => self.undocumented[3] | 0x8F, // Only bits 4-6 are used
 
             0xFF80..=0xFFFE /*  HRAM  */ => self.hram[(addr - 0xFF80) as usize],
             0xFFFF          /*   IE   */ => self.IE,
 
-            0xFF03 | 0xFF08..=0xFF0E | 0xFF6D..=0xFF7F /* Unused */=> 0xFF,
+            0xFF03 | 0xFF08..=0xFF0E | 0xFF6D..=0xFF6F | 0xFF71 | 0xFF76..=0xFF7F /* Unused */=> 0xFF,
         }
     }
 
@@ -92,31 +145,43 @@ impl MMU {
             0x8000..=0x9FFF /*  VRAM  */ => self.ppu.w(addr, val),
             0xA000..=0xBFFF /* ExtRAM */ => self.mbc.w(addr, val),
             0xC000..=0xCFFF /*  WRAM  */ => self.wram[(addr - 0xC000) as usize] = val,
-            0xD000..=0xDFFF /* WRAM BK*/ => self.wram[(addr - 0xD000 + (self.wbank as u16 * 0x1000)) as usize] = val,
+            0xD000..=0xDFFF /* WRAM BK*/ => self.wram[(addr - 0xD000 + (self.wbank.max(1) as u16 * 0x1000)) as usize] = val,
             0xE000..=0xFDFF /* Mirror */ => self.wram[(addr - 0xE000) as usize] = val,
             0xFE00..=0xFE9F /*  OAM   */ => self.ppu.w(addr, val),
 
             0xFEA0..=0xFEFF /*  N/A   */ => (),
-            0xFF00          /* Joypad */ => self.joyp = val,
-            0xFF01..=0xFF02 /* Serial */ => (),
+            0xFF00          /* Joypad */ => self.wjoyp(val),
+            0xFF01..=0xFF02 /* Serial */ => self.serial.w(addr, val),
             0xFF04..=0xFF07 /* Clock  */ => self.clock.w(addr, val),
             0xFF0F          /*   IF   */ => self.IF = val,
             0xFF10..=0xFF3F /*  APU   */ => self.apu.w(addr, val),
             0xFF46          /*  DMA   */ => self.dma(val),
-            0xFF4D          /* Speed  */ => if val & 0x01 != 0 { self.double_speed = !self.double_speed },
-            0xFF50          /*Boot ROM*/ => self.mbc.boot_rom_unmounted = val != 0,
+            0xFF4D          /* Speed  */ => self.speed_switch = val & 0x01 != 0,
+            0xFF50          /*Boot ROM*/ => if val != 0 { self.mbc.boot_rom_unmounted = true },
             0xFF51..=0xFF54 /*  HDMA  */ => self.hdma[(addr - 0xFF51) as usize] = val,
             0xFF55          /*  HDMA  */ => self.wvdma(val),
             0xFF40..=0xFF6C /* VRAM R */ => self.ppu.w(addr, val),
-            0xFF70          /* WBank  */ => self.wbank = if val & 0x07 == 0 { 0x01 } else { val & 0x07 },
+            0xFF70          /* WBank  */ => self.wbank = val & 0x07,
+            0xFF72..=0xFF74 /* Undoc  */ => self.undocumented[(addr - 0xFF72) as usize] = val,
+            0xFF75          /* Undoc  */ => self.undocumented[3] = val & 0x70,
 
             0xFF80..=0xFFFE /*  HRAM  */ => self.hram[(addr - 0xFF80) as usize] = val,
             0xFFFF          /*   IE   */ => self.IE = val,
 
-            0xFF03 | 0xFF08..=0xFF0E | 0xFF6D..=0xFF7F /* Unused */=> (),
+            0xFF03 | 0xFF08..=0xFF0E | 0xFF6D..=0xFF6F | 0xFF71 | 0xFF76..=0xFF7F /* Unused */=> (),
         }
     }
 
+    pub(crate) fn memory_regions(&mut self) -> [&mut Vec<u8>; 5] {
+        [
+            &mut self.wram,
+            &mut self.hram,
+            &mut self.ppu.vram,
+            &mut self.ppu.oam,
+            &mut self.mbc.ram,
+        ]
+    }
+
     pub fn rw(&self, addr: u16) -> u16 {
         u16::from_le_bytes([self.r(addr), self.r(addr + 1)])
     }
@@ -127,7 +192,35 @@ impl MMU {
         self.w(addr + 1, bh);
     }
 
+    fn rprohibited(&self, addr: u16) -> u8 {
+        // Reads return 0xFF while OAM is blocked, otherwise DMG returns 0x00 and CGB (rev. E) repeats the address high nibble
+        if self.ppu.oam_blocked() {
+            0xFF
+        } else if self.mbc.force_dmg() {
+            0x00
+        } else {
+            (addr as u8 & 0xF0) | (addr as u8 >> 4)
+        }
+    }
+
+    fn rjoyp(&self) -> u8 {
+        // In SGB multiplayer mode, the joypad ID is returned when no line is selected
+        match self.sgb.as_ref().and_then(|sgb| sgb.joypad_id()) {
+            Some(id) if self.joyp & 0x30 == 0x30 => 0xC0 | (self.joyp & 0x30) | id,
+            _ => self.joypad.get(self.joyp),
+        }
+    }
+
+    fn wjoyp(&mut self, val: u8) {
+        self.joyp = val;
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.w_joyp(val);
+        }
+    }
+
     fn dma(&mut self, src: u8) {
+        // Every write starts a transfer, even with the same source
+        self.dma_src = src;
         let src = (src as u16) << 8;
         for i in 0..=0x9F {
             self.w(0xFE00 + i, self.r(src + i));
@@ -137,12 +230,16 @@ impl MMU {
     fn wvdma(&mut self, val: u8) {
         let mode = val & 0x80 != 0;
         if self.hdma_mode.is_none() {
-            // Start VDMA
+            // Start VDMA. If started during HBlank, the first block is transferred in the next one
             self.hdma_mode = Some(mode);
             self.hdma_len = val & 0x7F;
-            self.hdma_last_ly = if mode { Some(self.ppu.ly) } else { None };
+            self.hdma_last_ly = if mode && self.ppu.mode() == PPUMode::HBLANK {
+                Some(self.ppu.ly)
+            } else {
+                None
+            };
         } else if !mode {
-            // Interrupt VDMA
+            // Terminate HDMA, the remaining length is still readable with bit 7 set
             self.hdma_mode = None;
             self.hdma_last_ly = None;
         }
@@ -166,7 +263,13 @@ impl MMU {
     }
 
     fn step_vdma(&mut self) -> u16 {
-        if self.hdma_mode == Some(true) {
+        if self.hdma_mode == Some(true) && !self.ppu.lcd_enabled() {
+            // HDMA with the LCD off: there are no HBlanks, so the remaining blocks are transferred immediately
+            while self.hdma_mode.is_some() {
+                self.step_hdma();
+            }
+            0
+        } else if self.hdma_mode == Some(true) {
             // HDMA: single block per HBlank
             if self.ppu.mode() == PPUMode::HBLANK && self.hdma_last_ly != Some(self.ppu.ly) {
                 self.hdma_last_ly = Some(self.ppu.ly);
@@ -186,20 +289,212 @@ impl MMU {
         } // Disabled
     }
 
-    pub fn step(&mut self, lcd: &mut LCD, mut elapsed_ticks: u16) -> bool {
+    pub fn step(&mut self, lcd: &mut LCD, elapsed_ticks: u16) -> bool {
+        self.tick(elapsed_ticks);
+        self.flush(lcd)
+    }
+
+    pub fn tick(&mut self, mut elapsed_ticks: u16) {
         // Perform HDMA/GDMA transfer if needed
         elapsed_ticks += self.step_vdma();
 
         // Update internal clock. In double speed mode, the clock also run at double speed.
         self.IF |= self.clock.step(elapsed_ticks * if self.double_speed { 2 } else { 1 });
 
+        // Update serial transfer, clocked like the timer by the CPU
+        self.IF |= self.serial.step(elapsed_ticks, self.double_speed);
+
+        // With a clock multiplier the CPU and timer run faster or slower, the other devices keep the normal speed
+        if self.clock_multiplier != 1.0 {
+            self.ticks_remainder += elapsed_ticks as f32 / self.clock_multiplier;
+            elapsed_ticks = self.ticks_remainder as u16;
+            self.ticks_remainder -= elapsed_ticks as f32;
+        }
+
+        // Update cartridge RTC
+        self.mbc.step(elapsed_ticks);
+
         // Update PPU status
-        let (frame_ready, ppu_interrupts) = self.ppu.step(lcd, elapsed_ticks);
+        let (frame_ready, ppu_interrupts) = self.ppu.tick(elapsed_ticks);
+        self.frame_ready |= frame_ready;
         self.IF |= ppu_interrupts;
 
         // Update APU status
         self.apu.step(elapsed_ticks);
+    }
 
+    pub fn flush(&mut self, lcd: &mut LCD) -> bool {
+        // Draw the pending scanline and return whether a frame has been completed since the last flush
+        self.ppu.flush(lcd);
+        let frame_ready = std::mem::take(&mut self.frame_ready);
+        if frame_ready {
+            // Palettes sent by SGB games are applied from the next frame
+            lcd.sgb_palette = self.sgb.as_ref().and_then(|sgb| sgb.palette(0));
+        }
         frame_ready
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MMU;
+    use crate::lcd::LCD;
+    use crate::mbc::header_checksum;
+    use crate::ppu::PPUMode;
+    use crate::utils::{test_rom, TEST_PROGRAM_LOOP};
+
+    fn cgb_mmu() -> MMU {
+        let mut rom = test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP);
+        rom[0x0143] = 0x80;
+        rom[0x014D] = header_checksum(&rom);
+        let mut mmu = MMU::new(&rom, false, None);
+        // Source data in WRAM, copied to the start of VRAM
+        for i in 0..0x100 {
+            mmu.w(0xC000 + i, (i + 1) as u8);
+        }
+        for (addr, val) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x80), (0xFF54, 0x00)] {
+            mmu.w(addr, val);
+        }
+        mmu.w(0xFF40, 0x91);
+        mmu
+    }
+
+    fn blocks_copied(mmu: &MMU) -> usize {
+        (0..0x100).take_while(|i| mmu.ppu.vram[*i] == (*i + 1) as u8).count() / 0x10
+    }
+
+    #[test]
+    fn undocumented_registers() {
+        let mut mmu = cgb_mmu();
+        assert_eq!(mmu.r(0xFF75), 0x8F);
+        for addr in 0xFF72..=0xFF74 {
+            mmu.w(addr, 0xA5);
+            assert_eq!(mmu.r(addr), 0xA5);
+        }
+        // Only bits 4-6 are writable, the others always read as 1
+        mmu.w(0xFF75, 0xFF);
+        assert_eq!(mmu.r(0xFF75), 0xFF);
+        mmu.w(0xFF75, 0x00);
+        assert_eq!(mmu.r(0xFF75), 0x8F);
+        mmu.w(0xFF75, 0x50);
+        assert_eq!(mmu.r(0xFF75), 0xDF);
+        // Neighbouring registers are still unused
+        mmu.w(0xFF76, 0x00);
+        assert_eq!(mmu.r(0xFF76), 0xFF);
+    }
+
+    #[test]
+    fn dma_register() {
+        let mut mmu = cgb_mmu();
+        mmu.w(0xFF40, 0x00);
+        assert_eq!(mmu.r(0xFF46), 0xFF);
+        mmu.w(0xFF46, 0xC0);
+        assert_eq!(mmu.r(0xFF46), 0xC0);
+        assert_eq!(mmu.r(0xFE10), 0x11);
+        // Writing the same source again repeats the transfer
+        mmu.w(0xC010, 0xAA);
+        mmu.w(0xFF46, 0xC0);
+        assert_eq!(mmu.r(0xFF46), 0xC0);
+        assert_eq!(mmu.r(0xFE10), 0xAA);
+    }
+
+    #[test]
+    fn gdma() {
+        let mut lcd = LCD::new();
+        let mut mmu = cgb_mmu();
+        assert_eq!(mmu.r(0xFF55), 0xFF);
+        assert_eq!(mmu.r(0xFF51), 0xFF);
+        mmu.w(0xFF55, 0x02); // 3 blocks
+        mmu.step(&mut lcd, 4);
+        assert_eq!(blocks_copied(&mmu), 3);
+        assert_eq!(mmu.r(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn hdma() {
+        let mut lcd = LCD::new();
+        let mut mmu = cgb_mmu();
+        let step_line = |mmu: &mut MMU, lcd: &mut LCD| {
+            let ly = mmu.r(0xFF44);
+            while mmu.r(0xFF44) == ly {
+                mmu.step(lcd, 4);
+            }
+        };
+
+        // Start a 4 blocks transfer outside of HBlank, one block is copied per HBlank
+        mmu.step(&mut lcd, 4);
+        mmu.w(0xFF55, 0x83);
+        assert_eq!(mmu.r(0xFF55), 0x03);
+        step_line(&mut mmu, &mut lcd);
+        assert_eq!(blocks_copied(&mmu), 1);
+        assert_eq!(mmu.r(0xFF55), 0x02);
+        step_line(&mut mmu, &mut lcd);
+        assert_eq!(blocks_copied(&mmu), 2);
+        assert_eq!(mmu.r(0xFF55), 0x01);
+
+        // Terminate, the remaining length is kept with bit 7 set
+        mmu.w(0xFF55, 0x00);
+        assert_eq!(mmu.r(0xFF55), 0x81);
+        step_line(&mut mmu, &mut lcd);
+        assert_eq!(blocks_copied(&mmu), 2);
+        assert_eq!(mmu.r(0xFF55), 0x81);
+
+        // Restart from the current addresses and run to completion
+        mmu.step(&mut lcd, 4);
+        mmu.w(0xFF55, 0x81);
+        assert_eq!(mmu.r(0xFF55), 0x01);
+        step_line(&mut mmu, &mut lcd);
+        step_line(&mut mmu, &mut lcd);
+        assert_eq!(blocks_copied(&mmu), 4);
+        assert_eq!(mmu.r(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn hdma_lcd_off() {
+        let mut lcd = LCD::new();
+        let mut mmu = cgb_mmu();
+        mmu.w(0xFF40, 0x11);
+        mmu.w(0xFF55, 0x83);
+        mmu.step(&mut lcd, 4);
+        assert_eq!(blocks_copied(&mmu), 4);
+        assert_eq!(mmu.r(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn prohibited_area() {
+        let mut lcd = LCD::new();
+        let step_to = |mmu: &mut MMU, lcd: &mut LCD, mode: PPUMode| {
+            while mmu.ppu.mode() != mode {
+                mmu.step(lcd, 4);
+            }
+        };
+        for force_dmg in [true, false] {
+            let mut mmu = MMU::new(&test_rom(0x00, 0x00, &TEST_PROGRAM_LOOP), force_dmg, None);
+            let unblocked = if force_dmg { [0x00, 0x00] } else { [0xAA, 0xFF] };
+            // LCD off, OAM is never blocked
+            mmu.w(0xFF40, 0x00);
+            assert_eq!([mmu.r(0xFEA5), mmu.r(0xFEFF)], unblocked);
+            // LCD on, blocked during OAM scan and drawing
+            mmu.w(0xFF40, 0x91);
+            for (mode, blocked) in [
+                (PPUMode::OAM, true),
+                (PPUMode::DRAW, true),
+                (PPUMode::HBLANK, false),
+                (PPUMode::VBLANK, false),
+            ] {
+                step_to(&mut mmu, &mut lcd, mode);
+                let expected = if blocked { [0xFF, 0xFF] } else { unblocked };
+                assert_eq!(
+                    [mmu.r(0xFEA5), mmu.r(0xFEFF)],
+                    expected,
+                    "force_dmg: {}, blocked: {}",
+                    force_dmg,
+                    blocked
+                );
+            }
+            // Writes are ignored
+            mmu.w(0xFEA5, 0x12);
+            assert_eq!(mmu.r(0xFEA5), unblocked[0]);
+        }
+    }
+}
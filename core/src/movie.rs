@@ -0,0 +1,88 @@
+use crate::joypad::Joypad;
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u8 = 2;
+const HEADER_SIZE: usize = 19; // Magic, version, ROM checksum, RTC base time and save size
+
+/*
+ Input movie, recorded from power-on. The .gbmovie file contains a small header with the
+ checksum of the ROM it was recorded on and the fixed time of the RTC, then the battery save
+ the recording started from, followed by the joypad state of each frame (one byte per frame).
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub checksum: u16,
+    pub rtc_base: u64,
+    pub save: Vec<u8>, // Battery RAM and RTC at power-on, as exported by GBEmu::export_sav
+    pub inputs: Vec<Joypad>,
+}
+
+impl Movie {
+    pub fn new(checksum: u16, rtc_base: u64, save: Vec<u8>) -> Self {
+        Self {
+            checksum,
+            rtc_base,
+            save,
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.save.len() + self.inputs.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&self.checksum.to_le_bytes());
+        data.extend_from_slice(&self.rtc_base.to_le_bytes());
+        data.extend_from_slice(&(self.save.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.save);
+        data.extend(self.inputs.iter().map(|joypad| joypad.bits()));
+        data
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 5 || &data[..4] != MAGIC {
+            return Err(String::from("Not a movie file"));
+        }
+        if data[4] != VERSION {
+            return Err(format!("Unsupported movie version {}", data[4]));
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(String::from("Movie file too short"));
+        }
+        let save_size = u32::from_le_bytes(data[15..19].try_into().unwrap()) as usize;
+        let (save, inputs) = data[HEADER_SIZE..]
+            .split_at_checked(save_size)
+            .ok_or(String::from("Movie file too short"))?;
+        Ok(Self {
+            checksum: u16::from_le_bytes([data[5], data[6]]),
+            rtc_base: u64::from_le_bytes(data[7..15].try_into().unwrap()),
+            save: save.to_vec(),
+            inputs: inputs.iter().map(|bits| Joypad::from_bits(*bits)).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Movie;
+    use crate::joypad::Joypad;
+
+    #[test]
+    fn serialize() {
+        let mut movie = Movie::new(0xBEEF, 1_000_000, vec![0x42; 3]);
+        movie.inputs.push(Joypad::default());
+        movie.inputs.push(Joypad {
+            a: true,
+            left: true,
+            start: true,
+            ..Joypad::default()
+        });
+        let data = movie.serialize();
+        assert_eq!(&data[..7], b"GBMV\x02\xEF\xBE");
+        assert_eq!(data.len(), 19 + 3 + 2);
+        assert_eq!(Movie::deserialize(&data), Ok(movie));
+        assert!(Movie::deserialize(b"GBMV").is_err());
+        assert!(Movie::deserialize(b"GBMV\x01\x00\x00").is_err());
+        assert!(Movie::deserialize(&data[..20]).is_err()); // Truncated save
+    }
+}
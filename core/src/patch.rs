@@ -0,0 +1,66 @@
+const IPS_MAGIC: &[u8; 5] = b"PATCH";
+const IPS_EOF: &[u8; 3] = b"EOF";
+
+/*
+ IPS patch: a list of records with a 24-bit offset and 16-bit size, followed by the data to write.
+ A zero size marks an RLE record, with a 16-bit count and the byte to repeat. Records may write past the
+ end of the ROM, extending it. The optional 24-bit value after "EOF" truncates the ROM to that size.
+*/
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(String::from("Not an IPS patch"));
+    }
+    let mut rom = rom.to_vec();
+    let mut pos = IPS_MAGIC.len();
+    let mut read = |len: usize| -> Result<&[u8], String> {
+        let bytes = patch.get(pos..pos + len).ok_or_else(|| String::from("Truncated IPS patch"))?;
+        pos += len;
+        Ok(bytes)
+    };
+    loop {
+        let offset = read(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = (offset[0] as usize) << 16 | (offset[1] as usize) << 8 | offset[2] as usize;
+        let size = u16::from_be_bytes(read(2)?.try_into().unwrap()) as usize;
+        let data = if size == 0 {
+            // RLE record
+            let count = u16::from_be_bytes(read(2)?.try_into().unwrap()) as usize;
+            vec![read(1)?[0]; count]
+        } else {
+            read(size)?.to_vec()
+        };
+        if rom.len() < offset + data.len() {
+            rom.resize(offset + data.len(), 0);
+        }
+        rom[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    if let Ok(size) = read(3) {
+        rom.truncate((size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize);
+    }
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::apply_ips;
+
+    #[test]
+    fn ips() {
+        let rom = [0x00; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]); // 2 bytes at 1
+        patch.extend([0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05, 0xCC]); // RLE, 5 bytes at 5 extending the ROM
+        patch.extend(b"EOF");
+        let patched = apply_ips(&rom, &patch).unwrap();
+        assert_eq!(patched, [0x00, 0xAA, 0xBB, 0x00, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]);
+        // Truncation extension
+        patch.extend([0x00, 0x00, 0x04]);
+        assert_eq!(apply_ips(&rom, &patch).unwrap(), [0x00, 0xAA, 0xBB, 0x00]);
+        // Invalid patches
+        assert!(apply_ips(&rom, b"PATCX").is_err());
+        assert!(apply_ips(&rom, &patch[..10]).is_err());
+        assert!(apply_ips(&rom, b"PATCH").is_err());
+    }
+}
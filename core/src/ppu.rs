@@ -1,5 +1,8 @@
+use std::collections::VecDeque;
+
 use crate::cpu::{INT_STAT, INT_VBLANK};
 use crate::lcd::{LCD, LCDH, LCDW};
+use crate::state::state_fields;
 use crate::utils::pack_bits;
 
 #[rustfmt::skip::macros(byte_register)]
@@ -16,10 +19,106 @@ mod ppu_registers {
 use ppu_registers::*;
 
 const VRAM_SIZE: usize = 0x4000;
-const OAM_SIZE: usize = 0x9F00;
+const OAM_SIZE: usize = 0x00A0;
 
 const SCANLINE_TICKS: u16 = 456;
 const LY_MAX: u8 = 154;
+const LY_153_DOTS: u16 = 4; // Dots LY reads 153 on the last line, before already reading 0
+const DRAW_START: u16 = 80; // First and last dots of mode 3, used by the FIFO renderer
+const DRAW_END: u16 = 253;
+
+// Decoded OAM entry, with the position in screen coordinates
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sprite {
+    pub y: i16,
+    pub x: i16,
+    pub tile: u8,   // Bit 0 is ignored in 8x16 mode
+    pub height: u8, // 8 or 16, depending on LCDC
+    pub flags: u8,  // Raw attributes, decoded below
+    pub bg_priority: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub dmg_palette: u8,
+    pub bank: bool,
+    pub cgb_palette: u8,
+}
+state_fields!(Sprite {
+    y,
+    x,
+    tile,
+    height,
+    flags,
+    bg_priority,
+    y_flip,
+    x_flip,
+    dmg_palette,
+    bank,
+    cgb_palette
+});
+
+// Layers that can be hidden for debugging
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
+// Pixel resolved by the FIFO renderer, written to the LCD when the scanline is flushed
+#[derive(Clone, Copy, Default)]
+struct FifoOutput {
+    px: u8,
+    dmg_palette: u8,
+    palette: [u8; 8],
+    foreground: bool,
+}
+state_fields!(FifoOutput {
+    px,
+    dmg_palette,
+    palette,
+    foreground
+});
+
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    px: u8,
+    flags: u8,
+    window: bool,
+}
+state_fields!(BgPixel { px, flags, window });
+
+#[derive(Clone, Copy, Default)]
+struct ObjPixel {
+    px: u8, // 0 = transparent
+    flags: u8,
+    oam_idx: u16,
+}
+state_fields!(ObjPixel { px, flags, oam_idx });
+
+// State of the pixel FIFO renderer for the current scanline
+#[derive(Clone, Default)]
+struct PixelFifo {
+    active: bool,
+    lx: u8,                                              // Next pixel to be pushed to the LCD
+    discard: u8,                                         // Pixels still to be dropped, for the fine scrolling
+    fetch_x: u8,                                         // Tile column of the next BG/window fetch
+    window: bool,                                        // Fetching window tiles
+    bg: VecDeque<BgPixel>,                               // BG/window pixels
+    obj: VecDeque<ObjPixel>,                             // OBJ pixels, aligned with the next LCD pixel
+    objs: Vec<(u16, Sprite)>,                            // Objects selected in the OAM scan
+    line: Vec<(Option<FifoOutput>, Option<FifoOutput>)>, // BG and OBJ pixels written to the LCD
+}
+state_fields!(PixelFifo {
+    active,
+    lx,
+    discard,
+    fetch_x,
+    window,
+    bg,
+    obj,
+    objs,
+    line
+});
 
 #[derive(PartialEq, Eq)]
 pub struct PPUMode(bool, bool);
@@ -32,8 +131,8 @@ impl PPUMode {
 
 #[derive(Clone)]
 pub struct PPU {
-    pub vram: [u8; VRAM_SIZE],
-    oam: [u8; OAM_SIZE],
+    pub vram: Vec<u8>,
+    pub(crate) oam: Vec<u8>,
 
     lcdc: LCDControl,   // LCD control register
     lcdstat: LCDStatus, // LCD status register
@@ -48,25 +147,65 @@ pub struct PPU {
     wx: u8,             // Window X coord
     wly: u8,            // Count lines with window pixels in it
 
-    cgb_mode: bool,      // Wether the current ROM supports CGB features
-    vbank: bool,         // VRAM bank (CGB)
-    opri: bool,          // Object priority mode (CGB)
-    bgpi: u8,            // BG palette index (CGB)
-    obpi: u8,            // OBJ palette index (CGB)
-    bgpalette: [u8; 64], // BG palette RAM (CGB)
-    obpalette: [u8; 64], // OBJ palette RAM (CGB)
+    cgb_mode: bool,        // Wether the current ROM supports CGB features
+    vbank: bool,           // VRAM bank (CGB)
+    opri: bool,            // Object priority mode (CGB)
+    bgpi: u8,              // BG palette index (CGB)
+    obpi: u8,              // OBJ palette index (CGB)
+    bgpalette: [u8; 64],   // BG palette RAM (CGB)
+    obpalette: [u8; 64],   // OBJ palette RAM (CGB)
+    compat_palettes: bool, // Color DMG games with the CGB compatibility palettes stored in palette RAM
+    sprite_limit: bool,    // Draw at most 10 objects per scanline, as the hardware does
+    fifo_renderer: bool,   // Render dot by dot during mode 3 instead of the whole scanline in HBlank
+    layers: [bool; 3],     // Visible layers, indexed by Layer
 
     // Emulator internal state
     scanline_ticks: u16,
+    pending_interrupts: u8,         // Interrupts raised by register writes, returned on the next step
+    line_pending: bool,             // Scanline entered in HBlank but not drawn to the LCD yet
+    window_drawn: bool,             // Window triggered on a line of the current frame
+    window_active: bool,            // Window triggered on the last completed frame
     scanline_bg_colors: [u8; LCDW], // BG color indexes
     scanline_bg_pri: [bool; LCDW],  // BG priorities values
+    fifo: PixelFifo,
 }
+// The rendering settings of the frontend are not part of the state
+state_fields!(PPU {
+    vram,
+    oam,
+    lcdc,
+    lcdstat,
+    scy,
+    scx,
+    ly,
+    lyc,
+    bgp,
+    obp0,
+    obp1,
+    wy,
+    wx,
+    wly,
+    vbank,
+    opri,
+    bgpi,
+    obpi,
+    bgpalette,
+    obpalette,
+    scanline_ticks,
+    pending_interrupts,
+    line_pending,
+    window_drawn,
+    window_active,
+    scanline_bg_colors,
+    scanline_bg_pri,
+    fifo
+});
 
 impl PPU {
     pub fn new(cgb_mode: bool) -> Self {
         Self {
-            vram: [0; VRAM_SIZE],
-            oam: [0; OAM_SIZE],
+            vram: vec![0; VRAM_SIZE],
+            oam: vec![0; OAM_SIZE],
             lcdc: LCDControl::from(0),
             lcdstat: LCDStatus::from(0),
             scy: 0,
@@ -86,9 +225,18 @@ impl PPU {
             obpi: 0,
             bgpalette: [0xFF; 64],
             obpalette: [0xFF; 64],
+            compat_palettes: false,
+            sprite_limit: true,
+            fifo_renderer: false,
+            layers: [true; 3],
             scanline_ticks: 0,
+            pending_interrupts: 0,
+            line_pending: false,
+            window_drawn: false,
+            window_active: false,
             scanline_bg_colors: [0; LCDW],
             scanline_bg_pri: [false; LCDW],
+            fifo: PixelFifo::default(),
         }
     }
 
@@ -104,10 +252,10 @@ impl PPU {
             0xFE00..=0xFE9F => self.oam[addr as usize - 0xFE00],
             /* Registers */
             0xFF40 => u8::from(&self.lcdc),
-            0xFF41 => u8::from(&self.lcdstat),
+            0xFF41 => self.rstat(),
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.ly,
+            0xFF44 => self.read_ly(),
             0xFF45 => self.lyc,
             0xFF47 => self.bgp,
             0xFF48 => self.obp0,
@@ -115,9 +263,9 @@ impl PPU {
             0xFF4A => self.wy,
             0xFF4B => self.wx,
             0xFF4F => self.vbank as u8 | 0xFE,
-            0xFF68 => self.bgpi,
+            0xFF68 => self.bgpi | 0x40,
             0xFF69 => self.bgpalette[(self.bgpi & 0x3F) as usize],
-            0xFF6A => self.obpi,
+            0xFF6A => self.obpi | 0x40,
             0xFF6B => self.obpalette[(self.obpi & 0x3F) as usize],
             0xFF6C => self.opri as u8,
             0xFF4C..=0xFF67 => 0xFF,
@@ -133,11 +281,11 @@ impl PPU {
             0xFE00..=0xFE9F => self.oam[addr as usize - 0xFE00] = val,
             /* Registers */
             0xFF40 => self.lcdc.w(val),
-            0xFF41 => self.lcdstat.w(val & 0xF8), // Mask r/o bits
+            0xFF41 => self.wstat(val),
             0xFF42 => self.scy = val,
             0xFF43 => self.scx = val,
             0xFF44 => (), // LY r/o
-            0xFF45 => self.lyc = val,
+            0xFF45 => self.wlyc(val),
             0xFF47 => self.bgp = val,
             0xFF48 => self.obp0 = val,
             0xFF49 => self.obp1 = val,
@@ -154,6 +302,47 @@ impl PPU {
         }
     }
 
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    pub fn bg_palette(&self, index: u8) -> &[u8] {
+        PPU::rpalette(&self.bgpalette, index)
+    }
+
+    pub fn sprite_limit_enabled(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_sprite_limit_enabled(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn fifo_renderer_enabled(&self) -> bool {
+        self.fifo_renderer
+    }
+
+    pub fn set_fifo_renderer_enabled(&mut self, enabled: bool) {
+        self.fifo_renderer = enabled;
+    }
+
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        self.layers[layer as usize]
+    }
+
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        // A hidden BG is drawn with color 0, a hidden window or sprite layer is skipped as if disabled in LCDC
+        self.layers[layer as usize] = enabled;
+    }
+
+    pub fn set_compat_palettes(&mut self, palettes: [&[u8]; 3]) {
+        let [bg, obj0, obj1] = palettes;
+        self.bgpalette[0..8].copy_from_slice(bg);
+        self.obpalette[0..8].copy_from_slice(obj0);
+        self.obpalette[8..16].copy_from_slice(obj1);
+        self.compat_palettes = true;
+    }
+
     fn rtilemap(&self, x: u8, y: u8, mode: bool, vbank: bool) -> u8 {
         let addr = x as u16 + (y as u16 * 32) + if mode { 0x9C00 } else { 0x9800 };
         self.vram[PPU::vram_addr(addr, vbank)]
@@ -192,23 +381,131 @@ impl PPU {
         }
     }
 
+    fn w_dmg(&self, lcd: &mut LCD, x: u8, px: u8, palette: u8, compat_palette: &[u8], is_foreground: bool) {
+        if self.compat_palettes {
+            // Map the DMG shade to the CGB palette
+            lcd.w_cgb(x, self.ly, (palette >> (px * 2)) & 0x03, compat_palette, is_foreground);
+        } else {
+            lcd.w_dmg(x, self.ly, px, palette, is_foreground);
+        }
+    }
+
+    fn rstat(&self) -> u8 {
+        // Bit 7 is unused and always set, mode bits read as 0 while the LCD is off
+        let stat = u8::from(&self.lcdstat) | 0x80;
+        if self.lcdc.lcd_enable {
+            stat
+        } else {
+            stat & !0x03
+        }
+    }
+
+    fn wstat(&mut self, val: u8) {
+        // Mask r/o bits and the unused bit 7
+        let mode_bits = u8::from(&self.lcdstat) & 0x07;
+        self.lcdstat.w((val & 0x78) | mode_bits);
+        // DMG bug: writing to STAT behaves as if all the STAT sources were enabled for one cycle
+        if !self.cgb_mode && self.lcdc.lcd_enable {
+            let mode = self.mode();
+            if mode == PPUMode::HBLANK || mode == PPUMode::VBLANK || self.lcdstat.ly_eq_lyc {
+                self.pending_interrupts |= INT_STAT.0;
+            }
+        }
+    }
+
+    fn wlyc(&mut self, val: u8) {
+        self.lyc = val;
+        // Compare immediately with the new value, and trigger the interrupt only if LY == LYC just became true
+        if self.lcdc.lcd_enable {
+            let was_equal = self.lcdstat.ly_eq_lyc;
+            let interrupts = self.compare_lyc();
+            if !was_equal {
+                self.pending_interrupts |= interrupts;
+            }
+        }
+    }
+
+    // LY as seen by the CPU: on line 153 it reads 0 after the first dots, while still in VBlank
+    fn read_ly(&self) -> u8 {
+        if self.ly == LY_MAX - 1 && self.scanline_ticks >= LY_153_DOTS {
+            0
+        } else {
+            self.ly
+        }
+    }
+
+    fn compare_lyc(&mut self) -> u8 {
+        self.lcdstat.ly_eq_lyc = self.read_ly() == self.lyc;
+        if self.lcdstat.lyc_int && self.lcdstat.ly_eq_lyc {
+            INT_STAT.0
+        } else {
+            0
+        }
+    }
+
     fn set_ly(&mut self, ly: u8) -> u8 {
         self.ly = ly;
         if ly == 0 {
             self.wly = 0;
         }
-        self.lcdstat.ly_eq_lyc = self.ly == self.lyc;
-        let mut interrupts = 0;
-        if self.lcdstat.lyc_int && self.lcdstat.ly_eq_lyc {
-            interrupts |= INT_STAT.0
-        }
-        interrupts
+        self.compare_lyc()
     }
 
     pub fn mode(&self) -> PPUMode {
         PPUMode(self.lcdstat.ppu_mode_1, self.lcdstat.ppu_mode_0)
     }
 
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn sprite(&self, i: u16) -> Sprite {
+        let [y, x, tile, raw_flags] = [0, 1, 2, 3].map(|offset| self.oam[i as usize * 4 + offset]);
+        let flags = OBJFlags::from(raw_flags);
+        let height = if self.lcdc.obj_size { 16 } else { 8 };
+        Sprite {
+            y: y as i16 - 16,
+            x: x as i16 - 8,
+            tile: tile & if height == 16 { 0xFE } else { 0xFF }, // Last bit is ignored in 8x16 mode
+            height,
+            flags: raw_flags,
+            bg_priority: flags.bg_priority,
+            y_flip: flags.y_flip,
+            x_flip: flags.x_flip,
+            dmg_palette: flags.obp as u8,
+            bank: flags.bank,
+            cgb_palette: pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]),
+        }
+    }
+
+    pub fn sprites(&self) -> Vec<Sprite> {
+        (0..40).map(|i| self.sprite(i)).collect()
+    }
+
+    pub fn bg_tile_ids(&self, map: bool) -> [[u8; 32]; 32] {
+        // Tile numbers of the 0x9800 (false) or 0x9C00 (true) tilemap, by row
+        let mut ids = [[0; 32]; 32];
+        for (y, row) in ids.iter_mut().enumerate() {
+            for (x, id) in row.iter_mut().enumerate() {
+                *id = self.rtilemap(x as u8, y as u8, map, false);
+            }
+        }
+        ids
+    }
+
+    pub fn window_active(&self) -> bool {
+        // Whether the window was triggered by LCDC, WY and WX on the last frame, also while its layer is hidden
+        self.window_active
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc.lcd_enable
+    }
+
+    pub fn oam_blocked(&self) -> bool {
+        self.lcdc.lcd_enable && matches!(self.mode(), PPUMode::OAM | PPUMode::DRAW)
+    }
+
     fn update_mode(&mut self) -> (u8, Option<PPUMode>) {
         let current_mode: PPUMode = match self.scanline_ticks {
             _ if self.ly >= LCDH as u8 => PPUMode::VBLANK,
@@ -231,147 +528,920 @@ impl PPU {
     }
 
     pub fn step(&mut self, lcd: &mut LCD, elapsed_ticks: u16) -> (bool, u8) {
+        let result = self.tick(elapsed_ticks);
+        self.flush(lcd);
+        result
+    }
+
+    pub fn flush(&mut self, lcd: &mut LCD) {
+        // Draw the scanline entered in HBlank since the last flush, if any
+        if std::mem::take(&mut self.line_pending) {
+            if self.fifo_renderer {
+                self.write_fifo_line(lcd);
+            } else {
+                self.draw_line(lcd);
+            }
+        }
+    }
+
+    fn select_objs(&self) -> Vec<(u16, Sprite)> {
+        // OAM scan, selecting the first 10 objects on the current line (or all of them without the sprite limit)
+        let mut selected_objs = Vec::with_capacity(10);
+        for i in 0..40 {
+            let obj = self.sprite(i);
+            if obj.y <= (self.ly as i16) && (self.ly as i16) < obj.y + obj.height as i16 && obj.y < LCDH as i16 {
+                selected_objs.push((i, obj));
+                if self.sprite_limit && selected_objs.len() >= 10 {
+                    break;
+                }
+            }
+        }
+        selected_objs
+    }
+
+    fn fifo_start(&mut self) {
+        // Start of mode 3, the fine scrolling is latched here
+        let objs = self.select_objs();
+        let fifo = &mut self.fifo;
+        (fifo.active, fifo.lx, fifo.discard, fifo.fetch_x, fifo.window) = (true, 0, self.scx % 8, 0, false);
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.obj.extend([ObjPixel::default(); 8]);
+        fifo.objs = objs;
+        fifo.line.clear();
+        fifo.line.resize(LCDW, (None, None));
+    }
+
+    fn fifo_fetch_bg(&mut self) {
+        // Fetch the next 8 pixels, with the registers values at the time of the fetch
+        let (tile_x, tile_y, mode) = if self.fifo.window {
+            (self.fifo.fetch_x % 32, self.wly, self.lcdc.window_mode)
+        } else {
+            (
+                (self.scx / 8).wrapping_add(self.fifo.fetch_x) % 32,
+                self.scy.wrapping_add(self.ly),
+                self.lcdc.bg_mode,
+            )
+        };
+        let tile_nr = self.rtilemap(tile_x, tile_y / 8, mode, false);
+        let raw_flags = self.rtilemap(tile_x, tile_y / 8, mode, true);
+        let flags = BGFlags::from(raw_flags);
+        let tile_row = if !flags.y_flip { tile_y % 8 } else { 7 - tile_y % 8 };
+        let tile = self.rtile(tile_nr, tile_row, false, flags.bank);
+        let window = self.fifo.window;
+        for i in 0..8 {
+            let px = PPU::rpx(tile, i, flags.x_flip);
+            self.fifo.bg.push_back(BgPixel {
+                px,
+                flags: raw_flags,
+                window,
+            });
+        }
+        self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+    }
+
+    fn fifo_fetch_objs(&mut self) {
+        // Merge the objects starting at the current pixel into the OBJ FIFO, only replacing transparent or lower priority pixels
+        let lx = self.fifo.lx as i16;
+        for j in 0..self.fifo.objs.len() {
+            let (i, obj) = self.fifo.objs[j];
+            if obj.x != lx && !(lx == 0 && obj.x < 0) {
+                continue;
+            }
+            let tile_row = if !obj.y_flip {
+                self.ly as i16 - obj.y
+            } else {
+                (obj.height as i16 - 1) - (self.ly as i16 - obj.y)
+            };
+            let tile = self.rtile(obj.tile, tile_row as u8, true, obj.bank);
+            for px_idx in 0..8 {
+                let x = obj.x + px_idx as i16;
+                let px = PPU::rpx(tile, px_idx, obj.x_flip);
+                if x < lx || px == 0 {
+                    continue;
+                }
+                let slot = &mut self.fifo.obj[(x - lx) as usize];
+                if slot.px == 0 || (self.cgb_mode && i < slot.oam_idx) {
+                    *slot = ObjPixel {
+                        px,
+                        flags: obj.flags,
+                        oam_idx: i,
+                    };
+                }
+            }
+        }
+    }
+
+    fn fifo_step(&mut self) {
+        // Push a single pixel to the LCD
+        if !self.fifo.active || self.fifo.lx as usize >= LCDW {
+            return;
+        }
+        let bg_visible = self.lcdc.bg_enable || self.cgb_mode;
+        let window_line = self.lcdc.window_enable && bg_visible && self.wy <= self.ly && self.wx <= 166;
+        if !self.fifo.window && window_line && self.fifo.discard == 0 && self.fifo.lx as u16 + 7 >= self.wx as u16 {
+            // Switch to the window, if WX < 7 its first pixels are hidden
+            self.fifo.window = true;
+            self.fifo.bg.clear();
+            self.fifo.fetch_x = 0;
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+        if self.fifo.bg.is_empty() {
+            self.fifo_fetch_bg();
+        }
+        let mut bg = self.fifo.bg.pop_front().unwrap();
+        // Hidden layers are drawn with color 0, without affecting the window state
+        if !self.layer_enabled(if bg.window { Layer::Window } else { Layer::Background }) {
+            bg.px = 0;
+        }
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        self.fifo_fetch_objs();
+        let obj = self.fifo.obj.pop_front().unwrap();
+        self.fifo.obj.push_back(ObjPixel::default());
+
+        // Mix the BG and OBJ pixels
+        let bg_flags = BGFlags::from(bg.flags);
+        let bg_px = if bg_visible { bg.px } else { 0 };
+        let bg_out = bg_visible.then(|| {
+            if self.cgb_mode {
+                let cgbp = pack_bits(&[bg_flags.cgbp2, bg_flags.cgbp1, bg_flags.cgbp0]);
+                FifoOutput {
+                    px: bg.px,
+                    dmg_palette: 0,
+                    palette: PPU::rpalette(&self.bgpalette, cgbp).try_into().unwrap(),
+                    foreground: bg.window,
+                }
+            } else {
+                FifoOutput {
+                    px: bg.px,
+                    dmg_palette: self.bgp,
+                    palette: self.bgpalette[0..8].try_into().unwrap(),
+                    foreground: bg.window,
+                }
+            }
+        });
+        let obj_flags = OBJFlags::from(obj.flags);
+        let bg_has_priority = bg_px != 0
+            && if self.cgb_mode {
+                self.lcdc.bg_enable && (obj_flags.bg_priority || bg_flags.bg_priority)
+            } else {
+                obj_flags.bg_priority
+            };
+        let obj_out = (self.lcdc.obj_enable && self.layer_enabled(Layer::Sprites) && obj.px != 0 && !bg_has_priority).then(|| {
+            if self.cgb_mode {
+                let cgbp = pack_bits(&[obj_flags.cgbp2, obj_flags.cgbp1, obj_flags.cgbp0]);
+                FifoOutput {
+                    px: obj.px,
+                    dmg_palette: 0,
+                    palette: PPU::rpalette(&self.obpalette, cgbp).try_into().unwrap(),
+                    foreground: true,
+                }
+            } else {
+                FifoOutput {
+                    px: obj.px,
+                    dmg_palette: if obj_flags.obp { self.obp1 } else { self.obp0 },
+                    palette: PPU::rpalette(&self.obpalette, obj_flags.obp as u8).try_into().unwrap(),
+                    foreground: true,
+                }
+            }
+        });
+        self.fifo.line[self.fifo.lx as usize] = (bg_out, obj_out);
+        self.fifo.lx += 1;
+    }
+
+    fn fifo_finish(&mut self) {
+        // Push the pixels left, if mode 3 was shorter than needed
+        if !self.fifo.active {
+            self.fifo_start();
+        }
+        while (self.fifo.lx as usize) < LCDW {
+            self.fifo_step();
+        }
+        if self.fifo.window {
+            self.wly += 1;
+            self.window_drawn = true;
+        }
+        self.fifo.active = false;
+    }
+
+    fn write_fifo_line(&mut self, lcd: &mut LCD) {
+        for (x, (bg, obj)) in self.fifo.line.iter().enumerate() {
+            for out in [bg, obj].into_iter().flatten() {
+                if self.cgb_mode {
+                    lcd.w_cgb(x as u8, self.ly, out.px, &out.palette, out.foreground);
+                } else {
+                    self.w_dmg(lcd, x as u8, out.px, out.dmg_palette, &out.palette, out.foreground);
+                }
+            }
+        }
+    }
+
+    fn draw_line(&mut self, lcd: &mut LCD) {
+        // Draw background
+        if self.lcdc.bg_enable || self.cgb_mode {
+            for lx in 0..(LCDW as u8 / 8 + 1) {
+                let tilemap_x = ((self.scx / 8) + lx) % 32;
+                let tilemap_y = self.scy.wrapping_add(self.ly);
+                let tile_nr = self.rtilemap(tilemap_x, tilemap_y / 8, self.lcdc.bg_mode, false);
+                let flags = BGFlags::from(self.rtilemap(tilemap_x, tilemap_y / 8, self.lcdc.bg_mode, true));
+                let tile_row = if !flags.y_flip { tilemap_y % 8 } else { 7 - tilemap_y % 8 };
+                let tile = self.rtile(tile_nr, tile_row, false, flags.bank);
+                for i in 0..8 {
+                    let x = (lx * 8) as i16 - (self.scx % 8) as i16 + i as i16;
+                    if x < 0 || x >= LCDW as i16 {
+                        continue;
+                    }
+                    let px = if self.layer_enabled(Layer::Background) {
+                        PPU::rpx(tile, i, flags.x_flip)
+                    } else {
+                        0
+                    };
+                    self.scanline_bg_colors[x as usize] = px;
+                    self.scanline_bg_pri[x as usize] = flags.bg_priority;
+                    if self.cgb_mode {
+                        let cgbp = pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]);
+                        let palette = PPU::rpalette(&self.bgpalette, cgbp);
+                        lcd.w_cgb(x as u8, self.ly, px, palette, false);
+                    } else {
+                        self.w_dmg(lcd, x as u8, px, self.bgp, &self.bgpalette[0..8], false);
+                    }
+                }
+            }
+        }
+        // Draw window
+        let wx = self.wx as i16 - 7;
+        if self.lcdc.window_enable && (self.lcdc.bg_enable || self.cgb_mode) && self.wy <= self.ly && wx < LCDH as i16 {
+            for lx in 0..(LCDW as u8 / 8 + 1) {
+                let tile_nr = self.rtilemap(lx, self.wly / 8, self.lcdc.window_mode, false);
+                let flags = BGFlags::from(self.rtilemap(lx, self.wly / 8, self.lcdc.window_mode, true));
+                let tile_row = if !flags.y_flip { self.wly % 8 } else { 7 - self.wly % 8 };
+                let tile = self.rtile(tile_nr, tile_row, false, flags.bank);
+                for i in 0..8 {
+                    let x = (lx * 8) as i16 + wx + i as i16;
+                    if x < 0 || x >= LCDW as i16 {
+                        continue;
+                    }
+                    let px = if self.layer_enabled(Layer::Window) {
+                        PPU::rpx(tile, i, flags.x_flip)
+                    } else {
+                        0
+                    };
+                    self.scanline_bg_colors[x as usize] = px;
+                    self.scanline_bg_pri[x as usize] = flags.bg_priority;
+                    if self.cgb_mode {
+                        let cgbp = pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]);
+                        let palette = PPU::rpalette(&self.bgpalette, cgbp);
+                        lcd.w_cgb(x as u8, self.ly, px, palette, true);
+                    } else {
+                        self.w_dmg(lcd, x as u8, px, self.bgp, &self.bgpalette[0..8], true);
+                    }
+                }
+            }
+            self.wly += 1;
+            self.window_drawn = true;
+        }
+        // Draw OBJs
+        if self.lcdc.obj_enable && self.layer_enabled(Layer::Sprites) {
+            let obj_h = if self.lcdc.obj_size { 16 } else { 8 };
+            // Select firt 10 objects to be drawn (or all of them without the sprite limit) and sort them by priority
+            let mut selected_objs = self.select_objs();
+            // Sort by priority (higher priorities are drawn later so they overwrite lower priorities)
+            if self.cgb_mode {
+                selected_objs.sort_by(|(ai, _), (bi, _)| ai.cmp(&bi).reverse());
+            } else {
+                selected_objs.sort_by(|(ai, a), (bi, b)| a.x.cmp(&b.x).reverse().then(ai.cmp(&bi).reverse()));
+            }
+            // Draw selected objects
+            for (_, obj) in selected_objs {
+                let flags = OBJFlags::from(obj.flags);
+                let tile_row = if !flags.y_flip {
+                    self.ly as i16 - obj.y
+                } else {
+                    (obj_h - 1) - (self.ly as i16 - obj.y)
+                };
+                let tile = self.rtile(obj.tile, tile_row as u8, true, flags.bank);
+                // Write pixel by pixel to buffer
+                for i in 0..8 {
+                    let x = obj.x + i as i16;
+                    if x < 0 || x >= LCDW as i16 {
+                        continue;
+                    }
+                    let px = PPU::rpx(tile, i, flags.x_flip);
+                    // Skip pixel if transparent or if piority is set to BG and BG is not transparent
+                    let bg_has_priority = self.scanline_bg_colors[x as usize] != 0
+                        && if self.cgb_mode {
+                            self.lcdc.bg_enable && (flags.bg_priority || self.scanline_bg_pri[x as usize])
+                        } else {
+                            flags.bg_priority
+                        };
+                    if px == 0 || bg_has_priority {
+                        continue;
+                    }
+                    // Draw
+                    if self.cgb_mode {
+                        let cgbp = pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]);
+                        let palette = PPU::rpalette(&self.obpalette, cgbp);
+                        lcd.w_cgb(x as u8, self.ly, px, palette, true);
+                    } else {
+                        let palette = if flags.obp { self.obp1 } else { self.obp0 };
+                        self.w_dmg(lcd, x as u8, px, palette, PPU::rpalette(&self.obpalette, flags.obp as u8), true);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, elapsed_ticks: u16) -> (bool, u8) {
         // Wait until the LCD is enabled to start PPU and reset PPU status.
         if !self.lcdc.lcd_enable {
             self.set_ly(0);
             self.scanline_ticks = 0;
             (self.lcdstat.ppu_mode_1, self.lcdstat.ppu_mode_0) = (PPUMode::HBLANK.0, PPUMode::HBLANK.1);
+            self.pending_interrupts = 0;
+            self.line_pending = false;
+            (self.window_drawn, self.window_active) = (false, false);
             return (false, 0);
         }
-        let mut interrupts: u8 = 0;
+        let mut interrupts: u8 = std::mem::take(&mut self.pending_interrupts);
+        let mut frame_ready = false;
+        // Push the pixels of the elapsed mode 3 dots
+        if self.fifo_renderer && self.ly < LCDH as u8 {
+            let end = (self.scanline_ticks + elapsed_ticks).min(DRAW_END + 1);
+            for dot in self.scanline_ticks.max(DRAW_START)..end {
+                if dot == DRAW_START {
+                    self.fifo_start();
+                }
+                self.fifo_step();
+            }
+        }
         // Set current mode and trigger interrupt if needed.
+        let ly_before = self.read_ly();
         self.scanline_ticks += elapsed_ticks;
+        if self.read_ly() != ly_before {
+            // LY switched to 0 during line 153, compare it with LYC early
+            interrupts |= self.compare_lyc();
+        }
         let (mode_interrupts, new_mode) = self.update_mode();
         interrupts |= mode_interrupts;
         // Draw single scanline when the PPU enters HBlank
         if new_mode == Some(PPUMode::HBLANK) {
-            // Draw background
-            if self.lcdc.bg_enable || self.cgb_mode {
-                for lx in 0..(LCDW as u8 / 8 + 1) {
-                    let tilemap_x = ((self.scx / 8) + lx) % 32;
-                    let tilemap_y = self.scy.wrapping_add(self.ly);
-                    let tile_nr = self.rtilemap(tilemap_x, tilemap_y / 8, self.lcdc.bg_mode, false);
-                    let flags = BGFlags::from(self.rtilemap(tilemap_x, tilemap_y / 8, self.lcdc.bg_mode, true));
-                    let tile_row = if !flags.y_flip { tilemap_y % 8 } else { 7 - tilemap_y % 8 };
-                    let tile = self.rtile(tile_nr, tile_row, false, flags.bank);
-                    for i in 0..8 {
-                        let x = (lx * 8) as i16 - (self.scx % 8) as i16 + i as i16;
-                        if x < 0 || x >= LCDW as i16 {
-                            continue;
-                        }
-                        let px = PPU::rpx(tile, i, flags.x_flip);
-                        self.scanline_bg_colors[x as usize] = px;
-                        self.scanline_bg_pri[x as usize] = flags.bg_priority;
-                        if self.cgb_mode {
-                            let cgbp = pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]);
-                            let palette = PPU::rpalette(&self.bgpalette, cgbp);
-                            lcd.w_cgb(x as u8, self.ly, px, palette, false);
-                        } else {
-                            lcd.w_dmg(x as u8, self.ly, px, self.bgp, false);
-                        }
-                    }
+            if self.fifo_renderer {
+                self.fifo_finish();
+            }
+            self.line_pending = true;
+        } else if self.scanline_ticks > SCANLINE_TICKS {
+            // Go to new line when a scanline is done
+            self.scanline_ticks %= SCANLINE_TICKS;
+            if self.ly + 1 < LY_MAX {
+                interrupts |= self.set_ly(self.ly + 1);
+            } else {
+                // Last scanline reached, LY already read 0 so LY=LYC was already requested on line 153
+                let was_equal = self.lcdstat.ly_eq_lyc;
+                let lyc_interrupts = self.set_ly(0);
+                if !was_equal {
+                    interrupts |= lyc_interrupts;
                 }
+                self.window_active = std::mem::take(&mut self.window_drawn);
+                frame_ready = true;
             }
-            // Draw window
-            let wx = self.wx as i16 - 7;
-            if self.lcdc.window_enable && (self.lcdc.bg_enable || self.cgb_mode) && self.wy <= self.ly && wx < LCDH as i16 {
-                for lx in 0..(LCDW as u8 / 8 + 1) {
-                    let tile_nr = self.rtilemap(lx, self.wly / 8, self.lcdc.window_mode, false);
-                    let flags = BGFlags::from(self.rtilemap(lx, self.wly / 8, self.lcdc.window_mode, true));
-                    let tile_row = if !flags.y_flip { self.wly % 8 } else { 7 - self.wly % 8 };
-                    let tile = self.rtile(tile_nr, tile_row, false, flags.bank);
-                    for i in 0..8 {
-                        let x = (lx * 8) as i16 + wx + i as i16;
-                        if x < 0 || x >= LCDW as i16 {
-                            continue;
-                        }
-                        let px = PPU::rpx(tile, i, flags.x_flip);
-                        self.scanline_bg_colors[x as usize] = px;
-                        self.scanline_bg_pri[x as usize] = flags.bg_priority;
-                        if self.cgb_mode {
-                            let cgbp = pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]);
-                            let palette = PPU::rpalette(&self.bgpalette, cgbp);
-                            lcd.w_cgb(x as u8, self.ly, px, palette, true);
-                        } else {
-                            lcd.w_dmg(x as u8, self.ly, px, self.bgp, true);
-                        }
-                    }
+        }
+
+        (frame_ready, interrupts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Layer, Sprite, PPU, SCANLINE_TICKS};
+    use crate::cpu::INT_STAT;
+    use crate::lcd::{LCD, LCDH, LCDW};
+
+    #[test]
+    fn lyc_write_compare() {
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(true);
+        ppu.w(0xFF41, 0x40); // Enable LYC interrupt
+        ppu.w(0xFF45, 0x05);
+        ppu.w(0xFF40, 0x80); // Enable LCD
+        assert_eq!(ppu.step(&mut lcd, 0).1 & INT_STAT.0, 0);
+        // LY == LYC becomes true on write
+        ppu.w(0xFF45, 0x00);
+        assert_eq!(ppu.r(0xFF41) & 0x04, 0x04);
+        assert_eq!(ppu.step(&mut lcd, 0).1 & INT_STAT.0, INT_STAT.0);
+        // Writing the same value again does not trigger a new interrupt
+        ppu.w(0xFF45, 0x00);
+        assert_eq!(ppu.step(&mut lcd, 0).1 & INT_STAT.0, 0);
+        // LY == LYC becomes false on write
+        ppu.w(0xFF45, 0x01);
+        assert_eq!(ppu.r(0xFF41) & 0x04, 0x00);
+        assert_eq!(ppu.step(&mut lcd, 0).1 & INT_STAT.0, 0);
+    }
+
+    #[test]
+    fn obj_line_limit() {
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(false);
+        ppu.vram[0x0010..0x0020].fill(0xFF); // Tile 1 with color 3
+
+        // 11 objects on line 0, the last one by OAM index is placed at the lowest X
+        for i in 0..11u8 {
+            let x = if i < 10 { 24 + i * 8 } else { 8 };
+            ppu.oam[i as usize * 4..][..4].copy_from_slice(&[16, x, 1, 0]);
+        }
+        ppu.w(0xFF48, 0xE4);
+        ppu.w(0xFF40, 0x83); // Enable LCD, BG and objects
+        ppu.step(&mut lcd, 0); // OAM scan
+        ppu.step(&mut lcd, 300); // HBlank
+        let (bg, obj) = (LCD::to_color_dmg(0, 0x00, 0), LCD::to_color_dmg(3, 0xE4, 0));
+        assert!(lcd.frame[0..8].iter().all(|px| *px == bg));
+        assert!(lcd.frame[8..16].iter().all(|px| *px == bg));
+        assert!(lcd.frame[16..96].iter().all(|px| *px == obj));
+        assert_eq!(lcd.frame[96], bg);
+    }
+
+    #[test]
+    fn sprite_limit_disabled() {
+        for sprite_limit in [true, false] {
+            let mut lcd = LCD::new();
+            let mut ppu = PPU::new(false);
+            ppu.set_sprite_limit_enabled(sprite_limit);
+            ppu.vram[0x0010..0x0020].fill(0xFF); // Tile 1 with color 3
+            for i in 0..12u8 {
+                ppu.oam[i as usize * 4..][..4].copy_from_slice(&[16, 8 + i * 8, 1, 0]);
+            }
+            ppu.w(0xFF48, 0xE4);
+            ppu.w(0xFF40, 0x83); // Enable LCD, BG and objects
+            ppu.step(&mut lcd, 0); // OAM scan
+            ppu.step(&mut lcd, 300); // HBlank
+            let obj = LCD::to_color_dmg(3, 0xE4, 0);
+            let drawn = lcd.frame[0..LCDW].iter().take_while(|px| **px == obj).count() / 8;
+            assert_eq!(drawn, if sprite_limit { 10 } else { 12 });
+        }
+    }
+
+    #[test]
+    fn cgb_raw_colors() {
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(true);
+        // Tile 0 row 0 with colors 0, 1, 2, 3, 0, 1, 2, 3
+        ppu.vram[0x0000..0x0002].copy_from_slice(&[0b01010101, 0b00110011]);
+        // BG palette 0 with arbitrary colors, written with auto-increment
+        let colors: [u16; 4] = [0x7FFF, 0x1234, 0x4321, 0x0ABC];
+        ppu.w(0xFF68, 0x80);
+        for byte in colors.iter().flat_map(|c| c.to_le_bytes()) {
+            ppu.w(0xFF69, byte);
+        }
+        ppu.w(0xFF40, 0x91); // Enable LCD and BG
+        ppu.step(&mut lcd, 0); // OAM scan
+        ppu.step(&mut lcd, 300); // HBlank
+        assert_eq!(lcd.frame_bgr555[0..4], colors);
+        assert_eq!(lcd.frame_bgr555[4..8], colors);
+        assert_eq!(lcd.frame[1], LCD::to_color_cgb(1, &ppu.bgpalette[0..8]));
+    }
+
+    #[test]
+    fn stat_read() {
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(true);
+        ppu.w(0xFF41, 0x00);
+        assert_eq!(ppu.r(0xFF41), 0x80);
+        ppu.w(0xFF45, 0x01);
+        ppu.w(0xFF40, 0x80);
+        for (ticks, mode) in [(0, 2), (100, 3), (200, 0)] {
+            ppu.step(&mut lcd, ticks);
+            assert_eq!(ppu.r(0xFF41), 0x80 | mode);
+            // Writes do not change the read-only bits
+            ppu.w(0xFF41, 0xFF);
+            assert_eq!(ppu.r(0xFF41), 0xF8 | mode);
+            ppu.w(0xFF41, 0x00);
+        }
+        while ppu.ly < 144 {
+            ppu.step(&mut lcd, 4);
+        }
+        ppu.step(&mut lcd, 4);
+        assert_eq!(ppu.r(0xFF41), 0x80 | 0x01);
+        // Mode reads as 0 as soon as the LCD is turned off
+        ppu.w(0xFF40, 0x00);
+        assert_eq!(ppu.r(0xFF41), 0x80);
+    }
+
+    #[test]
+    fn dmg_stat_write_bug() {
+        for cgb_mode in [false, true] {
+            let mut lcd = LCD::new();
+            let mut ppu = PPU::new(cgb_mode);
+            ppu.w(0xFF45, 0x05);
+            ppu.w(0xFF40, 0x80);
+            ppu.step(&mut lcd, 0); // OAM scan
+            ppu.w(0xFF41, 0x00);
+            assert_eq!(ppu.step(&mut lcd, 0).1 & INT_STAT.0, 0);
+            ppu.step(&mut lcd, 300); // HBlank
+            ppu.w(0xFF41, 0x00);
+            let expected = if cgb_mode { 0 } else { INT_STAT.0 };
+            assert_eq!(ppu.step(&mut lcd, 0).1 & INT_STAT.0, expected);
+        }
+    }
+
+    #[test]
+    fn raster_effects() {
+        // Registers changed between scanlines are used to draw the following lines
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(false);
+        for row in ppu.vram[0x0000..0x0010].chunks_mut(2) {
+            row.copy_from_slice(&[0xF0, 0xF0]); // Tile 0 with colors 3, 3, 3, 3, 0, 0, 0, 0
+        }
+        ppu.w(0xFF40, 0x91); // Enable LCD and BG, with unsigned tile addressing
+        let lines = [(0, 0xE4), (4, 0xE4), (4, 0x1B), (2, 0x1B)]; // SCX and BGP for each line
+        for (scx, bgp) in lines {
+            ppu.w(0xFF43, scx);
+            ppu.w(0xFF47, bgp);
+            ppu.step(&mut lcd, 0); // OAM scan
+            ppu.step(&mut lcd, 300); // HBlank
+            ppu.step(&mut lcd, 160); // Next line
+        }
+        for (ly, (scx, bgp)) in lines.iter().enumerate() {
+            let row = &lcd.frame[ly * LCDW..][..8];
+            let expected: Vec<u32> = (0..8)
+                .map(|x| LCD::to_color_dmg(if (x + scx) % 8 < 4 { 3 } else { 0 }, *bgp, 0))
+                .collect();
+            assert_eq!(row, expected, "line {}", ly);
+        }
+
+        // CGB palette RAM changed between scanlines
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(true);
+        ppu.vram[0x0000..0x0010].fill(0xFF); // Tile 0 with color 3
+        ppu.w(0xFF40, 0x91);
+        let colors: [u16; 3] = [0x001F, 0x03E0, 0x7C00];
+        for color in colors {
+            ppu.w(0xFF68, 0x80 | 0x06); // Color 3 of BG palette 0, with auto-increment
+            for byte in color.to_le_bytes() {
+                ppu.w(0xFF69, byte);
+            }
+            ppu.step(&mut lcd, 0);
+            ppu.step(&mut lcd, 300);
+            ppu.step(&mut lcd, 160);
+        }
+        for (ly, color) in colors.iter().enumerate() {
+            assert!(lcd.frame_bgr555[ly * LCDW..][..LCDW].iter().all(|px| px == color), "line {}", ly);
+        }
+    }
+
+    #[test]
+    fn sprites() {
+        let mut ppu = PPU::new(true);
+        ppu.w(0xFF40, 0x80); // 8x8 objects
+        for (addr, val) in [(0xFE04, 0x20), (0xFE05, 0x18), (0xFE06, 0x43), (0xFE07, 0xBD)] {
+            ppu.w(addr, val);
+        }
+        let expected = Sprite {
+            y: 0x10,
+            x: 0x10,
+            tile: 0x43,
+            height: 8,
+            flags: 0xBD,
+            bg_priority: true,
+            y_flip: false,
+            x_flip: true,
+            dmg_palette: 1,
+            bank: true,
+            cgb_palette: 5,
+        };
+        let sprites = ppu.sprites();
+        assert_eq!(sprites.len(), 40);
+        assert_eq!(sprites[1], expected);
+        assert_eq!((sprites[0].y, sprites[0].x), (-16, -8));
+        // The tile number LSB is ignored in 8x16 mode
+        ppu.w(0xFF40, 0x84);
+        assert_eq!(
+            ppu.sprites()[1],
+            Sprite {
+                tile: 0x42,
+                height: 16,
+                ..expected
+            }
+        );
+    }
+
+    fn fifo_test_ppu(fifo_renderer: bool) -> PPU {
+        let mut ppu = PPU::new(false);
+        ppu.set_fifo_renderer_enabled(fifo_renderer);
+        // Tiles 0-3 with solid colors 0-3, tile 4 with colors 3, 3, 3, 3, 0, 0, 0, 0
+        for (tile, rows) in ppu.vram[0x0000..0x0050].chunks_mut(16).enumerate() {
+            for row in rows.chunks_mut(2) {
+                let [lo, hi] = if tile < 4 {
+                    [0xFF * (tile as u8 & 1), 0xFF * (tile as u8 >> 1)]
+                } else {
+                    [0xF0, 0xF0]
+                };
+                row.copy_from_slice(&[lo, hi]);
+            }
+        }
+        // BG map columns cycling through tiles 0-3, window map with tile 4
+        for (i, tile) in ppu.vram[0x1800..0x1C00].iter_mut().enumerate() {
+            *tile = (i % 32 % 4) as u8;
+        }
+        ppu.vram[0x1C00..0x2000].fill(4);
+        ppu.w(0xFF47, 0xE4);
+        ppu.w(0xFF48, 0xE4);
+        ppu.w(0xFF49, 0x1B);
+        ppu
+    }
+
+    fn step_line(ppu: &mut PPU, lcd: &mut LCD, dots: &[u16]) {
+        // Step to the given dots of the scanline, then to the start of the next one
+        for dot in dots.iter().chain([SCANLINE_TICKS + 1].iter()) {
+            let ticks = dot.saturating_sub(ppu.scanline_ticks);
+            ppu.step(lcd, ticks);
+        }
+    }
+
+    #[test]
+    fn fifo_renderer() {
+        // Without mid-scanline changes both renderers draw the same frame
+        let frames = [false, true].map(|fifo_renderer| {
+            let mut lcd = LCD::new();
+            let mut ppu = fifo_test_ppu(fifo_renderer);
+            ppu.oam[0..12].copy_from_slice(&[20, 5, 4, 0x00, 30, 60, 3, 0x20, 40, 100, 1, 0x90]);
+            ppu.w(0xFF42, 3); // SCY
+            ppu.w(0xFF43, 5); // SCX
+            ppu.w(0xFF4A, 64); // WY
+            ppu.w(0xFF4B, 87); // WX
+            ppu.w(0xFF40, 0xB3); // LCD, window at 0x9C00, unsigned tiles, objects and BG
+            for _ in 0..LCDH {
+                step_line(&mut ppu, &mut lcd, &[0, 4, 90, 200, 300]);
+            }
+            lcd.frame.to_vec()
+        });
+        assert!(frames[0] == frames[1]);
+
+        // A mid-scanline SCX change is only visible with the FIFO renderer
+        let lines = [false, true].map(|fifo_renderer| {
+            let mut lcd = LCD::new();
+            let mut ppu = fifo_test_ppu(fifo_renderer);
+            ppu.w(0xFF40, 0x91);
+            ppu.step(&mut lcd, 0);
+            ppu.step(&mut lcd, 120); // 40 pixels pushed
+            ppu.w(0xFF43, 16);
+            step_line(&mut ppu, &mut lcd, &[]);
+            lcd.frame[..LCDW].to_vec()
+        });
+        let column_color = |x: usize| LCD::to_color_dmg((x / 8 % 4) as u8, 0xE4, 0);
+        let expected_scanline: Vec<u32> = (0..LCDW).map(|x| column_color(x + 16)).collect();
+        let expected_fifo: Vec<u32> = (0..LCDW).map(|x| column_color(if x < 40 { x } else { x + 16 })).collect();
+        assert!(lines[0] == expected_scanline);
+        assert!(lines[1] == expected_fifo);
+    }
+
+    #[test]
+    fn window_active() {
+        for fifo_renderer in [false, true] {
+            let mut lcd = LCD::new();
+            let mut ppu = fifo_test_ppu(fifo_renderer);
+            ppu.w(0xFF4A, 64); // WY
+            ppu.w(0xFF4B, 87); // WX
+            ppu.w(0xFF40, 0xB3); // LCD, window at 0x9C00, unsigned tiles, objects and BG
+            ppu.step(&mut lcd, 0);
+            assert!(!ppu.window_active());
+            for _ in 0..154 {
+                step_line(&mut ppu, &mut lcd, &[0, 4, 90, 200, 300]);
+            }
+            assert!(ppu.window_active(), "fifo_renderer: {}", fifo_renderer);
+            // Hiding the layer doesn't change what the game does
+            ppu.set_layer_enabled(Layer::Window, false);
+            for _ in 0..154 {
+                step_line(&mut ppu, &mut lcd, &[0, 4, 90, 200, 300]);
+            }
+            assert!(ppu.window_active(), "fifo_renderer: {}", fifo_renderer);
+            ppu.set_layer_enabled(Layer::Window, true);
+            // Window disabled for the whole next frame
+            ppu.w(0xFF40, 0x93);
+            for _ in 0..154 {
+                step_line(&mut ppu, &mut lcd, &[0, 4, 90, 200, 300]);
+            }
+            assert!(!ppu.window_active(), "fifo_renderer: {}", fifo_renderer);
+            // Enabled, but WY below the last visible line
+            ppu.w(0xFF4A, 150);
+            ppu.w(0xFF40, 0xB3);
+            for _ in 0..154 {
+                step_line(&mut ppu, &mut lcd, &[0, 4, 90, 200, 300]);
+            }
+            assert!(!ppu.window_active(), "fifo_renderer: {}", fifo_renderer);
+        }
+    }
+
+    #[test]
+    fn bg_tile_ids() {
+        let mut ppu = PPU::new(true);
+        ppu.vram[0x1800 + 32 + 4] = 0x12; // 0x9800 map, row 1 column 4
+        ppu.vram[0x1C00 + 31 * 32 + 31] = 0x34; // 0x9C00 map, last tile
+        ppu.vram[0x3C00] = 0x56; // Attributes in bank 1 are not tile numbers
+        let (map0, map1) = (ppu.bg_tile_ids(false), ppu.bg_tile_ids(true));
+        assert_eq!(map0[1][4], 0x12);
+        assert_eq!(map0.iter().flatten().filter(|id| **id != 0).count(), 1);
+        assert_eq!(map1[31][31], 0x34);
+        assert_eq!(map1[0][0], 0x00);
+        assert_eq!(map1.iter().flatten().filter(|id| **id != 0).count(), 1);
+    }
+
+    #[test]
+    fn ly_153() {
+        let mut lcd = LCD::new();
+        let mut ppu = PPU::new(false);
+        ppu.w(0xFF45, 0); // LYC
+        ppu.w(0xFF41, 0x40); // LY=LYC interrupt
+        ppu.w(0xFF40, 0x91);
+        ppu.step(&mut lcd, 0);
+        while ppu.ly < 153 {
+            ppu.step(&mut lcd, 1);
+        }
+        // LY reads 153 for the first dots of the line, then 0 while still in VBlank
+        assert_eq!(ppu.scanline_ticks, 1);
+        assert_eq!(ppu.r(0xFF44), 153);
+        assert_eq!(ppu.step(&mut lcd, 2).1 & INT_STAT.0, 0);
+        assert_eq!(ppu.r(0xFF44), 153);
+        assert_eq!(ppu.step(&mut lcd, 1).1 & INT_STAT.0, INT_STAT.0);
+        assert_eq!(ppu.r(0xFF44), 0);
+        assert_eq!(ppu.r(0xFF41) & 0x07, 0x05); // LY=LYC, mode 1
+
+        // No second LY=LYC interrupt when the frame starts
+        let mut interrupts = 0;
+        while ppu.ly != 0 {
+            interrupts |= ppu.step(&mut lcd, 4).1;
+        }
+        assert_eq!(interrupts & INT_STAT.0, 0);
+        assert_eq!(ppu.r(0xFF44), 0);
+        assert_eq!(ppu.r(0xFF41) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn layers() {
+        let draw = |fifo_renderer: bool, layer: Option<Layer>| {
+            let mut lcd = LCD::new();
+            let mut ppu = PPU::new(false);
+            ppu.set_fifo_renderer_enabled(fifo_renderer);
+            for row in ppu.vram[0..16].chunks_mut(2) {
+                row.copy_from_slice(&[0xFF, 0x00]); // BG tile 0, color 1
+            }
+            for row in ppu.vram[16..32].chunks_mut(2) {
+                row.copy_from_slice(&[0xFF, 0xFF]); // OBJ tile 1, color 3
+            }
+            ppu.oam[0..4].copy_from_slice(&[16, 8, 1, 0x00]);
+            ppu.w(0xFF47, 0xE4);
+            ppu.w(0xFF48, 0xE4);
+            if let Some(layer) = layer {
+                ppu.set_layer_enabled(layer, false);
+            }
+            ppu.w(0xFF40, 0x93);
+            ppu.step(&mut lcd, 0);
+            ppu.step(&mut lcd, 300);
+            (lcd.frame[0..8].to_vec(), lcd.frame[8..16].to_vec())
+        };
+        let color = |px: u8| vec![LCD::to_color_dmg(px, 0xE4, 0); 8];
+        for fifo_renderer in [false, true] {
+            assert_eq!(draw(fifo_renderer, None), (color(3), color(1)));
+            // No sprite pixels, the BG is still drawn
+            assert_eq!(draw(fifo_renderer, Some(Layer::Sprites)), (color(1), color(1)));
+            // BG drawn with color 0 below the sprites
+            assert_eq!(draw(fifo_renderer, Some(Layer::Background)), (color(3), color(0)));
+        }
+    }
+
+    #[test]
+    fn hidden_window() {
+        // The hidden window is drawn with color 0, while its line counter keeps running
+        for fifo_renderer in [false, true] {
+            let mut lcd = LCD::new();
+            let mut ppu = fifo_test_ppu(fifo_renderer);
+            ppu.set_layer_enabled(Layer::Window, false);
+            ppu.w(0xFF4A, 64); // WY
+            ppu.w(0xFF4B, 87); // WX
+            ppu.w(0xFF40, 0xB3); // LCD, window at 0x9C00, unsigned tiles, objects and BG
+            ppu.step(&mut lcd, 0);
+            for _ in 0..LCDH {
+                step_line(&mut ppu, &mut lcd, &[0, 4, 90, 200, 300]);
+            }
+            assert_eq!(ppu.wly, LCDH as u8 - 64, "fifo_renderer: {}", fifo_renderer);
+            let line = &lcd.frame[100 * LCDW..101 * LCDW];
+            assert_eq!(line[8], LCD::to_color_dmg(1, 0xE4, 0)); // BG tile 1
+            assert!(
+                line[80..].iter().all(|px| *px == LCD::to_color_dmg(0, 0xE4, 0)),
+                "fifo_renderer: {}",
+                fifo_renderer
+            );
+        }
+    }
+
+    #[test]
+    fn cgb_bg_priority() {
+        // BG map columns: tiles 0-3 with color 1, 4-7 with color 0, odd columns with the BG priority attribute
+        let bg_palette = [0x1F, 0x00, 0xE0, 0x03, 0x00, 0x00, 0x00, 0x00];
+        let obj_palette = [0x00, 0x00, 0x00, 0x7C, 0x00, 0x00, 0x00, 0x00];
+        for fifo_renderer in [false, true] {
+            for (master_priority, obj_priority) in [(false, false), (false, true), (true, false), (true, true)] {
+                let mut lcd = LCD::new();
+                let mut ppu = PPU::new(true);
+                ppu.set_fifo_renderer_enabled(fifo_renderer);
+                ppu.bgpalette[0..8].copy_from_slice(&bg_palette);
+                ppu.obpalette[0..8].copy_from_slice(&obj_palette);
+                for row in ppu.vram[0..16].chunks_mut(2) {
+                    row.copy_from_slice(&[0xFF, 0x00]); // BG tile 0, color 1
                 }
-                self.wly += 1;
-            }
-            // Draw OBJs
-            if self.lcdc.obj_enable {
-                let obj_h = if self.lcdc.obj_size { 16 } else { 8 };
-                // Select firt 10 objects to be drawn and sort them by priority
-                let mut selected_objs = Vec::with_capacity(10);
-                for i in 0..40 {
-                    let obj_y = self.r(0xFE00 + i * 4) as i16 - 16;
-                    if obj_y <= (self.ly as i16) && (self.ly as i16) < obj_y + obj_h && obj_y < LCDH as i16 {
-                        let obj_x = self.r(0xFE00 + i * 4 + 1) as i16 - 8;
-                        selected_objs.push((i, obj_x, obj_y));
-                        if selected_objs.len() >= 10 {
-                            break;
-                        }
-                    }
+                for row in ppu.vram[16..32].chunks_mut(2) {
+                    row.copy_from_slice(&[0xFF, 0x00]); // OBJ tile 1, color 1
                 }
-                // Sort by priority (higher priorities are drawn later so they overwrite lower priorities)
-                if self.cgb_mode {
-                    selected_objs.sort_by(|(ai, _, _), (bi, _, _)| ai.cmp(&bi).reverse());
-                } else {
-                    selected_objs.sort_by(|(ai, ax, _), (bi, bx, _)| ax.cmp(&bx).reverse().then(ai.cmp(&bi).reverse()));
+                for col in 0..8 {
+                    ppu.vram[0x1800 + col] = if col < 4 { 0 } else { 2 };
+                    ppu.vram[0x3800 + col] = if col % 2 == 1 { 0x80 } else { 0x00 };
+                    let flags = if obj_priority { 0x80 } else { 0x00 };
+                    ppu.oam[col * 4..][..4].copy_from_slice(&[16, col as u8 * 8 + 8, 1, flags]);
                 }
-                // Draw selected objects
-                for (i, obj_x, obj_y) in selected_objs {
-                    let tile_nr = self.r(0xFE00 + i * 4 + 2) & if obj_h == 16 { 0xFE } else { 0xFF }; // Last bit is ignored in 8x16 mode
-                    let flags = OBJFlags::from(self.r(0xFE00 + i * 4 + 3));
-                    let tile_row = if !flags.y_flip {
-                        self.ly as i16 - obj_y
+                ppu.w(0xFF40, if master_priority { 0x93 } else { 0x92 });
+                ppu.step(&mut lcd, 0);
+                ppu.step(&mut lcd, 300);
+                for col in 0..8 {
+                    let (bg_px, bg_priority) = (if col < 4 { 1 } else { 0 }, col % 2 == 1);
+                    // With LCDC.0 clear OBJs are always on top, BG color 0 is always behind them
+                    let obj_visible = !(master_priority && bg_px != 0 && (obj_priority || bg_priority));
+                    let expected = if obj_visible {
+                        LCD::to_color_cgb(1, &obj_palette)
                     } else {
-                        (obj_h - 1) - (self.ly as i16 - obj_y)
+                        LCD::to_color_cgb(bg_px, &bg_palette)
                     };
-                    let tile = self.rtile(tile_nr, tile_row as u8, true, flags.bank);
-                    // Write pixel by pixel to buffer
-                    for i in 0..8 {
-                        let x = obj_x + i as i16;
-                        if x < 0 || x >= LCDW as i16 {
-                            continue;
-                        }
-                        let px = PPU::rpx(tile, i, flags.x_flip);
-                        // Skip pixel if transparent or if piority is set to BG and BG is not transparent
-                        let bg_has_priority = self.scanline_bg_colors[x as usize] != 0
-                            && if self.cgb_mode {
-                                self.lcdc.bg_enable && (flags.bg_priority || self.scanline_bg_pri[x as usize])
-                            } else {
-                                flags.bg_priority
-                            };
-                        if px == 0 || bg_has_priority {
-                            continue;
-                        }
-                        // Draw
-                        if self.cgb_mode {
-                            let cgbp = pack_bits(&[flags.cgbp2, flags.cgbp1, flags.cgbp0]);
-                            let palette = PPU::rpalette(&self.obpalette, cgbp);
-                            lcd.w_cgb(x as u8, self.ly, px, palette, true);
-                        } else {
-                            lcd.w_dmg(x as u8, self.ly, px, if flags.obp { self.obp1 } else { self.obp0 }, true);
-                        }
-                    }
+                    assert_eq!(
+                        lcd.frame[col * 8..][..8],
+                        [expected; 8],
+                        "column {} LCDC.0={} OBJ priority={} fifo={}",
+                        col,
+                        master_priority,
+                        obj_priority,
+                        fifo_renderer
+                    );
                 }
+                // BG is still drawn where there are no OBJs
+                assert_eq!(lcd.frame[64..72], [LCD::to_color_cgb(1, &bg_palette); 8]);
             }
-        } else if self.scanline_ticks > SCANLINE_TICKS {
-            // Go to new line when a scanline is done
-            self.scanline_ticks %= SCANLINE_TICKS;
-            interrupts |= self.set_ly(self.ly + 1);
         }
+    }
 
-        // Return frame to be drawn when the last scanline has been reached
-        let frame_ready = if self.ly >= LY_MAX {
-            interrupts |= self.set_ly(0);
-            true
-        } else {
-            false
-        };
+    #[test]
+    fn scx_fine_scroll() {
+        // Each BG map column with its own tile, with a different color pattern for each column
+        let color = |bg_x: usize| ((bg_x / 8) * 3 + (bg_x % 8) * (bg_x % 8) + bg_x % 8 / 3) as u8 % 4;
+        for fifo_renderer in [false, true] {
+            let mut ppu = PPU::new(false);
+            ppu.set_fifo_renderer_enabled(fifo_renderer);
+            for tile in 0..32 {
+                let row = (0..8)
+                    .map(|px| color(tile * 8 + px))
+                    .fold([0u8; 2], |[lo, hi], c| [lo << 1 | (c & 1), hi << 1 | (c >> 1)]);
+                for tile_row in ppu.vram[tile * 16..][..16].chunks_mut(2) {
+                    tile_row.copy_from_slice(&row);
+                }
+                ppu.vram[0x1800 + tile] = tile as u8;
+            }
+            ppu.w(0xFF47, 0xE4);
+            for scx in 0..=255u8 {
+                let mut lcd = LCD::new();
+                ppu.w(0xFF40, 0x00);
+                ppu.step(&mut lcd, 0); // Reset to line 0
+                ppu.w(0xFF43, scx);
+                ppu.w(0xFF40, 0x91); // Enable LCD and BG, with unsigned tile addressing
+                ppu.step(&mut lcd, 0); // OAM scan
+                ppu.step(&mut lcd, 300); // HBlank
+                let expected: Vec<u32> = (0..LCDW)
+                    .map(|x| LCD::to_color_dmg(color((x + scx as usize) % 256), 0xE4, 0))
+                    .collect();
+                assert!(lcd.frame[..LCDW] == expected, "scx={} fifo={}", scx, fifo_renderer);
+            }
+        }
+    }
 
-        (frame_ready, interrupts)
+    #[test]
+    fn dmg_palettes() {
+        for fifo_renderer in [false, true] {
+            let mut lcd = LCD::new();
+            let mut ppu = fifo_test_ppu(fifo_renderer);
+            // Objects with tile 4 (colors 3, 3, 3, 3, 0, 0, 0, 0) at X 0 with OBP0 and at X 16 with OBP1
+            ppu.oam[0..8].copy_from_slice(&[16, 8, 4, 0x00, 16, 24, 4, 0x10]);
+            ppu.w(0xFF48, 0xE7); // OBP0 and OBP1 (0x1B) with a non-zero color 0
+            ppu.w(0xFF40, 0x93); // LCD, unsigned tiles, objects and BG
+            step_line(&mut ppu, &mut lcd, &[0, 100, 300]);
+            // BGP changed between scanlines recolors the following lines only
+            ppu.w(0xFF47, 0x1B);
+            step_line(&mut ppu, &mut lcd, &[0, 100, 300]);
+            for (ly, bgp) in [(0, 0xE4), (1, 0x1B)] {
+                let row = &lcd.frame[ly * LCDW..][..24];
+                // Object color 0 is transparent regardless of the OBP bits 0-1
+                assert!(row[0..4].iter().all(|px| *px == LCD::to_color_dmg(3, 0xE7, 0)));
+                assert!(row[4..8].iter().all(|px| *px == LCD::to_color_dmg(0, bgp, 0)));
+                assert!(row[8..16].iter().all(|px| *px == LCD::to_color_dmg(1, bgp, 0)));
+                assert!(row[16..20].iter().all(|px| *px == LCD::to_color_dmg(3, 0x1B, 0)));
+                assert!(row[20..24].iter().all(|px| *px == LCD::to_color_dmg(2, bgp, 0)));
+            }
+        }
     }
 }
@@ -1,4 +1,5 @@
 use crate::cpu::CPU;
+use crate::state::state_fields;
 use crate::utils::{byte_register, Get, Set};
 use std::convert::{From, Into};
 
@@ -46,6 +47,18 @@ pub struct Registers {
     pub sp: u16,
     pub pc: u16,
 }
+state_fields!(Registers {
+    a,
+    b,
+    c,
+    d,
+    e,
+    f,
+    h,
+    l,
+    sp,
+    pc
+});
 
 impl Registers {
     pub fn new() -> Self {
@@ -65,6 +78,32 @@ impl Registers {
     }
 }
 
+// Public copy of the CPU state, independent from the internal representation
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegistersSnapshot {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub zero: bool,
+    pub subtract: bool,
+    pub half_carry: bool,
+    pub carry: bool,
+    pub ime: bool,
+    pub halt: bool,
+}
+
+impl RegistersSnapshot {
+    pub fn f(&self) -> u8 {
+        (self.zero as u8) << 7 | (self.subtract as u8) << 6 | (self.half_carry as u8) << 5 | (self.carry as u8) << 4
+    }
+}
+
 byte_register!(FlagsRegister {
     z, // Zero
     n, // Substraction
@@ -80,7 +119,7 @@ impl Get<R8, u8> for CPU {
             R8::D => self.reg.d,
             R8::E => self.reg.e,
             R8::H => self.reg.h,
-            R8::HL => self.mmu.r(u16::from_be_bytes([self.reg.h, self.reg.l])),
+            R8::HL => self.read_unsynced(u16::from_be_bytes([self.reg.h, self.reg.l])),
             R8::L => self.reg.l,
             R8::A => self.reg.a,
         }
@@ -95,7 +134,7 @@ impl Set<R8, u8> for CPU {
             R8::D => self.reg.d = val,
             R8::E => self.reg.e = val,
             R8::H => self.reg.h = val,
-            R8::HL => self.mmu.w(u16::from_be_bytes([self.reg.h, self.reg.l]), val),
+            R8::HL => self.write(u16::from_be_bytes([self.reg.h, self.reg.l]), val),
             R8::L => self.reg.l = val,
             R8::A => self.reg.a = val,
         }
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CPU;
+use crate::state::StateField;
+
+const NUM_REGIONS: usize = 5;
+
+struct Snapshot {
+    state: Vec<u8>,              // Serialized CPU state with the memory regions stripped out
+    sizes: [usize; NUM_REGIONS], // Size of each stripped memory region
+    delta: Vec<u8>,              // Memory regions XOR-ed with the previous snapshot and run-length encoded
+}
+
+/*
+ Ring buffer of CPU states. Only the most recent memory content is stored in full, while each snapshot
+ keeps the delta with respect to the previous one, which is mostly empty between close frames.
+*/
+pub struct RewindBuffer {
+    snapshots: VecDeque<Snapshot>,
+    capacity: usize,
+    memory: Vec<u8>, // Memory content of the most recent snapshot
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            memory: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        // Drop the oldest snapshots, the remaining deltas are still valid since they are applied from the most recent one
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots = VecDeque::new();
+        self.memory = Vec::new();
+    }
+
+    pub fn push(&mut self, cpu: &CPU) {
+        let mut cpu = cpu.clone();
+        // Strip the memory regions from the state and compute the delta with the previous snapshot
+        let mut sizes = [0; NUM_REGIONS];
+        let mut memory = Vec::with_capacity(self.memory.len());
+        for (size, region) in sizes.iter_mut().zip(cpu.mmu.memory_regions()) {
+            let region = std::mem::take(region);
+            *size = region.len();
+            memory.extend_from_slice(&region);
+        }
+        let mut delta = encode_delta(&memory, &self.memory);
+        delta.shrink_to_fit();
+        self.memory = memory;
+        // Serialize the rest of the state, so that no heap memory of the CPU is kept around
+        let mut state = Vec::new();
+        cpu.save(&mut state);
+        state.shrink_to_fit();
+        self.snapshots.push_back(Snapshot { state, sizes, delta });
+        self.trim();
+    }
+
+    pub fn pop(&mut self, current: &CPU) -> Option<CPU> {
+        let Snapshot { state, sizes, delta } = self.snapshots.pop_back()?;
+        // Load into a copy of the current CPU, which provides the ROM and the fields that are not part of the state
+        let mut cpu = current.clone();
+        cpu.mmu.apu.buffer = Vec::new();
+        cpu.load(&mut state.as_slice()).expect("Invalid rewind snapshot");
+        // Restore the memory regions, then move the stored memory content back to the previous snapshot
+        let mut offset = 0;
+        for (size, region) in sizes.iter().zip(cpu.mmu.memory_regions()) {
+            *region = self.memory[offset..offset + size].to_vec();
+            offset += size;
+        }
+        apply_delta(&delta, &mut self.memory);
+        Some(cpu)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        // Snapshots are made of heap buffers only, so this is all the memory held by the buffer
+        let snapshots_size: usize = self
+            .snapshots
+            .iter()
+            .map(|s| std::mem::size_of::<Snapshot>() + s.state.capacity() + s.delta.capacity())
+            .sum();
+        snapshots_size + self.memory.capacity()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let (mut val, mut shift) = (0, 0);
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+fn encode_delta(current: &[u8], previous: &[u8]) -> Vec<u8> {
+    // Encode as a sequence of (unchanged bytes count, changed bytes count, changed bytes XOR-ed)
+    let xor = |i: usize| current[i] ^ previous.get(i).unwrap_or(&0);
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < current.len() {
+        let start = i;
+        while i < current.len() && xor(i) == 0 {
+            i += 1;
+        }
+        if i == current.len() {
+            break;
+        }
+        let skip = i - start;
+        let start = i;
+        while i < current.len() && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut out, skip);
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+fn apply_delta(delta: &[u8], memory: &mut [u8]) {
+    let (mut pos, mut i) = (0, 0);
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let len = read_varint(delta, &mut pos);
+        for (dst, src) in memory[i..i + len].iter_mut().zip(&delta[pos..pos + len]) {
+            *dst ^= src;
+        }
+        i += len;
+        pos += len;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RewindBuffer;
+    use crate::cpu::CPU;
+    use crate::utils::{test_rom, TEST_PROGRAM_LOOP};
+
+    #[test]
+    fn delta_snapshots() {
+        let rom = test_rom(0x03, 0x02, &TEST_PROGRAM_LOOP);
+        let mut cpu = CPU::new(&rom, false, None);
+        let mut buffer = RewindBuffer::new(599);
+        for i in 0..600u16 {
+            cpu.reg.a = i as u8;
+            cpu.mmu.w(0xC000 + i, i as u8);
+            cpu.mmu.w(0x8000 + i, !i as u8);
+            buffer.push(&cpu);
+        }
+        assert_eq!(buffer.len(), 599);
+        for i in (1..600u16).rev() {
+            let cpu = buffer.pop(&cpu).unwrap();
+            assert_eq!(cpu.reg.a, i as u8);
+            assert_eq!(cpu.mmu.r(0xC000 + i), i as u8);
+            assert_eq!(cpu.mmu.r(0x8000 + i), !i as u8);
+            assert_eq!(cpu.mmu.r(0xC000 + i + 1), 0);
+            assert_eq!(cpu.mmu.r(0xC000 + i - 1), (i - 1) as u8);
+        }
+        assert!(buffer.pop(&cpu).is_none());
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "zip"];
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .map(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+        .unwrap_or(false)
+}
+
+// Read a ROM file, or a ROM from a zip archive. Also return the ROM path, used to name the save file.
+pub fn read_rom(path: &Path, entry: Option<&str>) -> Result<(Vec<u8>, PathBuf), String> {
+    if !has_extension(path, &["zip"]) {
+        let rom = fs::read(path).map_err(|err| format!("Could not read ROM {}: {}", path.display(), err))?;
+        return Ok((rom, path.to_path_buf()));
+    }
+    read_zip_rom(path, entry)
+}
+
+#[cfg(not(feature = "zip"))]
+fn read_zip_rom(path: &Path, _entry: Option<&str>) -> Result<(Vec<u8>, PathBuf), String> {
+    Err(format!("Could not read archive {}: zip support is disabled", path.display()))
+}
+
+#[cfg(feature = "zip")]
+fn read_zip_rom(path: &Path, entry: Option<&str>) -> Result<(Vec<u8>, PathBuf), String> {
+    use std::fs::File;
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    let file = File::open(path).map_err(|err| format!("Could not read archive {}: {}", path.display(), err))?;
+    let mut archive = ZipArchive::new(file).map_err(|err| format!("Invalid zip archive {}: {}", path.display(), err))?;
+    let roms: Vec<String> = (0..archive.len())
+        .filter_map(|i| archive.by_index(i).ok().map(|file| file.name().to_string()))
+        .filter(|name| has_extension(Path::new(name), &["gb", "gbc"]))
+        .collect();
+    let name = match (entry, roms.as_slice()) {
+        (Some(entry), _) => roms
+            .iter()
+            .find(|name| *name == entry || Path::new(name).file_name().is_some_and(|file_name| file_name == entry))
+            .ok_or_else(|| format!("ROM {} not found in {}, available ROMs: {}", entry, path.display(), roms.join(", ")))?,
+        (None, [name]) => name,
+        (None, []) => return Err(format!("No ROM found in {}", path.display())),
+        (None, _) => {
+            return Err(format!(
+                "Multiple ROMs found in {}, select one with --entry: {}",
+                path.display(),
+                roms.join(", ")
+            ))
+        }
+    };
+    let mut rom = Vec::new();
+    archive
+        .by_name(name)
+        .map_err(|err| err.to_string())
+        .and_then(|mut file| file.read_to_end(&mut rom).map_err(|err| err.to_string()))
+        .map_err(|err| format!("Could not extract {} from {}: {}", name, path.display(), err))?;
+    // Save files are stored next to the archive
+    let rom_path = path.with_file_name(Path::new(name).file_name().unwrap());
+    Ok((rom, rom_path))
+}
+
+// Expand the given paths into a playlist, directories are scanned (non-recursively) for ROM files
+pub fn list_roms(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut roms = Vec::new();
+    for path in paths.iter().map(Path::new) {
+        if path.is_dir() {
+            let entries = fs::read_dir(path).map_err(|err| format!("Could not read directory {}: {}", path.display(), err))?;
+            let mut dir_roms: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file() && has_extension(path, &ROM_EXTENSIONS))
+                .collect();
+            dir_roms.sort();
+            roms.extend(dir_roms);
+        } else {
+            roms.push(path.to_path_buf());
+        }
+    }
+    if roms.is_empty() {
+        return Err(format!("No ROM found in {}", paths.join(", ")));
+    }
+    Ok(roms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::list_roms;
+    use std::fs;
+
+    #[test]
+    fn playlist() {
+        let dir = std::env::temp_dir().join(format!("gbemu_playlist_{}", std::process::id()));
+        fs::create_dir_all(dir.join("subdir.gb")).unwrap();
+        for name in ["b.gbc", "a.GB", "c.zip", "notes.txt", "a.gb.sav", ".a.gb.sav"] {
+            fs::write(dir.join(name), []).unwrap();
+        }
+        let dir_str = dir.to_string_lossy().to_string();
+        let roms = list_roms(&[dir_str.clone(), String::from("other.gb")]).unwrap();
+        let names: Vec<String> = roms
+            .iter()
+            .map(|rom| rom.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, ["a.GB", "b.gbc", "c.zip", "other.gb"]);
+        // Directories without ROMs are rejected
+        fs::remove_dir_all(&dir).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        assert!(list_roms(&[dir_str]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use crate::cpu::INT_SERIAL;
+use crate::state::state_fields;
+
+const BIT_TICKS: u16 = 512; // 8192 Hz with the internal clock
+const BIT_TICKS_FAST: u16 = 16; // 262144 Hz with the CGB fast clock
+
+#[derive(Copy, Clone)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    cgb_mode: bool,
+    bits_left: u8,  // Bits still to be shifted in the current transfer
+    bit_ticks: u16, // Ticks elapsed since the last shifted bit
+}
+state_fields!(Serial {
+    sb,
+    sc,
+    bits_left,
+    bit_ticks
+});
+
+impl Serial {
+    pub fn new(cgb_mode: bool) -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            cgb_mode,
+            bits_left: 0,
+            bit_ticks: 0,
+        }
+    }
+
+    pub fn r(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | if self.cgb_mode { 0x7C } else { 0x7E }, // Unused bits read as 1
+            _ => panic!("Address {:#06x} not part of serial", addr),
+        }
+    }
+
+    pub fn w(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF01 => self.sb = val,
+            0xFF02 => {
+                // The fast clock bit is only available on CGB
+                self.sc = val & if self.cgb_mode { 0x83 } else { 0x81 };
+                self.bits_left = if val & 0x80 != 0 { 8 } else { 0 };
+                self.bit_ticks = 0;
+            }
+            _ => panic!("Address {:#06x} not part of serial", addr),
+        }
+    }
+
+    pub fn bit_period(&self, double_speed: bool) -> u16 {
+        // Ticks to shift one bit with the internal clock, the serial clock doubles in double speed mode
+        let ticks = if self.sc & 0x02 != 0 { BIT_TICKS_FAST } else { BIT_TICKS };
+        if double_speed {
+            ticks / 2
+        } else {
+            ticks
+        }
+    }
+
+    pub fn step(&mut self, elapsed_ticks: u16, double_speed: bool) -> u8 {
+        // Only transfers with the internal clock progress, as no other device is ever connected
+        if self.sc & 0x81 != 0x81 {
+            return 0;
+        }
+        let period = self.bit_period(double_speed);
+        self.bit_ticks += elapsed_ticks;
+        while self.bit_ticks >= period && self.bits_left > 0 {
+            // Without a link partner, 1s are shifted in
+            self.bit_ticks -= period;
+            self.sb = self.sb << 1 | 0x01;
+            self.bits_left -= 1;
+        }
+        if self.bits_left == 0 {
+            self.sc &= 0x7F;
+            INT_SERIAL.0
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Serial;
+    use crate::cpu::INT_SERIAL;
+
+    #[test]
+    fn transfer_duration() {
+        for (cgb_mode, sc, double_speed, expected_ticks) in [
+            (false, 0x81, false, 4096),
+            (false, 0x83, false, 4096), // No fast clock on DMG
+            (true, 0x81, false, 4096),
+            (true, 0x81, true, 2048),
+            (true, 0x83, false, 128),
+            (true, 0x83, true, 64),
+        ] {
+            let mut serial = Serial::new(cgb_mode);
+            serial.w(0xFF01, 0x00);
+            serial.w(0xFF02, sc);
+            let mut ticks = 0;
+            while serial.step(4, double_speed) == 0 {
+                ticks += 4;
+                assert!(ticks < 5000, "Transfer not completed");
+            }
+            ticks += 4;
+            assert_eq!(
+                ticks, expected_ticks,
+                "cgb_mode: {}, sc: {:#04x}, double_speed: {}",
+                cgb_mode, sc, double_speed
+            );
+            assert_eq!(serial.r(0xFF01), 0xFF);
+            assert_eq!(serial.r(0xFF02) & 0x80, 0x00);
+            assert_eq!(serial.step(4, double_speed), 0);
+        }
+    }
+
+    #[test]
+    fn external_clock() {
+        // Without a link partner, a transfer with the external clock never completes
+        let mut serial = Serial::new(false);
+        serial.w(0xFF02, 0x80);
+        for _ in 0..10_000 {
+            assert_eq!(serial.step(4, false) & INT_SERIAL.0, 0);
+        }
+        assert_eq!(serial.r(0xFF02), 0xFE);
+    }
+}
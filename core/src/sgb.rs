@@ -0,0 +1,172 @@
+/*
+ Super Game Boy commands source: https://gbdev.io/pandocs/SGB_Command_Summary.html
+*/
+
+use crate::state::state_fields;
+
+const PACKET_SIZE: usize = 16;
+
+#[derive(Clone, Default)]
+pub struct Sgb {
+    prev_joyp: u8,
+    packet_bit: Option<usize>, // Index of the next bit to be received, if a transfer is in progress
+    packet: [u8; PACKET_SIZE],
+    data: Vec<u8>, // Received data for the current command, which can span multiple packets
+
+    palettes: [[u16; 4]; 4], // SGB palettes as BGR555 colors
+    palettes_set: bool,      // Whether the game sent any palette, otherwise use the DMG palettes
+    players: u8,             // Number of joypads requested with MLT_REQ
+    player: u8,              // Currently selected joypad
+}
+state_fields!(Sgb {
+    prev_joyp,
+    packet_bit,
+    packet,
+    data,
+    palettes,
+    palettes_set,
+    players,
+    player
+});
+
+impl Sgb {
+    pub fn w_joyp(&mut self, val: u8) {
+        let joyp = val & 0x30;
+        let prev_joyp = std::mem::replace(&mut self.prev_joyp, joyp);
+        // Select the next joypad on the rising edge of P15
+        if self.players > 1 && prev_joyp & 0x20 == 0 && joyp & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        // Each bit is sent as a low pulse on P14 (0) or P15 (1), with both lines high in-between
+        match joyp {
+            0x00 => {
+                // Reset pulse, start receiving a new packet
+                self.packet_bit = Some(0);
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x10 | 0x20 if prev_joyp == 0x30 => {
+                if let Some(bit) = self.packet_bit {
+                    if bit < PACKET_SIZE * 8 {
+                        self.packet[bit / 8] |= ((joyp == 0x10) as u8) << (bit % 8);
+                        self.packet_bit = Some(bit + 1);
+                    } else {
+                        // Stop bit
+                        self.packet_bit = None;
+                        self.receive_packet();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn joypad_id(&self) -> Option<u8> {
+        if self.players > 1 {
+            Some(0x0F - self.player)
+        } else {
+            None
+        }
+    }
+
+    pub fn palette(&self, index: usize) -> Option<[u32; 4]> {
+        if self.palettes_set {
+            Some(self.palettes[index].map(Sgb::to_color))
+        } else {
+            None
+        }
+    }
+
+    fn to_color(color15: u16) -> u32 {
+        let (r5, g5, b5) = (color15 & 0x1F, (color15 >> 5) & 0x1F, (color15 >> 10) & 0x1F);
+        let (r8, g8, b8) = ((r5 << 3) | (r5 >> 2), (g5 << 3) | (g5 >> 2), (b5 << 3) | (b5 >> 2));
+        (r8 as u32) << 24 | (g8 as u32) << 16 | (b8 as u32) << 8 | 0xFF
+    }
+
+    fn receive_packet(&mut self) {
+        if self.data.is_empty() && self.packet[0] & 0x07 == 0 {
+            return; // Invalid packet length
+        }
+        self.data.extend_from_slice(&self.packet);
+        let num_packets = (self.data[0] & 0x07) as usize;
+        if self.data.len() >= num_packets * PACKET_SIZE {
+            let data = std::mem::take(&mut self.data);
+            self.run_command(data[0] >> 3, &data[1..]);
+        }
+    }
+
+    fn run_command(&mut self, command: u8, data: &[u8]) {
+        match command {
+            0x00 => self.set_palettes(0, 1, data), // PAL01
+            0x01 => self.set_palettes(2, 3, data), // PAL23
+            0x02 => self.set_palettes(0, 3, data), // PAL03
+            0x03 => self.set_palettes(1, 2, data), // PAL12
+            0x11 => {
+                // MLT_REQ
+                self.players = match data[0] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            _ => (), // Not supported
+        }
+    }
+
+    fn set_palettes(&mut self, pa: usize, pb: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+        // Color 0 is shared by all palettes
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[pa][i] = color(i);
+            self.palettes[pb][i] = color(i + 3);
+        }
+        self.palettes_set = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sgb;
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; 16]) {
+        sgb.w_joyp(0x00);
+        sgb.w_joyp(0x30);
+        for byte in packet {
+            for bit in 0..8 {
+                sgb.w_joyp(if byte & (1 << bit) != 0 { 0x10 } else { 0x20 });
+                sgb.w_joyp(0x30);
+            }
+        }
+        sgb.w_joyp(0x20);
+        sgb.w_joyp(0x30);
+    }
+
+    #[test]
+    fn pal_command() {
+        let mut sgb = Sgb::default();
+        assert_eq!(sgb.palette(0), None);
+        // PAL01 with one packet: color 0 = white, palette 0 = red/green/blue, palette 1 = black
+        let mut packet = [0; 16];
+        packet[0] = 0x01; // Command (PAL01) and length
+        packet[1..3].copy_from_slice(&[0xFF, 0x7F]);
+        packet[3..9].copy_from_slice(&[0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C]);
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.palette(0), Some([0xFFFFFFFF, 0xFF0000FF, 0x00FF00FF, 0x0000FFFF]));
+        assert_eq!(sgb.palette(1), Some([0xFFFFFFFF, 0x000000FF, 0x000000FF, 0x000000FF]));
+        assert_eq!(sgb.palette(2).unwrap()[0], 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn mlt_req_command() {
+        let mut sgb = Sgb::default();
+        assert_eq!(sgb.joypad_id(), None);
+        send_packet(&mut sgb, &[0x11 << 3 | 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(sgb.joypad_id(), Some(0x0F));
+        sgb.w_joyp(0x10);
+        sgb.w_joyp(0x30);
+        assert_eq!(sgb.joypad_id(), Some(0x0E));
+    }
+}
@@ -1,5 +1,8 @@
 use crate::lcd::{LCD, LCDH, LCDW, LCD_BUFFER_SIZE};
 
+// Names of the shaders, in the order used by `LCD::draw_frame`
+pub const SHADER_NAMES: [&str; 6] = ["Normal", "LCD", "CRT", "Drop shadow", "Anaglyph 3D", "Side-by-side 3D"];
+
 pub fn normal(frame: &[u32; LCD_BUFFER_SIZE], out: &mut [u8], scale: usize) {
     for (frame_row, out_block) in frame.chunks_exact(LCDW).zip(out.chunks_exact_mut(LCDW * scale * scale * 4)) {
         // Horizontal scaling: copy src row on first out row
@@ -49,6 +52,25 @@ pub fn drop_shadow(
     }
 }
 
+fn stereo_px(
+    background: &[u32; LCD_BUFFER_SIZE],
+    foreground: &[u32; LCD_BUFFER_SIZE],
+    x: usize,
+    y: usize,
+    offset_background: usize,
+    offset_foreground: usize,
+) -> u32 {
+    // Pixel seen from a displaced point of view, with a different displacement for foreground and background
+    let (x_bg, x_fg) = (x + offset_background, x + offset_foreground);
+    if x_fg < LCDW && foreground[LCD::to_idx(x_fg, y, 1, 0, 0)] != 0 {
+        foreground[LCD::to_idx(x_fg, y, 1, 0, 0)]
+    } else if x_bg < LCDW {
+        background[LCD::to_idx(x_bg, y, 1, 0, 0)]
+    } else {
+        0
+    }
+}
+
 pub fn anaglyph_3d(
     background: &[u32; LCD_BUFFER_SIZE],
     foreground: &[u32; LCD_BUFFER_SIZE],
@@ -59,24 +81,9 @@ pub fn anaglyph_3d(
 ) {
     for xr in 0..LCDW {
         for y in 0..LCDH {
-            // Retrieve right pixel from original frame
-            let idxr: usize = LCD::to_idx(xr, y, 1, 0, 0);
-            let pxr = if foreground[idxr] != 0 {
-                foreground[idxr]
-            } else {
-                background[idxr]
-            };
-            // Retrieve left pixel with a different displacement for foreground and background
-            let (xl_bg, xl_fg) = (xr + offset_background, xr + offset_foreground);
-            let idxl_fg = LCD::to_idx(xl_fg, y, 1, 0, 0);
-            let pxl = if xl_fg < LCDW && foreground[idxl_fg] != 0 {
-                foreground[idxl_fg]
-            } else if xl_bg < LCDW {
-                let idxl_bg = LCD::to_idx(xl_bg, y, 1, 0, 0);
-                background[idxl_bg]
-            } else {
-                0
-            };
+            // Retrieve right pixel from original frame, and the displaced left pixel
+            let pxr = stereo_px(background, foreground, xr, y, 0, 0);
+            let pxl = stereo_px(background, foreground, xr, y, offset_background, offset_foreground);
             // Mix channels, GB from right pixel and R from left pixel
             let rgba: [u8; 4] = ((pxr & 0x00FFFFFF) | (pxl & 0xFF0000FF)).to_be_bytes();
             // Source: https://www.3dtv.at/knowhow/anaglyphcomparison_en.aspx
@@ -90,6 +97,30 @@ pub fn anaglyph_3d(
     }
 }
 
+pub fn side_by_side_3d(
+    background: &[u32; LCD_BUFFER_SIZE],
+    foreground: &[u32; LCD_BUFFER_SIZE],
+    out: &mut [u8],
+    scale: usize,
+    offset_background: usize,
+    offset_foreground: usize,
+) {
+    // Left and right views squeezed horizontally into each half of the output, for stereo viewers
+    let half_width = LCDW * scale / 2;
+    for out_y in 0..LCDH * scale {
+        for out_x in 0..LCDW * scale {
+            let (x, y) = ((out_x % half_width) * LCDW / half_width, out_y / scale);
+            let px = if out_x < half_width {
+                stereo_px(background, foreground, x, y, offset_background, offset_foreground)
+            } else {
+                stereo_px(background, foreground, x, y, 0, 0)
+            };
+            let idx = 4 * (out_y * LCDW * scale + out_x);
+            out[idx..idx + 4].copy_from_slice(&px.to_be_bytes());
+        }
+    }
+}
+
 pub fn lcd(frame: &[u32; LCD_BUFFER_SIZE], out: &mut [u8], scale: usize, dmg_bg_palette: Option<u32>) {
     for x in 0..LCDW {
         for y in 0..LCDH {
@@ -148,3 +179,31 @@ pub fn crt(frame: &[u32; LCD_BUFFER_SIZE], out: &mut [u8], scale: usize) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{anaglyph_3d, side_by_side_3d};
+    use crate::lcd::{LCDW, LCD_BUFFER_SIZE};
+
+    #[test]
+    fn stereo_3d() {
+        let (mut background, mut foreground) = (vec![0u32; LCD_BUFFER_SIZE], vec![0u32; LCD_BUFFER_SIZE]);
+        background[0..8].copy_from_slice(&[0x112233FF, 0, 0, 0, 0x010203FF, 0, 0x778899FF, 0]);
+        foreground[6] = 0xAABBCCFF;
+        let (background, foreground) = (background.as_slice().try_into().unwrap(), foreground.as_slice().try_into().unwrap());
+        let px = |out: &[u8], x: usize| u32::from_be_bytes(out[x * 4..][..4].try_into().unwrap());
+        let mut out = vec![0u8; LCD_BUFFER_SIZE * 4 * 4];
+        // Red from the left view, displaced by 6 in the foreground and by 2 in the background
+        anaglyph_3d(background, foreground, &mut out, 1, 2, 6);
+        assert_eq!(px(&out, 0), 0xAA2233FF);
+        assert_eq!(px(&out, 4), 0x770203FF);
+        anaglyph_3d(background, foreground, &mut out, 1, 0, 0);
+        assert_eq!(px(&out, 0), 0x112233FF);
+        // Left view in the left half, right view in the right half
+        side_by_side_3d(background, foreground, &mut out, 2, 2, 6);
+        assert_eq!(px(&out, 0), 0xAABBCCFF);
+        assert_eq!(px(&out, 4), 0x778899FF);
+        assert_eq!(px(&out, LCDW), 0x112233FF);
+        assert_eq!(px(&out, LCDW + 4), 0x010203FF);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+
+// Field of the emulator state that can be written to and read from a serialized state, in little endian
+pub(crate) trait StateField {
+    fn save(&self, out: &mut Vec<u8>);
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String>;
+}
+
+pub(crate) fn take<const N: usize>(data: &mut &[u8]) -> Result<[u8; N], String> {
+    if data.len() < N {
+        return Err(String::from("State too short"));
+    }
+    let (bytes, rest) = data.split_at(N);
+    *data = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+macro_rules! state_field_number {
+    ($($type:ty),*) => {
+        $(impl StateField for $type {
+            fn save(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+                *self = <$type>::from_le_bytes(take(data)?);
+                Ok(())
+            }
+        })*
+    };
+}
+state_field_number!(u8, u16, u32, u64, i16, f32);
+
+impl StateField for usize {
+    // Always 64 bits, so that states are portable between native and wasm32 builds
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self as u64).save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut val = 0u64;
+        val.load(data)?;
+        *self = usize::try_from(val).map_err(|_| String::from("State value out of range"))?;
+        Ok(())
+    }
+}
+
+impl StateField for bool {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        *self = take::<1>(data)?[0] != 0;
+        Ok(())
+    }
+}
+
+impl<T: StateField, const N: usize> StateField for [T; N] {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.iter().for_each(|item| item.save(out));
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.iter_mut().try_for_each(|item| item.load(data))
+    }
+}
+
+impl<A: StateField, B: StateField> StateField for (A, B) {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.0.save(out);
+        self.1.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.0.load(data)?;
+        self.1.load(data)
+    }
+}
+
+impl<T: StateField + Default> StateField for Option<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.is_some().save(out);
+        if let Some(val) = self {
+            val.save(out);
+        }
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut is_some = false;
+        is_some.load(data)?;
+        *self = if is_some {
+            // Load into the current value if present, to keep the fields that are not part of the state
+            let mut val = self.take().unwrap_or_default();
+            val.load(data)?;
+            Some(val)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+// Sequences are prefixed by their length
+fn load_len(data: &mut &[u8]) -> Result<usize, String> {
+    let len = u32::from_le_bytes(take(data)?) as usize;
+    if len > data.len() {
+        return Err(String::from("State too short"));
+    }
+    Ok(len)
+}
+
+impl<T: StateField + Default> StateField for Vec<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).save(out);
+        self.iter().for_each(|item| item.save(out));
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let len = load_len(data)?;
+        self.clear();
+        for _ in 0..len {
+            let mut item = T::default();
+            item.load(data)?;
+            self.push(item);
+        }
+        Ok(())
+    }
+}
+
+impl<T: StateField + Default> StateField for VecDeque<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).save(out);
+        self.iter().for_each(|item| item.save(out));
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let len = load_len(data)?;
+        self.clear();
+        for _ in 0..len {
+            let mut item = T::default();
+            item.load(data)?;
+            self.push_back(item);
+        }
+        Ok(())
+    }
+}
+
+macro_rules! state_fields {
+    ($name:ident { $($field:ident),* }) => {
+        impl crate::state::StateField for $name {
+            fn save(&self, out: &mut Vec<u8>) {
+                $(crate::state::StateField::save(&self.$field, out);)*
+            }
+
+            fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+                $(crate::state::StateField::load(&mut self.$field, data)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+pub(crate) use state_fields;
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::StateField;
+
+    #[test]
+    fn round_trip() {
+        let value = (
+            vec![1u8, 2, 3],
+            (Some([0x1234u16, 0x5678]), (VecDeque::from([true, false]), (None::<u8>, -2i16))),
+        );
+        let mut data = Vec::new();
+        value.save(&mut data);
+        let mut loaded = (Vec::new(), (None, (VecDeque::new(), (Some(1), 0))));
+        let mut slice = data.as_slice();
+        loaded.load(&mut slice).unwrap();
+        assert!(slice.is_empty());
+        assert_eq!(loaded, value);
+
+        // Truncated states are rejected
+        let mut slice = &data[..data.len() - 1];
+        assert!(loaded.load(&mut slice).is_err());
+        let mut slice = &[0xFF, 0xFF, 0xFF, 0xFF, 0x00][..];
+        assert!(Vec::<u8>::default().load(&mut slice).is_err());
+    }
+}
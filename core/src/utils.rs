@@ -1,3 +1,7 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
 pub trait Get<R, T> {
     fn r(&self, r: R) -> T;
 }
@@ -39,6 +43,19 @@ macro_rules! byte_register {
             }
         }
 
+        impl crate::state::StateField for $name {
+            fn save(&self, out: &mut Vec<u8>) {
+                crate::state::StateField::save(&u8::from(self), out);
+            }
+
+            fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+                let mut value = 0u8;
+                crate::state::StateField::load(&mut value, data)?;
+                self.w(value);
+                Ok(())
+            }
+        }
+
     };
 }
 
@@ -47,3 +64,63 @@ pub fn pack_bits(bools: &[bool]) -> u8 {
 }
 
 pub(crate) use byte_register;
+
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    // Write to a temporary file next to the destination and rename it, so that a crash never leaves a partially written file
+    let tmp_path = path.with_file_name(format!("{}.tmp", path.file_name().unwrap_or_default().to_string_lossy()));
+    let result = File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    match result {
+        Ok(()) => fs::rename(&tmp_path, path),
+        Err(err) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn test_rom(cart_type: u8, ram_size: u8, program: &[u8]) -> Vec<u8> {
+    // Build a 32KB ROM with a valid header, that jumps to `program` at 0x0150
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]); // NOP; JP 0x0150
+    rom[0x0104..0x0134].copy_from_slice(&crate::header::NINTENDO_LOGO);
+    rom[0x0134..0x0138].copy_from_slice(b"TEST");
+    rom[0x0147] = cart_type;
+    rom[0x0149] = ram_size;
+    rom[0x014D] = crate::mbc::header_checksum(&rom);
+    rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+    rom
+}
+
+#[cfg(test)]
+pub const TEST_PROGRAM_LOOP: [u8; 6] = [
+    0x3E, 0x91, // LD A, 0x91
+    0xE0, 0x40, // LDH [LCDC], A
+    0x18, 0xFE, // JR -2
+];
+
+#[cfg(test)]
+mod test {
+    use super::write_atomic;
+    use std::fs;
+
+    #[test]
+    fn atomic_write() {
+        let dir = std::env::temp_dir().join(format!("gbemu_atomic_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".game.gb.sav");
+        write_atomic(&path, &[0x01; 16]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0x01; 16]);
+        // The previous content is fully replaced, and no temporary file is left behind
+        write_atomic(&path, &[0x02; 8]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0x02; 8]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        // On failure the destination is left untouched
+        assert!(write_atomic(&dir.join("missing").join("file.sav"), &[0x03]).is_err());
+        assert_eq!(fs::read(&path).unwrap(), [0x02; 8]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const GB_FPS: f64 = 59.7275; // Refresh rate of the Gameboy LCD, 4194304 Hz / 70224 ticks per frame
+
+// Paces the drawn frames by sleeping, independently of the refresh rate of the display
+pub struct FrameLimiter {
+    interval: Duration,
+    deadline: Option<Instant>, // When the next frame should be presented
+}
+
+impl FrameLimiter {
+    pub fn new(fps: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / fps),
+            deadline: None,
+        }
+    }
+
+    pub fn wait(&mut self) {
+        let delay = self.delay(Instant::now());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    fn delay(&mut self, now: Instant) -> Duration {
+        // Deadlines follow each other to avoid drifting, but restart from now when late by more than a frame (e.g. after a
+        // stall), instead of rushing the next frames to catch up
+        let deadline = match self.deadline {
+            Some(deadline) if now <= deadline + self.interval => deadline,
+            _ => now,
+        };
+        self.deadline = Some(deadline + self.interval);
+        deadline.saturating_duration_since(now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameLimiter, GB_FPS};
+    use std::time::{Duration, Instant};
+
+    fn frame_times(limiter: &mut FrameLimiter, work: &[Duration]) -> Vec<Instant> {
+        // Simulate frames taking the given time to emulate, returning when each one is presented
+        let mut now = Instant::now();
+        work.iter()
+            .map(|work| {
+                now += *work;
+                now += limiter.delay(now);
+                now
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_interval() {
+        let mut limiter = FrameLimiter::new(GB_FPS);
+        let interval = Duration::from_secs_f64(1.0 / GB_FPS);
+        let times = frame_times(&mut limiter, &[Duration::from_millis(5); 120]);
+        for pair in times[1..].windows(2) {
+            let elapsed = pair[1] - pair[0];
+            assert!(elapsed.abs_diff(interval) < Duration::from_micros(1), "{:?}", elapsed);
+        }
+        // Uneven frame times are absorbed, keeping the average rate
+        let work: Vec<Duration> = (0..120).map(|i| Duration::from_millis(i % 12)).collect();
+        let times = frame_times(&mut limiter, &work);
+        let average = (times[119] - times[0]) / 119;
+        assert!(average.abs_diff(interval) < Duration::from_micros(1), "{:?}", average);
+    }
+
+    #[test]
+    fn stall() {
+        // After a stall the next frames are paced from it, not rushed to catch up
+        let mut limiter = FrameLimiter::new(GB_FPS);
+        let interval = Duration::from_secs_f64(1.0 / GB_FPS);
+        let work = [
+            Duration::ZERO,
+            Duration::ZERO,
+            Duration::from_millis(200),
+            Duration::ZERO,
+            Duration::ZERO,
+        ];
+        let times = frame_times(&mut limiter, &work);
+        assert_eq!(times[3] - times[2], interval);
+        assert_eq!(times[4] - times[3], interval);
+    }
+}
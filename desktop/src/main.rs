@@ -1,51 +1,296 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use gb_core::debug;
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::PixelFormatEnum;
-use std::{fs, path::Path};
+use std::cell::RefCell;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use gb_core::{apu, lcd, GBEmu, Joypad};
+use gb_core::{apu, lcd, patch, rom, utils, ColorFilter, GBEmu, Joypad, Layer, Turbo};
+
+mod limiter;
+mod overlay;
+mod resampler;
+
+use limiter::{FrameLimiter, GB_FPS};
+use overlay::{Hud, Overlay};
+use resampler::Resampler;
 
 const AUDIO_SAMPLE_SIZE: usize = 2048;
+const AUDIO_QUEUE_MAX: usize = AUDIO_SAMPLE_SIZE * 16;
+const VOLUME_STEP: f32 = 0.1;
+const FADE_OUT_SAMPLES: u32 = apu::AUDIO_FREQUENCY / 100; // 10ms fade-out of the audio when quitting, to avoid a click
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum FrameLimit {
+    /// Sleep between frames to run at the Gameboy refresh rate
+    Timer,
+    /// Wait for the display refresh, too fast on displays above 60 Hz
+    Vsync,
+    /// Run uncapped
+    Off,
+}
 
 #[derive(Parser)]
 #[command(about = "A simple Gameboy emulator written in Rust")]
 struct Args {
-    /// ROM path (.gb/.gbc)
-    #[arg(short, long)]
-    file: String,
+    /// ROM paths (.gb/.gbc/.zip) or directories containing ROMs, switch between them with PageUp/PageDown
+    #[arg(short, long, num_args = 1.., required = true)]
+    file: Vec<String>,
+
+    /// ROM to load from a zip archive containing multiple ROMs
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// Boot ROM to run instead of the embedded one (256 bytes for DMG, 2304 bytes for CGB)
+    #[arg(long)]
+    boot_rom: Option<String>,
+
+    /// IPS patch to apply to the first ROM when loading it
+    #[arg(long)]
+    patch: Option<String>,
 
     /// Scale of the diplay
     #[arg(short, long, default_value_t = 4)]
     scale: u32,
 
+    /// Displacement in pixels of the background and foreground in the 3D shaders
+    #[arg(long, num_args = 2, value_names = ["BACKGROUND", "FOREGROUND"], default_values_t = [2, 6])]
+    depth_3d: Vec<usize>,
+
     /// Force games to run in DMG (Non-Color GB)
     #[arg(long, action)]
     force_dmg: bool,
 
-    /// Display loaded tiles
+    /// Display loaded tiles, press B on the tile window to switch between the VRAM banks
     #[arg(long, action)]
     tiles: bool,
 
-    /// Print OP codes and registers
+    /// Separate the tiles with a grid and highlight the ones in use by the tilemaps and OBJs in the tile window
+    #[arg(long, action, requires = "tiles")]
+    tile_grid: bool,
+
+    /// Run the boot ROM at uncapped speed
+    #[arg(long, action)]
+    fast_boot: bool,
+
+    /// Tick the hardware after each memory access instead of after each instruction (slower)
+    #[arg(long, action)]
+    cycle_accurate: bool,
+
+    /// Render the pixels dot by dot with the PPU pixel FIFO, to show mid-scanline effects (slower)
     #[arg(long, action)]
-    debug: bool,
+    fifo_renderer: bool,
+
+    /// Speed of the emulated CPU and timer relative to the normal one, to overclock or underclock the console
+    #[arg(long, default_value_t = 1.0, value_parser = parse_clock_multiplier)]
+    clock_multiplier: f32,
+
+    /// How the frame rate is limited
+    #[arg(long, value_enum, default_value_t = FrameLimit::Timer)]
+    frame_limit: FrameLimit,
+
+    /// Mute the audio while running faster than normal speed, instead of playing it at a higher pitch
+    #[arg(long, action)]
+    mute_fast_forward: bool,
+
+    /// Auto-fire rate in presses per second of the turbo buttons, press T then a button to toggle its turbo
+    #[arg(long, default_value_t = 10)]
+    turbo_rate: u32,
+
+    /// Print OP codes and registers, repeat to also print the interrupt, PPU and timer registers
+    #[arg(long, action = clap::ArgAction::Count)]
+    debug: u8,
+
+    /// Write the executed instructions to a file in the Gameboy Doctor log format
+    #[arg(long)]
+    trace_doctor: Option<String>,
+
+    /// Print the tile at the given VRAM address (hex, with an optional bank prefix as in 1:8010) when exiting
+    #[arg(long, value_parser = parse_tile_addr)]
+    dump_tile: Option<(u16, bool)>,
+
+    /// Record the inputs from power-on to a .gbmovie file, written when exiting
+    #[arg(long, conflicts_with = "play_movie")]
+    record_movie: Option<String>,
+
+    /// Replay the inputs of a .gbmovie file recorded on the same ROM
+    #[arg(long)]
+    play_movie: Option<String>,
+
+    /// Run the given number of frames without video and audio output, and report the emulation speed
+    #[arg(long)]
+    bench: Option<u64>,
+}
+
+fn parse_clock_multiplier(arg: &str) -> Result<f32, String> {
+    match arg.parse::<f32>() {
+        Ok(multiplier) if multiplier > 0.0 => Ok(multiplier),
+        Ok(_) => Err(String::from("the multiplier must be positive")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_tile_addr(arg: &str) -> Result<(u16, bool), String> {
+    let (bank, addr) = arg.split_once(':').unwrap_or(("0", arg));
+    let addr = u16::from_str_radix(addr.trim_start_matches("0x"), 16).map_err(|err| err.to_string())?;
+    if !(0x8000..=0x97FF).contains(&addr) {
+        return Err(String::from("address must be in the tile data range 8000-97FF"));
+    }
+    match bank {
+        "0" => Ok((addr, false)),
+        "1" => Ok((addr, true)),
+        _ => Err(String::from("bank must be 0 or 1")),
+    }
+}
+
+fn print_tile(tile: &[[u8; 8]; 8]) {
+    for row in tile {
+        // Two characters per pixel to keep the tile square
+        let line: String = row.iter().map(|px| ["  ", "░░", "▒▒", "██"][*px as usize]).collect();
+        println!("{}", line);
+    }
+}
+
+fn audio_muted(speed: u64, mute_fast_forward: bool) -> bool {
+    mute_fast_forward && speed > 1
+}
+
+fn game_file(rom_path: &Path, extension: &str) -> PathBuf {
+    // Hidden file stored next to the ROM
+    rom_path.with_file_name(format!(".{}.{}", rom_path.file_name().unwrap().to_string_lossy(), extension))
+}
+
+fn write_save(emulator: &mut GBEmu, rom_path: &Path) {
+    // Only written if the cartridge RAM changed since the last save
+    if emulator.ram_dirty() {
+        if let Err(err) = utils::write_atomic(&game_file(rom_path, "sav"), emulator.save()) {
+            println!("Could not write save file: {}", err);
+        }
+    }
+}
+
+fn read_save(rom_path: &Path) -> Vec<u8> {
+    // An empty save is loaded if the file is missing
+    fs::read(game_file(rom_path, "sav")).unwrap_or_else(|_| {
+        println!("Could not find save file");
+        Vec::new()
+    })
+}
+
+fn load_game_files(emulator: &mut GBEmu, rom_path: &Path) {
+    if let Err(err) = emulator.load_save(&read_save(rom_path)) {
+        println!("Could not load save file: {}", err);
+    }
+    load_volume(emulator, rom_path);
+}
+
+fn load_volume(emulator: &mut GBEmu, rom_path: &Path) {
+    // Load the volume for this game if previously changed
+    if let Some(volume) = fs::read_to_string(game_file(rom_path, "volume"))
+        .ok()
+        .and_then(|volume| volume.trim().parse().ok())
+    {
+        emulator.set_volume(volume);
+    }
+}
+
+fn bench(emulator: &mut GBEmu, frames: u64) {
+    emulator.set_rewind_enabled(false);
+    emulator.set_audio_enabled(false);
+    emulator.set_joypad(&Joypad::default());
+    let start = Instant::now();
+    for _ in 0..frames {
+        emulator.step_frame();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("Frames: {}, time: {:.3}s", frames, elapsed);
+    println!(
+        "Speed: {:.1} FPS, {:.3} MHz",
+        frames as f64 / elapsed,
+        emulator.tick_count() as f64 / elapsed / 1e6
+    );
 }
 
 fn main() {
     let args = Args::parse();
-    debug::set_enabled(args.debug);
-    let filepath = Path::new(&args.file);
-    let rom = fs::read(filepath).expect("ROM not found");
-    let mut emulator = GBEmu::new(&rom, args.force_dmg);
+    debug::set_level(args.debug);
+    let playlist = match rom::list_roms(&args.file) {
+        Ok(playlist) => playlist,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    let mut playlist_idx = 0;
+    let (mut rom, mut filepath) = match rom::read_rom(&playlist[playlist_idx], args.entry.as_deref()) {
+        Ok(rom) => rom,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    if let Some(patch_path) = &args.patch {
+        match fs::read(patch_path)
+            .map_err(|err| err.to_string())
+            .and_then(|patch| patch::apply_ips(&rom, &patch))
+        {
+            Ok(patched) => rom = patched,
+            Err(err) => {
+                println!("Could not apply patch: {}", err);
+                return;
+            }
+        }
+    }
+    let mut emulator = match GBEmu::new(&rom, args.force_dmg) {
+        Ok(emulator) => emulator,
+        Err(err) => {
+            println!("Could not load ROM: {}", err);
+            return;
+        }
+    };
+    emulator.set_cycle_accurate(args.cycle_accurate);
+    emulator.set_fifo_renderer_enabled(args.fifo_renderer);
+    emulator.set_clock_multiplier(args.clock_multiplier);
+    emulator.set_depth_3d(args.depth_3d[0], args.depth_3d[1]);
+    if let Some(boot_rom_path) = &args.boot_rom {
+        match fs::read(boot_rom_path)
+            .map_err(|err| err.to_string())
+            .and_then(|boot_rom| emulator.set_boot_rom(Some(&boot_rom)).map_err(|err| err.to_string()))
+        {
+            Ok(()) => (),
+            Err(err) => println!("Could not load boot ROM: {}, using the embedded one", err),
+        }
+    }
+    if let Some(trace_path) = &args.trace_doctor {
+        match File::create(trace_path) {
+            Ok(file) => emulator.set_trace(Some(Rc::new(RefCell::new(BufWriter::new(file))))),
+            Err(err) => println!("Could not create trace file: {}", err),
+        }
+    }
+
+    if let Some(frames) = args.bench {
+        bench(&mut emulator, frames);
+        return;
+    }
+
+    // Load savefile and settings if present
+    load_game_files(&mut emulator, &filepath);
 
-    // Load savefile if present
-    let savepath = filepath.with_file_name(format!(".{}.sav", filepath.file_name().unwrap().to_string_lossy()));
-    match fs::read(savepath.clone()) {
-        Ok(savefile) => emulator.load_save(&savefile),
-        Err(_) => println!("Could not find save file"),
+    // Start recording or playing a movie, both restart the emulation from power-on
+    if let Some(movie_path) = &args.record_movie {
+        emulator.start_recording(Path::new(movie_path));
+    } else if let Some(movie_path) = &args.play_movie {
+        if let Err(err) = emulator.play_movie(Path::new(movie_path)) {
+            println!("{}", err);
+        }
     }
 
     // Initialize SDL
@@ -56,17 +301,19 @@ fn main() {
     let mut event_pump = sdl_context.event_pump().unwrap();
 
     // Setup output window
-    let mut canvas = video_subsystem
+    let vsync = args.frame_limit == FrameLimit::Vsync;
+    let mut canvas_builder = video_subsystem
         .window(emulator.rom_title().as_str(), lcdw, lcdh)
         .position_centered()
         .opengl()
         .build()
         .unwrap()
         .into_canvas()
-        .accelerated()
-        .present_vsync()
-        .build()
-        .unwrap();
+        .accelerated();
+    if vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build().unwrap();
 
     let texture_creator = canvas.texture_creator();
     let mut texture = texture_creator
@@ -74,17 +321,18 @@ fn main() {
         .unwrap();
 
     // Setup tilemap window
-    let mut tile_canvas = video_subsystem
+    let mut tile_canvas_builder = video_subsystem
         .window("TILES", debug::TILEW as u32, debug::TILEH as u32)
         .opengl()
         .hidden()
         .build()
         .unwrap()
         .into_canvas()
-        .accelerated()
-        .present_vsync()
-        .build()
-        .unwrap();
+        .accelerated();
+    if vsync {
+        tile_canvas_builder = tile_canvas_builder.present_vsync();
+    }
+    let mut tile_canvas = tile_canvas_builder.build().unwrap();
 
     let tile_texture_creator = tile_canvas.texture_creator();
     let mut tile_texture = tile_texture_creator
@@ -106,14 +354,25 @@ fn main() {
 
     // Start audio playback
     audio_device.resume();
+    let mut resampler = Resampler::new();
 
     // Start emulation loop
     let mut running = true;
+    let mut quitting = false;
     let mut rewinding = false;
     let mut joypad = Joypad::default();
+    let mut turbo = Turbo::new(args.turbo_rate);
+    let mut turbo_armed = false;
     let mut speed: u64 = 1;
     let mut frame_count: u64 = 0;
+    let mut overlay = Overlay::default();
+    let mut hud = Hud::default();
+    let mut tile_bank = debug::TileBank::Both;
+    let mut limiter = (args.frame_limit == FrameLimit::Timer).then(|| FrameLimiter::new(GB_FPS));
     while running {
+        // Skip all frames while the boot ROM is running, if fast boot is enabled
+        let booting = args.fast_boot && !emulator.boot_completed();
+
         // Run emulator step, i.e. execute next opcode
         let frame_buffer = if rewinding && emulator.can_rewind() {
             // Rewind to last state
@@ -128,37 +387,139 @@ fn main() {
             frame_count += 1;
 
             // Skip frames based on speed
-            if frame_count % speed == 0 {
+            let drawn = !booting && frame_count % speed == 0;
+            if hud.enabled {
+                hud.frame(drawn, audio_device.size() as f64 / AUDIO_QUEUE_MAX as f64);
+            }
+            if drawn {
                 // Write frame to buffer
                 texture
-                    .with_lock(None, |buffer: &mut [u8], _| frame_buffer.draw_frame(buffer, args.scale as usize))
+                    .with_lock(None, |buffer: &mut [u8], _| {
+                        frame_buffer.draw_frame(buffer, args.scale as usize);
+                        if overlay.is_visible() {
+                            overlay.draw(buffer, lcdw as usize, args.scale as usize);
+                        }
+                        if hud.enabled {
+                            hud.draw(buffer, lcdw as usize, lcdh as usize, args.scale as usize);
+                        }
+                    })
                     .unwrap();
                 canvas.copy(&texture, None, None).unwrap();
+                if let Some(limiter) = &mut limiter {
+                    limiter.wait();
+                }
                 canvas.present();
 
                 // Write tiles
                 if args.tiles {
                     tile_texture
-                        .with_lock(None, |buffer: &mut [u8], _| emulator.draw_tilemap(buffer))
+                        .with_lock(None, |buffer: &mut [u8], _| {
+                            emulator.draw_tilemap(buffer, args.tile_grid, tile_bank)
+                        })
                         .unwrap();
                     tile_canvas.copy(&tile_texture, None, None).unwrap();
                     tile_canvas.present();
                 }
 
                 // Handle key events
+                let settings = (emulator.current_palette(), emulator.current_shader(), speed);
+                let pressed = joypad.bits();
                 #[cfg_attr(rustfmt, rustfmt_skip)]
                 for event in event_pump.poll_iter() {
                     match event {
                         // Shortcuts
-                        Event::Quit { .. } | Event::KeyUp { keycode: Some(Keycode::Escape), .. } => running = false,
+                        // SDL also sends a quit event on SIGINT and SIGTERM
+                        Event::Quit { .. } | Event::KeyUp { keycode: Some(Keycode::Escape), .. } if !quitting => {
+                            write_save(&mut emulator, &filepath);
+                            emulator.fade_out_audio(FADE_OUT_SAMPLES);
+                            quitting = true;
+                        }
+                        Event::KeyDown { keycode: Some(Keycode::R), keymod, repeat: false, .. } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                            if let Err(err) = emulator.reset() {
+                                overlay.show(err.to_string());
+                            }
+                        }
                         Event::KeyDown { keycode: Some(Keycode::R), repeat: false, ..} => rewinding = true,
                         Event::KeyUp { keycode: Some(Keycode::R), repeat: false, ..} => rewinding = false,
                         Event::KeyUp { keycode: Some(Keycode::Equals), .. } if speed < 32 => speed *= 2,
                         Event::KeyUp { keycode: Some(Keycode::Minus), .. } if speed > 1 => speed /= 2,
                         Event::KeyUp { keycode: Some(Keycode::Tab), keymod: Mod::NOMOD, .. } => emulator.set_palette(emulator.current_palette() + 1),
                         Event::KeyUp { keycode: Some(Keycode::Tab), keymod: Mod::LSHIFTMOD, .. } => emulator.set_palette(emulator.current_palette() - 1),
+                        Event::KeyUp { keycode: Some(Keycode::Tab), keymod, .. } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => emulator.cycle_palette_category(1),
                         Event::KeyUp { keycode: Some(Keycode::P), keymod: Mod::NOMOD, .. } => emulator.set_shader(emulator.current_shader() + 1),
                         Event::KeyUp { keycode: Some(Keycode::P), keymod: Mod::LSHIFTMOD, .. } => emulator.set_shader(emulator.current_shader() - 1),
+                        Event::KeyUp { keycode: Some(Keycode::F3), .. } => hud.toggle(),
+                        Event::KeyUp { keycode: Some(keycode @ (Keycode::F5 | Keycode::F6 | Keycode::F7)), .. } => {
+                            let (layer, name) = match keycode {
+                                Keycode::F5 => (Layer::Background, "Background"),
+                                Keycode::F6 => (Layer::Window, "Window"),
+                                _ => (Layer::Sprites, "Sprites"),
+                            };
+                            emulator.set_layer_enabled(layer, !emulator.layer_enabled(layer));
+                            overlay.show(format!("{} {}", name, if emulator.layer_enabled(layer) { "on" } else { "off" }));
+                        }
+                        // Tile window
+                        Event::KeyUp { keycode: Some(Keycode::B), window_id, .. } if window_id == tile_canvas.window().id() => {
+                            tile_bank = tile_bank.next();
+                            tile_canvas.window_mut().set_title(&format!("TILES - {:?}", tile_bank)).unwrap();
+                        }
+                        Event::MouseMotion { window_id, x, y, .. } if window_id == tile_canvas.window().id() => {
+                            let title = match debug::tile_at(tile_bank, x as usize, y as usize) {
+                                Some(tile_nr) => format!("TILES - {:?} - Tile {}:{:#06x}", tile_bank, tile_nr / 384, 0x8000 + (tile_nr % 384) * 16),
+                                None => format!("TILES - {:?}", tile_bank),
+                            };
+                            tile_canvas.window_mut().set_title(&title).unwrap();
+                        }
+                        Event::KeyUp { keycode: Some(Keycode::F8), .. } => {
+                            let idx = ColorFilter::ALL.iter().position(|filter| *filter == emulator.color_filter()).unwrap_or(0);
+                            emulator.set_color_filter(ColorFilter::ALL[(idx + 1) % ColorFilter::ALL.len()]);
+                            overlay.show(String::from(emulator.color_filter().name()));
+                        }
+                        Event::KeyUp { keycode: Some(keycode @ (Keycode::PageUp | Keycode::PageDown)), .. } if playlist.len() > 1 => {
+                            // Save the current game, then hot-swap the next ROM in the playlist
+                            write_save(&mut emulator, &filepath);
+                            let step = if keycode == Keycode::PageDown { 1 } else { playlist.len() - 1 };
+                            let next_idx = (playlist_idx + step) % playlist.len();
+                            // The current game keeps running if the next ROM or its save cannot be loaded
+                            let loaded = rom::read_rom(&playlist[next_idx], None).and_then(|(rom, path)| {
+                                emulator.load_rom(&rom, args.force_dmg, &read_save(&path)).map(|()| path).map_err(|err| err.to_string())
+                            });
+                            match loaded {
+                                Ok(path) => {
+                                    (playlist_idx, filepath) = (next_idx, path);
+                                    load_volume(&mut emulator, &filepath);
+                                    canvas.window_mut().set_title(&emulator.rom_title()).unwrap();
+                                    overlay.show(emulator.rom_title());
+                                }
+                                Err(err) => {
+                                    println!("Could not load {}: {}", playlist[next_idx].display(), err);
+                                    overlay.show(String::from("Could not load ROM"));
+                                }
+                            }
+                        }
+                        Event::KeyUp { keycode: Some(keycode @ (Keycode::LeftBracket | Keycode::RightBracket)), .. } => {
+                            let step = if keycode == Keycode::RightBracket { VOLUME_STEP } else { -VOLUME_STEP };
+                            emulator.set_volume(((emulator.volume() + step) * 10.0).round() / 10.0);
+                            overlay.show(format!("Volume {:.0}%", emulator.volume() * 100.0));
+                            if let Err(e) = fs::write(game_file(&filepath, "volume"), emulator.volume().to_string()) {
+                                println!("Could not save volume: {}", e);
+                            }
+                        }
+                        Event::KeyUp { keycode: Some(Keycode::F12), .. } => {
+                            let dumppath = filepath.with_extension("dump");
+                            match fs::write(&dumppath, emulator.dump_memory()) {
+                                Ok(_) => overlay.show(format!("Memory dumped to {}", dumppath.display())),
+                                Err(e) => println!("Could not write memory dump: {}", e),
+                            }
+                        }
+                        Event::KeyUp { keycode: Some(Keycode::T), .. } => {
+                            turbo_armed = true;
+                            overlay.show(String::from("Turbo: press a button"));
+                        }
+                        Event::KeyUp { keycode: Some(Keycode::L), .. } => {
+                            emulator.set_sprite_limit_enabled(!emulator.sprite_limit_enabled());
+                            overlay.show(format!("Sprite limit {}", if emulator.sprite_limit_enabled() { "on" } else { "off" }));
+                        }
                         // Joypad
                         Event::KeyDown { keycode: Some(Keycode::A), repeat: false, .. } => joypad.a = true,
                         Event::KeyUp { keycode: Some(Keycode::A), repeat: false, .. } => joypad.a = false,
@@ -179,23 +540,89 @@ fn main() {
                         _ => {}
                     }
                 }
-                emulator.set_joypad(&joypad);
 
-                // Save RAM content to file every 60 frames (~1s)
+                // Toggle the turbo of the first button pressed after arming it
+                let newly_pressed = joypad.bits() & !pressed;
+                if turbo_armed && newly_pressed != 0 {
+                    turbo.toggle(newly_pressed);
+                    turbo_armed = false;
+                    overlay.show(format!("Turbo {}", if turbo.buttons & newly_pressed != 0 { "on" } else { "off" }));
+                }
+
+                // Show the current settings after any of them changed
+                if settings != (emulator.current_palette(), emulator.current_shader(), speed) {
+                    overlay.show(format!(
+                        "{} - {} - {}X",
+                        emulator.current_palette_name(),
+                        emulator.current_shader_name(),
+                        speed
+                    ));
+                }
+
+                // Save RAM content to file every 60 frames (~1s), if it changed
                 if frame_count % 60 == 0 {
-                    let save_data = emulator.save();
-                    fs::write(savepath.clone(), save_data).unwrap();
+                    write_save(&mut emulator, &filepath);
                 }
             }
+            emulator.set_joypad(&turbo.apply(&joypad));
         }
 
-        // Play audio and skip samples if the audio buffer is full
+        // Play audio resampled to keep the queue half full, and skip samples if it is still full (e.g. fast-forward)
         let audio_buffer = emulator.audio_buffer();
         if audio_buffer.len() >= AUDIO_SAMPLE_SIZE {
-            if audio_device.size() as usize <= AUDIO_SAMPLE_SIZE * 16 {
-                audio_device.queue_audio(audio_buffer).unwrap();
+            if !audio_muted(speed, args.mute_fast_forward) && audio_device.size() as usize <= AUDIO_QUEUE_MAX {
+                let ratio = resampler::rate_ratio(audio_device.size() as f64 / AUDIO_QUEUE_MAX as f64);
+                audio_device.queue_audio(resampler.resample(audio_buffer, ratio)).unwrap();
             }
             emulator.clear_audio_buffer();
         }
+
+        // Keep running until the audio has faded out after quitting, rewinding produces no audio so exit directly
+        if quitting && (rewinding || emulator.audio_faded_out()) {
+            running = false;
+        }
+    }
+
+    // Save the RAM written during the fade-out
+    write_save(&mut emulator, &filepath);
+
+    // Play the end of the fade-out before closing the audio device
+    if !audio_muted(speed, args.mute_fast_forward) {
+        audio_device.queue_audio(resampler.resample(emulator.audio_buffer(), 1.0)).unwrap();
+        while audio_device.size() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    if args.record_movie.is_some() {
+        if let Err(err) = emulator.stop_recording() {
+            println!("{}", err);
+        }
+    }
+    if let Some((tile_addr, vbank)) = args.dump_tile {
+        print_tile(&emulator.dump_tile(tile_addr, vbank));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{audio_muted, parse_tile_addr};
+
+    #[test]
+    fn mute_fast_forward() {
+        assert!(!audio_muted(1, true));
+        assert!(audio_muted(2, true));
+        assert!(audio_muted(32, true));
+        // Without the option the audio is always queued
+        assert!(!audio_muted(4, false));
+    }
+
+    #[test]
+    fn tile_addr() {
+        assert_eq!(parse_tile_addr("8010"), Ok((0x8010, false)));
+        assert_eq!(parse_tile_addr("1:0x9000"), Ok((0x9000, true)));
+        assert!(parse_tile_addr("9800").is_err());
+        assert!(parse_tile_addr("2:8000").is_err());
+        assert!(parse_tile_addr("zz").is_err());
     }
 }
@@ -0,0 +1,103 @@
+use gb_core::lcd::{font, GLYPH_H, GLYPH_W};
+use std::time::{Duration, Instant};
+
+use crate::limiter::GB_FPS;
+
+const DURATION: Duration = Duration::from_secs(1);
+const MARGIN: usize = 2; // In LCD pixels
+const HUD_PERIOD: Duration = Duration::from_millis(500);
+
+pub fn draw_text(out: &mut [u8], width: usize, scale: usize, x: usize, y: usize, text: &str) {
+    // Draw white text over a black box, with coordinates and sizes in LCD pixels
+    let (box_w, box_h) = (text.chars().count() * (GLYPH_W + 1) + 1, GLYPH_H + 2);
+    let mut fill = |px: usize, py: usize, color: [u8; 4]| {
+        for dy in 0..scale {
+            let row = (py * scale + dy) * width;
+            for dx in 0..scale {
+                let idx = 4 * (row + px * scale + dx);
+                if let Some(out_px) = out.get_mut(idx..idx + 4) {
+                    out_px.copy_from_slice(&color);
+                }
+            }
+        }
+    };
+    for py in y..y + box_h {
+        for px in (x..x + box_w).filter(|px| px * scale < width) {
+            fill(px, py, [0x00, 0x00, 0x00, 0xFF]);
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in font::glyph(c).iter().enumerate() {
+            for col in (0..GLYPH_W).filter(|col| bits & (0b100 >> col) != 0) {
+                let px = x + 1 + i * (GLYPH_W + 1) + col;
+                if px * scale < width {
+                    fill(px, y + 1 + row, [0xFF, 0xFF, 0xFF, 0xFF]);
+                }
+            }
+        }
+    }
+}
+
+// Text shown for a short time after a setting changes
+#[derive(Default)]
+pub struct Overlay {
+    text: String,
+    shown_at: Option<Instant>,
+}
+
+impl Overlay {
+    pub fn show(&mut self, text: String) {
+        self.text = text;
+        self.shown_at = Some(Instant::now());
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.shown_at.is_some_and(|shown_at| shown_at.elapsed() < DURATION)
+    }
+
+    pub fn draw(&self, out: &mut [u8], width: usize, scale: usize) {
+        draw_text(out, width, scale, MARGIN, MARGIN, &self.text);
+    }
+}
+
+// Debug HUD with the measured FPS, the emulation speed and the audio queue fill level
+#[derive(Default)]
+pub struct Hud {
+    pub enabled: bool,
+    text: String,
+    period_start: Option<Instant>,
+    drawn_frames: u32,
+    emulated_frames: u32,
+}
+
+impl Hud {
+    pub fn toggle(&mut self) {
+        *self = Self {
+            enabled: !self.enabled,
+            ..Self::default()
+        };
+    }
+
+    pub fn frame(&mut self, drawn: bool, audio_fill: f64) {
+        // Called on every emulated frame, the values are refreshed periodically to keep them readable
+        self.emulated_frames += 1;
+        self.drawn_frames += drawn as u32;
+        let period_start = *self.period_start.get_or_insert_with(Instant::now);
+        let elapsed = period_start.elapsed();
+        if elapsed >= HUD_PERIOD {
+            let (secs, emulated_fps) = (elapsed.as_secs_f64(), self.emulated_frames as f64 / elapsed.as_secs_f64());
+            self.text = format!(
+                "{:.0} FPS {:.0}% AUDIO {:.0}%",
+                self.drawn_frames as f64 / secs,
+                100.0 * emulated_fps / GB_FPS,
+                100.0 * audio_fill
+            );
+            (self.period_start, self.drawn_frames, self.emulated_frames) = (Some(Instant::now()), 0, 0);
+        }
+    }
+
+    pub fn draw(&self, out: &mut [u8], width: usize, height: usize, scale: usize) {
+        let y = (height / scale).saturating_sub(GLYPH_H + 2 + MARGIN);
+        draw_text(out, width, scale, MARGIN, y, &self.text);
+    }
+}
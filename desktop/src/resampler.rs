@@ -0,0 +1,84 @@
+const MAX_RATE_DELTA: f64 = 0.005; // Max pitch deviation of the dynamic rate control, inaudible at 0.5%
+const TARGET_FILL: f64 = 0.5; // Fraction of the audio queue to keep filled
+
+/*
+ Dynamic rate control: the host audio clock and the emulated sample production drift apart over time,
+ so the stereo samples are resampled with a ratio slightly adjusted from the fill level of the audio queue.
+ A queue emptier than the target produces more samples, a fuller one produces less.
+*/
+pub struct Resampler {
+    pos: f64,         // Position of the next output sample, relative to the first input frame
+    last: [f32; 2],   // Last frame of the previous buffer, to interpolate across buffers
+    output: Vec<f32>, // Resampled interleaved stereo samples
+}
+
+// Resample ratio (output frames per input frame) for the given fill level (0.0 empty, 1.0 full)
+pub fn rate_ratio(fill: f64) -> f64 {
+    let error = (TARGET_FILL - fill.clamp(0.0, 1.0)) / TARGET_FILL.max(1.0 - TARGET_FILL);
+    1.0 + MAX_RATE_DELTA * error
+}
+
+impl Resampler {
+    pub fn new() -> Self {
+        Self {
+            pos: 1.0,
+            last: [0.0; 2],
+            output: Vec::new(),
+        }
+    }
+
+    pub fn resample(&mut self, input: &[f32], ratio: f64) -> &[f32] {
+        self.output.clear();
+        let frames = input.len() / 2;
+        let last = self.last;
+        let frame = |i: usize| if i == 0 { last } else { [input[i * 2 - 2], input[i * 2 - 1]] };
+        // Interpolate linearly between the last frame of the previous buffer (index 0) and the input frames (index 1..=frames)
+        let step = 1.0 / ratio;
+        let mut pos = self.pos;
+        while pos <= frames as f64 {
+            let i = pos as usize;
+            let t = (pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame((i + 1).min(frames)));
+            self.output.push(a[0] + (b[0] - a[0]) * t);
+            self.output.push(a[1] + (b[1] - a[1]) * t);
+            pos += step;
+        }
+        if frames > 0 {
+            self.last = frame(frames);
+            self.pos = pos - frames as f64;
+        }
+        &self.output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rate_ratio, Resampler, MAX_RATE_DELTA};
+
+    #[test]
+    fn rate_control() {
+        assert_eq!(rate_ratio(0.5), 1.0);
+        // Empty queue speeds up the production, full queue slows it down, always within the max delta
+        assert!(rate_ratio(0.2) > 1.0 && rate_ratio(0.8) < 1.0);
+        assert!(rate_ratio(0.1) > rate_ratio(0.3));
+        for fill in [-1.0, 0.0, 0.25, 0.75, 1.0, 4.0] {
+            assert!((rate_ratio(fill) - 1.0).abs() <= MAX_RATE_DELTA + f64::EPSILON);
+        }
+        assert_eq!(rate_ratio(0.0), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(rate_ratio(2.0), 1.0 - MAX_RATE_DELTA);
+    }
+
+    #[test]
+    fn resample() {
+        let mut resampler = Resampler::new();
+        let input: Vec<f32> = (0..2048).map(|i| (i / 2) as f32).collect();
+        assert_eq!(resampler.resample(&input, 1.0), &input[..]);
+        // The produced frames follow the ratio, with no samples dropped across buffers
+        let mut produced = 0;
+        for _ in 0..100 {
+            produced += resampler.resample(&input, 1.0 + MAX_RATE_DELTA).len() / 2;
+        }
+        let expected = (100.0 * 1024.0 * (1.0 + MAX_RATE_DELTA)) as usize;
+        assert!(produced.abs_diff(expected) <= 1);
+    }
+}
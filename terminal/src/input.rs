@@ -0,0 +1,54 @@
+use console_engine::{ConsoleEngine, KeyCode};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use gb_core::Joypad;
+
+// Terminals report key presses (repeated while held) but rarely key releases, so keys are released after a timeout
+const AUTO_RELEASE: Duration = Duration::from_millis(150);
+
+const KEYS: [KeyCode; 9] = [
+    KeyCode::Char('a'),
+    KeyCode::Char('s'),
+    KeyCode::Up,
+    KeyCode::Down,
+    KeyCode::Left,
+    KeyCode::Right,
+    KeyCode::Enter,
+    KeyCode::Backspace,
+    KeyCode::Char('r'),
+];
+
+#[derive(Default)]
+pub struct TermInput {
+    last_pressed: HashMap<KeyCode, Instant>,
+}
+
+impl TermInput {
+    pub fn update(&mut self, engine: &ConsoleEngine) {
+        let now = Instant::now();
+        for key in KEYS {
+            if engine.is_key_held(key) {
+                self.last_pressed.insert(key, now);
+            }
+        }
+        self.last_pressed.retain(|_, pressed| now.duration_since(*pressed) < AUTO_RELEASE);
+    }
+
+    pub fn is_held(&self, key: KeyCode) -> bool {
+        self.last_pressed.contains_key(&key)
+    }
+
+    pub fn joypad(&self) -> Joypad {
+        Joypad {
+            a: self.is_held(KeyCode::Char('a')),
+            b: self.is_held(KeyCode::Char('s')),
+            up: self.is_held(KeyCode::Up),
+            down: self.is_held(KeyCode::Down),
+            left: self.is_held(KeyCode::Left),
+            right: self.is_held(KeyCode::Right),
+            start: self.is_held(KeyCode::Enter),
+            select: self.is_held(KeyCode::Backspace),
+        }
+    }
+}
@@ -1,22 +1,76 @@
 use ansi_colours::ansi256_from_rgb;
 use clap::Parser;
-use console_engine::{pixel, Color, ConsoleEngine};
+use console_engine::crossterm::{cursor, queue, terminal};
+use console_engine::{pixel, Color, ConsoleEngine, KeyCode, KeyEventKind, KeyModifiers};
 use device_query::{DeviceQuery, DeviceState, Keycode};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{fs, path::Path};
 
-use gb_core::{lcd, GBEmu, Joypad};
+use gb_core::{lcd, rom, utils, GBEmu, Joypad};
+
+mod input;
+mod sixel;
+
+use input::TermInput;
+use sixel::SixelTerm;
+
+// Each terminal row shows two scanlines with half-block characters, plus one row for the controls help
+const TERM_ROWS: usize = lcd::LCDH.div_ceil(2);
+
+// Set by the SIGINT handler, so that the emulation loop can exit and write the save file
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_sigint() {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+    unsafe { libc::signal(libc::SIGINT, on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+}
+
+#[cfg(not(unix))]
+fn handle_sigint() {}
+
+fn write_save(path: &Path, emulator: &mut GBEmu) {
+    if let Err(err) = utils::write_atomic(path, emulator.save()) {
+        eprintln!("Could not write save file: {}", err);
+    }
+}
+
+fn row_scanlines(row: usize, height: usize) -> (usize, Option<usize>) {
+    // Scanlines drawn in the upper and lower half of a terminal row, the last one is unpaired if the height is odd
+    let top = row * 2;
+    (top, Some(top + 1).filter(|bottom| *bottom < height))
+}
 
 #[derive(Parser)]
 #[command(about = "A simple Gameboy emulator written in Rust")]
 struct Args {
-    /// ROM path (.gb/.gbc)
+    /// ROM path (.gb/.gbc/.zip)
     #[arg(short, long)]
     file: String,
 
+    /// ROM to load from a zip archive containing multiple ROMs
+    #[arg(long)]
+    entry: Option<String>,
+
     /// Convert colors to ANSI value (8bpp), in case the terminal does not support true colors (24bpp)
     #[arg(long, action)]
     ansi: bool,
 
+    /// Run the boot ROM at uncapped speed
+    #[arg(long, action)]
+    fast_boot: bool,
+
+    /// Render frames at full resolution using sixel graphics, if supported by the terminal
+    #[arg(long, action)]
+    sixel: bool,
+
+    /// Read inputs from the terminal key events instead of the global keyboard state (e.g. over SSH)
+    #[arg(long, action)]
+    term_input: bool,
+
     /// Force games to run in DMG (Non-Color GB)
     #[arg(long, action)]
     force_dmg: bool,
@@ -26,20 +80,57 @@ fn main() {
     let args = Args::parse();
 
     // Read ROM and init emulator state
-    let filepath = Path::new(&args.file);
-    let rom = fs::read(filepath).expect("ROM not found");
-    let mut emulator: GBEmu = GBEmu::new(&rom, args.force_dmg);
+    let (rom, filepath) = match rom::read_rom(Path::new(&args.file), args.entry.as_deref()) {
+        Ok(rom) => rom,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    let mut emulator: GBEmu = match GBEmu::new(&rom, args.force_dmg) {
+        Ok(emulator) => emulator,
+        Err(err) => {
+            println!("Could not load ROM: {}", err);
+            return;
+        }
+    };
 
     // Load savefile if present
     let savepath = filepath.with_file_name(format!(".{}.sav", filepath.file_name().unwrap().to_string_lossy()));
     match fs::read(savepath.clone()) {
-        Ok(savefile) => emulator.load_save(&savefile),
+        Ok(savefile) => {
+            if let Err(err) = emulator.load_save(&savefile) {
+                println!("Could not load save file: {}", err);
+            }
+        }
         Err(_) => println!("Could not find save file"),
     }
 
+    // Check sixel support before the terminal is taken over by the engine
+    let sixel_term = if args.sixel {
+        let sixel_term = SixelTerm::detect();
+        if sixel_term.is_none() {
+            println!("Sixel graphics not supported by the terminal, falling back to half-block characters");
+        }
+        sixel_term
+    } else {
+        None
+    };
+
     // Setup output canvas
-    let device_state = DeviceState::new();
-    let mut engine = ConsoleEngine::init(lcd::LCDW as u32, lcd::LCDH as u32 / 2 + 1, 60).unwrap();
+    let device_state = if args.term_input { None } else { Some(DeviceState::new()) };
+    let mut term_input = TermInput::default();
+    let mut engine = if sixel_term.is_some() {
+        ConsoleEngine::init_fill(60).unwrap()
+    } else {
+        ConsoleEngine::init(lcd::LCDW as u32, TERM_ROWS as u32 + 1, 60).unwrap()
+    };
+    // The sixel image is drawn below the controls help, leaving the last row free to avoid scrolling
+    let sixel_area = |engine: &ConsoleEngine| (engine.get_width() as u16, engine.get_height().saturating_sub(2) as u16);
+    let mut sixel_scale = sixel_term.as_ref().map(|term| {
+        let (cols, rows) = sixel_area(&engine);
+        term.scale(cols, rows)
+    });
     engine.set_title(emulator.rom_title().as_str());
     let controls_help = "\
         [A] A    [S]: B    [↑↓←→] D-PAD    \
@@ -50,9 +141,10 @@ fn main() {
     // Start emulation loop
     let mut running = true;
     let mut rewinding = false;
-    let mut joypad = Joypad::default();
     let mut frame_count: u64 = 0;
-    while running {
+    handle_sigint();
+    while running && !INTERRUPTED.load(Ordering::Relaxed) {
+        let booted = !args.fast_boot || emulator.boot_completed();
         // Run emulator step, i.e. execute next opcode
         let frame_buffer = if rewinding && emulator.can_rewind() {
             // Rewind to last state
@@ -62,58 +154,123 @@ fn main() {
             emulator.step()
         };
 
-        // Executed once per frame
-        if let Some(frame_buffer) = frame_buffer {
+        // Executed once per frame, skipped while the boot ROM is running if fast boot is enabled
+        if let Some(frame_buffer) = frame_buffer.filter(|_| booted) {
             // Wait for next frame and capture inputs
             engine.wait_frame();
             frame_count += 1;
 
-            // Draw frame to console buffer
-            for x in 0..lcd::LCDW {
-                for y in 0..lcd::LCDH / 2 {
-                    let idxh = lcd::LCD::to_idx(x, y * 2, 1, 0, 0);
-                    let idxl = lcd::LCD::to_idx(x, y * 2 + 1, 1, 0, 0);
-                    let [rh, gh, bh, _] = frame_buffer.frame[idxh].to_be_bytes();
-                    let [rl, gl, bl, _] = frame_buffer.frame[idxl].to_be_bytes();
-                    let (bg_color, fg_color) = if !args.ansi {
-                        (Color::Rgb { r: rh, g: gh, b: bh }, Color::Rgb { r: rl, g: gl, b: bl })
+            // Redraw the whole screen if the terminal has been resized
+            if let (Some(term), Some((cols, rows))) = (&sixel_term, engine.get_resize()) {
+                engine.resize(cols as u32, rows as u32);
+                let (cols, rows) = sixel_area(&engine);
+                sixel_scale = Some(term.scale(cols, rows));
+                queue!(io::stdout(), terminal::Clear(terminal::ClearType::All)).unwrap();
+                engine.request_full_draw();
+            }
+
+            if let Some(scale) = sixel_scale {
+                // Draw frame as a sixel image
+                engine.print(0, 0, controls_help);
+                engine.draw();
+                let mut stdout = io::stdout();
+                queue!(stdout, cursor::MoveTo(0, 1)).unwrap();
+                stdout.write_all(sixel::encode(&frame_buffer.frame, scale).as_bytes()).unwrap();
+                stdout.flush().unwrap();
+            } else {
+                // Draw frame to console buffer
+                let color = |px: u32| {
+                    let [r, g, b, _] = px.to_be_bytes();
+                    if !args.ansi {
+                        Color::Rgb { r, g, b }
                     } else {
-                        (
-                            Color::AnsiValue(ansi256_from_rgb((rh, gh, bh))),
-                            Color::AnsiValue(ansi256_from_rgb((rl, gl, bl))),
-                        )
-                    };
-                    engine.set_pxl(x as i32, y as i32, pixel::pxl_fbg('▄', fg_color, bg_color));
+                        Color::AnsiValue(ansi256_from_rgb((r, g, b)))
+                    }
+                };
+                for x in 0..lcd::LCDW {
+                    for y in 0..TERM_ROWS {
+                        let (top, bottom) = row_scanlines(y, lcd::LCDH);
+                        let top_color = color(frame_buffer.frame[lcd::LCD::to_idx(x, top, 1, 0, 0)]);
+                        let pxl = match bottom {
+                            Some(bottom) => pixel::pxl_fbg('▄', color(frame_buffer.frame[lcd::LCD::to_idx(x, bottom, 1, 0, 0)]), top_color),
+                            None => pixel::pxl_fg('▀', top_color),
+                        };
+                        engine.set_pxl(x as i32, y as i32, pxl);
+                    }
                 }
+                // The help is printed on its own row below the image, cut to the image width
+                let help: String = controls_help.chars().take(lcd::LCDW).collect();
+                engine.print(0, TERM_ROWS as i32, &help);
+                engine.draw();
             }
-            engine.print(0, lcd::LCDH as i32 / 2, controls_help);
-            engine.draw();
 
             // Retrieve current pressed keys and update joypad
-            let keys: Vec<Keycode> = device_state.get_keys();
-            joypad.a = keys.contains(&Keycode::A);
-            joypad.b = keys.contains(&Keycode::S);
-            joypad.up = keys.contains(&Keycode::Up);
-            joypad.down = keys.contains(&Keycode::Down);
-            joypad.left = keys.contains(&Keycode::Left);
-            joypad.right = keys.contains(&Keycode::Right);
-            joypad.start = keys.contains(&Keycode::Enter);
-            joypad.select = keys.contains(&Keycode::Backspace);
+            let joypad = if let Some(device_state) = &device_state {
+                let keys: Vec<Keycode> = device_state.get_keys();
+                rewinding = keys.contains(&Keycode::R);
+                Joypad {
+                    a: keys.contains(&Keycode::A),
+                    b: keys.contains(&Keycode::S),
+                    up: keys.contains(&Keycode::Up),
+                    down: keys.contains(&Keycode::Down),
+                    left: keys.contains(&Keycode::Left),
+                    right: keys.contains(&Keycode::Right),
+                    start: keys.contains(&Keycode::Enter),
+                    select: keys.contains(&Keycode::Backspace),
+                }
+            } else {
+                term_input.update(&engine);
+                rewinding = term_input.is_held(KeyCode::Char('r'));
+                term_input.joypad()
+            };
             emulator.set_joypad(&joypad);
 
             // Handle shortcuts
-            rewinding = keys.contains(&Keycode::R);
-            if engine.is_key_pressed(console_engine::KeyCode::Esc) {
+            // Ctrl+C is read as a key while the terminal is in raw mode
+            if engine.is_key_pressed(KeyCode::Esc)
+                || engine.is_key_pressed_with_modifier(KeyCode::Char('c'), KeyModifiers::CONTROL, KeyEventKind::Press)
+            {
                 running = false;
             }
-            if engine.is_key_pressed(console_engine::KeyCode::Tab) {
+            if engine.is_key_pressed(KeyCode::Tab) {
                 emulator.set_palette(emulator.current_palette() + 1)
             }
 
-            // Save RAM content to file every 60 frames (~1s)
-            if frame_count % 60 == 0 {
-                fs::write(savepath.clone(), emulator.save()).unwrap();
+            // Save RAM content to file every 60 frames (~1s), if it changed
+            if frame_count % 60 == 0 && emulator.ram_dirty() {
+                write_save(&savepath, &mut emulator);
             }
         }
     }
+
+    // Save the last changes when exiting, after restoring the terminal so that errors are visible
+    drop(engine);
+    if emulator.ram_dirty() {
+        write_save(&savepath, &mut emulator);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::row_scanlines;
+
+    #[test]
+    fn row_pairing() {
+        assert_eq!(row_scanlines(0, 144), (0, Some(1)));
+        assert_eq!(row_scanlines(71, 144), (142, Some(143)));
+        // With an odd height the last row only shows the last scanline, and every scanline is drawn exactly once
+        assert_eq!(row_scanlines(72, 145), (144, None));
+        for height in [143usize, 144, 145] {
+            let rows = height.div_ceil(2);
+            let mut scanlines: Vec<usize> = (0..rows)
+                .flat_map(|row| {
+                    let (top, bottom) = row_scanlines(row, height);
+                    [Some(top), bottom]
+                })
+                .flatten()
+                .collect();
+            scanlines.dedup();
+            assert_eq!(scanlines, (0..height).collect::<Vec<_>>());
+        }
+    }
 }
@@ -0,0 +1,149 @@
+use console_engine::crossterm::terminal;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use gb_core::lcd::{LCD, LCDH, LCDW};
+
+const MAX_COLORS: usize = 256; // Color registers guaranteed by most sixel terminals
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+const DEFAULT_CELL_SIZE: (usize, usize) = (8, 16);
+
+pub struct SixelTerm {
+    cell_size: (usize, usize), // Width and height of a character cell in pixels
+}
+
+impl SixelTerm {
+    pub fn detect() -> Option<Self> {
+        // Ask for the cell size in pixels and the primary device attributes, where parameter 4 means that sixels are supported
+        let response = query("\x1b[16t\x1b[c")?;
+        let attrs = response.split("\x1b[?").nth(1)?.trim_end_matches('c');
+        if !attrs.split(';').any(|attr| attr == "4") {
+            return None;
+        }
+        let cell_size = response
+            .split("\x1b[6;")
+            .nth(1)
+            .and_then(|size| size.split('t').next())
+            .and_then(|size| size.split_once(';'))
+            .and_then(|(h, w)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .filter(|(w, h)| *w > 0 && *h > 0)
+            .unwrap_or(DEFAULT_CELL_SIZE);
+        Some(Self { cell_size })
+    }
+
+    pub fn scale(&self, cols: u16, rows: u16) -> usize {
+        // Largest integer scale that fits the terminal area
+        let (width, height) = (cols as usize * self.cell_size.0, rows as usize * self.cell_size.1);
+        (width / LCDW).min(height / LCDH).max(1)
+    }
+}
+
+fn query(request: &str) -> Option<String> {
+    terminal::enable_raw_mode().ok()?;
+    print!("{}", request);
+    io::stdout().flush().ok()?;
+    let response = read_response(Instant::now() + QUERY_TIMEOUT);
+    terminal::disable_raw_mode().ok()?;
+    response
+}
+
+#[cfg(unix)]
+fn read_response(deadline: Instant) -> Option<String> {
+    // Poll stdin until the deadline, since terminals without support for the request might never answer.
+    // The file descriptor is read directly, one byte at a time, to avoid buffering any input following the response.
+    let mut response = Vec::new();
+    loop {
+        let timeout = deadline.checked_duration_since(Instant::now())?;
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: a single valid pollfd is passed
+        if unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } <= 0 {
+            return None;
+        }
+        let mut byte = 0u8;
+        // SAFETY: the buffer is a single valid byte
+        if unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) } != 1 {
+            return None;
+        }
+        response.push(byte);
+        if byte == b'c' && response.windows(3).any(|w| w == b"\x1b[?") {
+            return Some(String::from_utf8_lossy(&response).into_owned());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn read_response(_deadline: Instant) -> Option<String> {
+    // Stdin cannot be polled, sixels are not detected
+    None
+}
+
+pub fn encode(frame: &[u32], scale: usize) -> String {
+    // Map each pixel to a color register, quantizing to RGB332 if the frame has too many colors
+    let mut palette: Vec<u32> = frame.iter().map(|c| c >> 8).collect();
+    palette.sort_unstable();
+    palette.dedup();
+    let quantize = palette.len() > MAX_COLORS;
+    if quantize {
+        palette = (0..MAX_COLORS as u32)
+            .map(|c| ((c >> 5) * 0x24) << 16 | (((c >> 2) & 0x07) * 0x24) << 8 | ((c & 0x03) * 0x55))
+            .collect();
+    }
+    let pixels: Vec<usize> = frame
+        .iter()
+        .map(|c| {
+            let [r, g, b, _] = c.to_be_bytes();
+            if quantize {
+                (r as usize >> 5) << 5 | (g as usize >> 5) << 2 | b as usize >> 6
+            } else {
+                palette.binary_search(&(c >> 8)).unwrap()
+            }
+        })
+        .collect();
+
+    let (width, height) = (LCDW * scale, LCDH * scale);
+    let mut out = String::with_capacity(width * height / 2);
+    write!(out, "\x1bP0;1q\"1;1;{};{}", width, height).unwrap();
+    for (i, color) in palette.iter().enumerate() {
+        let [_, r, g, b] = color.to_be_bytes();
+        let pct = |v: u8| v as usize * 100 / 255;
+        write!(out, "#{};2;{};{};{}", i, pct(r), pct(g), pct(b)).unwrap();
+    }
+    // Each band covers 6 rows, drawn in one pass per color present in the band
+    let mut band = vec![0u8; width * palette.len()];
+    let mut used = vec![false; palette.len()];
+    for band_y in (0..height).step_by(6) {
+        band.fill(0);
+        used.fill(false);
+        for dy in 0..6.min(height - band_y) {
+            let y = (band_y + dy) / scale;
+            for x in 0..width {
+                let color = pixels[LCD::to_idx(x / scale, y, 1, 0, 0)];
+                band[color * width + x] |= 1 << dy;
+                used[color] = true;
+            }
+        }
+        for color in (0..palette.len()).filter(|c| used[*c]) {
+            write!(out, "#{}", color).unwrap();
+            let row = &band[color * width..][..width];
+            let mut x = 0;
+            while x < width {
+                let run = row[x..].iter().take_while(|v| **v == row[x]).count();
+                let chr = (b'?' + row[x]) as char;
+                match run {
+                    1..=3 => (0..run).for_each(|_| out.push(chr)),
+                    _ => write!(out, "!{}{}", run, chr).unwrap(),
+                }
+                x += run;
+            }
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
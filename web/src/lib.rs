@@ -1,4 +1,5 @@
 use base64::{engine::general_purpose, Engine as _};
+use js_sys::Array;
 use std::cell::RefCell;
 use std::{panic, rc::Rc};
 use wasm_bindgen::{prelude::*, Clamped};
@@ -9,8 +10,12 @@ use gb_core::{apu, lcd, GBEmu, Joypad};
 const SCALE: usize = 4;
 const PALETTE_IDX_KEY: &str = "palette_idx";
 const SHADER_IDX_KEY: &str = "shader_idx";
+const BUTTONS: [&str; 8] = ["a", "b", "up", "down", "left", "right", "start", "select"];
 const AUDIO_SAMPLE_SIZE: usize = 2048;
-const AUDIO_MAX_DELAY: f64 = 0.1;
+const AUDIO_SAMPLE_SIZE_RANGE: (usize, usize) = (256, 16384);
+const AUDIO_MAX_DELAY: f64 = 0.1; // In seconds
+const AUDIO_MAX_DELAY_RANGE: (f64, f64) = (0.02, 1.0);
+const FADE_OUT_SAMPLES: u32 = apu::AUDIO_FREQUENCY / 100; // 10ms fade-out of the audio when pausing, to avoid a click
 
 struct EmuState {
     speed: u32,
@@ -18,12 +23,206 @@ struct EmuState {
     switch_shader: Option<bool>,
     rewind: bool,
     joypad: Joypad,
+    audio_sample_size: usize,
+    audio_max_delay: f64,
+    paused: bool,
+    fast_boot: bool,
+    touch_held: u8,    // Buttons currently held on the virtual gamepad, one bit per button
+    touch_pressed: u8, // Buttons pressed since the last frame, kept until the emulator has seen them
+}
+
+#[wasm_bindgen]
+pub struct WebEmu {
+    emulator: Rc<RefCell<GBEmu>>,
+    state: Rc<RefCell<EmuState>>,
+}
+
+#[wasm_bindgen]
+impl WebEmu {
+    pub fn pause(&self) {
+        // The emulation keeps running until the audio has faded out
+        self.emulator.borrow_mut().fade_out_audio(FADE_OUT_SAMPLES);
+        self.state.borrow_mut().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.emulator.borrow_mut().cancel_audio_fade_out();
+        self.state.borrow_mut().paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.borrow().paused
+    }
+
+    pub fn reset(&self) -> Result<(), JsValue> {
+        self.emulator
+            .borrow_mut()
+            .reset()
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.state.borrow().speed
+    }
+
+    pub fn set_speed(&self, speed: u32) {
+        self.state.borrow_mut().speed = speed.clamp(1, 32);
+    }
+
+    pub fn load_rom(&self, rom: &[u8]) -> Result<(), JsValue> {
+        // Persist the save of the current ROM before replacing it
+        let mut emulator = self.emulator.borrow_mut();
+        store_save(&mut emulator);
+        // The save is looked up by the title and checksum of the loaded ROM
+        emulator
+            .load_rom(rom, false, &[])
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        load_save(&mut emulator);
+        window().unwrap().document().unwrap().set_title(emulator.rom_title().as_str());
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> String {
+        // Serialized state as base64, which JS can store or download
+        general_purpose::STANDARD.encode(self.emulator.borrow().save_state().serialize())
+    }
+
+    pub fn load_state(&self, state: &str) -> Result<(), JsValue> {
+        let data = general_purpose::STANDARD
+            .decode(state)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        let mut emulator = self.emulator.borrow_mut();
+        let state = emulator
+            .deserialize_state(&data)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        emulator.load_state(&state).map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn set_button(&self, name: &str, pressed: bool) {
+        // Buttons are tracked independently, so multiple touches can hold any combination of them
+        let Some(idx) = BUTTONS.iter().position(|button| *button == name) else {
+            console::log_1(&format!("Unknown button: {}", name).into());
+            return;
+        };
+        let mut state = self.state.borrow_mut();
+        if pressed {
+            state.touch_held |= 1 << idx;
+            state.touch_pressed |= 1 << idx;
+        } else {
+            state.touch_held &= !(1 << idx);
+        }
+    }
+
+    pub fn palette(&self) -> i16 {
+        self.emulator.borrow().current_palette()
+    }
+
+    pub fn set_palette(&self, palette_idx: i16) {
+        let mut emulator = self.emulator.borrow_mut();
+        emulator.set_palette(palette_idx);
+        store_setting(PALETTE_IDX_KEY, emulator.current_palette());
+    }
+
+    pub fn palette_count(&self) -> usize {
+        GBEmu::palette_names().len()
+    }
+
+    pub fn palette_names(&self) -> Array {
+        GBEmu::palette_names().into_iter().map(JsValue::from_str).collect()
+    }
+
+    pub fn shader(&self) -> i16 {
+        self.emulator.borrow().current_shader()
+    }
+
+    pub fn set_shader(&self, shader_idx: i16) {
+        let mut emulator = self.emulator.borrow_mut();
+        emulator.set_shader(shader_idx);
+        store_setting(SHADER_IDX_KEY, emulator.current_shader());
+    }
+
+    pub fn shader_count(&self) -> usize {
+        GBEmu::shader_names().len()
+    }
+
+    pub fn shader_names(&self) -> Array {
+        GBEmu::shader_names().iter().copied().map(JsValue::from_str).collect()
+    }
+
+    pub fn audio_sample_size(&self) -> usize {
+        self.state.borrow().audio_sample_size
+    }
+
+    pub fn set_audio_sample_size(&self, size: usize) {
+        // Number of interleaved stereo samples queued at once, larger values are more robust to frame drops
+        let (min, max) = AUDIO_SAMPLE_SIZE_RANGE;
+        self.state.borrow_mut().audio_sample_size = size.clamp(min, max) & !1;
+    }
+
+    pub fn audio_max_delay(&self) -> f64 {
+        self.state.borrow().audio_max_delay
+    }
+
+    pub fn set_audio_max_delay(&self, delay: f64) {
+        // Samples are dropped when the queued audio exceeds this delay, so it must stay bounded
+        let (min, max) = AUDIO_MAX_DELAY_RANGE;
+        let delay = if delay.is_finite() {
+            delay.clamp(min, max)
+        } else {
+            AUDIO_MAX_DELAY
+        };
+        self.state.borrow_mut().audio_max_delay = delay;
+    }
 }
 
 fn request_animation_frame(f: &Closure<dyn FnMut()>) {
     window().unwrap().request_animation_frame(f.as_ref().unchecked_ref()).unwrap();
 }
 
+fn touch_joypad(state: &EmuState) -> Joypad {
+    // Combine keyboard and touch input, where a tap shorter than a frame still counts as a press
+    let touch = state.touch_held | state.touch_pressed;
+    let held = |button: &str| touch & (1 << BUTTONS.iter().position(|b| *b == button).unwrap()) != 0;
+    let joypad = state.joypad;
+    Joypad {
+        a: joypad.a || held("a"),
+        b: joypad.b || held("b"),
+        up: joypad.up || held("up"),
+        down: joypad.down || held("down"),
+        left: joypad.left || held("left"),
+        right: joypad.right || held("right"),
+        start: joypad.start || held("start"),
+        select: joypad.select || held("select"),
+    }
+}
+
+fn store_setting(key: &str, idx: i16) {
+    let local_storage = window().unwrap().local_storage().unwrap().unwrap();
+    local_storage.set_item(key, &idx.to_string()).unwrap();
+}
+
+fn save_key(emulator: &GBEmu) -> String {
+    format!("{} - {}", emulator.rom_checksum(), emulator.rom_title())
+}
+
+fn store_save(emulator: &mut GBEmu) {
+    let local_storage = window().unwrap().local_storage().unwrap().unwrap();
+    let base64_save = general_purpose::STANDARD.encode(emulator.save());
+    local_storage.set_item(&save_key(emulator), &base64_save).unwrap();
+}
+
+fn load_save(emulator: &mut GBEmu) {
+    let local_storage = window().unwrap().local_storage().unwrap().unwrap();
+    match local_storage.get_item(save_key(emulator).as_str()).unwrap() {
+        Some(base64_save) => {
+            if let Err(err) = emulator.load_save(&general_purpose::STANDARD.decode(base64_save).unwrap()) {
+                console::log_1(&format!("Could not load save file: {}", err).into());
+            }
+        }
+        None => console::log_1(&"Could not find save file".into()),
+    }
+}
+
 fn key_status_change(state: &mut EmuState, event: &KeyboardEvent, is_down: bool) {
     event.prevent_default();
     match event.code().as_str() {
@@ -47,10 +246,10 @@ fn key_status_change(state: &mut EmuState, event: &KeyboardEvent, is_down: bool)
 }
 
 #[wasm_bindgen]
-pub fn start(rom: &[u8]) {
+pub fn start(rom: &[u8], fast_boot: bool) -> Result<WebEmu, JsValue> {
     // Init emulator
-    let mut emulator = GBEmu::new(&rom, false);
-    let savekey = format!("{} - {}", emulator.rom_checksum(), emulator.rom_title());
+    let emulator = GBEmu::new(&rom, false).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    let emulator = Rc::new(RefCell::new(emulator));
     let (lcdw, lcdh) = (lcd::LCDW * SCALE, lcd::LCDH * SCALE);
     let state = Rc::new(RefCell::new(EmuState {
         speed: 1,
@@ -58,6 +257,12 @@ pub fn start(rom: &[u8]) {
         switch_shader: None,
         rewind: false,
         joypad: Joypad::default(),
+        audio_sample_size: AUDIO_SAMPLE_SIZE,
+        audio_max_delay: AUDIO_MAX_DELAY,
+        paused: false,
+        touch_held: 0,
+        touch_pressed: 0,
+        fast_boot,
     }));
 
     // Init window and canvas
@@ -66,7 +271,7 @@ pub fn start(rom: &[u8]) {
     let local_storage = window().unwrap().local_storage().unwrap().unwrap();
     let canvas: HtmlCanvasElement = document.query_selector("canvas").unwrap().unwrap().dyn_into().unwrap();
     let context: CanvasRenderingContext2d = canvas.get_context("2d").unwrap().unwrap().dyn_into().unwrap();
-    document.set_title(emulator.rom_title().as_str());
+    document.set_title(emulator.borrow().rom_title().as_str());
     canvas.set_width(lcdw as u32);
     canvas.set_height(lcdh as u32);
     let mut image_data = vec![0; lcdw * lcdh * 4];
@@ -98,48 +303,55 @@ pub fn start(rom: &[u8]) {
     on_key_up.forget();
 
     // Load save file if present
-    match local_storage.get_item(savekey.as_str()).unwrap() {
-        Some(base64_save) => emulator.load_save(&general_purpose::STANDARD.decode(base64_save).unwrap()),
-        None => console::log_1(&"Could not find save file".into()),
-    }
+    load_save(&mut emulator.borrow_mut());
 
     // Restore last used palette
     match local_storage.get_item(PALETTE_IDX_KEY).unwrap() {
-        Some(palette_idx) => emulator.set_palette(palette_idx.parse().unwrap()),
+        Some(palette_idx) => emulator.borrow_mut().set_palette(palette_idx.parse().unwrap()),
         None => (),
     }
 
     // Restore last used shader
     match local_storage.get_item(SHADER_IDX_KEY).unwrap() {
-        Some(shader_idx) => emulator.set_shader(shader_idx.parse().unwrap()),
+        Some(shader_idx) => emulator.borrow_mut().set_shader(shader_idx.parse().unwrap()),
         None => (),
     }
 
+    let handle = WebEmu {
+        emulator: emulator.clone(),
+        state: state.clone(),
+    };
     let f = Rc::new(RefCell::new(None));
     let g = f.clone();
     let mut frame_count = 0;
     *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
         let mut state = state.borrow_mut();
+        let mut emulator = emulator.borrow_mut();
+        // Rewinding produces no audio, so there is nothing to fade out
+        if state.paused && (state.rewind || emulator.audio_faded_out()) {
+            request_animation_frame(f.borrow().as_ref().unwrap());
+            return;
+        }
         let frame_buffer = loop {
             // Update palette
             if let Some(switch) = state.switch_palette.take() {
                 let new_palette_idx = emulator.current_palette() + if switch { 1 } else { -1 };
                 emulator.set_palette(new_palette_idx);
-                local_storage.set_item(PALETTE_IDX_KEY, &new_palette_idx.to_string()).unwrap();
+                store_setting(PALETTE_IDX_KEY, emulator.current_palette());
             }
 
             // Update shader mode
             if let Some(switch) = state.switch_shader.take() {
                 let new_shader_idx = emulator.current_shader() + if switch { 1 } else { -1 };
                 emulator.set_shader(new_shader_idx);
-                local_storage.set_item(SHADER_IDX_KEY, &new_shader_idx.to_string()).unwrap();
+                store_setting(SHADER_IDX_KEY, emulator.current_shader());
             }
 
             // Play audio
             let audio_buffer = emulator.audio_buffer();
-            if audio_buffer.len() >= AUDIO_SAMPLE_SIZE {
+            if audio_buffer.len() >= state.audio_sample_size {
                 // Skip samples if the delay is too high
-                if audio_last_sample_end - audio_ctx.current_time() < AUDIO_MAX_DELAY {
+                if audio_last_sample_end - audio_ctx.current_time() < state.audio_max_delay {
                     // Copy buffer to left and right channels
                     let audio_queue = audio_ctx
                         .create_buffer(2, audio_buffer.len() as u32 / 2, apu::AUDIO_FREQUENCY as f32)
@@ -164,20 +376,24 @@ pub fn start(rom: &[u8]) {
                 emulator.clear_audio_buffer();
             }
 
+            // Skip all frames while the boot ROM is running, if fast boot is enabled
+            let booting = state.fast_boot && !emulator.boot_completed();
+
             let frame_buffer = if state.rewind && emulator.can_rewind() {
                 // Rewind state if requested
                 emulator.rewind()
             } else {
                 // Run emulator steps until a frame is available to be drawn
-                emulator.set_joypad(&state.joypad);
+                emulator.set_joypad(&touch_joypad(&state));
                 emulator.step()
             };
 
             // Return available frame
             if let Some(frame_buffer) = frame_buffer {
                 frame_count += 1;
+                state.touch_pressed = 0;
                 // Skip drawn frames to match the requested speed
-                if frame_count % state.speed == 0 {
+                if !booting && frame_count % state.speed == 0 {
                     break Some(frame_buffer);
                 }
             }
@@ -190,13 +406,13 @@ pub fn start(rom: &[u8]) {
         let image_data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&image_data), lcdw as u32, lcdh as u32).unwrap();
         context.put_image_data(&image_data, 0.0, 0.0).unwrap();
 
-        // Save RAM content to file every 60 frames (~1s)
-        if frame_count % 60 == 0 {
-            let base64_save = general_purpose::STANDARD.encode(emulator.save());
-            local_storage.set_item(&savekey, &base64_save).unwrap();
+        // Save RAM content to file every 60 frames (~1s), if it changed
+        if frame_count % 60 == 0 && emulator.ram_dirty() {
+            store_save(&mut emulator);
         }
         request_animation_frame(f.borrow().as_ref().unwrap());
     }) as Box<dyn FnMut()>));
 
     request_animation_frame(g.borrow().as_ref().unwrap());
+    Ok(handle)
 }